@@ -0,0 +1,272 @@
+//! asynchronous.rs
+//!
+//! Tokio-based client for the apcupsd NIS, behind the `async` feature. It
+//! speaks the same protocol as the blocking client and reports the same
+//! errors, but doesn't support SOCKS5 proxies.
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpSocket, TcpStream};
+use tracing::{info_span, Instrument};
+
+use crate::client::{is_closed, BUFFER_SIZE, MAX_RESPONSE_SIZE};
+use crate::protocol::{decode, ends_whole, is_complete, parse_lines, Anomalies, CMD_STATUS};
+use crate::{ApcAccessError, ConnectOptions, RequestStats, ResponseSize};
+
+/// Look up the host's addresses, the preferred family first.
+async fn resolve(host: &str, port: u16, options: &ConnectOptions) -> Result<Vec<SocketAddr>, ApcAccessError> {
+    let lookup = tokio::net::lookup_host((host, port));
+    let resolved = match options.resolve_timeout {
+        Some(limit) => tokio::time::timeout(limit, lookup).await.unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("looking up {}:{} took longer than {:?}", host, port, limit),
+            ))
+        }),
+        None => lookup.await,
+    };
+    let mut addrs: Vec<SocketAddr> = resolved.map_err(ApcAccessError::Dns)?.collect();
+    options.sort(&mut addrs);
+    if addrs.is_empty() {
+        return Err(ApcAccessError::Dns(std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("no addresses found for {}:{}", host, port),
+        )));
+    }
+    Ok(addrs)
+}
+
+/// Resolve the host and connect to the first address that accepts.
+async fn connect(host: &str, port: u16, timeout: Duration, options: &ConnectOptions) -> Result<TcpStream, ApcAccessError> {
+    if options.proxy.is_some() {
+        return Err(ApcAccessError::IoError(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "SOCKS5 proxies are only supported by the blocking client",
+        )));
+    }
+    let addrs = options.reachable(resolve(host, port, options).await?)?;
+
+    let mut last_error = ApcAccessError::ConnectTimeout;
+    for addr in &addrs {
+        let socket = options.socket(addr).and_then(|socket| {
+            socket.set_nonblocking(true)?;
+            Ok(TcpSocket::from_std_stream(socket.into()))
+        });
+        let connected = match socket {
+            Ok(socket) => tokio::time::timeout(timeout, socket.connect(*addr)).await,
+            Err(e) => Ok(Err(e)),
+        };
+        match connected {
+            Ok(Ok(stream)) => return Ok(stream),
+            Ok(Err(e)) => last_error = ApcAccessError::IoError(e),
+            Err(_) => last_error = ApcAccessError::ConnectTimeout,
+        }
+    }
+    Err(last_error)
+}
+
+/// Send the status command on an open connection and read the response.
+/// Returns the records, how the response strayed from the framing, and
+/// its size in bytes.
+async fn request(stream: &mut TcpStream, timeout: Duration) -> Result<(Vec<String>, Anomalies, usize), ApcAccessError> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let incomplete = || format!("no complete response within {} seconds", timeout.as_secs());
+    tokio::time::timeout_at(deadline, stream.write_all(CMD_STATUS))
+        .await
+        .map_err(|_| ApcAccessError::IoError(std::io::ErrorKind::TimedOut.into()))??;
+
+    // As in the blocking client, the whole response has to arrive within
+    // the timeout and completeness is checked on everything received so far.
+    let mut buffer = Vec::new();
+    let mut buf = [0u8; BUFFER_SIZE];
+    loop {
+        let n = match tokio::time::timeout_at(deadline, stream.read(&mut buf)).await {
+            Ok(read) => read.map_err(ApcAccessError::from_read)?,
+            Err(_) if buffer.is_empty() => return Err(ApcAccessError::ReadTimeout),
+            Err(_) if ends_whole(&buffer) => break,
+            Err(_) => return Err(ApcAccessError::truncated(&buffer, incomplete())),
+        };
+        if n == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&buf[..n]);
+        if buffer.len() > MAX_RESPONSE_SIZE {
+            return Err(ApcAccessError::truncated(
+                &buffer,
+                format!("response exceeds {} bytes", MAX_RESPONSE_SIZE),
+            ));
+        }
+        if is_complete(&buffer) {
+            break;
+        }
+    }
+
+    if buffer.is_empty() {
+        return Err(ApcAccessError::EmptyResponse);
+    }
+    let (lines, anomalies) = decode(&buffer)?;
+    Ok((lines, anomalies, buffer.len()))
+}
+
+/// Polls one apcupsd NIS from async code, optionally keeping the connection
+/// open between polls.
+pub struct AsyncNisClient {
+    pub host: String,
+    pub port: u16,
+    /// Timeout in seconds
+    pub timeout: u64,
+    /// Keep the connection open and re-send the status command on it
+    pub persistent: bool,
+    pub options: ConnectOptions,
+    stream: Option<TcpStream>,
+    stats: RequestStats,
+}
+
+impl AsyncNisClient {
+    pub fn new(host: &str, port: u16, timeout: u64, persistent: bool) -> Self {
+        AsyncNisClient {
+            host: host.to_string(),
+            port,
+            timeout,
+            persistent,
+            options: ConnectOptions::default(),
+            stream: None,
+            stats: RequestStats::default(),
+        }
+    }
+
+    /// Stats of the last request, successful or not
+    pub fn last_request(&self) -> RequestStats {
+        self.stats
+    }
+
+    /// Connect to the apcupsd NIS and request its status records, one line
+    /// each. A kept-open connection that the server has closed in the
+    /// meantime is replaced transparently.
+    pub async fn get(&mut self) -> Result<Vec<String>, ApcAccessError> {
+        let start = Instant::now();
+        self.stats = RequestStats::default();
+        let result = self.exchange().await.map(|(lines, anomalies, bytes)| {
+            self.stats.anomalies = anomalies;
+            self.stats.response = Some(ResponseSize {
+                bytes,
+                records: lines.len(),
+            });
+            lines
+        });
+        self.stats.total = start.elapsed();
+        result
+    }
+
+    async fn exchange(&mut self) -> Result<(Vec<String>, Anomalies, usize), ApcAccessError> {
+        let timeout = Duration::from_secs(self.timeout);
+        if self.stream.is_some() && self.moved().await {
+            tracing::debug!("{} resolves to other addresses now, reconnecting", self.host);
+            self.stream = None;
+        }
+        if let Some(mut stream) = self.stream.take() {
+            match request(&mut stream, timeout).instrument(info_span!("read")).await {
+                Ok(response) => {
+                    self.stream = Some(stream);
+                    return Ok(response);
+                }
+                Err(e) if is_closed(&e) => tracing::debug!("NIS connection to {} was closed, reconnecting", self.host),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let connecting = Instant::now();
+        let connected = connect(&self.host, self.port, timeout, &self.options)
+            .instrument(info_span!("connect", port = self.port))
+            .await;
+        self.stats.connect = Some(connecting.elapsed());
+        let mut stream = connected?;
+        let response = request(&mut stream, timeout).instrument(info_span!("read")).await?;
+        if self.persistent {
+            self.stream = Some(stream);
+        }
+        Ok(response)
+    }
+
+    /// Whether the host of a kept-open connection no longer resolves to the
+    /// address it's connected to, e.g. after a DHCP lease or DNS failover.
+    /// A failed lookup keeps the connection.
+    async fn moved(&self) -> bool {
+        let Some(peer) = self.stream.as_ref().and_then(|stream| stream.peer_addr().ok()) else {
+            return false;
+        };
+        match resolve(&self.host, self.port, &self.options).await {
+            Ok(addrs) => !addrs.contains(&peer),
+            Err(e) => {
+                tracing::debug!("Keeping the connection to {}, failed to look it up again: {}", self.host, e);
+                false
+            }
+        }
+    }
+
+    /// Fetch and parse the status.
+    pub async fn fetch_stats(&mut self, strip_units: bool) -> Result<BTreeMap<String, String>, ApcAccessError> {
+        let lines = self.get().await?;
+        Ok(info_span!("parse").in_scope(|| parse_lines(lines, strip_units)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// Serve one connection, answering each request with the next response
+    fn serve(responses: &'static [&'static [u8]]) -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            for response in responses {
+                let mut request = [0u8; CMD_STATUS.len()];
+                stream.read_exact(&mut request).unwrap();
+                stream.write_all(response).unwrap();
+            }
+            std::thread::sleep(Duration::from_secs(2));
+        });
+        port
+    }
+
+    #[test]
+    fn test_get_truncated() {
+        let port = serve(&[b"\x00\x12STATUS   : ONBATT\n\x00\x18APC "]);
+        let mut client = AsyncNisClient::new("127.0.0.1", port, 1, false);
+        match block_on(client.get()) {
+            Err(ApcAccessError::Protocol { partial, truncated, .. }) => {
+                assert!(truncated);
+                assert_eq!(partial, vec!["STATUS   : ONBATT"]);
+            }
+            other => panic!("expected a truncated response, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_persistent_client() {
+        const RESPONSE: &[u8] = b"\x00\x12STATUS   : ONLINE\n\x00\x00";
+        let port = serve(&[RESPONSE, RESPONSE]);
+        let mut client = AsyncNisClient::new("127.0.0.1", port, 2, true);
+        block_on(async {
+            for connects in [true, false] {
+                assert_eq!(client.fetch_stats(false).await.unwrap()["STATUS"], "ONLINE");
+                assert_eq!(client.last_request().connect.is_some(), connects);
+            }
+        });
+        assert_eq!(client.last_request().response, Some(ResponseSize { bytes: 22, records: 1 }));
+    }
+}
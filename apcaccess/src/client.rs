@@ -0,0 +1,564 @@
+//! client.rs
+//!
+//! Blocking client for the apcupsd NIS.
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tracing::info_span;
+
+use crate::protocol::{decode, ends_whole, is_complete, parse_lines, Anomalies, CMD_STATUS};
+use crate::socks5::Socks5Proxy;
+use crate::ApcAccessError;
+
+/// Buffer size for reading from socket
+pub(crate) const BUFFER_SIZE: usize = 1024;
+
+/// Largest response accepted; a full status is usually under 2 KiB
+pub(crate) const MAX_RESPONSE_SIZE: usize = 64 * 1024;
+
+/// Which of a host's addresses are tried first
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AddressPreference {
+    /// In the order the resolver returns them
+    #[default]
+    System,
+    Ipv4,
+    Ipv6,
+}
+
+/// How outbound connections to apcupsd are made
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOptions {
+    /// Local address to connect from, for apcupsd's NISIP access control on
+    /// multi-homed hosts
+    pub source_address: Option<IpAddr>,
+    /// Network interface to connect through (Linux only)
+    pub interface: Option<String>,
+    /// SOCKS5 proxy to tunnel the connection through
+    pub proxy: Option<Socks5Proxy>,
+    /// Longest a host name lookup may take, as long as the system's
+    /// resolver allows if unset
+    pub resolve_timeout: Option<Duration>,
+    pub prefer: AddressPreference,
+}
+
+impl ConnectOptions {
+    /// Read `NIS_SOURCE_ADDRESS`, `NIS_INTERFACE`, `NIS_RESOLVE_TIMEOUT`,
+    /// `NIS_PREFER_FAMILY` and the `NIS_SOCKS5_*` proxy settings.
+    pub fn from_env() -> Self {
+        let source_address = std::env::var("NIS_SOURCE_ADDRESS").ok().and_then(|a| match a.parse() {
+            Ok(address) => Some(address),
+            Err(_) => {
+                tracing::error!("Ignoring invalid NIS_SOURCE_ADDRESS {:?}", a);
+                None
+            }
+        });
+        let resolve_timeout = std::env::var("NIS_RESOLVE_TIMEOUT").ok().and_then(|t| {
+            match t.trim_end_matches('s').parse::<f64>().ok().filter(|s| *s > 0.0 && s.is_finite()) {
+                Some(seconds) => Some(Duration::from_secs_f64(seconds)),
+                None => {
+                    tracing::error!("Ignoring invalid NIS_RESOLVE_TIMEOUT {:?}", t);
+                    None
+                }
+            }
+        });
+        let prefer = match std::env::var("NIS_PREFER_FAMILY").unwrap_or_default().to_lowercase().as_str() {
+            "" => AddressPreference::System,
+            "ipv4" | "4" => AddressPreference::Ipv4,
+            "ipv6" | "6" => AddressPreference::Ipv6,
+            other => {
+                tracing::error!("Ignoring invalid NIS_PREFER_FAMILY {:?}, expected ipv4 or ipv6", other);
+                AddressPreference::System
+            }
+        };
+        ConnectOptions {
+            source_address,
+            interface: std::env::var("NIS_INTERFACE").ok().filter(|i| !i.is_empty()),
+            proxy: Socks5Proxy::from_env(),
+            resolve_timeout,
+            prefer,
+        }
+    }
+
+    /// Look up an address, giving up after the resolve timeout. The lookup
+    /// runs on a thread of its own, which is left to finish in the
+    /// background if it takes too long.
+    pub(crate) fn resolve<A>(&self, addr: A) -> Result<Vec<SocketAddr>, ApcAccessError>
+    where
+        A: ToSocketAddrs + std::fmt::Debug + Send + 'static,
+    {
+        let description = format!("{:?}", addr);
+        let resolved = match self.resolve_timeout {
+            None => addr.to_socket_addrs().map(Iterator::collect),
+            Some(timeout) => {
+                let (sender, receiver) = mpsc::channel();
+                thread::spawn(move || {
+                    let _ = sender.send(addr.to_socket_addrs().map(Iterator::collect::<Vec<_>>));
+                });
+                receiver.recv_timeout(timeout).unwrap_or_else(|_| {
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        format!("looking up {} took longer than {:?}", description, timeout),
+                    ))
+                })
+            }
+        };
+        let mut addrs: Vec<SocketAddr> = resolved.map_err(ApcAccessError::Dns)?;
+        if addrs.is_empty() {
+            return Err(ApcAccessError::Dns(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("no addresses found for {}", description),
+            )));
+        }
+        self.sort(&mut addrs);
+        Ok(addrs)
+    }
+
+    /// Put the preferred family first, keeping the resolver's order otherwise
+    pub(crate) fn sort(&self, addrs: &mut [SocketAddr]) {
+        match self.prefer {
+            AddressPreference::System => {}
+            AddressPreference::Ipv4 => addrs.sort_by_key(|addr| !addr.is_ipv4()),
+            AddressPreference::Ipv6 => addrs.sort_by_key(|addr| !addr.is_ipv6()),
+        }
+    }
+
+    /// An unconnected socket for `addr`, bound to the configured source
+    /// address and interface.
+    pub(crate) fn socket(&self, addr: &SocketAddr) -> std::io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(*addr), Type::STREAM, Some(Protocol::TCP))?;
+        if let Some(source) = self.source_address {
+            socket.bind(&SocketAddr::new(source, 0).into())?;
+        }
+        if let Some(interface) = &self.interface {
+            #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+            socket.bind_device(Some(interface.as_bytes()))?;
+            #[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("binding to interface {} is only supported on Linux", interface),
+            ));
+        }
+        Ok(socket)
+    }
+
+    /// Only addresses of the source address' family can be reached from
+    /// it. Fails if none of them is.
+    pub(crate) fn reachable(&self, addrs: Vec<SocketAddr>) -> Result<Vec<SocketAddr>, ApcAccessError> {
+        let Some(source) = self.source_address else {
+            return Ok(addrs);
+        };
+        let reachable: Vec<_> = addrs.into_iter().filter(|addr| source.is_ipv4() == addr.is_ipv4()).collect();
+        if reachable.is_empty() {
+            return Err(ApcAccessError::IoError(std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                format!("no address of the family of source address {}", source),
+            )));
+        }
+        Ok(reachable)
+    }
+
+    /// Connect to one address, from the configured source if any.
+    fn connect(&self, addr: &SocketAddr, timeout: Duration) -> std::io::Result<TcpStream> {
+        if self.source_address.is_none() && self.interface.is_none() {
+            return TcpStream::connect_timeout(addr, timeout);
+        }
+        let socket = self.socket(addr)?;
+        socket.connect_timeout(&(*addr).into(), timeout)?;
+        Ok(socket.into())
+    }
+}
+
+/// Resolve the host and connect to the first address that accepts. With a
+/// proxy, the proxy is connected to instead and resolves the host itself.
+fn connect(host: &str, port: u16, timeout: Duration, options: &ConnectOptions) -> Result<TcpStream, ApcAccessError> {
+    if let Some(proxy) = &options.proxy {
+        let mut stream = connect_direct(options.resolve(proxy.address.clone())?, timeout, options)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        proxy.connect(&mut stream, host, port).map_err(|e| match e.kind() {
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => ApcAccessError::ConnectTimeout,
+            _ => ApcAccessError::IoError(e),
+        })?;
+        return Ok(stream);
+    }
+    connect_direct(options.resolve((host.to_string(), port))?, timeout, options)
+}
+
+fn connect_direct(addrs: Vec<SocketAddr>, timeout: Duration, options: &ConnectOptions) -> Result<TcpStream, ApcAccessError> {
+    let mut last_error = ApcAccessError::ConnectTimeout;
+    for addr in &options.reachable(addrs)? {
+        match options.connect(addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => last_error = ApcAccessError::ConnectTimeout,
+            Err(e) => last_error = ApcAccessError::IoError(e),
+        }
+    }
+    Err(last_error)
+}
+
+/// Send the status command on an open connection and read the response.
+/// Returns the records, how the response strayed from the framing, and
+/// its size in bytes.
+fn request(stream: &mut TcpStream, timeout: Duration) -> Result<(Vec<String>, Anomalies, usize), ApcAccessError> {
+    stream.set_write_timeout(Some(timeout))?;
+
+    // Send the status command
+    stream.write_all(CMD_STATUS)?;
+
+    // Read until the response is complete. Records may be split across reads
+    // arbitrarily, so completeness is checked on everything received so far.
+    // The whole response has to arrive within the timeout, not each read.
+    // Servers that never send the terminating record are read until the
+    // timeout, and whole records received by then are the response.
+    let deadline = Instant::now() + timeout;
+    let mut buffer = Vec::new();
+    let mut buf = [0u8; BUFFER_SIZE];
+
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            if ends_whole(&buffer) {
+                break;
+            }
+            return Err(ApcAccessError::truncated(
+                &buffer,
+                format!("no complete response within {} seconds", timeout.as_secs()),
+            ));
+        }
+        stream.set_read_timeout(Some(remaining))?;
+        let n = match stream.read(&mut buf).map_err(ApcAccessError::from_read) {
+            Ok(n) => n,
+            Err(ApcAccessError::ReadTimeout) if ends_whole(&buffer) => break,
+            Err(ApcAccessError::ReadTimeout) if !buffer.is_empty() => {
+                return Err(ApcAccessError::truncated(
+                    &buffer,
+                    format!("no complete response within {} seconds", timeout.as_secs()),
+                ));
+            }
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&buf[..n]);
+        if buffer.len() > MAX_RESPONSE_SIZE {
+            return Err(ApcAccessError::truncated(
+                &buffer,
+                format!("response exceeds {} bytes", MAX_RESPONSE_SIZE),
+            ));
+        }
+
+        if is_complete(&buffer) {
+            break;
+        }
+    }
+
+    if buffer.is_empty() {
+        return Err(ApcAccessError::EmptyResponse);
+    }
+    let (lines, anomalies) = decode(&buffer)?;
+    Ok((lines, anomalies, buffer.len()))
+}
+
+/// How the last request went
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestStats {
+    /// Time to connect, unless a kept-open connection was reused
+    pub connect: Option<Duration>,
+    /// Time for the whole request, including connecting
+    pub total: Duration,
+    /// Size of the response, if a complete one was received
+    pub response: Option<ResponseSize>,
+    /// How the response strayed from the NIS framing
+    pub anomalies: Anomalies,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseSize {
+    pub bytes: usize,
+    pub records: usize,
+}
+
+/// Polls one apcupsd NIS, optionally keeping the connection open between
+/// polls.
+pub struct NisClient {
+    pub host: String,
+    pub port: u16,
+    /// Timeout in seconds
+    pub timeout: u64,
+    /// Keep the connection open and re-send the status command on it
+    pub persistent: bool,
+    pub options: ConnectOptions,
+    stream: Option<TcpStream>,
+    stats: RequestStats,
+}
+
+impl NisClient {
+    pub fn new(host: &str, port: u16, timeout: u64, persistent: bool) -> Self {
+        NisClient {
+            host: host.to_string(),
+            port,
+            timeout,
+            persistent,
+            options: ConnectOptions::default(),
+            stream: None,
+            stats: RequestStats::default(),
+        }
+    }
+
+    /// Stats of the last request, successful or not
+    pub fn last_request(&self) -> RequestStats {
+        self.stats
+    }
+
+    /// Connect to the apcupsd NIS and request its status records, one line
+    /// each. A kept-open connection that the server
+    /// has closed in the meantime is replaced transparently.
+    pub fn get(&mut self) -> Result<Vec<String>, ApcAccessError> {
+        let start = Instant::now();
+        self.stats = RequestStats::default();
+        let result = self.exchange().map(|(lines, anomalies, bytes)| {
+            self.stats.anomalies = anomalies;
+            self.stats.response = Some(ResponseSize {
+                bytes,
+                records: lines.len(),
+            });
+            lines
+        });
+        self.stats.total = start.elapsed();
+        result
+    }
+
+    fn exchange(&mut self) -> Result<(Vec<String>, Anomalies, usize), ApcAccessError> {
+        let timeout = Duration::from_secs(self.timeout);
+        if self.stream.is_some() && self.moved() {
+            tracing::debug!("{} resolves to other addresses now, reconnecting", self.host);
+            self.stream = None;
+        }
+        if let Some(mut stream) = self.stream.take() {
+            match info_span!("read").in_scope(|| request(&mut stream, timeout)) {
+                Ok(response) => {
+                    self.stream = Some(stream);
+                    return Ok(response);
+                }
+                Err(e) if is_closed(&e) => tracing::debug!("NIS connection to {} was closed, reconnecting", self.host),
+                Err(e) => return Err(e),
+            }
+        }
+
+        let connecting = Instant::now();
+        let connected = info_span!("connect", port = self.port)
+            .in_scope(|| connect(&self.host, self.port, timeout, &self.options));
+        self.stats.connect = Some(connecting.elapsed());
+        let mut stream = connected?;
+        let response = info_span!("read").in_scope(|| request(&mut stream, timeout))?;
+        if self.persistent {
+            self.stream = Some(stream);
+        }
+        Ok(response)
+    }
+
+    /// Whether the host of a kept-open connection no longer resolves to the
+    /// address it's connected to, e.g. after a DHCP lease or DNS failover.
+    /// A failed lookup keeps the connection. Through a proxy, the proxy
+    /// resolves the host, so the connection is kept too.
+    fn moved(&self) -> bool {
+        let Some(peer) = self.stream.as_ref().and_then(|stream| stream.peer_addr().ok()) else {
+            return false;
+        };
+        if self.options.proxy.is_some() {
+            return false;
+        }
+        match self.options.resolve((self.host.clone(), self.port)) {
+            Ok(addrs) => !addrs.contains(&peer),
+            Err(e) => {
+                tracing::debug!("Keeping the connection to {}, failed to look it up again: {}", self.host, e);
+                false
+            }
+        }
+    }
+
+    /// Fetch and parse the status.
+    pub fn fetch_stats(&mut self, strip_units: bool) -> Result<BTreeMap<String, String>, ApcAccessError> {
+        let lines = self.get()?;
+        Ok(info_span!("parse").in_scope(|| parse_lines(lines, strip_units)))
+    }
+}
+
+/// Whether an error on a reused connection means the server closed it
+pub(crate) fn is_closed(err: &ApcAccessError) -> bool {
+    match err {
+        ApcAccessError::EmptyResponse => true,
+        ApcAccessError::IoError(e) => matches!(
+            e.kind(),
+            std::io::ErrorKind::BrokenPipe | std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::ConnectionAborted
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(host: &str, port: u16, timeout: u64) -> Result<Vec<String>, ApcAccessError> {
+        NisClient::new(host, port, timeout, false).get()
+    }
+
+    /// Serve one connection with the given response, then close it
+    fn serve(response: &'static [u8], linger: Duration) -> u16 {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; CMD_STATUS.len()];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(response).unwrap();
+            std::thread::sleep(linger);
+        });
+        port
+    }
+
+    #[test]
+    fn test_get_errors() {
+        let port = serve(b"", Duration::ZERO);
+        assert!(matches!(get("127.0.0.1", port, 5), Err(ApcAccessError::EmptyResponse)));
+
+        let port = serve(b"\x00\x1bAPC      : 001,036,0876\n", Duration::ZERO);
+        let err = get("127.0.0.1", port, 5).unwrap_err();
+        assert_eq!(err.kind(), "protocol");
+
+        let port = serve(b"", Duration::from_secs(3));
+        assert_eq!(get("127.0.0.1", port, 1).unwrap_err().kind(), "read_timeout");
+
+        let err = get("apcupsd.invalid", 3551, 1).unwrap_err();
+        assert_eq!(err.kind(), "dns");
+        assert!(std::error::Error::source(&err).is_some());
+    }
+
+    #[test]
+    fn test_get_truncated() {
+        // One complete record, then the server stalls mid-record
+        let port = serve(b"\x00\x12STATUS   : ONBATT\n\x00\x18APC ", Duration::from_secs(3));
+        match get("127.0.0.1", port, 1) {
+            Err(ApcAccessError::Protocol { partial, truncated, .. }) => {
+                assert!(truncated);
+                assert_eq!(partial, vec!["STATUS   : ONBATT"]);
+            }
+            other => panic!("expected a truncated response, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_persistent_client() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            // Two requests on the first connection, then it's closed
+            for requests in [2, 1] {
+                let (mut stream, _) = listener.accept().unwrap();
+                for _ in 0..requests {
+                    let mut request = [0u8; CMD_STATUS.len()];
+                    stream.read_exact(&mut request).unwrap();
+                    stream.write_all(b"\x00\x12STATUS   : ONLINE\n\x00\x00").unwrap();
+                }
+            }
+        });
+
+        let mut client = NisClient::new("127.0.0.1", port, 2, true);
+        // Only the first request and the one after the close connect
+        for connects in [true, false, true] {
+            assert_eq!(client.fetch_stats(false).unwrap()["STATUS"], "ONLINE");
+            assert_eq!(client.last_request().connect.is_some(), connects);
+        }
+        assert_eq!(client.last_request().response, Some(ResponseSize { bytes: 22, records: 1 }));
+    }
+
+    #[test]
+    fn test_resolve() {
+        let mut options = ConnectOptions::default();
+        let addrs = options.resolve(("127.0.0.1".to_string(), 3551)).unwrap();
+        assert_eq!(addrs, vec!["127.0.0.1:3551".parse().unwrap()]);
+
+        let mut addrs: Vec<SocketAddr> = vec!["[::1]:3551".parse().unwrap(), "127.0.0.1:3551".parse().unwrap()];
+        options.sort(&mut addrs);
+        assert!(addrs[0].is_ipv6());
+        options.prefer = AddressPreference::Ipv4;
+        options.sort(&mut addrs);
+        assert!(addrs[0].is_ipv4());
+        options.prefer = AddressPreference::Ipv6;
+        options.sort(&mut addrs);
+        assert!(addrs[0].is_ipv6());
+
+        options.resolve_timeout = Some(Duration::from_secs(5));
+        assert_eq!(options.resolve("127.0.0.1:3551".to_string()).unwrap().len(), 1);
+        assert_eq!(options.resolve(("no such host.".to_string(), 3551)).unwrap_err().kind(), "dns");
+    }
+
+    #[test]
+    fn test_source_address() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let peer = std::thread::spawn(move || {
+            let (mut stream, peer) = listener.accept().unwrap();
+            let mut request = [0u8; CMD_STATUS.len()];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(b"\x00\x00").unwrap();
+            peer
+        });
+
+        let mut client = NisClient::new("127.0.0.1", port, 2, false);
+        client.options.source_address = Some("127.0.0.2".parse().unwrap());
+        client.get().unwrap();
+        assert_eq!(peer.join().unwrap().ip().to_string(), "127.0.0.2");
+
+        client.options.source_address = Some("::1".parse().unwrap());
+        let err = client.get().unwrap_err();
+        assert!(matches!(&err, ApcAccessError::IoError(e) if e.kind() == std::io::ErrorKind::AddrNotAvailable), "{}", err);
+    }
+
+    #[test]
+    fn test_get_split_records() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; CMD_STATUS.len()];
+            stream.read_exact(&mut request).unwrap();
+            for chunk in [&b"\x00"[..], b"\x12STATUS ", b"  : ONBATT\n\x00", b"\x00"] {
+                stream.write_all(chunk).unwrap();
+                stream.flush().unwrap();
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            // Keep the connection open, the terminator alone must end the read
+            std::thread::sleep(Duration::from_secs(3));
+        });
+        assert_eq!(get("127.0.0.1", port, 2).unwrap(), vec!["STATUS   : ONBATT"]);
+    }
+
+    #[test]
+    fn test_get_unterminated() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; CMD_STATUS.len()];
+            stream.read_exact(&mut request).unwrap();
+            // CRLF line endings and no terminating record, nor a close
+            stream.write_all(b"\x00\x13STATUS   : ONLINE\r\n").unwrap();
+            std::thread::sleep(Duration::from_secs(3));
+        });
+
+        let mut client = NisClient::new("127.0.0.1", port, 1, false);
+        assert_eq!(client.get().unwrap(), vec!["STATUS   : ONLINE"]);
+        let anomalies = client.last_request().anomalies;
+        assert_eq!(anomalies.kinds().collect::<Vec<_>>(), vec!["missing_eof", "crlf"]);
+    }
+}
@@ -0,0 +1,89 @@
+//! error.rs
+//!
+//! Errors from talking to apcupsd, classified so callers can tell a down
+//! daemon from a slow or misbehaving one.
+
+use crate::protocol::decode_frames;
+
+/// Error type for apcaccess operations
+#[derive(Debug)]
+pub enum ApcAccessError {
+    IoError(std::io::Error),
+    /// The host name couldn't be resolved
+    Dns(std::io::Error),
+    ConnectTimeout,
+    ReadTimeout,
+    /// The server sent something that isn't a valid NIS response. Any
+    /// complete records received are kept in `partial`, and `truncated` is
+    /// set if the response was cut short.
+    Protocol {
+        reason: String,
+        partial: Vec<String>,
+        truncated: bool,
+    },
+    /// The server closed the connection without sending anything
+    EmptyResponse,
+}
+
+impl ApcAccessError {
+    /// Short identifier used as a metric label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ApcAccessError::IoError(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => "connection_refused",
+            ApcAccessError::IoError(_) => "io",
+            ApcAccessError::Dns(_) => "dns",
+            ApcAccessError::ConnectTimeout => "connect_timeout",
+            ApcAccessError::ReadTimeout => "read_timeout",
+            ApcAccessError::Protocol { .. } => "protocol",
+            ApcAccessError::EmptyResponse => "empty_response",
+        }
+    }
+
+    /// A response that was cut short, keeping the records received so far
+    pub(crate) fn truncated(buffer: &[u8], reason: String) -> Self {
+        ApcAccessError::Protocol {
+            reason,
+            partial: decode_frames(buffer).records,
+            truncated: true,
+        }
+    }
+
+    /// Classify an error while reading the response
+    pub(crate) fn from_read(err: std::io::Error) -> Self {
+        match err.kind() {
+            std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut => ApcAccessError::ReadTimeout,
+            _ => ApcAccessError::IoError(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for ApcAccessError {
+    fn from(err: std::io::Error) -> Self {
+        ApcAccessError::IoError(err)
+    }
+}
+
+impl std::fmt::Display for ApcAccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApcAccessError::IoError(e) => write!(f, "IO Error: {}", e),
+            ApcAccessError::Dns(e) => write!(f, "DNS Error: {}", e),
+            ApcAccessError::ConnectTimeout => write!(f, "Connect Timeout"),
+            ApcAccessError::ReadTimeout => write!(f, "Read Timeout"),
+            ApcAccessError::Protocol { reason, partial, truncated: true } => {
+                write!(f, "Protocol Error: {} (truncated after {} records)", reason, partial.len())
+            }
+            ApcAccessError::Protocol { reason, .. } => write!(f, "Protocol Error: {}", reason),
+            ApcAccessError::EmptyResponse => write!(f, "Empty Response"),
+        }
+    }
+}
+
+impl std::error::Error for ApcAccessError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ApcAccessError::IoError(e) | ApcAccessError::Dns(e) => Some(e),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,29 @@
+//! lib.rs
+//!
+//! Client for the apcupsd Network Information Server (NIS), the protocol
+//! behind `apcaccess`: a blocking client, a tokio client behind the `async`
+//! feature, the parser for the status records, and `ApcStatus`, a typed view
+//! of them (serializable with the `serde` feature).
+//!
+//! ```no_run
+//! let mut client = apcaccess::NisClient::new("localhost", 3551, 10, false);
+//! let status = client.fetch_stats(true)?;
+//! println!("{}% charged", status["BCHARGE"]);
+//! # Ok::<(), apcaccess::ApcAccessError>(())
+//! ```
+
+#[cfg(feature = "async")]
+mod asynchronous;
+mod client;
+mod error;
+mod protocol;
+pub mod socks5;
+mod status;
+
+#[cfg(feature = "async")]
+pub use asynchronous::AsyncNisClient;
+pub use client::{AddressPreference, ConnectOptions, NisClient, RequestStats, ResponseSize};
+pub use error::ApcAccessError;
+pub use protocol::{decode, parse_hex, parse_lines, parse_number, split, strip_units_from_lines, Anomalies};
+pub use socks5::Socks5Proxy;
+pub use status::{ApcStatus, SelfTest, StatusFlag};
@@ -0,0 +1,476 @@
+//! protocol.rs
+//!
+//! The NIS wire format: the status request, the length-prefixed records of
+//! the response, and parsing them into key-value pairs.
+
+use std::collections::BTreeMap;
+
+use crate::ApcAccessError;
+
+/// Command to request status from apcupsd
+pub(crate) const CMD_STATUS: &[u8] = b"\x00\x06status";
+
+/// End-of-file marker
+pub(crate) const EOF: &str = "  \n\x00\x00";
+
+/// End-of-file marker of servers that end their lines with CRLF
+const EOF_CRLF: &str = "  \r\n\x00\x00";
+
+/// Separator for key-value pairs
+const SEP: char = ':';
+
+/// All supported units that can be stripped from values
+const ALL_UNITS: &[&str] = &[
+    "Minutes",
+    "Seconds",
+    "Percent",
+    "Volts",
+    "Watts",
+    "Amps",
+    "Hz",
+    "C",
+    "VA",
+    "Percent Load Capacity",
+];
+
+/// Keys of the temperatures, which apcupsd reports in Fahrenheit when set to
+const TEMPERATURE_KEYS: &[&str] = &["ITEMP", "AMBTEMP"];
+
+/// How a response strayed from the framing apcupsd uses. Forks and
+/// embedded re-implementations of the NIS get some of it wrong, and their
+/// responses are read anyway.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Anomalies {
+    /// The record lengths didn't add up, the response was split on the
+    /// end-of-file marker instead
+    pub unframed: bool,
+    /// The response ended without the terminating record
+    pub missing_eof: bool,
+    /// Lines ended with CRLF
+    pub crlf: bool,
+}
+
+impl Anomalies {
+    /// The anomalies seen, by name
+    pub fn kinds(&self) -> impl Iterator<Item = &'static str> {
+        [(self.unframed, "unframed"), (self.missing_eof, "missing_eof"), (self.crlf, "crlf")]
+            .into_iter()
+            .filter_map(|(seen, kind)| seen.then_some(kind))
+    }
+}
+
+/// Decode a complete response: length-prefixed records, falling back to the
+/// lenient EOF-marker split for servers whose framing doesn't add up, and
+/// accepting whole records without the terminating one once the server has
+/// stopped sending.
+pub fn decode(buffer: &[u8]) -> Result<(Vec<String>, Anomalies), ApcAccessError> {
+    let frames = decode_frames(buffer);
+    let mut anomalies = Anomalies {
+        crlf: frames.crlf,
+        ..Anomalies::default()
+    };
+    if frames.complete {
+        return Ok((frames.records, anomalies));
+    }
+    if ends_with_eof(buffer) {
+        let text = decode_text(buffer);
+        anomalies.unframed = true;
+        anomalies.crlf = text.contains("\r\n");
+        return Ok((split(&text), anomalies));
+    }
+    if ends_whole(buffer) {
+        anomalies.missing_eof = true;
+        return Ok((frames.records, anomalies));
+    }
+    Err(ApcAccessError::truncated(
+        buffer,
+        format!("connection closed after {} bytes, before the end-of-status record", buffer.len()),
+    ))
+}
+
+/// Whether a response has been received in full, by its terminating record
+/// or the end-of-file marker.
+pub(crate) fn is_complete(buffer: &[u8]) -> bool {
+    decode_frames(buffer).complete || ends_with_eof(buffer)
+}
+
+/// Whether a response without its terminating record ends on a record
+/// boundary, so it can be taken as it is once the server stops sending.
+pub(crate) fn ends_whole(buffer: &[u8]) -> bool {
+    let frames = decode_frames(buffer);
+    frames.whole && !frames.records.is_empty()
+}
+
+fn ends_with_eof(buffer: &[u8]) -> bool {
+    buffer.ends_with(EOF.as_bytes()) || buffer.ends_with(EOF_CRLF.as_bytes())
+}
+
+/// The records of a response, as far as it has been received
+pub(crate) struct Frames {
+    /// The complete records, without their line endings
+    pub records: Vec<String>,
+    /// Whether the terminating record has been received
+    pub complete: bool,
+    /// Whether the response ends on a record boundary
+    pub whole: bool,
+    /// Whether any record ended with CRLF
+    pub crlf: bool,
+}
+
+/// Decode NIS records: each is a 2-byte big-endian length followed by that
+/// many bytes, and a zero length ends the response.
+pub(crate) fn decode_frames(buffer: &[u8]) -> Frames {
+    let mut frames = Frames {
+        records: Vec::new(),
+        complete: false,
+        whole: false,
+        crlf: false,
+    };
+    let mut rest = buffer;
+    while let Some((length, tail)) = rest.split_first_chunk::<2>() {
+        let length = u16::from_be_bytes(*length) as usize;
+        if length == 0 {
+            frames.complete = true;
+            return frames;
+        }
+        if tail.len() < length {
+            return frames;
+        }
+        let (record, tail) = tail.split_at(length);
+        let record = decode_text(record);
+        frames.crlf |= record.ends_with("\r\n");
+        let record = record.trim_end_matches(['\r', '\n']);
+        if !record.is_empty() {
+            frames.records.push(record.to_string());
+        }
+        rest = tail;
+    }
+    frames.whole = rest.is_empty();
+    frames
+}
+
+/// Decode text from apcupsd. Localized builds may send Latin-1, e.g. in
+/// MODEL or UPSNAME, so anything that isn't valid UTF-8 is read as Latin-1,
+/// which maps every byte to the code point of the same value.
+fn decode_text(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+/// Split a raw response into lines, removing the length and newline chars.
+/// Without the EOF marker, properly framed records are read by their length,
+/// with or without the terminating record; anything else is split on the
+/// record separators, whatever the line endings.
+///
+/// # Arguments
+///
+/// * `raw_status` - The raw status string from the apcupsd server
+///
+/// # Returns
+///
+/// A vector of cleaned status lines
+pub fn split(raw_status: &str) -> Vec<String> {
+    // With the EOF marker, lengths that seem to add up are a coincidence
+    let raw = raw_status.as_bytes();
+    let frames = decode_frames(raw);
+    if !ends_with_eof(raw) && (frames.complete || ends_whole(raw)) {
+        return frames.records;
+    }
+
+    // Remove the EOF string, split status on the line endings (\x00), strip the
+    // length byte and newline chars off the beginning and end respectively.
+    let trimmed = raw_status
+        .strip_suffix(EOF)
+        .or_else(|| raw_status.strip_suffix(EOF_CRLF))
+        .unwrap_or(raw_status);
+
+    trimmed
+        .split('\x00')
+        .filter_map(|x| {
+            let mut chars = x.chars();
+            chars.next()?;
+            let line = chars.as_str().trim_end_matches(['\r', '\n']);
+            (!line.trim().is_empty()).then(|| line.to_string())
+        })
+        .collect()
+}
+
+/// Parse a number written with a decimal comma or thousands separators, as
+/// apcupsd builds for some locales print them, e.g. `230,4` or `1.234,5`.
+/// A lone separator is the decimal one, as apcupsd doesn't group
+/// thousands; several of one kind group them. Anything else that isn't
+/// digits and separators is not a number.
+pub fn parse_number(value: &str) -> Option<f64> {
+    let value = value.trim();
+    let digits = value.strip_prefix(['-', '+']).unwrap_or(value);
+    if !digits.bytes().any(|b| b.is_ascii_digit()) || !digits.bytes().all(|b| b.is_ascii_digit() || b == b'.' || b == b',') {
+        return None;
+    }
+    let (commas, dots) = (digits.matches(',').count(), digits.matches('.').count());
+    let (grouping, decimal) = match (commas, dots) {
+        (0, 0) => (None, None),
+        (1, 0) => (None, Some(',')),
+        (_, 0) => (Some(','), None),
+        (0, 1) => (None, Some('.')),
+        (0, _) => (Some('.'), None),
+        // Both, the last one is the decimal separator
+        _ if digits.rfind(',') > digits.rfind('.') => (Some('.'), Some(',')),
+        _ => (Some(','), Some('.')),
+    };
+    let (whole, fraction) = match decimal {
+        Some(decimal) => digits.rsplit_once(decimal)?,
+        None => (digits, "0"),
+    };
+    let all_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    let whole = match grouping {
+        Some(grouping) => {
+            let groups: Vec<&str> = whole.split(grouping).collect();
+            let grouped = (1..=3).contains(&groups[0].len()) && groups[1..].iter().all(|group| group.len() == 3);
+            if !grouped || !groups.iter().all(|group| all_digits(group)) {
+                return None;
+            }
+            groups.concat()
+        }
+        None => whole.to_string(),
+    };
+    if !all_digits(&whole) || !all_digits(fraction) {
+        return None;
+    }
+    let sign = if value.starts_with('-') { "-" } else { "" };
+    format!("{}{}.{}", sign, whole, fraction).parse().ok()
+}
+
+/// Parse a hexadecimal value such as `0x05000008 Status Flag`, as apcupsd
+/// reports STATFLAG and the REG1 to REG3 registers, ignoring the
+/// description after it.
+pub fn parse_hex(value: &str) -> Option<u64> {
+    let value = value.trim();
+    let hex = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X"))?;
+    let end = hex.find(|c: char| !c.is_ascii_hexdigit()).unwrap_or(hex.len());
+    if end == 0 || !hex[end..].chars().next().is_none_or(char::is_whitespace) {
+        return None;
+    }
+    u64::from_str_radix(&hex[..end], 16).ok()
+}
+
+/// Clean up status lines and return them as a BTreeMap.
+///
+/// # Arguments
+///
+/// * `lines` - The status lines from the apcupsd server
+/// * `strip_units` - Whether to strip units from the values, and write
+///   numbers with a decimal comma or thousands separators, and hexadecimal
+///   ones, the usual way
+///
+/// # Returns
+///
+/// A BTreeMap containing the parsed key-value pairs
+pub fn parse_lines(mut lines: Vec<String>, strip_units: bool) -> BTreeMap<String, String> {
+    if strip_units {
+        lines = strip_units_from_lines(&lines);
+    }
+
+    // Split each line on the SEP character, strip extraneous whitespace and
+    // create a BTreeMap out of the keys/values.
+    lines
+        .into_iter()
+        .filter_map(|line| {
+            let parts: Vec<&str> = line.splitn(2, SEP).collect();
+            if parts.len() == 2 {
+                let value = parts[1].trim();
+                let value = match (parse_number(value), parse_hex(value)) {
+                    (Some(number), _) if strip_units && (value.contains(',') || value.matches('.').count() > 1) => {
+                        number.to_string()
+                    }
+                    (_, Some(number)) if strip_units => number.to_string(),
+                    _ => value.to_string(),
+                };
+                Some((parts[0].trim().to_string(), value))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Removes all units from the ends of the lines. Temperatures in
+/// Fahrenheit, from UPSes set to report them so, become Celsius like the
+/// others, at full precision.
+///
+/// # Arguments
+///
+/// * `lines` - A slice of status lines
+///
+/// # Returns
+///
+/// A vector of lines with units stripped
+pub fn strip_units_from_lines(lines: &[String]) -> Vec<String> {
+    lines
+        .iter()
+        .map(|line| {
+            if let Some(stripped) = line.strip_suffix(" F")
+                && let Some((key, value)) = stripped.split_once(SEP)
+                && TEMPERATURE_KEYS.contains(&key.trim())
+                && let Some(fahrenheit) = parse_number(value)
+            {
+                return format!("{}{} {}", key, SEP, (fahrenheit - 32.0) * 5.0 / 9.0);
+            }
+            // Check each unit without allocating format string
+            for unit in ALL_UNITS {
+                if let Some(stripped) = line.strip_suffix(unit) {
+                    // Also strip the space before the unit
+                    if let Some(final_stripped) = stripped.strip_suffix(' ') {
+                        return final_stripped.to_string();
+                    }
+                }
+            }
+            // No unit found, return as-is
+            line.clone()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let raw_status = "\x001APC      : 001,036,0876\n\x00\x001STATUS   : ONLINE\n\x00  \n\x00\x00";
+        let lines = split(raw_status);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], "APC      : 001,036,0876");
+        assert_eq!(lines[1], "STATUS   : ONLINE");
+
+        // Without the EOF marker, and with CRLF line endings
+        let variant = "\x001APC      : 001,036,0876\r\n\x00\x001STATUS   : ONLINE\r\n\x00";
+        assert_eq!(split(variant), lines);
+        assert!(split("").is_empty());
+    }
+
+    #[test]
+    fn test_parse() {
+        let raw_status = "\x001APC      : 001,036,0876\n\x00\x001STATUS   : ONLINE\n\x00  \n\x00\x00";
+        let parsed = parse_lines(split(raw_status), false);
+        assert_eq!(parsed.get("APC"), Some(&"001,036,0876".to_string()));
+        assert_eq!(parsed.get("STATUS"), Some(&"ONLINE".to_string()));
+    }
+
+
+    #[test]
+    fn test_decode_frames() {
+        let response = b"\x00\x18APC      : 001,036,0876\n\x00\x12STATUS   : ONLINE\n\x00\x00";
+        let (lines, anomalies) = decode(response).unwrap();
+        assert_eq!(lines, vec!["APC      : 001,036,0876", "STATUS   : ONLINE"]);
+        assert_eq!(anomalies, Anomalies::default());
+
+        // Incomplete until the terminating record arrives, wherever reads split
+        for cut in 0..response.len() {
+            assert!(!is_complete(&response[..cut]));
+        }
+
+        // Wrong record length, but ends with the EOF marker
+        let legacy = b"\x00\x05STATUS   : ONLINE\n\x00  \n\x00\x00";
+        let (lines, anomalies) = decode(legacy).unwrap();
+        assert_eq!(lines, vec!["STATUS   : ONLINE"]);
+        assert_eq!(anomalies.kinds().collect::<Vec<_>>(), vec!["unframed"]);
+    }
+
+    #[test]
+    fn test_decode_variants() {
+        // CRLF line endings
+        let crlf = b"\x00\x13STATUS   : ONLINE\r\n\x00\x00";
+        let (lines, anomalies) = decode(crlf).unwrap();
+        assert_eq!(lines, vec!["STATUS   : ONLINE"]);
+        assert_eq!(anomalies.kinds().collect::<Vec<_>>(), vec!["crlf"]);
+
+        // No terminating record, once the server has closed the connection
+        let unterminated = b"\x00\x18APC      : 001,036,0876\n\x00\x12STATUS   : ONLINE\n";
+        let (lines, anomalies) = decode(unterminated).unwrap();
+        assert_eq!(lines, vec!["APC      : 001,036,0876", "STATUS   : ONLINE"]);
+        assert_eq!(anomalies.kinds().collect::<Vec<_>>(), vec!["missing_eof"]);
+        assert!(!is_complete(unterminated));
+
+        // Unframed, with CRLF and its EOF marker
+        let legacy = b"\x00\x05STATUS   : ONLINE\r\n\x00  \r\n\x00\x00";
+        assert!(is_complete(legacy));
+        let (lines, anomalies) = decode(legacy).unwrap();
+        assert_eq!(lines, vec!["STATUS   : ONLINE"]);
+        assert_eq!(anomalies.kinds().collect::<Vec<_>>(), vec!["unframed", "crlf"]);
+
+        // Cut off within a record is still an error
+        assert_eq!(decode(&unterminated[..30]).unwrap_err().kind(), "protocol");
+        assert_eq!(decode(b"").unwrap_err().kind(), "protocol");
+    }
+
+    #[test]
+    fn test_decode_latin1() {
+        assert_eq!(decode_text("UPSNAME  : Salle serveur n°2".as_bytes()), "UPSNAME  : Salle serveur n°2");
+        assert_eq!(decode_text(b"UPSNAME  : Salle serveur n\xb02"), "UPSNAME  : Salle serveur n°2");
+        assert_eq!(decode(b"\x00\x0fMODEL    : \xc9t\xe9\n\x00\x00").unwrap().0, vec!["MODEL    : Été"]);
+    }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number("230,4"), Some(230.4));
+        assert_eq!(parse_number("-0,5"), Some(-0.5));
+        assert_eq!(parse_number("1.234,5"), Some(1234.5));
+        assert_eq!(parse_number("1,234.5"), Some(1234.5));
+        assert_eq!(parse_number("1,234,567"), Some(1234567.0));
+        assert_eq!(parse_number("1.234.567"), Some(1234567.0));
+        assert_eq!(parse_number("120.0"), Some(120.0));
+        assert_eq!(parse_number("15"), Some(15.0));
+        // Not numbers: the APC field, dates, hex and text
+        assert_eq!(parse_number("001,036,0879"), None);
+        assert_eq!(parse_number("2024-06-01"), None);
+        assert_eq!(parse_number("0x05000008"), None);
+        assert_eq!(parse_number("1,2.3,4"), None);
+        assert_eq!(parse_number(","), None);
+        assert_eq!(parse_number("ONLINE"), None);
+
+        let stats = parse_lines(vec!["LINEV    : 230,4 Volts".to_string(), "APC      : 001,036,0879".to_string()], true);
+        assert_eq!(stats["LINEV"], "230.4");
+        assert_eq!(stats["APC"], "001,036,0879");
+        assert_eq!(parse_lines(vec!["LINEV    : 230,4 Volts".to_string()], false)["LINEV"], "230,4 Volts");
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(parse_hex("0x05000008 Status Flag"), Some(0x05000008));
+        assert_eq!(parse_hex("0x00 Register 1"), Some(0));
+        assert_eq!(parse_hex("0XFF"), Some(255));
+        assert_eq!(parse_hex("0x Bad"), None);
+        assert_eq!(parse_hex("0x12z"), None);
+        assert_eq!(parse_hex("05000008"), None);
+
+        let stats = parse_lines(vec!["STATFLAG : 0x05000008 Status Flag".to_string()], true);
+        assert_eq!(stats["STATFLAG"], "83886088");
+        assert_eq!(parse_lines(vec!["STATFLAG : 0x05000008 Status Flag".to_string()], false)["STATFLAG"], "0x05000008 Status Flag");
+    }
+
+    #[test]
+    fn test_strip_units() {
+        let lines = vec![
+            "LINEV    : 120.0 Volts".to_string(),
+            "LOADPCT  : 15.0 Percent".to_string(),
+            "BCHARGE  : 100.0 Percent".to_string(),
+            "TIMELEFT : 45.0 Minutes".to_string(),
+            "ITEMP    : 29.2 C".to_string(),
+            "ITEMP    : 86.0 F".to_string(),
+            "AMBTEMP  : 77.5 F".to_string(),
+            "BATTV    : 27.0 F".to_string(),
+        ];
+        let stripped = strip_units_from_lines(&lines);
+        assert_eq!(stripped[0], "LINEV    : 120.0");
+        assert_eq!(stripped[1], "LOADPCT  : 15.0");
+        assert_eq!(stripped[2], "BCHARGE  : 100.0");
+        assert_eq!(stripped[3], "TIMELEFT : 45.0");
+        assert_eq!(stripped[4], "ITEMP    : 29.2");
+        assert_eq!(stripped[5], "ITEMP    : 30");
+        assert_eq!(stripped[6], format!("AMBTEMP  : {}", 45.5 * 5.0 / 9.0));
+        // Only temperatures are converted
+        assert_eq!(stripped[7], "BATTV    : 27.0 F");
+    }
+}
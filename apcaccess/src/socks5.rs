@@ -0,0 +1,199 @@
+//! socks5.rs
+//!
+//! Minimal SOCKS5 client (RFC 1928, with RFC 1929 username/password
+//! authentication) for reaching apcupsd through a bastion host.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpStream};
+
+const VERSION: u8 = 5;
+const NO_AUTH: u8 = 0;
+const USER_PASS: u8 = 2;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CONNECT: u8 = 1;
+
+#[derive(Debug, Clone)]
+pub struct Socks5Proxy {
+    /// Proxy address as `host:port`
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl Socks5Proxy {
+    /// Read `NIS_SOCKS5_PROXY`, `NIS_SOCKS5_USERNAME` and
+    /// `NIS_SOCKS5_PASSWORD`. Returns `None` unless the proxy is set.
+    pub fn from_env() -> Option<Self> {
+        Some(Socks5Proxy {
+            address: std::env::var("NIS_SOCKS5_PROXY").ok().filter(|a| !a.is_empty())?,
+            username: std::env::var("NIS_SOCKS5_USERNAME").ok(),
+            password: std::env::var("NIS_SOCKS5_PASSWORD").ok(),
+        })
+    }
+
+    /// Ask the proxy, already connected on `stream`, to connect to the
+    /// target. Host names are resolved by the proxy.
+    pub fn connect(&self, stream: &mut TcpStream, host: &str, port: u16) -> std::io::Result<()> {
+        self.authenticate(stream)?;
+
+        let mut request = vec![VERSION, CONNECT, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) => {
+                let name = u8::try_from(host.len())
+                    .map_err(|_| Error::new(ErrorKind::InvalidInput, "host name too long for SOCKS5"))?;
+                request.push(3);
+                request.push(name);
+                request.extend_from_slice(host.as_bytes());
+            }
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(invalid("unexpected SOCKS version in reply"));
+        }
+        if reply[1] != 0 {
+            return Err(reply_error(reply[1]));
+        }
+        // Skip the bound address, which isn't needed
+        let skip = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0u8; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            _ => return Err(invalid("unknown address type in SOCKS reply")),
+        };
+        let mut bound = vec![0u8; skip + 2];
+        stream.read_exact(&mut bound)?;
+        Ok(())
+    }
+
+    fn authenticate(&self, stream: &mut TcpStream) -> std::io::Result<()> {
+        let credentials = self.username.as_deref().zip(self.password.as_deref());
+        if credentials.is_some() {
+            stream.write_all(&[VERSION, 2, NO_AUTH, USER_PASS])?;
+        } else {
+            stream.write_all(&[VERSION, 1, NO_AUTH])?;
+        }
+
+        let mut choice = [0u8; 2];
+        stream.read_exact(&mut choice)?;
+        match (choice[1], credentials) {
+            (NO_AUTH, _) => Ok(()),
+            (USER_PASS, Some((username, password))) => {
+                let mut request = vec![1];
+                for field in [username, password] {
+                    let len = u8::try_from(field.len())
+                        .map_err(|_| Error::new(ErrorKind::InvalidInput, "SOCKS5 credentials too long"))?;
+                    request.push(len);
+                    request.extend_from_slice(field.as_bytes());
+                }
+                stream.write_all(&request)?;
+                let mut status = [0u8; 2];
+                stream.read_exact(&mut status)?;
+                if status[1] != 0 {
+                    return Err(Error::new(ErrorKind::PermissionDenied, "SOCKS5 proxy rejected the credentials"));
+                }
+                Ok(())
+            }
+            (NO_ACCEPTABLE_METHOD, _) => Err(Error::new(
+                ErrorKind::PermissionDenied,
+                "SOCKS5 proxy accepted none of the authentication methods",
+            )),
+            _ => Err(invalid("SOCKS5 proxy chose an unsupported authentication method")),
+        }
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// Map a SOCKS5 reply code to an error like a direct connection would give
+fn reply_error(code: u8) -> Error {
+    match code {
+        2 => Error::new(ErrorKind::PermissionDenied, "SOCKS5 proxy: connection not allowed by ruleset"),
+        3 => Error::new(ErrorKind::NetworkUnreachable, "SOCKS5 proxy: network unreachable"),
+        4 => Error::new(ErrorKind::HostUnreachable, "SOCKS5 proxy: host unreachable"),
+        5 => Error::new(ErrorKind::ConnectionRefused, "SOCKS5 proxy: connection refused"),
+        6 => Error::new(ErrorKind::TimedOut, "SOCKS5 proxy: TTL expired"),
+        code => Error::other(format!("SOCKS5 proxy: request failed with code {}", code)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_connect_with_credentials() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 4];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [5, 2, NO_AUTH, USER_PASS]);
+            stream.write_all(&[5, USER_PASS]).unwrap();
+
+            let mut auth = [0u8; 11];
+            stream.read_exact(&mut auth).unwrap();
+            assert_eq!(&auth, b"\x01\x04user\x04pass");
+            stream.write_all(&[1, 0]).unwrap();
+
+            let mut request = [0u8; 4 + 1 + 10 + 2];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(&request[..5], &[5, CONNECT, 0, 3, 10]);
+            assert_eq!(&request[5..15], b"ups.remote");
+            assert_eq!(&request[15..], &3551u16.to_be_bytes());
+            stream.write_all(&[5, 0, 0, 1, 10, 0, 0, 1, 0x0d, 0xdf]).unwrap();
+        });
+
+        let proxy = Socks5Proxy {
+            address: address.clone(),
+            username: Some("user".to_string()),
+            password: Some("pass".to_string()),
+        };
+        let mut stream = TcpStream::connect(&address).unwrap();
+        proxy.connect(&mut stream, "ups.remote", 3551).unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0u8; 3];
+            stream.read_exact(&mut buf).unwrap();
+            stream.write_all(&[5, NO_AUTH]).unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            stream.write_all(&[5, 5, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+        });
+
+        let proxy = Socks5Proxy {
+            address: address.clone(),
+            username: None,
+            password: None,
+        };
+        let mut stream = TcpStream::connect(&address).unwrap();
+        let err = proxy.connect(&mut stream, "10.0.0.1", 3551).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+    }
+}
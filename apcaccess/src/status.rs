@@ -0,0 +1,360 @@
+//! status.rs
+//!
+//! Typed view of the status records, for callers that want numbers, durations
+//! and timestamps rather than the raw strings.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, NaiveDate};
+
+use crate::protocol::parse_hex;
+
+/// Keys that are mapped onto `ApcStatus` fields. Everything else is kept in
+/// `ApcStatus::other`.
+const TYPED_KEYS: &[&str] = &[
+    "APC", "DATE", "HOSTNAME", "VERSION", "UPSNAME", "CABLE", "DRIVER", "UPSMODE", "STARTTIME", "MODEL",
+    "STATUS", "LINEV", "LOADPCT", "BCHARGE", "TIMELEFT", "MBATTCHG", "MINTIMEL", "MAXTIME", "OUTPUTV",
+    "ITEMP", "BATTV", "LINEFREQ", "NOMOUTV", "NOMINV", "NOMBATTV", "NOMPOWER", "NOMAPNT", "HITRANS",
+    "LOTRANS", "ALARMDEL", "LASTXFER", "NUMXFERS", "XONBATT", "TONBATT", "CUMONBATT", "XOFFBATT",
+    "SELFTEST", "LASTSTEST", "SERIALNO", "BATTDATE", "FIRMWARE", "STATFLAG", "END APC",
+];
+
+/// One flag of the STATUS value
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum StatusFlag {
+    Calibration,
+    Trim,
+    Boost,
+    Online,
+    OnBattery,
+    Overload,
+    LowBattery,
+    ReplaceBattery,
+    NoBattery,
+    Slave,
+    SlaveDown,
+    CommLost,
+    ShuttingDown,
+    Other(String),
+}
+
+impl StatusFlag {
+    /// Split a STATUS value such as `ONBATT LOWBATT` into its flags.
+    /// `SHUTTING DOWN` is a single flag.
+    pub fn parse_all(status: &str) -> Vec<StatusFlag> {
+        let mut flags = Vec::new();
+        let mut words = status.split_whitespace().peekable();
+        while let Some(word) = words.next() {
+            flags.push(match word {
+                "CAL" => StatusFlag::Calibration,
+                "TRIM" => StatusFlag::Trim,
+                "BOOST" => StatusFlag::Boost,
+                "ONLINE" => StatusFlag::Online,
+                "ONBATT" => StatusFlag::OnBattery,
+                "OVERLOAD" => StatusFlag::Overload,
+                "LOWBATT" => StatusFlag::LowBattery,
+                "REPLACEBATT" => StatusFlag::ReplaceBattery,
+                "NOBATT" => StatusFlag::NoBattery,
+                "SLAVE" => StatusFlag::Slave,
+                "SLAVEDOWN" => StatusFlag::SlaveDown,
+                "COMMLOST" => StatusFlag::CommLost,
+                "SHUTTING" if words.peek() == Some(&"DOWN") => {
+                    words.next();
+                    StatusFlag::ShuttingDown
+                }
+                other => StatusFlag::Other(other.to_string()),
+            });
+        }
+        flags
+    }
+}
+
+/// Result of the last self-test
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SelfTest {
+    /// `OK`
+    Passed,
+    /// `BT`: the battery capacity was insufficient
+    FailedCapacity,
+    /// `NG`: failed, e.g. due to overload
+    Failed,
+    /// `WN`
+    Warning,
+    /// `IP`
+    InProgress,
+    /// `NO`: no test results available
+    NoResult,
+    Other(String),
+}
+
+impl SelfTest {
+    pub fn parse(value: &str) -> SelfTest {
+        match value.trim() {
+            "OK" => SelfTest::Passed,
+            "BT" => SelfTest::FailedCapacity,
+            "NG" => SelfTest::Failed,
+            "WN" => SelfTest::Warning,
+            "IP" => SelfTest::InProgress,
+            "NO" => SelfTest::NoResult,
+            other => SelfTest::Other(other.to_string()),
+        }
+    }
+}
+
+/// The status of a UPS, typed. Every field is optional since what apcupsd
+/// reports depends on the UPS and its driver. Voltages are in volts,
+/// percentages in 0-100 and temperatures in °C.
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ApcStatus {
+    pub upsname: Option<String>,
+    pub hostname: Option<String>,
+    pub model: Option<String>,
+    pub serialno: Option<String>,
+    pub firmware: Option<String>,
+    /// apcupsd version
+    pub version: Option<String>,
+    pub cable: Option<String>,
+    pub driver: Option<String>,
+    pub upsmode: Option<String>,
+    /// When apcupsd produced the status
+    pub date: Option<DateTime<FixedOffset>>,
+    /// When apcupsd started
+    pub starttime: Option<DateTime<FixedOffset>>,
+    pub status: Vec<StatusFlag>,
+    /// The status bits STATUS is made from, as reported in STATFLAG
+    pub statflag: Option<u64>,
+    pub linev: Option<f64>,
+    pub loadpct: Option<f64>,
+    pub bcharge: Option<f64>,
+    #[cfg_attr(feature = "serde", serde(with = "seconds"))]
+    pub timeleft: Option<Duration>,
+    /// Charge at which apcupsd shuts down
+    pub mbattchg: Option<f64>,
+    /// Runtime left at which apcupsd shuts down
+    #[cfg_attr(feature = "serde", serde(with = "seconds"))]
+    pub mintimel: Option<Duration>,
+    /// Time on battery after which apcupsd shuts down, zero if disabled
+    #[cfg_attr(feature = "serde", serde(with = "seconds"))]
+    pub maxtime: Option<Duration>,
+    pub outputv: Option<f64>,
+    pub itemp: Option<f64>,
+    pub battv: Option<f64>,
+    pub linefreq: Option<f64>,
+    pub nomoutv: Option<f64>,
+    pub nominv: Option<f64>,
+    pub nombattv: Option<f64>,
+    /// Nominal power in watts
+    pub nompower: Option<f64>,
+    /// Nominal apparent power in VA
+    pub nomapnt: Option<f64>,
+    pub hitrans: Option<f64>,
+    pub lotrans: Option<f64>,
+    #[cfg_attr(feature = "serde", serde(with = "seconds"))]
+    pub alarmdel: Option<Duration>,
+    /// Reason for the last transfer to battery
+    pub lastxfer: Option<String>,
+    pub numxfers: Option<u32>,
+    pub xonbatt: Option<DateTime<FixedOffset>>,
+    #[cfg_attr(feature = "serde", serde(with = "seconds"))]
+    pub tonbatt: Option<Duration>,
+    #[cfg_attr(feature = "serde", serde(with = "seconds"))]
+    pub cumonbatt: Option<Duration>,
+    pub xoffbatt: Option<DateTime<FixedOffset>>,
+    pub selftest: Option<SelfTest>,
+    pub laststest: Option<DateTime<FixedOffset>>,
+    pub battdate: Option<NaiveDate>,
+    /// Keys without a field, as reported
+    pub other: BTreeMap<String, String>,
+}
+
+/// The number at the start of a value, with or without its unit
+fn number<T: std::str::FromStr>(value: &str) -> Option<T> {
+    value.split_whitespace().next()?.parse().ok()
+}
+
+/// A duration in the unit apcupsd reports unless the value names its own
+fn duration(value: &str, default_seconds: f64) -> Option<Duration> {
+    let amount: f64 = number(value)?;
+    let scale = match value.split_whitespace().nth(1) {
+        Some("Minutes") => 60.0,
+        Some("Seconds") => 1.0,
+        _ => default_seconds,
+    };
+    Duration::try_from_secs_f64(amount * scale).ok()
+}
+
+/// A timestamp such as `2024-06-01 12:00:00 +0000`. `N/A` and older
+/// formats give `None`.
+fn timestamp(value: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_str(value.trim(), "%Y-%m-%d %H:%M:%S %z").ok()
+}
+
+fn date(value: &str) -> Option<NaiveDate> {
+    let value = value.trim();
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(value, "%m/%d/%y"))
+        .ok()
+}
+
+impl From<&BTreeMap<String, String>> for ApcStatus {
+    /// Convert parsed records, whether or not their units were stripped.
+    fn from(stats: &BTreeMap<String, String>) -> Self {
+        let text = |key: &str| stats.get(key).map(|v| v.trim().to_string()).filter(|v| !v.is_empty());
+        let float = |key: &str| stats.get(key).and_then(|v| number::<f64>(v));
+        let minutes = |key: &str| stats.get(key).and_then(|v| duration(v, 60.0));
+        let seconds = |key: &str| stats.get(key).and_then(|v| duration(v, 1.0));
+        let time = |key: &str| stats.get(key).and_then(|v| timestamp(v));
+        ApcStatus {
+            upsname: text("UPSNAME"),
+            hostname: text("HOSTNAME"),
+            model: text("MODEL"),
+            serialno: text("SERIALNO"),
+            firmware: text("FIRMWARE"),
+            version: text("VERSION"),
+            cable: text("CABLE"),
+            driver: text("DRIVER"),
+            upsmode: text("UPSMODE"),
+            date: time("DATE"),
+            starttime: time("STARTTIME"),
+            status: stats.get("STATUS").map(|s| StatusFlag::parse_all(s)).unwrap_or_default(),
+            statflag: stats.get("STATFLAG").and_then(|v| parse_hex(v).or_else(|| number(v))),
+            linev: float("LINEV"),
+            loadpct: float("LOADPCT"),
+            bcharge: float("BCHARGE"),
+            timeleft: minutes("TIMELEFT"),
+            mbattchg: float("MBATTCHG"),
+            mintimel: minutes("MINTIMEL"),
+            maxtime: seconds("MAXTIME"),
+            outputv: float("OUTPUTV"),
+            itemp: float("ITEMP"),
+            battv: float("BATTV"),
+            linefreq: float("LINEFREQ"),
+            nomoutv: float("NOMOUTV"),
+            nominv: float("NOMINV"),
+            nombattv: float("NOMBATTV"),
+            nompower: float("NOMPOWER"),
+            nomapnt: float("NOMAPNT"),
+            hitrans: float("HITRANS"),
+            lotrans: float("LOTRANS"),
+            alarmdel: seconds("ALARMDEL"),
+            lastxfer: text("LASTXFER"),
+            numxfers: stats.get("NUMXFERS").and_then(|v| number(v)),
+            xonbatt: time("XONBATT"),
+            tonbatt: seconds("TONBATT"),
+            cumonbatt: seconds("CUMONBATT"),
+            xoffbatt: time("XOFFBATT"),
+            selftest: stats.get("SELFTEST").map(|v| SelfTest::parse(v)),
+            laststest: time("LASTSTEST"),
+            battdate: stats.get("BATTDATE").and_then(|v| date(v)),
+            other: stats
+                .iter()
+                .filter(|(key, _)| !TYPED_KEYS.contains(&key.as_str()))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl From<BTreeMap<String, String>> for ApcStatus {
+    fn from(stats: BTreeMap<String, String>) -> Self {
+        ApcStatus::from(&stats)
+    }
+}
+
+impl ApcStatus {
+    pub fn has_flag(&self, flag: &StatusFlag) -> bool {
+        self.status.contains(flag)
+    }
+}
+
+/// Durations as fractional seconds
+#[cfg(feature = "serde")]
+mod seconds {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(duration) => serializer.serialize_some(&duration.as_secs_f64()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Option::<f64>::deserialize(deserializer)?
+            .map(|secs| Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_from_stats() {
+        let status = ApcStatus::from(stats(&[
+            ("UPSNAME", "rack1"),
+            ("STATUS", "ONBATT LOWBATT"),
+            ("STATFLAG", "0x05000050 Status Flag"),
+            ("LINEV", "0.0 Volts"),
+            ("BCHARGE", "9.0"),
+            ("TIMELEFT", "2.5 Minutes"),
+            ("MAXTIME", "0"),
+            ("NUMXFERS", "3"),
+            ("XONBATT", "2024-06-01 12:00:00 +0200"),
+            ("XOFFBATT", "N/A"),
+            ("SELFTEST", "BT"),
+            ("BATTDATE", "2023-01-15"),
+            ("ALARMDEL", "No alarm"),
+            ("STESTI", "336"),
+        ]));
+        assert_eq!(status.upsname.as_deref(), Some("rack1"));
+        assert_eq!(status.status, vec![StatusFlag::OnBattery, StatusFlag::LowBattery]);
+        assert!(status.has_flag(&StatusFlag::LowBattery));
+        assert_eq!(status.statflag, Some(0x05000050));
+        assert_eq!(ApcStatus::from(stats(&[("STATFLAG", "83886088")])).statflag, Some(0x05000008));
+        assert_eq!(status.linev, Some(0.0));
+        assert_eq!(status.bcharge, Some(9.0));
+        assert_eq!(status.timeleft, Some(Duration::from_secs(150)));
+        assert_eq!(status.maxtime, Some(Duration::ZERO));
+        assert_eq!(status.numxfers, Some(3));
+        assert_eq!(status.xonbatt.unwrap().to_rfc3339(), "2024-06-01T12:00:00+02:00");
+        assert_eq!(status.xoffbatt, None);
+        assert_eq!(status.selftest, Some(SelfTest::FailedCapacity));
+        assert_eq!(status.battdate, NaiveDate::from_ymd_opt(2023, 1, 15));
+        assert_eq!(status.alarmdel, None);
+        assert_eq!(status.other, stats(&[("STESTI", "336")]));
+    }
+
+    #[test]
+    fn test_status_flags() {
+        assert_eq!(
+            StatusFlag::parse_all("SHUTTING DOWN ONBATT"),
+            vec![StatusFlag::ShuttingDown, StatusFlag::OnBattery]
+        );
+        assert_eq!(StatusFlag::parse_all("CAL FOO"), vec![StatusFlag::Calibration, StatusFlag::Other("FOO".to_string())]);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let status = ApcStatus::from(stats(&[("STATUS", "ONLINE"), ("TIMELEFT", "45.5"), ("SELFTEST", "OK")]));
+        let json = serde_json::to_value(&status).unwrap();
+        assert_eq!(json["status"], serde_json::json!(["online"]));
+        assert_eq!(json["timeleft"], 2730.0);
+        assert_eq!(json["selftest"], "passed");
+        assert_eq!(serde_json::from_value::<ApcStatus>(json).unwrap(), status);
+    }
+}
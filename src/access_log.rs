@@ -0,0 +1,205 @@
+//! access_log.rs
+//!
+//! Optional access log of the HTTP requests, to see who scrapes the
+//! exporter and how often.
+
+use std::net::IpAddr;
+use std::time::Duration;
+
+use tracing::info;
+
+use crate::server;
+
+/// Template used unless `ACCESS_LOG_FORMAT` is set
+pub const DEFAULT_FORMAT: &str = "{remote} \"{method} {path}\" {status} {latency_ms}ms \"{user_agent}\"";
+
+/// An IP network such as `10.0.0.0/8`, or a single address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    pub fn parse(s: &str) -> Option<Self> {
+        let (address, prefix) = match s.trim().split_once('/') {
+            Some((address, prefix)) => (address.parse().ok()?, Some(prefix.parse().ok()?)),
+            None => (s.trim().parse().ok()?, None),
+        };
+        let bits = if matches!(address, IpAddr::V4(_)) { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(bits);
+        if prefix > bits {
+            return None;
+        }
+        Some(Network { address, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let mask = |bits: u32| if self.prefix == 0 { 0 } else { u128::MAX << (bits - self.prefix as u32) };
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = mask(32) as u32;
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = mask(128);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The parts of a request that are logged once it has been served
+pub struct Request {
+    remote: String,
+    method: String,
+    path: String,
+    user_agent: String,
+}
+
+pub struct AccessLog {
+    format: String,
+    /// Proxies whose `X-Forwarded-For` is believed
+    trusted_proxies: Vec<Network>,
+}
+
+impl AccessLog {
+    /// Build the access log from `ACCESS_LOG`, `ACCESS_LOG_FORMAT` and
+    /// `TRUSTED_PROXIES`. Returns `None` unless `ACCESS_LOG` is enabled.
+    pub fn from_env() -> Option<Self> {
+        let enabled = std::env::var("ACCESS_LOG")
+            .map(|v| matches!(v.as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        if !enabled {
+            return None;
+        }
+        let trusted_proxies = std::env::var("TRUSTED_PROXIES")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|n| !n.trim().is_empty())
+            .filter_map(|n| {
+                let network = Network::parse(n);
+                if network.is_none() {
+                    tracing::warn!("Ignoring invalid TRUSTED_PROXIES entry {:?}", n);
+                }
+                network
+            })
+            .collect();
+        Some(AccessLog {
+            format: std::env::var("ACCESS_LOG_FORMAT").unwrap_or_else(|_| DEFAULT_FORMAT.to_string()),
+            trusted_proxies,
+        })
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|n| n.contains(ip))
+    }
+
+    /// The client address. Behind trusted proxies, that is the last address
+    /// in `X-Forwarded-For` that isn't one of them.
+    fn client(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> String {
+        let Some(peer) = peer else {
+            return "-".to_string();
+        };
+        if !self.is_trusted(peer) {
+            return peer.to_string();
+        }
+        let mut client = peer;
+        for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+            match hop.trim().parse() {
+                Ok(ip) => {
+                    client = ip;
+                    if !self.is_trusted(ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        client.to_string()
+    }
+
+    pub fn request(&self, request: &server::Request) -> Request {
+        Request {
+            remote: self.client(request.peer, request.forwarded_for.as_deref()),
+            method: request.method.clone(),
+            path: match &request.query {
+                Some(query) => format!("{}?{}", request.path, query),
+                None => request.path.clone(),
+            },
+            user_agent: request.user_agent.clone().unwrap_or_else(|| "-".to_string()),
+        }
+    }
+
+    fn render(&self, request: &Request, status: u16, latency: Duration) -> String {
+        self.format
+            .replace("{remote}", &request.remote)
+            .replace("{method}", &request.method)
+            .replace("{path}", &request.path)
+            .replace("{status}", &status.to_string())
+            .replace("{latency_ms}", &format!("{:.1}", latency.as_secs_f64() * 1000.0))
+            .replace("{user_agent}", &request.user_agent)
+    }
+
+    /// Log a served request, with the parts as fields for structured output.
+    pub fn log(&self, request: &Request, status: u16, latency: Duration) {
+        info!(
+            target: "access_log",
+            remote = %request.remote,
+            method = %request.method,
+            path = %request.path,
+            status,
+            latency_ms = latency.as_millis() as u64,
+            "{}",
+            self.render(request, status, latency)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn access_log(trusted: &[&str]) -> AccessLog {
+        AccessLog {
+            format: DEFAULT_FORMAT.to_string(),
+            trusted_proxies: trusted.iter().map(|n| Network::parse(n).unwrap()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_network() {
+        let network = Network::parse("10.0.0.0/8").unwrap();
+        assert!(network.contains("10.1.2.3".parse().unwrap()));
+        assert!(network.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!network.contains("192.168.1.1".parse().unwrap()));
+        assert!(Network::parse("::1").unwrap().contains("::1".parse().unwrap()));
+        assert_eq!(Network::parse("10.0.0.0/33"), None);
+    }
+
+    #[test]
+    fn test_client() {
+        let log = access_log(&["10.0.0.0/8"]);
+        let peer = |s: &str| Some(s.parse().unwrap());
+        assert_eq!(log.client(peer("192.168.1.5"), Some("1.2.3.4")), "192.168.1.5");
+        assert_eq!(log.client(peer("10.0.0.1"), Some("1.2.3.4, 10.0.0.2")), "1.2.3.4");
+        assert_eq!(log.client(peer("10.0.0.1"), Some("6.6.6.6, 1.2.3.4")), "1.2.3.4");
+        assert_eq!(log.client(peer("10.0.0.1"), None), "10.0.0.1");
+        assert_eq!(log.client(None, None), "-");
+    }
+
+    #[test]
+    fn test_render() {
+        let request = Request {
+            remote: "192.168.1.5".to_string(),
+            method: "GET".to_string(),
+            path: "/metrics".to_string(),
+            user_agent: "Prometheus/2.53.0".to_string(),
+        };
+        assert_eq!(
+            access_log(&[]).render(&request, 200, Duration::from_micros(1500)),
+            "192.168.1.5 \"GET /metrics\" 200 1.5ms \"Prometheus/2.53.0\""
+        );
+    }
+}
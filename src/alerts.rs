@@ -0,0 +1,356 @@
+//! alerts.rs
+//!
+//! Threshold alerts from the `[[alerts]]` entries of the config file, such
+//! as `timeleft < 10m` or `battery_age > 3y`, evaluated against every poll.
+//! Each is exported as `apcupsd_alert{name,severity}`, and raises `alert`
+//! and `alert_resolved` events as it starts and stops firing, so the
+//! notifications follow the same thresholds as the dashboards.
+
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+use apcaccess::ApcStatus;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+use crate::events::{Event, EventKind, Severities, Severity};
+use crate::snapshot::Snapshot;
+
+/// The values a condition can compare, those that are durations in seconds
+/// marked as such. `battery_age` is the time since `BATTDATE`.
+const FIELDS: &[(&str, bool)] = &[
+    ("linev", false),
+    ("loadpct", false),
+    ("bcharge", false),
+    ("timeleft", true),
+    ("mbattchg", false),
+    ("mintimel", true),
+    ("maxtime", true),
+    ("outputv", false),
+    ("itemp", false),
+    ("battv", false),
+    ("linefreq", false),
+    ("nomoutv", false),
+    ("nominv", false),
+    ("nombattv", false),
+    ("nompower", false),
+    ("nomapnt", false),
+    ("hitrans", false),
+    ("lotrans", false),
+    ("alarmdel", true),
+    ("numxfers", false),
+    ("tonbatt", true),
+    ("cumonbatt", true),
+    ("battery_age", true),
+];
+
+/// One `[[alerts]]` entry of the config file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    /// The `name` label of the alert's series
+    pub name: String,
+    /// When the alert fires, e.g. `loadpct > 85`
+    pub expr: Condition,
+    /// Overrides the severity of `alert` in `[severities]`
+    pub severity: Option<Severity>,
+}
+
+/// An alert's state for a target after a poll
+#[derive(Debug, PartialEq)]
+pub struct AlertState<'a> {
+    pub name: &'a str,
+    pub severity: Severity,
+    pub firing: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Op {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Op {
+    /// Longer operators first, so `<=` isn't taken for `<`
+    const ALL: &[(&str, Op)] =
+        &[("<=", Op::Le), (">=", Op::Ge), ("==", Op::Eq), ("!=", Op::Ne), ("<", Op::Lt), (">", Op::Gt)];
+
+    fn holds(&self, value: f64, threshold: f64) -> bool {
+        match self {
+            Op::Lt => value < threshold,
+            Op::Le => value <= threshold,
+            Op::Gt => value > threshold,
+            Op::Ge => value >= threshold,
+            Op::Eq => value == threshold,
+            Op::Ne => value != threshold,
+        }
+    }
+}
+
+/// A comparison of one value of a poll with a threshold, such as
+/// `timeleft < 10m`. Durations take the units `s`, `m`, `h`, `d` and `y`
+/// and are compared in seconds; other values are plain numbers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Condition {
+    field: &'static str,
+    op: Op,
+    threshold: f64,
+    /// As written, for logs and notifications
+    text: String,
+}
+
+impl std::str::FromStr for Condition {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let start = text
+            .find(['<', '>', '=', '!'])
+            .ok_or_else(|| format!("{:?} has no comparison, e.g. \"loadpct > 85\"", text))?;
+        let (field, rest) = text.split_at(start);
+        let field = field.trim().to_lowercase();
+        let &(field, duration) = FIELDS
+            .iter()
+            .find(|(name, _)| *name == field)
+            .ok_or_else(|| format!("unknown value {:?} in {:?}", field, text))?;
+        let &(symbol, op) = Op::ALL
+            .iter()
+            .find(|(symbol, _)| rest.starts_with(symbol))
+            .ok_or_else(|| format!("invalid comparison in {:?}", text))?;
+        let threshold = rest[symbol.len()..].trim().trim_end_matches('%');
+        let (number, unit) = threshold.split_at(threshold.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(threshold.len()));
+        let multiplier = match (unit, duration) {
+            ("", _) | ("s", true) => 1.0,
+            ("m", true) => 60.0,
+            ("h", true) => 3600.0,
+            ("d", true) => 86_400.0,
+            ("y", true) => 365.0 * 86_400.0,
+            (_, true) => return Err(format!("unknown unit {:?} in {:?}", unit, text)),
+            (_, false) => return Err(format!("{} is not a duration, but {:?} has a unit", field, text)),
+        };
+        let number: f64 = number.trim().parse().map_err(|_| format!("invalid threshold in {:?}", text))?;
+        Ok(Condition {
+            field,
+            op,
+            threshold: number * multiplier,
+            text: text.trim().to_string(),
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Condition {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl std::fmt::Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Condition {
+    /// Whether the condition holds for a poll, never if the UPS doesn't
+    /// report the value.
+    pub fn holds(&self, status: &ApcStatus, now: SystemTime) -> bool {
+        value(self.field, status, now).is_some_and(|value| self.op.holds(value, self.threshold))
+    }
+}
+
+/// The value of a field of `FIELDS`, durations in seconds
+fn value(field: &str, status: &ApcStatus, now: SystemTime) -> Option<f64> {
+    let seconds = |d: Option<std::time::Duration>| d.map(|d| d.as_secs_f64());
+    match field {
+        "linev" => status.linev,
+        "loadpct" => status.loadpct,
+        "bcharge" => status.bcharge,
+        "timeleft" => seconds(status.timeleft),
+        "mbattchg" => status.mbattchg,
+        "mintimel" => seconds(status.mintimel),
+        "maxtime" => seconds(status.maxtime),
+        "outputv" => status.outputv,
+        "itemp" => status.itemp,
+        "battv" => status.battv,
+        "linefreq" => status.linefreq,
+        "nomoutv" => status.nomoutv,
+        "nominv" => status.nominv,
+        "nombattv" => status.nombattv,
+        "nompower" => status.nompower,
+        "nomapnt" => status.nomapnt,
+        "hitrans" => status.hitrans,
+        "lotrans" => status.lotrans,
+        "alarmdel" => seconds(status.alarmdel),
+        "numxfers" => status.numxfers.map(f64::from),
+        "tonbatt" => seconds(status.tonbatt),
+        "cumonbatt" => seconds(status.cumonbatt),
+        "battery_age" => {
+            let today = DateTime::<Utc>::from(now).date_naive();
+            status.battdate.map(|battdate| (today - battdate).num_seconds() as f64)
+        }
+        _ => None,
+    }
+}
+
+/// The alerts of the config file, and which of them fire for each target
+#[derive(Default)]
+pub struct Alerts {
+    rules: Vec<AlertRule>,
+    severities: Severities,
+    /// The names of the firing alerts, by target. Targets are only in it
+    /// once evaluated.
+    firing: HashMap<String, HashSet<String>>,
+}
+
+impl Alerts {
+    pub fn new(rules: Vec<AlertRule>, severities: Severities) -> Self {
+        Alerts {
+            rules,
+            severities,
+            firing: HashMap::new(),
+        }
+    }
+
+    pub fn rules(&self) -> &[AlertRule] {
+        &self.rules
+    }
+
+    /// Evaluate every alert against a poll. Returns the state of each, and
+    /// the events of those that started or stopped firing since the
+    /// target's last poll. The first poll of a target only establishes the
+    /// baseline, as for the power events.
+    pub fn evaluate(&mut self, snapshot: &Snapshot) -> (Vec<AlertState<'_>>, Vec<Event>) {
+        if self.rules.is_empty() {
+            return (Vec::new(), Vec::new());
+        }
+        let status = ApcStatus::from(&snapshot.stats);
+        let known = self.firing.contains_key(&snapshot.host);
+        let firing = self.firing.entry(snapshot.host.clone()).or_default();
+        let mut events = Vec::new();
+        let results = self
+            .rules
+            .iter()
+            .map(|rule| {
+                let fires = rule.expr.holds(&status, snapshot.timestamp);
+                let changed = match fires {
+                    true => firing.insert(rule.name.clone()),
+                    false => firing.remove(&rule.name),
+                };
+                let severity = self.severities.of_alert(rule, &snapshot.host);
+                if changed && known {
+                    let kind = if fires { EventKind::Alert } else { EventKind::AlertResolved };
+                    events.push(Event {
+                        kind,
+                        previous_status: snapshot.stats.get("STATUS").cloned().unwrap_or_default(),
+                        snapshot: snapshot.clone(),
+                        severity: match fires {
+                            true => severity,
+                            false => self.severities.of(kind, &snapshot.host),
+                        },
+                        test: false,
+                        alert: Some(rule.clone()),
+                    });
+                }
+                AlertState { name: &rule.name, severity, firing: fires }
+            })
+            .collect();
+        (results, events)
+    }
+
+    /// Forget a target that is no longer polled
+    pub fn remove(&mut self, target: &str) {
+        self.firing.remove(target);
+    }
+
+    /// Alerts of the same name would share their series
+    pub fn validate(rules: &[AlertRule]) -> Result<(), String> {
+        let mut names = HashSet::new();
+        match rules.iter().find(|rule| !names.insert(&rule.name)) {
+            Some(rule) => Err(format!("alert {:?} is defined twice", rule.name)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn snapshot(stats: &[(&str, &str)]) -> Snapshot {
+        let stats = stats.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let mut snapshot = Snapshot::new("ups1", stats);
+        // 2024-06-01
+        snapshot.timestamp = UNIX_EPOCH + Duration::from_secs(1717243200);
+        snapshot
+    }
+
+    #[test]
+    fn test_conditions() {
+        let condition: Condition = "timeleft < 10m".parse().unwrap();
+        assert_eq!((condition.field, condition.op, condition.threshold), ("timeleft", Op::Lt, 600.0));
+        assert_eq!("LOADPCT>=85%".parse::<Condition>().unwrap().threshold, 85.0);
+        assert_eq!("battery_age > 3y".parse::<Condition>().unwrap().threshold, 3.0 * 365.0 * 86_400.0);
+        assert!("timeleft".parse::<Condition>().is_err());
+        assert!("flux > 1".parse::<Condition>().is_err());
+        assert!("loadpct > 85m".parse::<Condition>().is_err());
+        assert!("timeleft < 10w".parse::<Condition>().is_err());
+        assert!("timeleft < soon".parse::<Condition>().is_err());
+
+        let status = ApcStatus::from(&snapshot(&[("TIMELEFT", "8.5"), ("BATTDATE", "2020-05-01")]).stats);
+        let now = UNIX_EPOCH + Duration::from_secs(1717243200);
+        assert!(condition.holds(&status, now));
+        assert!("battery_age > 3y".parse::<Condition>().unwrap().holds(&status, now));
+        assert!(!"loadpct > 85".parse::<Condition>().unwrap().holds(&status, now));
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let rules: Vec<AlertRule> = toml::from_str::<BTreeMap<String, Vec<AlertRule>>>(
+            r#"
+            [[alerts]]
+            name = "low_runtime"
+            expr = "timeleft < 10m"
+            severity = "critical"
+
+            [[alerts]]
+            name = "overload"
+            expr = "loadpct > 85"
+            "#,
+        )
+        .unwrap()
+        .remove("alerts")
+        .unwrap();
+        assert_eq!(rules[1].severity, None);
+        let mut alerts = Alerts::new(rules, Severities::default());
+        let firing = |states: Vec<AlertState>| states.iter().map(|s| s.firing).collect::<Vec<_>>();
+
+        // Already firing on the first poll, but without an event
+        let (states, events) = alerts.evaluate(&snapshot(&[("TIMELEFT", "5.0"), ("LOADPCT", "40.0")]));
+        assert_eq!(states[0], AlertState { name: "low_runtime", severity: Severity::Critical, firing: true });
+        assert_eq!(states[1].severity, Severity::Warning);
+        assert!(events.is_empty());
+
+        let (states, events) = alerts.evaluate(&snapshot(&[("TIMELEFT", "30.0"), ("LOADPCT", "90.0")]));
+        assert_eq!(firing(states), vec![false, true]);
+        let events: Vec<_> =
+            events.iter().map(|e| (e.kind, e.alert.as_ref().unwrap().name.as_str(), e.severity)).collect();
+        assert_eq!(
+            events,
+            vec![
+                (EventKind::AlertResolved, "low_runtime", Severity::Info),
+                (EventKind::Alert, "overload", Severity::Warning)
+            ]
+        );
+
+        let (_, events) = alerts.evaluate(&snapshot(&[("TIMELEFT", "30.0"), ("LOADPCT", "91.0")]));
+        assert!(events.is_empty());
+
+        let duplicate = vec![alerts.rules()[0].clone(), alerts.rules()[0].clone()];
+        assert!(Alerts::validate(&duplicate).is_err());
+    }
+}
@@ -0,0 +1,105 @@
+//! apcupsd_conf.rs
+//!
+//! The settings of an apcupsd on the same host, from its apcupsd.conf, so
+//! the exporter finds its NIS without any configuration of its own.
+
+use std::io::ErrorKind;
+
+use tracing::{debug, warn};
+
+/// Where apcupsd reads its configuration from by default
+pub const DEFAULT_PATH: &str = "/etc/apcupsd/apcupsd.conf";
+
+/// The directives of apcupsd.conf the exporter uses
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DaemonConfig {
+    /// NISIP, the address the NIS listens on
+    pub nis_ip: Option<String>,
+    /// NISPORT
+    pub nis_port: Option<u16>,
+    /// UPSNAME
+    pub upsname: Option<String>,
+}
+
+impl DaemonConfig {
+    /// Read the file at `APCUPSD_CONF`, or at the default path. Without
+    /// apcupsd on the host, the default path doesn't exist, which is
+    /// expected; `APCUPSD_CONF=` stops it being read at all.
+    pub fn from_env() -> Option<Self> {
+        let (path, explicit) = match std::env::var("APCUPSD_CONF") {
+            Ok(path) if path.is_empty() => return None,
+            Ok(path) => (path, true),
+            Err(_) => (DEFAULT_PATH.to_string(), false),
+        };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                let config = DaemonConfig::parse(&text);
+                debug!("Read {}: {:?}", path, config);
+                Some(config)
+            }
+            Err(e) if explicit || e.kind() != ErrorKind::NotFound => {
+                warn!("Failed to read {}: {}", path, e);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Parse the directives, one per line with its value after whitespace.
+    /// Lines starting with `#` are comments, and anything unknown or
+    /// invalid is left out.
+    pub fn parse(text: &str) -> Self {
+        let mut config = DaemonConfig::default();
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (directive, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim().trim_matches('"');
+            if value.is_empty() {
+                continue;
+            }
+            match directive.to_uppercase().as_str() {
+                "NISIP" => config.nis_ip = Some(value.to_string()),
+                "NISPORT" => config.nis_port = value.parse().ok(),
+                "UPSNAME" => config.upsname = Some(value.to_string()),
+                _ => {}
+            }
+        }
+        config
+    }
+
+    /// The host to reach the NIS at, NISIP unless it listens on every
+    /// address
+    pub fn host(&self) -> Option<&str> {
+        self.nis_ip.as_deref().filter(|ip| !matches!(*ip, "0.0.0.0" | "::"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = DaemonConfig::parse(
+            "## apcupsd.conf v1.1 ##\n\
+             UPSNAME rack-ups\n\
+             UPSCABLE usb\n\
+             # NISPORT 3552\n\
+             NETSERVER on\n\
+             NISIP 127.0.0.1\n\
+             NISPORT 3552\n",
+        );
+        assert_eq!(config.upsname.as_deref(), Some("rack-ups"));
+        assert_eq!(config.host(), Some("127.0.0.1"));
+        assert_eq!(config.nis_port, Some(3552));
+
+        // The defaults apcupsd ships with: no name, listening everywhere
+        let config = DaemonConfig::parse("UPSNAME\nNISIP 0.0.0.0\nNISPORT 3551\n");
+        assert_eq!(config.upsname, None);
+        assert_eq!(config.host(), None);
+        assert_eq!(config.nis_port, Some(3551));
+        assert_eq!(DaemonConfig::parse("NISPORT nis\n").nis_port, None);
+    }
+}
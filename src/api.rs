@@ -0,0 +1,973 @@
+//! api.rs
+//!
+//! The exporter's routes: `/metrics`, the JSON management API under
+//! `/api/v1`, and operational endpoints under `/-/`. Handlers only see
+//! `server::Request`, so they are the same whichever HTTP server runs them.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prometheus::{Encoder, TextEncoder};
+use serde::Deserialize;
+
+use crate::config::{deserialize_duration, parse_duration};
+use crate::discovery::managed::{ChangeError, ManagedTargets};
+use crate::events::{Event, EventKind};
+use crate::history::memory::MemoryHistory;
+use crate::logging::LogLevel;
+use crate::metrics::TargetRegistries;
+use crate::notify::silence::Silences;
+use crate::server::{Reply, Request};
+use crate::snapshot::Snapshot;
+use crate::systemd::Heartbeat;
+use crate::targets::{ActiveTargets, TargetConfig};
+use crate::AppState;
+
+/// What `/-/healthy` checks: that the poll loop keeps completing iterations
+pub struct Health {
+    pub heartbeat: Arc<Heartbeat>,
+    pub max_age: std::time::Duration,
+}
+
+/// Everything the handlers share
+pub struct Api {
+    pub state: Arc<AppState>,
+    /// The gauges of each target, for `/probe`
+    pub registries: TargetRegistries,
+    pub silences: Arc<Silences>,
+    pub log_level: LogLevel,
+    pub health: Health,
+    /// Targets added and removed through the API, if `TARGETS_API` is set
+    pub targets: Option<ManagedTargets>,
+    /// The targets being polled and how their polls went
+    pub active: Arc<ActiveTargets>,
+    /// `STALE_AFTER`, how old the last successful poll of a target may be
+    /// before `/probe` answers 503 for it, and `/metrics` once every target
+    /// is that stale
+    pub stale_after: Option<std::time::Duration>,
+    /// `MAX_CONSECUTIVE_FAILURES`, shown next to each target's failures
+    pub max_failures: Option<u32>,
+    /// Recent values of every target, unless `HISTORY_RETENTION` is 0
+    pub history: Option<Arc<MemoryHistory>>,
+    /// The SQLite history, if `SQLITE_PATH` is set
+    #[cfg(feature = "sqlite")]
+    pub store: Option<Arc<crate::history::sqlite::SqliteQueries>>,
+}
+
+const SILENCE: &str = "/api/v1/silence/";
+const TARGET: &str = "/api/v1/targets/";
+
+/// The route a path belongs to, as used for the metrics' `path` label.
+/// Unknown paths have none, so they can't blow up the label cardinality.
+pub fn pattern(path: &str) -> Option<&'static str> {
+    match path {
+        "/metrics" => Some("/metrics"),
+        "/probe" => Some("/probe"),
+        "/api/v1/silence" => Some("/api/v1/silence"),
+        "/api/v1/notify/test" => Some("/api/v1/notify/test"),
+        "/api/v1/status" => Some("/api/v1/status"),
+        "/api/v1/targets" => Some("/api/v1/targets"),
+        "/api/v1/history" => Some("/api/v1/history"),
+        "/api/v1/history.csv" => Some("/api/v1/history.csv"),
+        "/api/v1/query" => Some("/api/v1/query"),
+        "/api/v1/snapshot" => Some("/api/v1/snapshot"),
+        "/sd" => Some("/sd"),
+        "/grafana" | "/grafana/" => Some("/grafana"),
+        "/grafana/search" => Some("/grafana/search"),
+        "/grafana/query" => Some("/grafana/query"),
+        "/-/healthy" => Some("/-/healthy"),
+        "/-/loglevel" => Some("/-/loglevel"),
+        _ => {
+            let item = |prefix| path.strip_prefix(prefix).filter(|id: &&str| !id.is_empty() && !id.contains('/'));
+            match (item(SILENCE), item(TARGET)) {
+                (Some(_), _) => Some("/api/v1/silence/{id}"),
+                (_, Some(_)) => Some("/api/v1/targets/{name}"),
+                _ => None,
+            }
+        }
+    }
+}
+
+/// Route a request to its handler. Unknown paths get 404, known paths with
+/// another method 405.
+pub async fn route(api: &Api, request: &Request) -> Reply {
+    let Some(pattern) = pattern(&request.path) else {
+        return Reply::empty(404);
+    };
+    match (pattern, request.method.as_str()) {
+        ("/metrics", "GET") => metrics(&api.state, &api.active, api.stale_after),
+        ("/probe", "GET") => probe(&api.state, &api.registries, &api.active, api.stale_after, request.query.as_deref()),
+        ("/api/v1/silence", "GET") => list_silences(&api.silences),
+        ("/api/v1/silence", "POST") => create_silence(&api.silences, &api.active, &request.body),
+        ("/api/v1/silence/{id}", "DELETE") => delete_silence(&api.silences, &api.active, &request.path[SILENCE.len()..]),
+        ("/api/v1/notify/test", "POST") => notify_test(&api.state, &api.active, &request.body).await,
+        ("/api/v1/status", "GET") => status(&api.state, &api.active, request.query.as_deref()),
+        ("/api/v1/targets", "GET") => list_targets(&api.active, api.max_failures),
+        ("/api/v1/targets", "POST") => add_target(api.targets.as_ref(), &request.body).await,
+        ("/api/v1/targets/{name}", "DELETE") => remove_target(api.targets.as_ref(), &request.path[TARGET.len()..]).await,
+        ("/api/v1/history", "GET") => history(api.history.as_deref(), request.query.as_deref()),
+        ("/api/v1/history.csv", "GET") => history_csv(api, request.query.as_deref()).await,
+        ("/api/v1/query", "GET") => query_history(api, request.query.as_deref()).await,
+        ("/api/v1/snapshot", "POST") => snapshot(api).await,
+        ("/sd", "GET") => service_discovery(&api.active),
+        ("/grafana", "GET") => Reply::text(200, "OK"),
+        ("/grafana/search", "POST") => grafana_search(api, &request.body).await,
+        ("/grafana/query", "POST") => grafana_query(api, &request.body).await,
+        ("/-/healthy", "GET") => healthy(&api.health),
+        ("/-/loglevel", "GET") => Reply::text(200, api.log_level.current()),
+        ("/-/loglevel", "PUT") => set_log_level(&api.log_level, &request.body),
+        _ => Reply::empty(405),
+    }
+}
+
+/// The target named, or the only one if none is, answering 404 for an
+/// unknown target and 400 if several could be meant
+fn pick_target(active: &ActiveTargets, target: Option<&str>) -> Result<String, Reply> {
+    let names: Vec<String> = active.list().into_iter().map(|t| t.target.name).collect();
+    match (target, names.as_slice()) {
+        (Some(target), _) if names.iter().any(|name| name == target) => Ok(target.to_string()),
+        (Some(target), _) => Err(Reply::text(404, format!("Unknown target {:?}", target))),
+        (None, [only]) => Ok(only.clone()),
+        (None, []) => Err(Reply::text(404, "No target is polled")),
+        (None, _) => Err(Reply::text(400, "Several targets are polled, pick one with target")),
+    }
+}
+
+/// Parse a JSON body, answering 400 if it doesn't fit
+fn json_body<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, Reply> {
+    serde_json::from_slice(body).map_err(|e| Reply::text(400, format!("Invalid request body: {}", e)))
+}
+
+/// The parameters of a query string, the last of each name winning
+fn query_params(query: Option<&str>) -> HashMap<String, String> {
+    form_urlencoded::parse(query.unwrap_or_default().as_bytes()).into_owned().collect()
+}
+
+/// `STALE_AFTER`, if `/metrics` and `/probe` should fail once no poll has
+/// succeeded for that long
+pub fn stale_after() -> Option<std::time::Duration> {
+    let value = std::env::var("STALE_AFTER").ok().filter(|v| !v.trim().is_empty())?;
+    match parse_duration(&value).filter(|age| !age.is_zero()) {
+        Some(age) => Some(age),
+        None => {
+            tracing::error!("Ignoring STALE_AFTER, {:?} is not a duration", value);
+            None
+        }
+    }
+}
+
+/// Serve the registry, or 503 if no target has been polled successfully
+/// within `stale_after`, so Prometheus marks the exporter down instead of
+/// storing the last values again. While some targets still succeed, the
+/// stale ones show in their own failure metrics, and `/probe` of them
+/// fails. Only `gather` touches the registry's internal lock, briefly;
+/// encoding works on the gathered copy, so scrapes and the poller never
+/// wait on each other.
+pub fn metrics(state: &AppState, active: &ActiveTargets, stale_after: Option<std::time::Duration>) -> Reply {
+    if let Some(max_age) = stale_after
+        && let Some(age) = active.list().iter().map(|t| t.success_age()).min()
+        && age > max_age
+    {
+        return Reply::text(503, format!("No successful poll of any target for {} seconds", age.as_secs()));
+    }
+    encode(state, &state.registry.gather())
+}
+
+/// The UPS metrics of the target given as `target`, by name, for scraping
+/// each target as a job of its own like the blackbox exporter. The
+/// exporter's own metrics are left to `/metrics`. 503 if the target hasn't
+/// been polled successfully within `stale_after`.
+fn probe(
+    state: &AppState,
+    registries: &TargetRegistries,
+    active: &ActiveTargets,
+    stale_after: Option<std::time::Duration>,
+    query: Option<&str>,
+) -> Reply {
+    let params = query_params(query);
+    let Some(target) = params.get("target") else {
+        return Reply::text(400, "Missing target parameter");
+    };
+    if let Some(max_age) = stale_after
+        && let Some(age) = active.list().iter().find(|t| &t.target.name == target).map(|t| t.success_age())
+        && age > max_age
+    {
+        return Reply::text(503, format!("No successful poll of {} for {} seconds", target, age.as_secs()));
+    }
+    match registries.gather(target) {
+        Some(metric_families) => encode(state, &metric_families),
+        None => Reply::text(404, format!("Unknown target {:?}", target)),
+    }
+}
+
+/// Encode gathered families in the text format
+fn encode(state: &AppState, metric_families: &[prometheus::proto::MetricFamily]) -> Reply {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(metric_families, &mut buffer) {
+        tracing::error!("Failed to encode metrics: {}", e);
+        state.metric_errors.record("encode");
+        return Reply::text(500, e.to_string());
+    }
+    Reply {
+        status: 200,
+        content_type: "text/plain; charset=utf-8",
+        body: buffer,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SilenceRequest {
+    /// apcupsd host to mute, all if unset
+    target: Option<String>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    duration: std::time::Duration,
+    comment: Option<String>,
+}
+
+fn list_silences(silences: &Silences) -> Reply {
+    let active: Vec<_> = silences.active().iter().map(|s| s.to_json()).collect();
+    Reply::json(200, &active)
+}
+
+/// Update `apcupsd_exporter_muted` of every target after a silence changed
+fn refresh_muted(silences: &Silences, active: &ActiveTargets) {
+    for target in active.list() {
+        silences.refresh(&target.target.name);
+    }
+}
+
+fn create_silence(silences: &Silences, active: &ActiveTargets, body: &[u8]) -> Reply {
+    let request: SilenceRequest = match json_body(body) {
+        Ok(request) => request,
+        Err(reply) => return reply,
+    };
+    let Some(silence) = silences.add(request.target, request.duration, request.comment) else {
+        return Reply::text(400, "Silence duration is too long");
+    };
+    tracing::info!(
+        "Muted notifications for {} until {:?} (silence {})",
+        silence.target.as_deref().unwrap_or("all targets"),
+        silence.until,
+        silence.id
+    );
+    refresh_muted(silences, active);
+    Reply::json(201, &silence.to_json())
+}
+
+fn delete_silence(silences: &Silences, active: &ActiveTargets, id: &str) -> Reply {
+    let Some(id) = id.parse::<u64>().ok().filter(|&id| silences.remove(id)) else {
+        return Reply::empty(404);
+    };
+    tracing::info!("Removed silence {}", id);
+    refresh_muted(silences, active);
+    Reply::empty(204)
+}
+
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct NotifyTestRequest {
+    /// Notifier name or type, every channel without side effects if unset
+    channel: Option<String>,
+    event: Option<EventKind>,
+    /// Target whose values the event is built from, needed with several
+    target: Option<String>,
+}
+
+/// Send a test event built from the latest values of a target, or from no
+/// values before it has been polled. Responds with the outcome per channel,
+/// and 502 if any of them failed.
+async fn notify_test(state: &AppState, active: &ActiveTargets, body: &[u8]) -> Reply {
+    let request = if body.is_empty() {
+        NotifyTestRequest::default()
+    } else {
+        match json_body(body) {
+            Ok(request) => request,
+            Err(reply) => return reply,
+        }
+    };
+    let target = match pick_target(active, request.target.as_deref()) {
+        Ok(target) => target,
+        Err(reply) => return reply,
+    };
+    let snapshot = match state.snapshots.load().get(&target) {
+        Some(snapshot) => Snapshot::clone(snapshot),
+        None => Snapshot::new(&target, Default::default()),
+    };
+    let event = Event::test(request.event.unwrap_or(EventKind::OnBattery), snapshot, &state.severities);
+    let results = tokio::task::spawn_blocking(move || {
+        // Never exec hooks: anyone who can reach the listener could run them
+        crate::notify::send_test(&crate::notify::from_env(), request.channel.as_deref(), &event, false)
+            .into_iter()
+            .map(|(name, result)| (name, result.err().map(|e| e.to_string())))
+            .collect::<Vec<_>>()
+    })
+    .await;
+    let results = match results {
+        Ok(results) => results,
+        Err(e) => return Reply::text(500, e.to_string()),
+    };
+
+    if results.is_empty() {
+        return Reply::json(404, &serde_json::json!({
+            "error": "no matching notification channel is configured, exec hooks can only be tested with the notify-test command",
+        }));
+    }
+    let failed = results.iter().any(|(_, error)| error.is_some());
+    let body: Vec<_> = results
+        .into_iter()
+        .map(|(channel, error)| serde_json::json!({"channel": channel, "ok": error.is_none(), "error": error}))
+        .collect();
+    Reply::json(if failed { 502 } else { 200 }, &body)
+}
+
+/// The reply to a refused change of the targets
+fn change_error(error: ChangeError) -> Reply {
+    let status = match error {
+        ChangeError::Exists(_) => 409,
+        ChangeError::NotFound => 404,
+        ChangeError::Invalid(_) => 400,
+        ChangeError::Persist(_) => 500,
+    };
+    Reply::json(status, &serde_json::json!({"error": error.to_string()}))
+}
+
+fn targets_disabled() -> Reply {
+    Reply::json(404, &serde_json::json!({
+        "error": "target management is disabled, set TARGETS_API to enable it",
+    }))
+}
+
+/// Every target being polled with the outcome of its last poll, for
+/// debugging what the exporter is scraping
+fn list_targets(active: &ActiveTargets, max_failures: Option<u32>) -> Reply {
+    let targets: Vec<_> = active.list().iter().map(|t| t.to_json(max_failures)).collect();
+    Reply::json(200, &targets)
+}
+
+/// The targets in the format of Prometheus' `http_sd`: a group per target
+/// with its NIS address and labels, its name as the `target` parameter of
+/// `/probe`, and its name and source as meta labels to relabel with. The
+/// address is kept for the `instance` label; relabelling points
+/// `__address__` at the exporter.
+fn service_discovery(active: &ActiveTargets) -> Reply {
+    let groups: Vec<_> = active
+        .list()
+        .iter()
+        .map(|active| {
+            let mut labels = active.target.labels.clone();
+            labels.insert("__meta_apcupsd_target".to_string(), active.target.name.clone());
+            labels.insert("__meta_apcupsd_source".to_string(), active.source.to_string());
+            labels.insert("__param_target".to_string(), active.target.name.clone());
+            serde_json::json!({
+                "targets": [crate::discovery::file::format_address(&active.target.host, active.target.port)],
+                "labels": labels,
+            })
+        })
+        .collect();
+    Reply::json(200, &groups)
+}
+
+/// Start polling a target, given like a `[[targets]]` entry of the config
+/// file
+async fn add_target(targets: Option<&ManagedTargets>, body: &[u8]) -> Reply {
+    let Some(targets) = targets else {
+        return targets_disabled();
+    };
+    let config: TargetConfig = match json_body(body) {
+        Ok(config) => config,
+        Err(reply) => return reply,
+    };
+    match targets.add(config).await {
+        Ok(target) => {
+            tracing::info!("Added target {} at {}:{} through the API", target.name, target.host, target.port);
+            Reply::json(201, &serde_json::json!({
+                "name": target.name,
+                "host": target.host,
+                "port": target.port,
+                "interval": target.interval.as_secs_f64(),
+                "labels": target.labels,
+            }))
+        }
+        Err(e) => change_error(e),
+    }
+}
+
+async fn remove_target(targets: Option<&ManagedTargets>, name: &str) -> Reply {
+    let Some(targets) = targets else {
+        return targets_disabled();
+    };
+    match targets.remove(name).await {
+        Ok(()) => {
+            tracing::info!("Removed target {} through the API", name);
+            Reply::empty(204)
+        }
+        Err(e) => change_error(e),
+    }
+}
+
+/// The values of `key` over the last `range`, everything kept by default,
+/// of every target or only of `target`
+fn history(history: Option<&MemoryHistory>, query: Option<&str>) -> Reply {
+    let Some(history) = history else {
+        return Reply::json(404, &serde_json::json!({
+            "error": "history is disabled, HISTORY_RETENTION is 0",
+        }));
+    };
+    let params = query_params(query);
+    let Some(key) = params.get("key").filter(|key| !key.is_empty()) else {
+        return Reply::json(400, &serde_json::json!({"error": "key is missing, e.g. key=LINEV"}));
+    };
+    let range = match params.get("range").map(|range| parse_duration(range)) {
+        Some(Some(range)) => range,
+        Some(None) => return Reply::json(400, &serde_json::json!({"error": "range is not a duration, e.g. 1h"})),
+        None => history.retention(),
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let key = key.to_ascii_uppercase();
+    let series: Vec<_> = history
+        .range(&key, now.saturating_sub(range.as_secs()), params.get("target").map(String::as_str))
+        .into_iter()
+        .map(|(target, values)| serde_json::json!({"target": target, "values": values}))
+        .collect();
+    Reply::json(200, &serde_json::json!({
+        "key": key,
+        "range": range.as_secs(),
+        "series": series,
+    }))
+}
+
+/// The values of `keys` of every poll over the last `range`, a day by
+/// default, as CSV for spreadsheets. They come from the SQLite history if
+/// there is one, which goes back further, and from memory otherwise.
+async fn history_csv(api: &Api, query: Option<&str>) -> Reply {
+    let params = query_params(query);
+    let keys: Vec<String> = params
+        .get("keys")
+        .map(|keys| keys.split(',').map(|key| key.trim().to_ascii_uppercase()).filter(|key| !key.is_empty()).collect())
+        .unwrap_or_default();
+    if keys.is_empty() {
+        return Reply::json(400, &serde_json::json!({"error": "keys is missing, e.g. keys=LINEV,LOADPCT"}));
+    }
+    if let Some(key) = keys.iter().find(|key| !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')) {
+        return Reply::json(400, &serde_json::json!({"error": format!("invalid key {:?}", key)}));
+    }
+    let range = match params.get("range").map(|range| parse_duration(range)) {
+        Some(Some(range)) => range,
+        Some(None) => return Reply::json(400, &serde_json::json!({"error": "range is not a duration, e.g. 24h"})),
+        None => std::time::Duration::from_secs(86_400),
+    };
+    let since = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs().saturating_sub(range.as_secs());
+
+    #[cfg(feature = "sqlite")]
+    if let Some(store) = api.store.clone() {
+        return match tokio::task::spawn_blocking(move || store.table(&keys, since)).await {
+            Ok(Ok(table)) => csv(&table),
+            Ok(Err(e)) => Reply::json(500, &serde_json::json!({"error": e.to_string()})),
+            Err(e) => Reply::json(500, &serde_json::json!({"error": e.to_string()})),
+        };
+    }
+    match &api.history {
+        Some(history) => csv(&history.table(&keys, since)),
+        None => history_disabled(),
+    }
+}
+
+fn csv(table: &crate::history::Table) -> Reply {
+    Reply {
+        status: 200,
+        content_type: "text/csv; charset=utf-8",
+        body: table.to_csv().into_bytes(),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct GrafanaSearch {
+    #[serde(default)]
+    target: String,
+}
+
+/// A query of Grafana's JSON datasource. Grafana sends more fields, which
+/// are ignored.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GrafanaQuery {
+    range: GrafanaRange,
+    interval_ms: Option<u64>,
+    targets: Vec<GrafanaTarget>,
+}
+
+#[derive(Deserialize)]
+struct GrafanaRange {
+    from: chrono::DateTime<chrono::Utc>,
+    to: chrono::DateTime<chrono::Utc>,
+}
+
+#[derive(Deserialize)]
+struct GrafanaTarget {
+    #[serde(default)]
+    target: String,
+}
+
+fn history_disabled() -> Reply {
+    Reply::json(404, &serde_json::json!({
+        "error": "history is disabled, set SQLITE_PATH or HISTORY_RETENTION to enable it",
+    }))
+}
+
+/// The keys a Grafana panel can chart, those containing the search text
+async fn grafana_search(api: &Api, body: &[u8]) -> Reply {
+    let search: GrafanaSearch = match body.is_empty() {
+        true => GrafanaSearch::default(),
+        false => match json_body(body) {
+            Ok(search) => search,
+            Err(reply) => return reply,
+        },
+    };
+    #[cfg(feature = "sqlite")]
+    let keys = match api.store.clone() {
+        Some(store) => match tokio::task::spawn_blocking(move || store.keys()).await {
+            Ok(Ok(keys)) => Some(keys),
+            Ok(Err(e)) => return Reply::json(500, &serde_json::json!({"error": e.to_string()})),
+            Err(e) => return Reply::json(500, &serde_json::json!({"error": e.to_string()})),
+        },
+        None => None,
+    };
+    #[cfg(not(feature = "sqlite"))]
+    let keys: Option<Vec<String>> = None;
+    let Some(keys) = keys.or_else(|| api.history.as_ref().map(|history| history.keys().into_iter().collect())) else {
+        return history_disabled();
+    };
+    let search = search.target.to_ascii_uppercase();
+    let keys: Vec<_> = keys.into_iter().filter(|key| key.contains(&search)).collect();
+    Reply::json(200, &keys)
+}
+
+/// The series of the keys of a Grafana panel, one per key and target named
+/// `<target> <key>`, as `[value, milliseconds]` pairs. From the SQLite
+/// history averaged to the panel's interval if there is one, and as polled
+/// from memory otherwise.
+async fn grafana_query(api: &Api, body: &[u8]) -> Reply {
+    let query: GrafanaQuery = match json_body(body) {
+        Ok(query) => query,
+        Err(reply) => return reply,
+    };
+    let from = u64::try_from(query.range.from.timestamp()).unwrap_or(0);
+    let to = u64::try_from(query.range.to.timestamp()).unwrap_or(0);
+    let step = query.interval_ms.unwrap_or(0).div_ceil(1000).max(1);
+    let keys: Vec<String> =
+        query.targets.iter().map(|t| t.target.to_ascii_uppercase()).filter(|key| !key.is_empty()).collect();
+
+    let mut series = Vec::new();
+    for key in keys {
+        #[cfg(feature = "sqlite")]
+        let found = match api.store.clone() {
+            Some(store) => {
+                let query = crate::history::sqlite::Query {
+                    key: key.clone(),
+                    target: None,
+                    start: from,
+                    end: to,
+                    step,
+                    aggregation: crate::history::sqlite::Aggregation::Avg,
+                };
+                match tokio::task::spawn_blocking(move || store.query(&query)).await {
+                    Ok(Ok(found)) => Some(found),
+                    Ok(Err(e)) => return Reply::json(500, &serde_json::json!({"error": e.to_string()})),
+                    Err(e) => return Reply::json(500, &serde_json::json!({"error": e.to_string()})),
+                }
+            }
+            None => None,
+        };
+        #[cfg(not(feature = "sqlite"))]
+        let found = {
+            let _ = step;
+            None
+        };
+        let found = found.or_else(|| {
+            api.history.as_ref().map(|history| {
+                let mut found = history.range(&key, from, None);
+                found.values_mut().for_each(|values| values.retain(|&(t, _)| t <= to));
+                found
+            })
+        });
+        let Some(found) = found else {
+            return history_disabled();
+        };
+        series.extend(grafana_series(&key, found));
+    }
+    Reply::json(200, &series)
+}
+
+/// A key's series by target, as Grafana's JSON datasource takes them
+fn grafana_series(key: &str, found: std::collections::BTreeMap<String, Vec<(u64, f64)>>) -> Vec<serde_json::Value> {
+    found
+        .into_iter()
+        .map(|(target, values)| {
+            let datapoints: Vec<_> = values.iter().map(|&(t, value)| (value, t * 1000)).collect();
+            serde_json::json!({"target": format!("{} {}", target, key), "datapoints": datapoints})
+        })
+        .collect()
+}
+
+/// The time a query parameter names, in Unix seconds or RFC 3339
+#[cfg(feature = "sqlite")]
+fn timestamp(value: &str) -> Option<u64> {
+    value.parse().ok().or_else(|| {
+        chrono::DateTime::parse_from_rfc3339(value).ok().and_then(|time| u64::try_from(time.timestamp()).ok())
+    })
+}
+
+/// The query of `/api/v1/query`: the last day at 5 minute steps averaged,
+/// unless the parameters say otherwise
+#[cfg(feature = "sqlite")]
+fn parse_query(query: Option<&str>) -> Result<crate::history::sqlite::Query, String> {
+    /// Most steps a query may cover, as in Prometheus
+    const MAX_STEPS: u64 = 11_000;
+    let params = query_params(query);
+    let key = params.get("key").filter(|key| !key.is_empty()).ok_or("key is missing, e.g. key=LOADPCT")?;
+    let time = |name: &str| match params.get(name) {
+        Some(value) => timestamp(value).map(Some).ok_or(format!("{} is not a Unix or RFC 3339 timestamp", name)),
+        None => Ok(None),
+    };
+    let end = time("end")?.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs());
+    let start = time("start")?.unwrap_or(end.saturating_sub(86_400));
+    let step = match params.get("step") {
+        Some(step) => parse_duration(step).map(|step| step.as_secs()).ok_or("step is not a duration, e.g. 5m")?,
+        None => 300,
+    };
+    if step == 0 {
+        return Err("step must be at least a second".to_string());
+    }
+    if start > end {
+        return Err("start is after end".to_string());
+    }
+    if (end - start) / step > MAX_STEPS {
+        return Err(format!("the range covers more than {} steps, use a larger step", MAX_STEPS));
+    }
+    Ok(crate::history::sqlite::Query {
+        key: key.to_ascii_uppercase(),
+        target: params.get("target").cloned(),
+        start,
+        end,
+        step,
+        aggregation: params.get("agg").map(|agg| agg.parse()).transpose()?.unwrap_or(crate::history::sqlite::Aggregation::Avg),
+    })
+}
+
+/// Samples of the SQLite history, one value per `step` aggregated by `agg`,
+/// for charts that span longer than the in-memory history
+#[cfg(feature = "sqlite")]
+async fn query_history(api: &Api, query: Option<&str>) -> Reply {
+    let Some(store) = api.store.clone() else {
+        return Reply::json(404, &serde_json::json!({
+            "error": "the SQLite history is disabled, set SQLITE_PATH to enable it",
+        }));
+    };
+    let query = match parse_query(query) {
+        Ok(query) => query,
+        Err(e) => return Reply::json(400, &serde_json::json!({"error": e})),
+    };
+    let result = tokio::task::spawn_blocking(move || store.query(&query).map(|series| (query, series))).await;
+    let (query, series) = match result {
+        Ok(Ok(result)) => result,
+        Ok(Err(e)) => return Reply::json(500, &serde_json::json!({"error": e.to_string()})),
+        Err(e) => return Reply::json(500, &serde_json::json!({"error": e.to_string()})),
+    };
+    let series: Vec<_> =
+        series.into_iter().map(|(target, values)| serde_json::json!({"target": target, "values": values})).collect();
+    Reply::json(200, &serde_json::json!({
+        "key": query.key,
+        "start": query.start,
+        "end": query.end,
+        "step": query.step,
+        "agg": query.aggregation.name(),
+        "series": series,
+    }))
+}
+
+/// A backup of the SQLite history as a gzipped tarball, which
+/// `SQLITE_RESTORE` restores on another machine
+#[cfg(feature = "sqlite")]
+async fn snapshot(api: &Api) -> Reply {
+    let Some(store) = api.store.clone() else {
+        return Reply::json(404, &serde_json::json!({
+            "error": "the SQLite history is disabled, set SQLITE_PATH to enable it",
+        }));
+    };
+    let result = tokio::task::spawn_blocking(move || {
+        let mut tarball = Vec::new();
+        crate::history::backup::create(&store, &mut tarball).map(|()| tarball)
+    })
+    .await;
+    match result {
+        Ok(Ok(tarball)) => {
+            tracing::info!("Made a backup of the history, {} bytes", tarball.len());
+            Reply {
+                status: 200,
+                content_type: "application/gzip",
+                body: tarball,
+            }
+        }
+        Ok(Err(e)) => Reply::json(500, &serde_json::json!({"error": e.to_string()})),
+        Err(e) => Reply::json(500, &serde_json::json!({"error": e.to_string()})),
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+async fn snapshot(_api: &Api) -> Reply {
+    Reply::json(404, &serde_json::json!({
+        "error": "backups of the history need a build with the sqlite feature",
+    }))
+}
+
+#[cfg(not(feature = "sqlite"))]
+async fn query_history(_api: &Api, _query: Option<&str>) -> Reply {
+    Reply::json(404, &serde_json::json!({
+        "error": "querying the history needs a build with the sqlite feature",
+    }))
+}
+
+/// The latest status of the target given as `target`, or of the only one,
+/// typed: numbers, durations in seconds and timestamps. 503 until its first
+/// successful poll.
+fn status(state: &AppState, active: &ActiveTargets, query: Option<&str>) -> Reply {
+    let target = match pick_target(active, query_params(query).get("target").map(String::as_str)) {
+        Ok(target) => target,
+        Err(reply) => return reply,
+    };
+    let snapshots = state.snapshots.load();
+    let Some(snapshot) = snapshots.get(&target) else {
+        return Reply::json(503, &serde_json::json!({
+            "error": "no successful poll yet",
+        }));
+    };
+    Reply::json(200, &serde_json::json!({
+        "host": snapshot.host,
+        "timestamp": snapshot.unix_timestamp(),
+        "status": apcaccess::ApcStatus::from(&snapshot.stats),
+    }))
+}
+
+/// 200 while the poll loop is alive, whether or not apcupsd answers, and
+/// 503 once a poll has hung for longer than it may take.
+fn healthy(health: &Health) -> Reply {
+    let age = health.heartbeat.age();
+    if age > health.max_age {
+        return Reply::text(503, format!("Poll loop hasn't completed for {} seconds", age.as_secs()));
+    }
+    Reply::text(200, "OK")
+}
+
+/// Replace the log filter with the directives in the body, e.g. `debug` or
+/// `rsapcupsdexporter::apcaccess=trace`.
+fn set_log_level(level: &LogLevel, body: &[u8]) -> Reply {
+    match level.set(&String::from_utf8_lossy(body)) {
+        Ok(()) => Reply::text(200, level.current()),
+        Err(e) => Reply::text(400, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use arc_swap::ArcSwap;
+    use prometheus::Registry;
+
+    fn state(stats: BTreeMap<String, String>) -> AppState {
+        let registry = Registry::new();
+        AppState {
+            metric_errors: crate::metrics::MetricErrors::new(&registry).unwrap(),
+            registry,
+            snapshots: ArcSwap::from_pointee(HashMap::from([("ups1".to_string(), Arc::new(Snapshot::new("ups1", stats)))])),
+            severities: Default::default(),
+        }
+    }
+
+    fn json(reply: &Reply) -> serde_json::Value {
+        serde_json::from_slice(&reply.body).unwrap()
+    }
+
+    #[test]
+    fn test_pattern() {
+        assert_eq!(pattern("/metrics"), Some("/metrics"));
+        assert_eq!(pattern("/api/v1/silence/3"), Some("/api/v1/silence/{id}"));
+        assert_eq!(pattern("/api/v1/silence/"), None);
+        assert_eq!(pattern("/api/v1/silence/3/x"), None);
+        assert_eq!(pattern("/api/v1/targets/rack1:3552"), Some("/api/v1/targets/{name}"));
+        assert_eq!(pattern("/api/v1/targets/"), None);
+        assert_eq!(pattern("/favicon.ico"), None);
+    }
+
+    #[test]
+    fn test_metrics() {
+        let stats = BTreeMap::from([
+            ("UPSNAME".to_string(), "rack1".to_string()),
+            ("BCHARGE".to_string(), "97.0".to_string()),
+        ]);
+        let state = state(stats.clone());
+        let registries = crate::metrics::TargetRegistries::new(&state.registry).unwrap();
+        let mut metrics = crate::metrics::UpsMetrics::new(&registries, state.metric_errors.clone(), "ups1").unwrap();
+        metrics.update(&stats);
+
+        let active = ActiveTargets::default();
+        let defaults = crate::targets::Defaults::from_env();
+        active.insert("file", TargetConfig::new("ups1", 3551).resolve(&defaults));
+        let stale_after = Some(std::time::Duration::from_millis(50));
+        let reply = super::metrics(&state, &active, stale_after);
+        let body = String::from_utf8(reply.body).unwrap();
+        assert!(body.contains("apcupsd_bcharge 97"));
+        assert!(body.contains("upsname=\"rack1\""));
+
+        // Stale once no target has succeeded for a while, not while any has
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        assert_eq!(super::metrics(&state, &active, stale_after).status, 503);
+        assert_eq!(super::metrics(&state, &active, None).status, 200);
+        assert_eq!(probe(&state, &registries, &active, stale_after, Some("target=ups1")).status, 503);
+        active.insert("file", TargetConfig::new("ups2", 3551).resolve(&defaults));
+        assert_eq!(super::metrics(&state, &active, stale_after).status, 200);
+        active.record("ups1", std::time::Duration::from_millis(20), None);
+        assert_eq!(probe(&state, &registries, &active, stale_after, Some("target=ups1")).status, 200);
+    }
+
+    #[test]
+    fn test_probe() {
+        let stats = BTreeMap::from([("BCHARGE".to_string(), "97.0".to_string())]);
+        let state = state(stats.clone());
+        let registries = crate::metrics::TargetRegistries::new(&state.registry).unwrap();
+        let mut metrics = crate::metrics::UpsMetrics::new(&registries, state.metric_errors.clone(), "ups1").unwrap();
+        metrics.update(&stats);
+
+        let reply = probe(&state, &registries, &ActiveTargets::default(), None, Some("target=ups1"));
+        let body = String::from_utf8(reply.body).unwrap();
+        assert!(body.contains("apcupsd_bcharge 97"));
+        assert!(!body.contains("apcupsd_exporter_metric_errors_total"));
+        assert_eq!(probe(&state, &registries, &ActiveTargets::default(), None, Some("target=ups2")).status, 404);
+        assert_eq!(probe(&state, &registries, &ActiveTargets::default(), None, None).status, 400);
+    }
+
+    #[test]
+    fn test_silence_lifecycle() {
+        let silences = Silences::new(Vec::new(), &Registry::new()).unwrap();
+        let active = ActiveTargets::default();
+
+        let created = create_silence(&silences, &active, br#"{"target": "ups1", "duration": "2h"}"#);
+        assert_eq!(created.status, 201);
+        assert!(silences.is_muted("ups1"));
+
+        let id = json(&created)["id"].to_string();
+        assert_eq!(delete_silence(&silences, &active, &id).status, 204);
+        assert!(!silences.is_muted("ups1"));
+        assert_eq!(delete_silence(&silences, &active, &id).status, 404);
+
+        assert_eq!(create_silence(&silences, &active, br#"{"duration": "forever"}"#).status, 400);
+    }
+
+    #[test]
+    fn test_status() {
+        let state = state(BTreeMap::from([
+            ("STATUS".to_string(), "ONBATT".to_string()),
+            ("TIMELEFT".to_string(), "12.5".to_string()),
+        ]));
+        let active = ActiveTargets::default();
+        let defaults = crate::targets::Defaults::from_env();
+        active.insert("file", TargetConfig::new("ups1", 3551).resolve(&defaults));
+        let body = json(&status(&state, &active, None));
+        assert_eq!(body["host"], "ups1");
+        assert_eq!(body["status"]["status"], serde_json::json!(["on_battery"]));
+        assert_eq!(body["status"]["timeleft"], 750.0);
+        assert_eq!(body["status"]["linev"], serde_json::Value::Null);
+
+        // With several targets, the one asked for
+        active.insert("file", TargetConfig::new("ups2", 3551).resolve(&defaults));
+        assert_eq!(status(&state, &active, None).status, 400);
+        assert_eq!(json(&status(&state, &active, Some("target=ups1")))["host"], "ups1");
+        assert_eq!(status(&state, &active, Some("target=ups2")).status, 503);
+        assert_eq!(status(&state, &active, Some("target=ups3")).status, 404);
+    }
+
+    #[test]
+    fn test_service_discovery() {
+        let active = ActiveTargets::default();
+        let mut target = TargetConfig::new("fd00::5", 3552);
+        target.labels.insert("dc".to_string(), "fra1".to_string());
+        active.insert("file", target.resolve(&crate::targets::Defaults::from_env()));
+        let body = json(&service_discovery(&active));
+        assert_eq!(body[0]["targets"], serde_json::json!(["[fd00::5]:3552"]));
+        assert_eq!(body[0]["labels"]["dc"], "fra1");
+        assert_eq!(body[0]["labels"]["__meta_apcupsd_target"], "fd00::5:3552");
+        assert_eq!(body[0]["labels"]["__meta_apcupsd_source"], "file");
+        assert_eq!(body[0]["labels"]["__param_target"], "fd00::5:3552");
+    }
+
+    #[test]
+    fn test_history() {
+        let memory = MemoryHistory::new(std::time::Duration::from_secs(3600));
+        memory.record(&Snapshot::new("ups1", BTreeMap::from([("LINEV".to_string(), "230.0".to_string())])));
+        memory.record(&Snapshot::new("ups2", BTreeMap::from([("LINEV".to_string(), "119.0".to_string())])));
+
+        let body = json(&history(Some(&memory), Some("key=linev&range=10m&target=ups2")));
+        assert_eq!(body["key"], "LINEV");
+        assert_eq!(body["range"], 600);
+        assert_eq!(body["series"].as_array().unwrap().len(), 1);
+        assert_eq!(body["series"][0]["target"], "ups2");
+        assert_eq!(body["series"][0]["values"][0][1], 119.0);
+        assert_eq!(json(&history(Some(&memory), Some("key=LINEV")))["series"].as_array().unwrap().len(), 2);
+
+        assert_eq!(history(Some(&memory), None).status, 400);
+        assert_eq!(history(Some(&memory), Some("key=LINEV&range=soon")).status, 400);
+        assert_eq!(history(None, Some("key=LINEV")).status, 404);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_parse_query() {
+        let query = parse_query(Some("key=loadpct&start=1000&end=2000&step=1m&agg=max&target=ups1")).unwrap();
+        assert_eq!((query.key.as_str(), query.start, query.end, query.step), ("LOADPCT", 1000, 2000, 60));
+        assert_eq!(query.aggregation, crate::history::sqlite::Aggregation::Max);
+        assert_eq!(query.target.as_deref(), Some("ups1"));
+        let query = parse_query(Some("key=LINEV&start=2024-06-01T12:00:00Z&end=2024-06-02T12:00:00%2B00:00")).unwrap();
+        assert_eq!((query.start, query.end, query.step), (1717243200, 1717329600, 300));
+
+        assert!(parse_query(Some("start=1000")).is_err());
+        assert!(parse_query(Some("key=LINEV&start=2000&end=1000")).is_err());
+        assert!(parse_query(Some("key=LINEV&start=0&end=100000&step=1s")).is_err());
+        assert!(parse_query(Some("key=LINEV&agg=median")).is_err());
+    }
+
+    #[test]
+    fn test_grafana_query() {
+        let query: GrafanaQuery = serde_json::from_slice(br#"{
+            "app": "dashboard", "requestId": "Q100", "timezone": "browser",
+            "range": {"from": "2024-06-01T12:00:00.000Z", "to": "2024-06-01T18:00:00.000Z", "raw": {"from": "now-6h", "to": "now"}},
+            "interval": "30s", "intervalMs": 30000, "maxDataPoints": 720,
+            "targets": [{"target": "linev", "refId": "A", "type": "timeserie"}]
+        }"#).unwrap();
+        assert_eq!(query.range.from.timestamp(), 1717243200);
+        assert_eq!(query.range.to.timestamp(), 1717264800);
+        assert_eq!(query.interval_ms, Some(30000));
+        assert_eq!(query.targets[0].target, "linev");
+
+        let found = BTreeMap::from([("ups1".to_string(), vec![(1717243200, 230.0)])]);
+        assert_eq!(
+            grafana_series("LINEV", found),
+            vec![serde_json::json!({"target": "ups1 LINEV", "datapoints": [[230.0, 1717243200000u64]]})]
+        );
+        assert_eq!(pattern("/grafana/"), Some("/grafana"));
+    }
+
+    #[test]
+    fn test_healthy() {
+        let heartbeat = Arc::new(Heartbeat::default());
+        let health = Health {
+            heartbeat: Arc::clone(&heartbeat),
+            max_age: std::time::Duration::from_secs(60),
+        };
+        assert_eq!(healthy(&health).status, 503);
+        heartbeat.beat();
+        assert_eq!(healthy(&health).status, 200);
+    }
+}
@@ -0,0 +1,298 @@
+//! battery.rs
+//!
+//! What the exporter works out about each battery from its polls:
+//!
+//! - How much of its capacity is left, from how it behaves rather than from
+//!   REPLACEBATT, which the UPS only raises once a self-test fails. Both
+//!   the rate at which BCHARGE drops while on battery and TIMELEFT at full
+//!   charge are scaled to the runtime at full load and compared with
+//!   `BATTERY_RATED_RUNTIME`, that of a new battery.
+//! - While on battery, how long until apcupsd shuts down, by whichever of
+//!   its limits is reached first.
+//! - How long the UPS was on battery over the last day and week, unlike
+//!   CUMONBATT, which counts since apcupsd started, and how long the last
+//!   outage lasted.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime};
+
+use apcaccess::{ApcStatus, StatusFlag};
+use tracing::error;
+
+use crate::config::parse_duration;
+use crate::snapshot::Snapshot;
+
+/// Percentage points BCHARGE must drop by on battery before the rate says
+/// anything
+const MIN_DROP: f64 = 10.0;
+
+/// BCHARGE from which the battery counts as fully charged
+const FULL_CHARGE: f64 = 99.0;
+
+const DAY: u64 = 86_400;
+
+/// How long outages are remembered, the longest window
+const WEEK: u64 = 7 * DAY;
+
+/// A discharge in progress
+struct Discharge {
+    since: SystemTime,
+    bcharge: f64,
+    /// LOADPCT of every poll since, to average
+    load: Vec<f64>,
+}
+
+/// What is known of one target's battery
+#[derive(Default)]
+struct Wear {
+    discharge: Option<Discharge>,
+    /// Runtime at full load, in seconds, by the last discharge
+    discharged: Option<f64>,
+    /// Runtime at full load, in seconds, by TIMELEFT the last time the
+    /// battery was full
+    at_full_charge: Option<f64>,
+    /// When the UPS went on battery and back within the last week, in Unix
+    /// seconds, the current outage without an end
+    outages: VecDeque<(u64, Option<u64>)>,
+}
+
+impl Wear {
+    /// Seconds on battery since `since`, up to `now`
+    fn on_battery_since(&self, since: u64, now: u64) -> f64 {
+        self.outages
+            .iter()
+            .map(|&(start, end)| end.unwrap_or(now).saturating_sub(start.max(since)))
+            .sum::<u64>() as f64
+    }
+}
+
+/// The estimates after a poll
+#[derive(Debug, Default, PartialEq)]
+pub struct Estimates {
+    /// Capacity as a fraction of a new battery's
+    pub health: Option<f64>,
+    /// Seconds until apcupsd shuts down, while on battery
+    pub until_shutdown: Option<f64>,
+    /// Seconds on battery over the last day and week
+    pub on_battery_24h: f64,
+    pub on_battery_7d: f64,
+    /// Seconds the last outage that is over lasted
+    pub last_outage: Option<f64>,
+}
+
+#[derive(Default)]
+pub struct BatteryTracker {
+    /// Runtime of a new battery at full load, without which the health
+    /// isn't estimated
+    rated: Option<Duration>,
+    targets: HashMap<String, Wear>,
+}
+
+impl BatteryTracker {
+    pub fn new(rated: Option<Duration>) -> Self {
+        BatteryTracker {
+            rated,
+            targets: HashMap::new(),
+        }
+    }
+
+    /// The health is only estimated once `BATTERY_RATED_RUNTIME` says what
+    /// new is
+    pub fn from_env() -> Self {
+        let rated = std::env::var("BATTERY_RATED_RUNTIME").ok().and_then(|value| {
+            let rated = parse_duration(&value).filter(|rated| !rated.is_zero());
+            if rated.is_none() {
+                error!("Ignoring BATTERY_RATED_RUNTIME, {:?} is not a duration", value);
+            }
+            rated
+        });
+        BatteryTracker::new(rated)
+    }
+
+    /// Take in a poll
+    pub fn update(&mut self, snapshot: &Snapshot) -> Estimates {
+        let status = ApcStatus::from(&snapshot.stats);
+        let wear = self.targets.entry(snapshot.host.clone()).or_default();
+        let load = status.loadpct.filter(|&load| load > 0.0);
+        let mut until_shutdown = None;
+        let now = snapshot.unix_timestamp();
+        let on_battery = status.has_flag(&StatusFlag::OnBattery);
+
+        // TONBATT dates an outage back to when it started, even one that
+        // started before the exporter, but not into the one before
+        match wear.outages.back_mut() {
+            Some((_, end @ None)) if !on_battery => *end = Some(now),
+            Some((_, None)) => {}
+            last if on_battery => {
+                let previous_end = last.and_then(|&mut (_, end)| end).unwrap_or(0);
+                let tonbatt = status.tonbatt.unwrap_or_default().as_secs();
+                wear.outages.push_back((now.saturating_sub(tonbatt).max(previous_end), None));
+            }
+            _ => {}
+        }
+        while wear.outages.front().is_some_and(|&(_, end)| end.is_some_and(|end| end < now.saturating_sub(WEEK))) {
+            wear.outages.pop_front();
+        }
+
+        match (on_battery, status.bcharge) {
+            (true, Some(bcharge)) => {
+                let discharge = wear.discharge.get_or_insert_with(|| Discharge {
+                    since: snapshot.timestamp,
+                    bcharge,
+                    load: Vec::new(),
+                });
+                discharge.load.extend(load);
+                let drop = discharge.bcharge - bcharge;
+                let elapsed = snapshot.timestamp.duration_since(discharge.since).unwrap_or_default().as_secs_f64();
+                if drop >= MIN_DROP && elapsed > 0.0 && !discharge.load.is_empty() {
+                    let load = discharge.load.iter().sum::<f64>() / discharge.load.len() as f64;
+                    // The time a full battery lasts at that rate, scaled to full load
+                    wear.discharged = Some(elapsed * 100.0 / drop * load / 100.0);
+                }
+                let rate = (drop > 0.0 && elapsed > 0.0).then(|| drop / elapsed);
+                until_shutdown = Some(until_shutdown_of(&status, bcharge, rate));
+            }
+            (true, None) => {}
+            (false, bcharge) => {
+                wear.discharge = None;
+                if let (Some(bcharge), Some(timeleft), Some(load)) = (bcharge, status.timeleft, load)
+                    && bcharge >= FULL_CHARGE
+                {
+                    wear.at_full_charge = Some(timeleft.as_secs_f64() * load / 100.0);
+                }
+            }
+        }
+
+        let estimates: Vec<f64> = [wear.discharged, wear.at_full_charge].into_iter().flatten().collect();
+        let health = match (self.rated, estimates.is_empty()) {
+            (Some(rated), false) => {
+                let runtime = estimates.iter().sum::<f64>() / estimates.len() as f64;
+                Some(runtime / rated.as_secs_f64())
+            }
+            _ => None,
+        };
+        // apcupsd's own times survive restarts of the exporter. While on
+        // battery, XONBATT is the current outage's and XOFFBATT the last's.
+        let last_outage = match (status.xonbatt, status.xoffbatt) {
+            (Some(on), Some(off)) if off >= on => Some((off - on).num_seconds() as f64),
+            _ => wear.outages.iter().rev().find_map(|&(start, end)| Some(end?.saturating_sub(start) as f64)),
+        };
+
+        Estimates {
+            health,
+            until_shutdown,
+            last_outage,
+            on_battery_24h: wear.on_battery_since(now.saturating_sub(DAY), now),
+            on_battery_7d: wear.on_battery_since(now.saturating_sub(WEEK), now),
+        }
+    }
+
+    /// Forget a target that is no longer polled
+    pub fn remove(&mut self, target: &str) {
+        self.targets.remove(target);
+    }
+}
+
+/// Seconds until the first of apcupsd's limits is reached: TIMELEFT down
+/// to MINTIMEL, BCHARGE down to MBATTCHG at the rate it drops (in points
+/// per second) and the time on battery up to MAXTIME, unless that is 0.
+fn until_shutdown_of(status: &ApcStatus, bcharge: f64, rate: Option<f64>) -> f64 {
+    let by_runtime = status
+        .timeleft
+        .map(|timeleft| timeleft.as_secs_f64() - status.mintimel.unwrap_or_default().as_secs_f64());
+    let by_charge = rate.map(|rate| (bcharge - status.mbattchg.unwrap_or_default()) / rate);
+    let by_time = status
+        .maxtime
+        .filter(|maxtime| !maxtime.is_zero())
+        .map(|maxtime| maxtime.as_secs_f64() - status.tonbatt.unwrap_or_default().as_secs_f64());
+    [by_runtime, by_charge, by_time].into_iter().flatten().fold(f64::INFINITY, f64::min).max(0.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn snapshot(timestamp: u64, stats: &[(&str, &str)]) -> Snapshot {
+        let stats = stats.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let mut snapshot = Snapshot::new("ups1", stats);
+        snapshot.timestamp = UNIX_EPOCH + Duration::from_secs(timestamp);
+        snapshot
+    }
+
+    #[test]
+    fn test_health() {
+        let mut tracker = BatteryTracker::new(Some(Duration::from_secs(600)));
+        let mut health = |snapshot| tracker.update(&snapshot).health;
+        let online = |timeleft| [("STATUS", "ONLINE"), ("BCHARGE", "100.0"), ("TIMELEFT", timeleft), ("LOADPCT", "50.0")];
+        // 16 minutes at half load is 8 at full load
+        assert_eq!(health(snapshot(0, &online("16.0"))), Some(0.8));
+
+        let onbatt = |bcharge| [("STATUS", "ONBATT"), ("BCHARGE", bcharge), ("TIMELEFT", "10.0"), ("LOADPCT", "50.0")];
+        assert_eq!(health(snapshot(100, &onbatt("100.0"))), Some(0.8));
+        assert_eq!(health(snapshot(160, &onbatt("95.0"))), Some(0.8));
+        // 20 points in 120 seconds at half load: 300 seconds at full load
+        assert_eq!(health(snapshot(220, &onbatt("80.0"))), Some((480.0 + 300.0) / 2.0 / 600.0));
+
+        // Charging again doesn't count as full
+        let charging = [("STATUS", "ONLINE"), ("BCHARGE", "90.0"), ("TIMELEFT", "12.0"), ("LOADPCT", "50.0")];
+        assert_eq!(health(snapshot(300, &charging)), Some(0.65));
+        tracker.remove("ups1");
+        assert_eq!(tracker.update(&snapshot(400, &[("STATUS", "ONLINE"), ("BCHARGE", "80.0")])).health, None);
+    }
+
+    #[test]
+    fn test_time_on_battery() {
+        let mut tracker = BatteryTracker::default();
+        let mut on_battery = |timestamp, status, tonbatt| {
+            let estimates = tracker.update(&snapshot(timestamp, &[("STATUS", status), ("TONBATT", tonbatt)]));
+            (estimates.on_battery_24h, estimates.on_battery_7d)
+        };
+        let start = 10 * WEEK;
+        assert_eq!(on_battery(start, "ONLINE", "0"), (0.0, 0.0));
+        // On battery for 30 seconds by the first poll that sees it
+        assert_eq!(on_battery(start + 100, "ONBATT", "30"), (30.0, 30.0));
+        assert_eq!(on_battery(start + 200, "ONBATT", "130"), (130.0, 130.0));
+        assert_eq!(on_battery(start + 300, "ONLINE", "0"), (230.0, 230.0));
+        assert_eq!(on_battery(start + 400, "ONLINE", "0"), (230.0, 230.0));
+        // A day later it's only in the week
+        assert_eq!(on_battery(start + 300 + DAY, "ONLINE", "0"), (0.0, 230.0));
+        assert_eq!(on_battery(start + 300 + DAY + 60, "ONBATT", "0"), (0.0, 230.0));
+        // Then for six days, the first outage out of the week
+        assert_eq!(on_battery(start + 300 + WEEK + 60, "ONLINE", "0"), (DAY as f64, (WEEK - DAY) as f64));
+    }
+
+    #[test]
+    fn test_last_outage() {
+        let mut tracker = BatteryTracker::default();
+        let mut last_outage = |timestamp, stats: &[(&str, &str)]| tracker.update(&snapshot(timestamp, stats)).last_outage;
+        assert_eq!(last_outage(1000, &[("STATUS", "ONLINE")]), None);
+        assert_eq!(last_outage(1010, &[("STATUS", "ONBATT")]), None);
+        assert_eq!(last_outage(1100, &[("STATUS", "ONLINE")]), Some(90.0));
+        // apcupsd's times take precedence, unless they are of an outage in progress
+        let xfers = [("XONBATT", "2024-06-01 12:00:00 +0000"), ("XOFFBATT", "2024-06-01 12:02:05 +0000")];
+        assert_eq!(last_outage(1200, &[&[("STATUS", "ONLINE")], &xfers[..]].concat()), Some(125.0));
+        let xfers = [("XONBATT", "2024-06-01 13:00:00 +0000"), ("XOFFBATT", "2024-06-01 12:02:05 +0000")];
+        assert_eq!(last_outage(1300, &[&[("STATUS", "ONBATT")], &xfers[..]].concat()), Some(90.0));
+    }
+
+    #[test]
+    fn test_until_shutdown() {
+        let mut tracker = BatteryTracker::default();
+        let limits = [("MINTIMEL", "3.0"), ("MBATTCHG", "10.0"), ("MAXTIME", "0")];
+        let poll = |timestamp, stats: &[(&str, &str)]| snapshot(timestamp, &[stats, &limits[..]].concat());
+        let online = poll(0, &[("STATUS", "ONLINE"), ("BCHARGE", "100.0"), ("TIMELEFT", "30.0")]);
+        assert_eq!(tracker.update(&online).until_shutdown, None);
+        // Only the runtime tells at first: 20 minutes down to 3
+        let onbatt = poll(10, &[("STATUS", "ONBATT"), ("BCHARGE", "100.0"), ("TIMELEFT", "20.0")]);
+        assert_eq!(tracker.update(&onbatt).until_shutdown, Some(1020.0));
+        // 10 points in 100 seconds leaves 800 seconds down to 10%
+        let onbatt = poll(110, &[("STATUS", "ONBATT"), ("BCHARGE", "90.0"), ("TIMELEFT", "18.0")]);
+        assert_eq!(tracker.update(&onbatt).until_shutdown, Some(800.0));
+        // MAXTIME ends it sooner
+        let onbatt = poll(120, &[("STATUS", "ONBATT"), ("BCHARGE", "89.0"), ("TIMELEFT", "18.0"), ("TONBATT", "110")]);
+        let mut status = ApcStatus::from(&onbatt.stats);
+        status.maxtime = Some(Duration::from_secs(300));
+        assert_eq!(until_shutdown_of(&status, 89.0, Some(0.1)), 190.0);
+    }
+}
@@ -0,0 +1,222 @@
+//! check.rs
+//!
+//! The `check` subcommand: a Nagios/Icinga plugin that polls apcupsd once
+//! and reports the state through its exit code, with perfdata.
+
+use std::collections::BTreeMap;
+
+use clap::Args;
+
+/// Plugin states, whose values are the exit codes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    Ok = 0,
+    Warning = 1,
+    Critical = 2,
+    Unknown = 3,
+}
+
+impl State {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Ok => "OK",
+            State::Warning => "WARNING",
+            State::Critical => "CRITICAL",
+            State::Unknown => "UNKNOWN",
+        }
+    }
+
+    /// The more severe of two states. As in the monitoring plugins, a
+    /// definite problem outranks an unknown.
+    fn worst(self, other: State) -> State {
+        let rank = |state| match state {
+            State::Ok => 0,
+            State::Unknown => 1,
+            State::Warning => 2,
+            State::Critical => 3,
+        };
+        if rank(other) > rank(self) { other } else { self }
+    }
+}
+
+/// Alert thresholds. Runtime and charge alert below theirs, load above.
+#[derive(Args, Debug, Default)]
+pub struct Thresholds {
+    /// Warn when the runtime left drops below this many minutes
+    #[arg(long)]
+    pub warn_timeleft: Option<f64>,
+    /// Critical when the runtime left drops below this many minutes
+    #[arg(long)]
+    pub crit_timeleft: Option<f64>,
+    /// Warn when the battery charge drops below this percentage
+    #[arg(long)]
+    pub warn_charge: Option<f64>,
+    /// Critical when the battery charge drops below this percentage
+    #[arg(long)]
+    pub crit_charge: Option<f64>,
+    /// Warn when the load rises above this percentage
+    #[arg(long)]
+    pub warn_load: Option<f64>,
+    /// Critical when the load rises above this percentage
+    #[arg(long)]
+    pub crit_load: Option<f64>,
+}
+
+/// A checked value, for the message and the perfdata
+struct Measure {
+    label: &'static str,
+    key: &'static str,
+    unit: &'static str,
+    /// Perfdata unit of measure
+    uom: &'static str,
+    warn: Option<f64>,
+    crit: Option<f64>,
+    /// Whether low values are bad
+    below: bool,
+    max: Option<f64>,
+}
+
+impl Measure {
+    fn state(&self, value: f64) -> State {
+        let breached = |threshold: Option<f64>| {
+            threshold.is_some_and(|t| if self.below { value < t } else { value > t })
+        };
+        if breached(self.crit) {
+            State::Critical
+        } else if breached(self.warn) {
+            State::Warning
+        } else {
+            State::Ok
+        }
+    }
+
+    /// `label=value[uom];warn;crit;min;max`, with thresholds for low values
+    /// in Nagios range notation, e.g. `10:`
+    fn perfdata(&self, value: f64) -> String {
+        let range = |threshold: Option<f64>| match threshold {
+            Some(t) if self.below => format!("{}:", t),
+            Some(t) => t.to_string(),
+            None => String::new(),
+        };
+        format!(
+            "{}={}{};{};{};0;{}",
+            self.key.to_lowercase(),
+            value,
+            self.uom,
+            range(self.warn),
+            range(self.crit),
+            self.max.map(|m| m.to_string()).unwrap_or_default()
+        )
+    }
+}
+
+/// The state from the UPS status flags: on battery warns, a low battery or
+/// lost communication with the UPS is critical.
+fn status_state(status: &str) -> State {
+    let flags: Vec<&str> = status.split_whitespace().collect();
+    if flags.iter().any(|f| matches!(*f, "LOWBATT" | "COMMLOST" | "SHUTTING")) {
+        State::Critical
+    } else if flags.contains(&"ONBATT") || flags.contains(&"REPLACEBATT") {
+        State::Warning
+    } else {
+        State::Ok
+    }
+}
+
+/// Evaluate values parsed with their units stripped. Returns the worst state
+/// and the plugin output line.
+pub fn evaluate(stats: &BTreeMap<String, String>, thresholds: &Thresholds) -> (State, String) {
+    let measures = [
+        Measure {
+            label: "charge",
+            key: "BCHARGE",
+            unit: "%",
+            uom: "%",
+            warn: thresholds.warn_charge,
+            crit: thresholds.crit_charge,
+            below: true,
+            max: Some(100.0),
+        },
+        Measure {
+            label: "runtime",
+            key: "TIMELEFT",
+            unit: " min",
+            uom: "",
+            warn: thresholds.warn_timeleft,
+            crit: thresholds.crit_timeleft,
+            below: true,
+            max: None,
+        },
+        Measure {
+            label: "load",
+            key: "LOADPCT",
+            unit: "%",
+            uom: "%",
+            warn: thresholds.warn_load,
+            crit: thresholds.crit_load,
+            below: false,
+            max: Some(100.0),
+        },
+    ];
+
+    let status = stats.get("STATUS").map(String::as_str).unwrap_or("");
+    let mut state = status_state(status);
+    let mut summary = vec![format!(
+        "{} {}",
+        stats.get("UPSNAME").map(String::as_str).unwrap_or("UPS"),
+        if status.is_empty() { "status unknown" } else { status }
+    )];
+    let mut perfdata = Vec::new();
+    for measure in &measures {
+        let raw = stats.get(measure.key);
+        let Some(value) = raw.and_then(|v| v.parse::<f64>().ok()) else {
+            if measure.warn.is_some() || measure.crit.is_some() {
+                state = state.worst(State::Unknown);
+                summary.push(format!("{} missing", measure.label));
+            }
+            continue;
+        };
+        state = state.worst(measure.state(value));
+        summary.push(format!("{} {}{}", measure.label, raw.map_or("", String::as_str), measure.unit));
+        perfdata.push(measure.perfdata(value));
+    }
+    (state, format!("UPS {} - {} | {}", state.as_str(), summary.join(", "), perfdata.join(" ")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(status: &str, charge: &str, timeleft: &str, load: &str) -> BTreeMap<String, String> {
+        BTreeMap::from([
+            ("UPSNAME".to_string(), "rack1".to_string()),
+            ("STATUS".to_string(), status.to_string()),
+            ("BCHARGE".to_string(), charge.to_string()),
+            ("TIMELEFT".to_string(), timeleft.to_string()),
+            ("LOADPCT".to_string(), load.to_string()),
+        ])
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let thresholds = Thresholds {
+            warn_timeleft: Some(10.0),
+            crit_timeleft: Some(5.0),
+            warn_load: Some(80.0),
+            ..Default::default()
+        };
+        let (state, output) = evaluate(&stats("ONLINE", "100.0", "45.3", "12.0"), &thresholds);
+        assert_eq!(state, State::Ok);
+        assert_eq!(
+            output,
+            "UPS OK - rack1 ONLINE, charge 100.0%, runtime 45.3 min, load 12.0% | \
+             bcharge=100%;;;0;100 timeleft=45.3;10:;5:;0; loadpct=12%;80;;0;100"
+        );
+
+        assert_eq!(evaluate(&stats("ONLINE", "100.0", "45.3", "85.0"), &thresholds).0, State::Warning);
+        assert_eq!(evaluate(&stats("ONBATT", "60.0", "4.5", "12.0"), &thresholds).0, State::Critical);
+        assert_eq!(evaluate(&stats("ONBATT", "90.0", "30.0", "12.0"), &thresholds).0, State::Warning);
+        assert_eq!(evaluate(&stats("ONLINE", "100.0", "n/a", "12.0"), &thresholds).0, State::Unknown);
+        assert_eq!(evaluate(&stats("ONBATT LOWBATT", "9.0", "n/a", "12.0"), &thresholds).0, State::Critical);
+    }
+}
@@ -0,0 +1,109 @@
+//! cli.rs
+//!
+//! Command line interface. Without a subcommand the exporter runs; settings
+//! still come from the environment.
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+#[derive(Parser)]
+#[command(version, about = "Prometheus exporter for apcupsd")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Send a test event through the configured notifiers and report the outcome
+    NotifyTest {
+        /// Only notify this channel, by name (e.g. `webhook #2`) or type
+        /// (e.g. `webhook`). Exec hooks only run when selected here.
+        #[arg(long)]
+        channel: Option<String>,
+        /// Event type to simulate
+        #[arg(long, default_value = "on_battery", value_parser = parse_event)]
+        event: crate::events::EventKind,
+    },
+    /// Poll apcupsd once and print its values as a table
+    Status {
+        #[command(flatten)]
+        nis: Nis,
+    },
+    /// Show the key values in the terminal, refreshed in place with
+    /// sparklines of their history. Stop with Ctrl-C.
+    Watch {
+        #[command(flatten)]
+        nis: Nis,
+        /// Seconds between refreshes
+        #[arg(long, default_value_t = 2)]
+        interval: u64,
+    },
+    /// Poll apcupsd once as a Nagios/Icinga plugin: print the state with
+    /// perfdata and exit 0 (OK), 1 (WARNING), 2 (CRITICAL) or 3 (UNKNOWN)
+    Check {
+        #[command(flatten)]
+        nis: Nis,
+        #[command(flatten)]
+        thresholds: crate::check::Thresholds,
+    },
+    /// Print the configuration in effect, from the environment with defaults
+    /// filled in and the config file, with secrets redacted
+    PrintConfig {
+        #[arg(long, value_enum, default_value_t = ConfigFormat::Toml)]
+        format: ConfigFormat,
+    },
+    /// Check a config file and the routes in it against the notifiers in the
+    /// environment. Exits non-zero if it doesn't load.
+    Validate {
+        /// Config file to check, instead of `CONFIG_FILE`
+        #[arg(short, long)]
+        config: Option<std::path::PathBuf>,
+        /// Also fail on warnings
+        #[arg(long)]
+        strict: bool,
+    },
+    /// Write a backup of the SQLite history at `SQLITE_PATH` to a gzipped
+    /// tarball, which `SQLITE_RESTORE` restores. Safe while the exporter runs.
+    Backup {
+        /// Tarball to write
+        #[arg(short, long, default_value = "rsapcupsdexporter-backup.tar.gz")]
+        output: std::path::PathBuf,
+    },
+    /// Print a shell completion script, e.g. for bash:
+    /// `rsapcupsdexporter completions bash > /etc/bash_completion.d/rsapcupsdexporter`
+    Completions {
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Exit 0 if the running exporter is healthy and 1 otherwise, for
+    /// container health checks. In textfile mode, checks the metrics file.
+    Healthcheck {
+        /// URL to check instead of `/-/healthy` on the local `METRICS_PORT`
+        #[arg(long)]
+        url: Option<String>,
+        /// Seconds to wait for a response
+        #[arg(long, default_value_t = 5)]
+        timeout: u64,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Json,
+}
+
+/// The apcupsd to poll, for subcommands that talk to it directly
+#[derive(Args)]
+pub struct Nis {
+    /// apcupsd host, instead of `APCUPSD_HOST`
+    #[arg(long)]
+    pub host: Option<String>,
+    /// apcupsd NIS port, instead of `APCUPSD_PORT`
+    #[arg(long)]
+    pub port: Option<u16>,
+}
+
+fn parse_event(name: &str) -> Result<crate::events::EventKind, String> {
+    crate::events::EventKind::parse(name).ok_or_else(|| format!("unknown event type {:?}", name))
+}
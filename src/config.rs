@@ -0,0 +1,301 @@
+//! config.rs
+//!
+//! Optional TOML config file for settings that don't fit in environment
+//! variables. Its path is taken from `CONFIG_FILE`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::alerts::{AlertRule, Alerts};
+use crate::events::{Severities, SeverityMap};
+use crate::notify::routes::Route;
+use crate::notify::silence::MaintenanceWindow;
+use crate::targets::{TargetConfig, TargetGroup};
+
+/// Error type for loading the config file
+#[derive(Debug)]
+pub enum ConfigError {
+    IoError(std::io::Error),
+    ParseError(toml::de::Error),
+    /// Parsed, but refers to something that doesn't exist
+    Invalid(String),
+}
+
+impl From<std::io::Error> for ConfigError {
+    fn from(err: std::io::Error) -> Self {
+        ConfigError::IoError(err)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(err: toml::de::Error) -> Self {
+        ConfigError::ParseError(err)
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::IoError(e) => write!(f, "IO Error: {}", e),
+            ConfigError::ParseError(e) => write!(f, "Parse Error: {}", e),
+            ConfigError::Invalid(e) => write!(f, "Invalid: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// apcupsd hosts to poll instead of `APCUPSD_HOST`
+    #[serde(default)]
+    pub targets: Vec<TargetConfig>,
+    /// Settings and routes shared by the targets naming the group
+    #[serde(default)]
+    pub group: BTreeMap<String, TargetGroup>,
+    /// Which events each notification channel receives
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Recurring windows during which notifications are muted
+    #[serde(default)]
+    pub maintenance: Vec<MaintenanceWindow>,
+    /// Thresholds exported as `apcupsd_alert` and raised as events
+    #[serde(default)]
+    pub alerts: Vec<AlertRule>,
+    /// Severities of event types other than the built-in ones
+    #[serde(default)]
+    pub severities: SeverityMap,
+}
+
+/// Notifier types that routes can name
+const CHANNEL_TYPES: &[&str] = &["webhook", "slack", "discord", "telegram", "ntfy", "pagerduty", "email", "exec"];
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path)?;
+        let mut config: Config = toml::from_str(&content)?;
+        config.apply_groups().map_err(ConfigError::Invalid)?;
+        Alerts::validate(&config.alerts).map_err(ConfigError::Invalid)?;
+        MaintenanceWindow::validate(&config.maintenance).map_err(ConfigError::Invalid)?;
+        Ok(config)
+    }
+
+    /// Fill in the targets' settings from their groups, and add the groups'
+    /// routes limited to their targets.
+    fn apply_groups(&mut self) -> Result<(), String> {
+        for target in &mut self.targets {
+            let Some(name) = &target.group else { continue };
+            let group = self
+                .group
+                .get(name)
+                .ok_or_else(|| format!("target {} is in group {:?}, which isn't defined", target.name(), name))?;
+            target.inherit(group);
+        }
+        for (name, group) in &self.group {
+            let members: Vec<String> =
+                self.targets.iter().filter(|t| t.group.as_ref() == Some(name)).map(TargetConfig::name).collect();
+            for route in &group.routes {
+                let mut route = route.clone();
+                route.targets = Some(match route.targets {
+                    Some(targets) => members.iter().filter(|m| targets.contains(m)).cloned().collect(),
+                    None => members.clone(),
+                });
+                self.routes.push(route);
+            }
+        }
+        Ok(())
+    }
+
+    /// The severities of event types, with those of a group for its targets
+    pub fn severities(&self) -> Severities {
+        let targets: HashMap<String, SeverityMap> = self
+            .targets
+            .iter()
+            .filter_map(|target| {
+                let group = self.group.get(target.group.as_ref()?)?;
+                (!group.severities.is_empty()).then(|| (target.name(), group.severities.clone()))
+            })
+            .collect();
+        Severities::new(self.severities.clone(), targets)
+    }
+
+    /// Load the file named by `CONFIG_FILE`, or the defaults if it isn't set.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        match std::env::var("CONFIG_FILE") {
+            Ok(path) => Config::load(Path::new(&path)),
+            Err(_) => Ok(Config::default()),
+        }
+    }
+
+    /// Settings that load fine but probably don't do what was meant, given
+    /// the names of the notifiers configured in the environment.
+    pub fn warnings(&self, notifiers: &[&str]) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (i, route) in self.routes.iter().enumerate() {
+            let entry = format!("routes[{}] ({:?})", i, route.channel);
+            let kind = route.channel.split(' ').next().unwrap_or_default();
+            if !CHANNEL_TYPES.contains(&kind) {
+                warnings.push(format!("{}: unknown channel type, expected one of {}", entry, CHANNEL_TYPES.join(", ")));
+            } else if !notifiers.iter().any(|n| crate::notify::channel_matches(n, &route.channel)) {
+                warnings.push(format!("{}: no such notifier is configured, so the route has no effect", entry));
+            }
+            if route.events.as_ref().is_some_and(Vec::is_empty) || route.severities.as_ref().is_some_and(Vec::is_empty) {
+                warnings.push(format!("{}: empty events or severities, so the channel never receives an event", entry));
+            }
+        }
+        for name in self.group.keys() {
+            if !self.targets.iter().any(|t| t.group.as_ref() == Some(name)) {
+                warnings.push(format!("group.{}: no target is in it, so it has no effect", name));
+            }
+        }
+        for (i, window) in self.maintenance.iter().enumerate() {
+            if window.duration.is_zero() {
+                warnings.push(format!("maintenance[{}]: zero duration, so it never mutes anything", i));
+            }
+            if window.days.as_ref().is_some_and(Vec::is_empty) {
+                warnings.push(format!("maintenance[{}]: empty days, so it never starts", i));
+            }
+        }
+        warnings
+    }
+}
+
+/// Parse a duration such as `90`, `90s`, `30m`, `2h` or `1d`. Bare numbers
+/// are seconds. Durations too long to count in seconds are invalid.
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let multiplier = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86_400,
+        _ => return None,
+    };
+    number.parse::<u64>().ok().and_then(|n| n.checked_mul(multiplier)).map(Duration::from_secs)
+}
+
+/// Deserialize a duration given as seconds or as a string for `parse_duration`.
+pub fn deserialize_duration<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Seconds(u64),
+        Text(String),
+    }
+    match Value::deserialize(deserializer)? {
+        Value::Seconds(seconds) => Ok(Duration::from_secs(seconds)),
+        Value::Text(text) => {
+            parse_duration(&text).ok_or_else(|| serde::de::Error::custom(format!("invalid duration {:?}", text)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{EventKind, Severity};
+
+    #[test]
+    fn test_unknown_section() {
+        assert!(toml::from_str::<Config>("[route]\nchannel = \"email\"\n").is_err());
+        assert!(toml::from_str::<Config>("[[routes]]\nchannel = \"email\"\nevents = [\"flood\"]\n").is_err());
+        let config: Config = toml::from_str("[[routes]]\nchannel = \"email\"\n").unwrap();
+        assert_eq!(config.routes.len(), 1);
+    }
+
+    #[test]
+    fn test_warnings() {
+        let config: Config = toml::from_str(
+            r#"
+            [[routes]]
+            channel = "emial"
+
+            [[routes]]
+            channel = "webhook #2"
+            events = []
+
+            [[maintenance]]
+            start = "02:00"
+            duration = 0
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.warnings(&["webhook #1", "email"]),
+            vec![
+                "routes[0] (\"emial\"): unknown channel type, expected one of webhook, slack, discord, telegram, ntfy, pagerduty, email, exec",
+                "routes[1] (\"webhook #2\"): no such notifier is configured, so the route has no effect",
+                "routes[1] (\"webhook #2\"): empty events or severities, so the channel never receives an event",
+                "maintenance[0]: zero duration, so it never mutes anything",
+            ]
+        );
+        assert_eq!(config.warnings(&["webhook #2", "emial"]).len(), 3);
+    }
+
+    #[test]
+    fn test_groups() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [group.dc1]
+            interval = "30s"
+            labels = { dc = "dc1", rack = "a" }
+
+            [group.dc1.severities]
+            comm_lost = "critical"
+
+            [[group.dc1.routes]]
+            channel = "pagerduty"
+            events = ["LOWBATT"]
+
+            [[targets]]
+            host = "ups1"
+            group = "dc1"
+            labels = { rack = "b" }
+
+            [[targets]]
+            host = "ups2"
+            interval = 5
+            group = "dc1"
+
+            [[targets]]
+            host = "ups3"
+
+            [severities]
+            COMMLOST = "warning"
+            "#,
+        )
+        .unwrap();
+        config.apply_groups().unwrap();
+        let severities = config.severities();
+        assert_eq!(severities.of(EventKind::CommLost, "ups1"), Severity::Critical);
+        assert_eq!(severities.of(EventKind::CommLost, "ups3"), Severity::Warning);
+        assert_eq!(severities.of(EventKind::LowBattery, "ups3"), Severity::Critical);
+        assert_eq!(config.targets[0].interval, Some(Duration::from_secs(30)));
+        assert_eq!(config.targets[0].labels["dc"], "dc1");
+        assert_eq!(config.targets[0].labels["rack"], "b");
+        assert_eq!(config.targets[1].interval, Some(Duration::from_secs(5)));
+        assert_eq!(config.targets[2].interval, None);
+        assert_eq!(config.routes[0].targets, Some(vec!["ups1".to_string(), "ups2".to_string()]));
+
+        let mut config: Config = toml::from_str("[[targets]]\nhost = \"ups1\"\ngroup = \"dc2\"\n").unwrap();
+        assert!(config.apply_groups().is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(1800)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("2 weeks"), None);
+        assert_eq!(parse_duration("h"), None);
+        assert_eq!(parse_duration("999999999999999999d"), None);
+    }
+}
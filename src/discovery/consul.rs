@@ -0,0 +1,177 @@
+//! discovery/consul.rs
+//!
+//! Targets registered in the Consul catalog as the service named by
+//! `CONSUL_SERVICE`, optionally only those with every tag in `CONSUL_TAGS`.
+//! The catalog is watched with blocking queries, so changes show up as soon
+//! as Consul knows about them. Service metadata becomes target labels.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use super::Provider;
+use crate::targets::TargetConfig;
+
+/// How long Consul may hold a blocking query before answering anyway
+const WAIT: &str = "30s";
+
+/// Pause before asking again after a failed lookup
+const RETRY: Duration = Duration::from_secs(10);
+
+/// The fields used of an entry of `/v1/catalog/service/:service`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CatalogService {
+    node: String,
+    address: String,
+    #[serde(default)]
+    service_address: String,
+    service_port: u16,
+    /// Null for services registered without any
+    #[serde(default)]
+    service_meta: Option<BTreeMap<String, String>>,
+}
+
+pub struct ConsulProvider {
+    agent: ureq::Agent,
+    url: String,
+    service: String,
+    tags: Vec<String>,
+    datacenter: Option<String>,
+    token: Option<String>,
+    /// `X-Consul-Index` of the last answer, to block until it changes
+    index: u64,
+    failed: bool,
+}
+
+impl ConsulProvider {
+    pub fn from_env() -> Option<Self> {
+        let service = std::env::var("CONSUL_SERVICE").ok().filter(|s| !s.trim().is_empty())?;
+        let address = std::env::var("CONSUL_HTTP_ADDR").unwrap_or_else(|_| "http://127.0.0.1:8500".to_string());
+        // Consul's own tools accept the address without a scheme
+        let url = match address.contains("://") {
+            true => address.trim_end_matches('/').to_string(),
+            false => format!("http://{}", address.trim_end_matches('/')),
+        };
+        let tags = std::env::var("CONSUL_TAGS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|t| !t.is_empty())
+            .map(str::to_string)
+            .collect();
+        Some(ConsulProvider {
+            // Long enough to outlast the blocking query
+            agent: crate::notify::http_agent(Duration::from_secs(45)),
+            url,
+            service: service.trim().to_string(),
+            tags,
+            datacenter: std::env::var("CONSUL_DATACENTER").ok().filter(|d| !d.is_empty()),
+            token: std::env::var("CONSUL_HTTP_TOKEN").ok().filter(|t| !t.is_empty()),
+            index: 0,
+            failed: false,
+        })
+    }
+
+    fn lookup(&mut self) -> Result<Vec<TargetConfig>, String> {
+        let mut request = self
+            .agent
+            .get(&format!("{}/v1/catalog/service/{}", self.url, self.service))
+            .query("index", &self.index.to_string())
+            .query("wait", WAIT);
+        for tag in &self.tags {
+            request = request.query("tag", tag);
+        }
+        if let Some(datacenter) = &self.datacenter {
+            request = request.query("dc", datacenter);
+        }
+        if let Some(token) = &self.token {
+            request = request.set("X-Consul-Token", token);
+        }
+        let response = request.call().map_err(|e| e.to_string())?;
+        let index = response.header("X-Consul-Index").and_then(|i| i.parse().ok()).unwrap_or(0);
+        let services: Vec<CatalogService> = response.into_json().map_err(|e| e.to_string())?;
+        // An index going backwards means Consul's state was reset, start over
+        self.index = if index < self.index { 0 } else { index };
+        Ok(services.iter().map(to_target).collect())
+    }
+}
+
+impl Provider for ConsulProvider {
+    fn name(&self) -> &'static str {
+        "consul"
+    }
+
+    /// The blocking query does the waiting, the pause only keeps a Consul
+    /// that answers right away from being asked in a loop
+    fn every(&self) -> Duration {
+        match self.failed {
+            true => RETRY,
+            false => Duration::from_secs(1),
+        }
+    }
+
+    fn discover(&mut self) -> Result<Vec<TargetConfig>, String> {
+        let result = self.lookup();
+        self.failed = result.is_err();
+        if self.failed {
+            self.index = 0;
+        }
+        result
+    }
+}
+
+/// A target at the service's address, or its node's if the service has
+/// none, named after the node. Metadata keys are made valid label names.
+fn to_target(service: &CatalogService) -> TargetConfig {
+    let host = match service.service_address.is_empty() {
+        true => &service.address,
+        false => &service.service_address,
+    };
+    let mut target = TargetConfig::new(host, service.service_port);
+    target.name = Some(match service.service_port {
+        3551 => service.node.clone(),
+        port => format!("{}:{}", service.node, port),
+    });
+    target.labels =
+        service.service_meta.iter().flatten().map(|(key, value)| (label_name(key), value.clone())).collect();
+    target
+}
+
+/// Replace what isn't allowed in a Prometheus label name with underscores
+fn label_name(key: &str) -> String {
+    let mut name: String = key.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_target() {
+        let services: Vec<CatalogService> = serde_json::from_str(
+            r#"[
+                {"Node": "nas", "Address": "10.0.0.5", "ServiceAddress": "", "ServicePort": 3551,
+                 "ServiceMeta": {"rack": "r1", "power-feed": "a"}, "ServiceTags": ["ups"]},
+                {"Node": "nas", "Address": "10.0.0.5", "ServiceAddress": "10.0.1.5", "ServicePort": 3552,
+                 "ServiceMeta": null}
+            ]"#,
+        )
+        .unwrap();
+        let target = to_target(&services[0]);
+        assert_eq!(target.host, "10.0.0.5");
+        assert_eq!(target.name.as_deref(), Some("nas"));
+        assert_eq!(target.labels.get("power_feed").map(String::as_str), Some("a"));
+        assert_eq!(target.labels.get("rack").map(String::as_str), Some("r1"));
+        let target = to_target(&services[1]);
+        assert_eq!(target.host, "10.0.1.5");
+        assert_eq!(target.name.as_deref(), Some("nas:3552"));
+        assert!(target.labels.is_empty());
+        assert_eq!(label_name("1st.floor"), "_1st_floor");
+    }
+}
@@ -0,0 +1,167 @@
+//! discovery/file.rs
+//!
+//! Targets from a Prometheus `file_sd`-style file named by `TARGETS_FILE`:
+//! a JSON or YAML list of groups of `host:port` targets with their labels.
+//! The file is read again every `TARGETS_FILE_INTERVAL`, so targets can be
+//! added and removed without a restart.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::Provider;
+use crate::config::parse_duration;
+use crate::targets::TargetConfig;
+
+/// One group of the file, the targets share its labels
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Group {
+    pub targets: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+}
+
+pub struct FileProvider {
+    path: PathBuf,
+    every: Duration,
+}
+
+impl FileProvider {
+    pub fn from_env() -> Option<Self> {
+        let path = std::env::var_os("TARGETS_FILE").filter(|p| !p.is_empty())?;
+        let every = std::env::var("TARGETS_FILE_INTERVAL")
+            .ok()
+            .and_then(|v| parse_duration(&v))
+            .filter(|d| !d.is_zero())
+            .unwrap_or(Duration::from_secs(10));
+        Some(FileProvider { path: path.into(), every })
+    }
+}
+
+impl Provider for FileProvider {
+    fn name(&self) -> &'static str {
+        "file"
+    }
+
+    fn every(&self) -> Duration {
+        self.every
+    }
+
+    fn discover(&mut self) -> Result<Vec<TargetConfig>, String> {
+        load(&self.path).map_err(|e| format!("{}: {}", self.path.display(), e))
+    }
+}
+
+fn is_yaml(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "yml" || ext == "yaml")
+}
+
+/// Read the groups of a file, as YAML if it's named `.yml` or `.yaml` and
+/// as JSON otherwise.
+pub fn read(path: &Path) -> Result<Vec<Group>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    match is_yaml(path) {
+        true => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        false => serde_json::from_str(&content).map_err(|e| e.to_string()),
+    }
+}
+
+/// Replace the file with the groups, in the format it's read in. The file
+/// is swapped in whole, so it's never read half written.
+#[cfg(any(feature = "http", feature = "http-lite"))]
+pub fn write(path: &Path, groups: &[Group]) -> Result<(), String> {
+    let content = match is_yaml(path) {
+        true => serde_yaml::to_string(groups).map_err(|e| e.to_string())?,
+        false => serde_json::to_string_pretty(groups).map_err(|e| e.to_string())? + "\n",
+    };
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    std::fs::write(&tmp, content).map_err(|e| e.to_string())?;
+    std::fs::rename(&tmp, path).map_err(|e| e.to_string())
+}
+
+/// Read the targets of a file
+pub fn load(path: &Path) -> Result<Vec<TargetConfig>, String> {
+    let mut targets = Vec::new();
+    for group in read(path)? {
+        // Labels starting with __ are Prometheus' internal ones
+        let labels: BTreeMap<String, String> =
+            group.labels.into_iter().filter(|(name, _)| !name.starts_with("__")).collect();
+        for address in &group.targets {
+            let (host, port) = parse_address(address).ok_or_else(|| format!("invalid target {:?}", address))?;
+            let mut target = TargetConfig::new(&host, port);
+            target.labels = labels.clone();
+            targets.push(target);
+        }
+    }
+    Ok(targets)
+}
+
+/// A target's address as written in the file, the reverse of
+/// `parse_address`
+#[cfg(any(feature = "http", feature = "http-lite"))]
+pub fn format_address(host: &str, port: u16) -> String {
+    match host.contains(':') {
+        true => format!("[{}]:{}", host, port),
+        false => format!("{}:{}", host, port),
+    }
+}
+
+/// Split `host`, `host:port` or `[v6 address]:port`, the port defaulting
+/// to 3551.
+pub fn parse_address(address: &str) -> Option<(String, u16)> {
+    let address = address.trim();
+    if let Some(rest) = address.strip_prefix('[') {
+        let (host, port) = rest.split_once(']')?;
+        let port = match port.strip_prefix(':') {
+            Some(port) => port.parse().ok()?,
+            None if port.is_empty() => 3551,
+            None => return None,
+        };
+        return Some((host.to_string(), port));
+    }
+    match address.split_once(':') {
+        Some((host, port)) if !host.is_empty() => Some((host.to_string(), port.parse().ok()?)),
+        Some(_) => None,
+        None if address.is_empty() => None,
+        None => Some((address.to_string(), 3551)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(parse_address("rack1"), Some(("rack1".to_string(), 3551)));
+        assert_eq!(parse_address("10.0.0.5:3552"), Some(("10.0.0.5".to_string(), 3552)));
+        assert_eq!(parse_address("[fd00::5]:3552"), Some(("fd00::5".to_string(), 3552)));
+        assert_eq!(parse_address("[fd00::5]"), Some(("fd00::5".to_string(), 3551)));
+        assert_eq!(parse_address("rack1:http"), None);
+        assert_eq!(parse_address(""), None);
+    }
+
+    #[test]
+    fn test_load() {
+        let dir = std::env::temp_dir();
+        let json = dir.join(format!("rsapcupsdexporter-targets-{}.json", std::process::id()));
+        std::fs::write(&json, r#"[{"targets": ["rack1", "rack2:3552"], "labels": {"dc": "fra1", "__meta_x": "y"}}]"#).unwrap();
+        let targets = load(&json).unwrap();
+        std::fs::remove_file(&json).unwrap();
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[1].host, "rack2");
+        assert_eq!(targets[1].port, 3552);
+        assert_eq!(targets[1].labels, BTreeMap::from([("dc".to_string(), "fra1".to_string())]));
+
+        let yaml = dir.join(format!("rsapcupsdexporter-targets-{}.yml", std::process::id()));
+        std::fs::write(&yaml, "- targets: [\"10.0.0.5\"]\n").unwrap();
+        let targets = load(&yaml).unwrap();
+        std::fs::remove_file(&yaml).unwrap();
+        assert_eq!(targets[0].host, "10.0.0.5");
+        assert!(targets[0].labels.is_empty());
+    }
+}
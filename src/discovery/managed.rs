@@ -0,0 +1,234 @@
+//! discovery/managed.rs
+//!
+//! Targets added and removed through `/api/v1/targets`, enabled with
+//! `TARGETS_API`. They are kept in memory as a source of their own, or with
+//! `TARGETS_API_PERSIST` written to `TARGETS_FILE`, so they outlast a
+//! restart and the file stays the one list of discovered targets.
+
+use std::path::PathBuf;
+
+use tokio::sync::{mpsc, Mutex};
+use tracing::error;
+
+use super::{file, Discovered};
+use crate::targets::{Defaults, Target, TargetConfig};
+
+/// Why a change was refused
+#[derive(Debug, PartialEq)]
+pub enum ChangeError {
+    /// A target of that name is already managed
+    Exists(String),
+    NotFound,
+    Invalid(String),
+    /// The targets file couldn't be read or written
+    Persist(String),
+}
+
+impl std::fmt::Display for ChangeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChangeError::Exists(name) => write!(f, "target {} already exists", name),
+            ChangeError::NotFound => write!(f, "no such target"),
+            ChangeError::Invalid(e) => write!(f, "{}", e),
+            ChangeError::Persist(e) => write!(f, "failed to update the targets file: {}", e),
+        }
+    }
+}
+
+pub struct ManagedTargets {
+    defaults: Defaults,
+    /// `TARGETS_FILE`, if changes are written to it
+    file: Option<PathBuf>,
+    /// The targets added in memory. The lock is held until the fleet has
+    /// the change, so concurrent changes reach it in order.
+    targets: Mutex<Vec<TargetConfig>>,
+    fleet: mpsc::Sender<Discovered>,
+}
+
+impl ManagedTargets {
+    pub fn from_env(defaults: &Defaults, fleet: mpsc::Sender<Discovered>) -> Option<Self> {
+        let enabled = |name: &str| {
+            std::env::var(name)
+                .map(|v| matches!(v.as_str(), "1" | "true" | "yes"))
+                .unwrap_or(false)
+        };
+        if !enabled("TARGETS_API") {
+            return None;
+        }
+        let file = match enabled("TARGETS_API_PERSIST") {
+            true => match std::env::var_os("TARGETS_FILE").filter(|p| !p.is_empty()) {
+                Some(path) => Some(path.into()),
+                None => {
+                    error!("TARGETS_API_PERSIST is set without TARGETS_FILE, targets added through the API are lost on restart");
+                    None
+                }
+            },
+            false => None,
+        };
+        Some(ManagedTargets {
+            defaults: defaults.clone(),
+            file,
+            targets: Mutex::new(Vec::new()),
+            fleet,
+        })
+    }
+
+    /// Start polling a target, unless one of the same name is managed already
+    pub async fn add(&self, target: TargetConfig) -> Result<Target, ChangeError> {
+        validate(&target)?;
+        let name = target.name();
+        let mut targets = self.targets.lock().await;
+        match &self.file {
+            Some(path) => {
+                // The file only knows addresses and labels
+                if target.name.is_some()
+                    || target.interval.is_some()
+                    || target.timeout.is_some()
+                    || target.retries.is_some()
+                    || target.jitter.is_some()
+                    || target.on_failure.is_some()
+                {
+                    return Err(ChangeError::Invalid(
+                        "only host, port and labels can be kept in TARGETS_FILE".to_string(),
+                    ));
+                }
+                let mut groups = match path.exists() {
+                    true => file::read(path).map_err(ChangeError::Persist)?,
+                    false => Vec::new(),
+                };
+                if groups.iter().flat_map(|g| &g.targets).any(|a| address_name(a).as_deref() == Some(&name)) {
+                    return Err(ChangeError::Exists(name));
+                }
+                groups.push(file::Group {
+                    targets: vec![file::format_address(&target.host, target.port)],
+                    labels: target.labels.clone(),
+                });
+                file::write(path, &groups).map_err(ChangeError::Persist)?;
+                self.reload(path).await?;
+            }
+            None => {
+                if targets.iter().any(|t| t.name() == name) {
+                    return Err(ChangeError::Exists(name));
+                }
+                targets.push(target.clone());
+                let _ = self.fleet.send(("api", targets.clone())).await;
+            }
+        }
+        Ok(target.resolve(&self.defaults))
+    }
+
+    /// Stop polling a target added before
+    pub async fn remove(&self, name: &str) -> Result<(), ChangeError> {
+        let mut targets = self.targets.lock().await;
+        match &self.file {
+            Some(path) => {
+                let mut groups = file::read(path).map_err(ChangeError::Persist)?;
+                let before: usize = groups.iter().map(|g| g.targets.len()).sum();
+                for group in &mut groups {
+                    group.targets.retain(|a| address_name(a).as_deref() != Some(name));
+                }
+                groups.retain(|g| !g.targets.is_empty());
+                if groups.iter().map(|g| g.targets.len()).sum::<usize>() == before {
+                    return Err(ChangeError::NotFound);
+                }
+                file::write(path, &groups).map_err(ChangeError::Persist)?;
+                self.reload(path).await?;
+            }
+            None => {
+                let before = targets.len();
+                targets.retain(|t| t.name() != name);
+                if targets.len() == before {
+                    return Err(ChangeError::NotFound);
+                }
+                let _ = self.fleet.send(("api", targets.clone())).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Hand the file's targets to the fleet right away, rather than once
+    /// the file is next read
+    async fn reload(&self, path: &std::path::Path) -> Result<(), ChangeError> {
+        let targets = file::load(path).map_err(ChangeError::Persist)?;
+        let _ = self.fleet.send(("file", targets)).await;
+        Ok(())
+    }
+}
+
+/// The name of a target of the file, as given by its address
+fn address_name(address: &str) -> Option<String> {
+    file::parse_address(address).map(|(host, port)| TargetConfig::new(&host, port).name())
+}
+
+fn validate(target: &TargetConfig) -> Result<(), ChangeError> {
+    if target.host.trim().is_empty() {
+        return Err(ChangeError::Invalid("host is empty".to_string()));
+    }
+    if target.group.is_some() {
+        return Err(ChangeError::Invalid("groups only apply to the targets of the config file".to_string()));
+    }
+    let valid = |name: &str| {
+        name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            && !name.starts_with("__")
+    };
+    match target.labels.keys().find(|name| !valid(name)) {
+        Some(name) => Err(ChangeError::Invalid(format!("invalid label name {:?}", name))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn managed(file: Option<PathBuf>) -> (ManagedTargets, mpsc::Receiver<Discovered>) {
+        let (sender, receiver) = mpsc::channel(4);
+        let managed = ManagedTargets {
+            defaults: Defaults::from_env(),
+            file,
+            targets: Mutex::new(Vec::new()),
+            fleet: sender,
+        };
+        (managed, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_in_memory() {
+        let (managed, mut fleet) = managed(None);
+        let target = managed.add(TargetConfig::new("rack1", 3552)).await.unwrap();
+        assert_eq!(target.name, "rack1:3552");
+        assert_eq!(fleet.recv().await.unwrap(), ("api", vec![TargetConfig::new("rack1", 3552)]));
+        assert_eq!(managed.add(TargetConfig::new("rack1", 3552)).await.unwrap_err(), ChangeError::Exists(target.name));
+
+        let mut invalid = TargetConfig::new("rack2", 3551);
+        invalid.labels.insert("power-feed".to_string(), "a".to_string());
+        assert!(matches!(managed.add(invalid).await, Err(ChangeError::Invalid(_))));
+
+        managed.remove("rack1:3552").await.unwrap();
+        assert_eq!(fleet.recv().await.unwrap(), ("api", Vec::new()));
+        assert_eq!(managed.remove("rack1:3552").await, Err(ChangeError::NotFound));
+    }
+
+    #[tokio::test]
+    async fn test_persisted() {
+        let path = std::env::temp_dir().join(format!("rsapcupsdexporter-managed-{}.json", std::process::id()));
+        std::fs::write(&path, r#"[{"targets": ["rack1"], "labels": {"dc": "fra1"}}]"#).unwrap();
+        let (managed, mut fleet) = managed(Some(path.clone()));
+
+        let mut target = TargetConfig::new("fd00::5", 3552);
+        target.labels.insert("dc".to_string(), "ams2".to_string());
+        managed.add(target).await.unwrap();
+        let (source, targets) = fleet.recv().await.unwrap();
+        assert_eq!(source, "file");
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[1].host, "fd00::5");
+        assert_eq!(managed.add(TargetConfig::new("rack1", 3551)).await, Err(ChangeError::Exists("rack1".to_string())));
+
+        managed.remove("rack1").await.unwrap();
+        let (_, targets) = fleet.recv().await.unwrap();
+        assert_eq!(targets.len(), 1);
+        assert_eq!(file::read(&path).unwrap().len(), 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
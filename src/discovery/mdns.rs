@@ -0,0 +1,110 @@
+//! discovery/mdns.rs
+//!
+//! Targets advertising the apcupsd NIS service over mDNS, for zero
+//! configuration on flat networks. Enabled with `TARGETS_MDNS`; apcupsd
+//! doesn't advertise itself, but e.g. an Avahi service file can.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent, ServiceInfo};
+use tracing::{debug, error};
+
+use super::Provider;
+use crate::targets::TargetConfig;
+
+/// Service type browsed for unless `TARGETS_MDNS_SERVICE` says otherwise
+const SERVICE: &str = "_apcupsd._tcp.local.";
+
+pub struct MdnsProvider {
+    /// Browses in the background for as long as it's kept
+    _daemon: ServiceDaemon,
+    events: Receiver<ServiceEvent>,
+    /// Resolved services by their full name
+    found: BTreeMap<String, TargetConfig>,
+}
+
+impl MdnsProvider {
+    pub fn from_env() -> Option<Self> {
+        let enabled = std::env::var("TARGETS_MDNS")
+            .map(|v| matches!(v.as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        if !enabled {
+            return None;
+        }
+        let service = std::env::var("TARGETS_MDNS_SERVICE").unwrap_or_else(|_| SERVICE.to_string());
+        let browsing = ServiceDaemon::new().and_then(|daemon| daemon.browse(&service).map(|events| (daemon, events)));
+        match browsing {
+            Ok((daemon, events)) => Some(MdnsProvider {
+                _daemon: daemon,
+                events,
+                found: BTreeMap::new(),
+            }),
+            Err(e) => {
+                error!("Failed to browse mDNS for {}: {}", service, e);
+                None
+            }
+        }
+    }
+}
+
+impl Provider for MdnsProvider {
+    fn name(&self) -> &'static str {
+        "mdns"
+    }
+
+    fn every(&self) -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn discover(&mut self) -> Result<Vec<TargetConfig>, String> {
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                ServiceEvent::ServiceResolved(info) => match to_target(&info) {
+                    Some(target) => {
+                        self.found.insert(info.get_fullname().to_string(), target);
+                    }
+                    None => debug!("Ignoring {} without an address", info.get_fullname()),
+                },
+                ServiceEvent::ServiceRemoved(_, fullname) => {
+                    self.found.remove(&fullname);
+                }
+                _ => {}
+            }
+        }
+        Ok(self.found.values().cloned().collect())
+    }
+}
+
+/// A target at the service's lowest address, preferring IPv4, named after
+/// the advertised host. Addresses are used as `.local` names often don't
+/// resolve outside of mDNS.
+fn to_target(info: &ServiceInfo) -> Option<TargetConfig> {
+    let mut addresses: Vec<_> = info.get_addresses().iter().collect();
+    addresses.sort_by_key(|address| (address.is_ipv6(), **address));
+    let address = addresses.first()?;
+    let mut target = TargetConfig::new(&address.to_string(), info.get_port());
+    let hostname = info.get_hostname().trim_end_matches('.');
+    if !hostname.is_empty() {
+        target.name = Some(hostname.strip_suffix(".local").unwrap_or(hostname).to_string());
+    }
+    Some(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_target() {
+        let addresses = ["fe80::1", "192.168.1.20", "192.168.1.10"];
+        let info = ServiceInfo::new(SERVICE, "rack1", "nas.local.", &addresses[..], 3551, None).unwrap();
+        let target = to_target(&info).unwrap();
+        assert_eq!(target.host, "192.168.1.10");
+        assert_eq!(target.name.as_deref(), Some("nas"));
+        assert_eq!(target.port, 3551);
+
+        let info = ServiceInfo::new(SERVICE, "rack1", "nas.local.", "", 3551, None).unwrap();
+        assert!(to_target(&info).is_none());
+    }
+}
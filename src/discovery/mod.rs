@@ -0,0 +1,231 @@
+//! discovery/mod.rs
+//!
+//! Targets found at runtime rather than listed in the config file, and the
+//! fleet of pollers that follows them: a poller is started for every target
+//! that shows up and stopped for every one that goes away.
+
+pub mod consul;
+pub mod file;
+#[cfg(any(feature = "http", feature = "http-lite"))]
+pub mod managed;
+#[cfg(feature = "mdns")]
+pub mod mdns;
+pub mod probe;
+#[cfg(feature = "srv")]
+pub mod srv;
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+use crate::metrics::PollMetrics;
+use crate::poller::Poller;
+use crate::systemd::Heartbeat;
+use crate::targets::{ActiveTargets, Defaults, Target, TargetConfig};
+use crate::updater::Update;
+
+/// A source of targets, asked for the current set on an interval.
+pub trait Provider: Send {
+    /// Short name used in logs, which also tells the sources apart
+    fn name(&self) -> &'static str;
+
+    /// How long to wait between lookups
+    fn every(&self) -> Duration;
+
+    /// Look up the current targets. May block.
+    fn discover(&mut self) -> Result<Vec<TargetConfig>, String>;
+}
+
+/// Build every provider that is enabled through the environment.
+pub fn from_env() -> Vec<Box<dyn Provider>> {
+    let mut providers: Vec<Box<dyn Provider>> = Vec::new();
+
+    if let Some(provider) = file::FileProvider::from_env() {
+        providers.push(Box::new(provider));
+    }
+    #[cfg(feature = "srv")]
+    if let Some(provider) = srv::SrvProvider::from_env() {
+        providers.push(Box::new(provider));
+    }
+    #[cfg(not(feature = "srv"))]
+    if std::env::var_os("TARGETS_SRV").is_some() {
+        tracing::error!("TARGETS_SRV is set, but SRV discovery needs a build with the srv feature");
+    }
+    #[cfg(feature = "mdns")]
+    if let Some(provider) = mdns::MdnsProvider::from_env() {
+        providers.push(Box::new(provider));
+    }
+    #[cfg(not(feature = "mdns"))]
+    if std::env::var_os("TARGETS_MDNS").is_some() {
+        tracing::error!("TARGETS_MDNS is set, but mDNS discovery needs a build with the mdns feature");
+    }
+    if let Some(provider) = consul::ConsulProvider::from_env() {
+        providers.push(Box::new(provider));
+    }
+    if let Some(provider) = probe::ProbeProvider::from_env() {
+        providers.push(Box::new(provider));
+    }
+
+    for provider in &providers {
+        info!("Enabled {} target discovery", provider.name());
+    }
+    providers
+}
+
+/// The targets a source has found
+pub type Discovered = (&'static str, Vec<TargetConfig>);
+
+/// Ask the provider for targets until the fleet goes away, passing them on
+/// whenever they change. A failed lookup keeps the targets found before.
+pub async fn watch(mut provider: Box<dyn Provider>, sender: mpsc::Sender<Discovered>) {
+    let mut last = None;
+    loop {
+        let looked_up = tokio::task::spawn_blocking(move || {
+            let result = provider.discover();
+            (provider, result)
+        })
+        .await;
+        let Ok((returned, result)) = looked_up else {
+            warn!("Target discovery panicked, stopping it");
+            return;
+        };
+        provider = returned;
+        let every = provider.every();
+        match result {
+            Ok(targets) if last.as_ref() != Some(&targets) => {
+                if sender.send((provider.name(), targets.clone())).await.is_err() {
+                    return;
+                }
+                last = Some(targets);
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Failed to discover targets through {}: {}", provider.name(), e),
+        }
+        tokio::time::sleep(every).await;
+    }
+}
+
+/// Everything a poller needs besides its target
+pub struct PollerSettings {
+    pub defaults: Defaults,
+    pub max_failures: Option<u32>,
+    pub heartbeat: Arc<Heartbeat>,
+    pub active: Arc<ActiveTargets>,
+    pub metrics: PollMetrics,
+    pub limit: Arc<Semaphore>,
+    /// Spread the first polls of targets that start together across their
+    /// interval
+    pub spread: bool,
+}
+
+/// The running pollers, one per target of every source
+pub struct Fleet {
+    settings: PollerSettings,
+    updates: mpsc::Sender<Update>,
+    sources: BTreeMap<&'static str, Vec<Target>>,
+    running: HashMap<String, (Target, JoinHandle<()>)>,
+}
+
+impl Fleet {
+    pub fn new(settings: PollerSettings, updates: mpsc::Sender<Update>) -> Self {
+        Fleet {
+            settings,
+            updates,
+            sources: BTreeMap::new(),
+            running: HashMap::new(),
+        }
+    }
+
+    /// Replace the targets of a source, then start and stop pollers to
+    /// match. A target whose settings changed is restarted. If sources
+    /// name the same target, the first one in alphabetical order wins.
+    pub async fn set(&mut self, source: &'static str, targets: Vec<Target>) {
+        self.sources.insert(source, targets);
+        let mut wanted: HashMap<&str, (&'static str, &Target)> = HashMap::new();
+        for (source, targets) in &self.sources {
+            for target in targets {
+                if wanted.contains_key(target.name.as_str()) {
+                    warn!("Target {} from {} is already defined elsewhere, ignoring it", target.name, source);
+                } else {
+                    wanted.insert(&target.name, (source, target));
+                }
+            }
+        }
+
+        let stopped: Vec<String> = self
+            .running
+            .iter()
+            .filter(|(name, (target, _))| wanted.get(name.as_str()).map(|(_, wanted)| *wanted) != Some(target))
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in stopped {
+            if let Some((target, poller)) = self.running.remove(&name) {
+                poller.abort();
+                self.settings.active.remove(&name);
+                self.settings.metrics.remove(&name);
+                let _ = self.updates.send(Update::Removed(name)).await;
+                info!("Stopped polling {} at {}:{}", target.name, target.host, target.port);
+            }
+        }
+
+        let mut started: Vec<(&'static str, Target)> = wanted
+            .into_values()
+            .filter(|(_, target)| !self.running.contains_key(&target.name))
+            .map(|(source, target)| (source, target.clone()))
+            .collect();
+        started.sort_by(|(_, a), (_, b)| a.name.cmp(&b.name));
+        let count = started.len();
+        for (i, (source, target)) in started.into_iter().enumerate() {
+            // Start each target at its own point of its interval, if asked to
+            let offset = match self.settings.spread {
+                true => target.interval.mul_f64(i as f64 / count as f64),
+                false => Duration::ZERO,
+            };
+            let _ = self.updates.send(Update::Added(target.name.clone(), target.labels.clone())).await;
+            self.settings.active.insert(source, target.clone());
+            let poller = Poller {
+                target: target.name.clone(),
+                client: target.client(&self.settings.defaults),
+                every: target.interval,
+                offset,
+                jitter: target.jitter,
+                retries: target.retries,
+                on_failure: target.on_failure,
+                max_failures: self.settings.max_failures,
+                heartbeat: Arc::clone(&self.settings.heartbeat),
+                active: Arc::clone(&self.settings.active),
+                metrics: self.settings.metrics.clone(),
+                limit: Arc::clone(&self.settings.limit),
+            };
+            info!("Polling {} at {}:{} every {:?}", target.name, target.host, target.port, target.interval);
+            let poller = tokio::spawn(poller.run(self.updates.clone()));
+            self.running.insert(target.name.clone(), (target, poller));
+        }
+    }
+
+    /// Follow the discovered targets. Once every provider has stopped, wait
+    /// for the pollers, which run until the updater goes away.
+    pub async fn run(mut self, mut discovered: mpsc::Receiver<Discovered>) {
+        while let Some((source, found)) = discovered.recv().await {
+            let mut targets: Vec<Target> = Vec::new();
+            for target in found.iter().map(|t| t.resolve(&self.settings.defaults)) {
+                if targets.iter().any(|t| t.name == target.name) {
+                    warn!("Target {} is listed twice by {}, ignoring the second", target.name, source);
+                    continue;
+                }
+                targets.push(target);
+            }
+            info!("{} target discovery found {} target(s)", source, targets.len());
+            self.set(source, targets).await;
+        }
+        drop(self.updates);
+        for (_, poller) in self.running.into_values() {
+            let _ = poller.await;
+        }
+    }
+}
+
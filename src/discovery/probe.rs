@@ -0,0 +1,127 @@
+//! discovery/probe.rs
+//!
+//! Targets found by asking every address of the subnets in
+//! `TARGETS_PROBE_SUBNETS` for an apcupsd status. This scans the network,
+//! so it only happens when asked for explicitly.
+
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use apcaccess::{ConnectOptions, NisClient};
+use tracing::error;
+
+use super::Provider;
+use crate::config::parse_duration;
+use crate::targets::TargetConfig;
+
+/// Largest subnet probed, a /20
+const MAX_ADDRESSES: u32 = 4096;
+
+/// Addresses probed at the same time
+const WORKERS: usize = 32;
+
+pub struct ProbeProvider {
+    addresses: Vec<Ipv4Addr>,
+    port: u16,
+    every: Duration,
+    options: ConnectOptions,
+}
+
+impl ProbeProvider {
+    pub fn from_env() -> Option<Self> {
+        let subnets = std::env::var("TARGETS_PROBE_SUBNETS").ok().filter(|s| !s.trim().is_empty())?;
+        let mut addresses = Vec::new();
+        for subnet in subnets.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            match hosts(subnet) {
+                Ok(hosts) => addresses.extend(hosts),
+                Err(e) => {
+                    error!("Ignoring TARGETS_PROBE_SUBNETS: {}", e);
+                    return None;
+                }
+            }
+        }
+        let port = std::env::var("TARGETS_PROBE_PORT").ok().and_then(|p| p.parse().ok()).unwrap_or(3551);
+        let every = std::env::var("TARGETS_PROBE_INTERVAL")
+            .ok()
+            .and_then(|v| parse_duration(&v))
+            .filter(|d| !d.is_zero())
+            .unwrap_or(Duration::from_secs(300));
+        Some(ProbeProvider {
+            addresses,
+            port,
+            every,
+            options: ConnectOptions::from_env(),
+        })
+    }
+}
+
+impl Provider for ProbeProvider {
+    fn name(&self) -> &'static str {
+        "probe"
+    }
+
+    fn every(&self) -> Duration {
+        self.every
+    }
+
+    /// Probe the addresses a few at a time, each with a one second timeout.
+    fn discover(&mut self) -> Result<Vec<TargetConfig>, String> {
+        let next = AtomicUsize::new(0);
+        let found = Mutex::new(Vec::new());
+        thread::scope(|scope| {
+            for _ in 0..WORKERS.min(self.addresses.len()) {
+                scope.spawn(|| {
+                    while let Some(address) = self.addresses.get(next.fetch_add(1, Ordering::Relaxed)) {
+                        let mut client = NisClient::new(&address.to_string(), self.port, 1, false);
+                        client.options = self.options.clone();
+                        if client.fetch_stats(false).is_ok() {
+                            found.lock().unwrap_or_else(|e| e.into_inner()).push(*address);
+                        }
+                    }
+                });
+            }
+        });
+        let mut found = found.into_inner().unwrap_or_else(|e| e.into_inner());
+        found.sort();
+        Ok(found.iter().map(|address| TargetConfig::new(&address.to_string(), self.port)).collect())
+    }
+}
+
+/// The host addresses of an IPv4 subnet such as `192.168.1.0/24`, without
+/// its network and broadcast addresses.
+fn hosts(subnet: &str) -> Result<Vec<Ipv4Addr>, String> {
+    let (address, prefix) = subnet.split_once('/').unwrap_or((subnet, "32"));
+    let address: Ipv4Addr = address.parse().map_err(|_| format!("invalid IPv4 subnet {:?}", subnet))?;
+    let prefix: u32 = prefix.parse().ok().filter(|&p| p <= 32).ok_or_else(|| format!("invalid IPv4 subnet {:?}", subnet))?;
+    let size = 1u64 << (32 - prefix);
+    if size > u64::from(MAX_ADDRESSES) {
+        return Err(format!("subnet {:?} is larger than a /20", subnet));
+    }
+    let network = u32::from(address) & (u32::MAX.checked_shl(32 - prefix).unwrap_or(0));
+    let range = match size {
+        1 | 2 => 0..size,
+        _ => 1..size - 1,
+    };
+    Ok(range.map(|i| Ipv4Addr::from(network + i as u32)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hosts() {
+        let hosts_24 = hosts("192.168.1.77/24").unwrap();
+        assert_eq!(hosts_24.len(), 254);
+        assert_eq!(hosts_24[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts_24[253], Ipv4Addr::new(192, 168, 1, 254));
+        assert_eq!(hosts("10.0.0.5").unwrap(), vec![Ipv4Addr::new(10, 0, 0, 5)]);
+        assert_eq!(hosts("10.0.0.4/31").unwrap().len(), 2);
+        assert!(hosts("10.0.0.0/8").is_err());
+        assert!(hosts("fd00::/120").is_err());
+        assert!(hosts("10.0.0.0/33").is_err());
+    }
+}
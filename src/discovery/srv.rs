@@ -0,0 +1,94 @@
+//! discovery/srv.rs
+//!
+//! Targets from DNS SRV records such as `_apcupsd._tcp.dc1.example.com`,
+//! named by `TARGETS_SRV` and looked up again every `TARGETS_SRV_INTERVAL`.
+//! Every record is a target, whatever its priority and weight.
+
+use std::time::Duration;
+
+use hickory_resolver::error::ResolveErrorKind;
+use hickory_resolver::Resolver;
+use tracing::error;
+
+use super::Provider;
+use crate::config::parse_duration;
+use crate::targets::TargetConfig;
+
+pub struct SrvProvider {
+    names: Vec<String>,
+    every: Duration,
+    resolver: Resolver,
+}
+
+impl SrvProvider {
+    pub fn from_env() -> Option<Self> {
+        let names: Vec<String> = std::env::var("TARGETS_SRV")
+            .ok()?
+            .split(',')
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+            .map(str::to_string)
+            .collect();
+        if names.is_empty() {
+            return None;
+        }
+        let every = std::env::var("TARGETS_SRV_INTERVAL")
+            .ok()
+            .and_then(|v| parse_duration(&v))
+            .filter(|d| !d.is_zero())
+            .unwrap_or(Duration::from_secs(60));
+        let resolver = match Resolver::from_system_conf() {
+            Ok(resolver) => resolver,
+            Err(e) => {
+                error!("Failed to set up a DNS resolver for TARGETS_SRV: {}", e);
+                return None;
+            }
+        };
+        Some(SrvProvider { names, every, resolver })
+    }
+}
+
+impl Provider for SrvProvider {
+    fn name(&self) -> &'static str {
+        "srv"
+    }
+
+    fn every(&self) -> Duration {
+        self.every
+    }
+
+    fn discover(&mut self) -> Result<Vec<TargetConfig>, String> {
+        let mut targets = Vec::new();
+        for name in &self.names {
+            let records = match self.resolver.srv_lookup(name.as_str()) {
+                Ok(lookup) => lookup.iter().map(|srv| (srv.target().to_utf8(), srv.port())).collect(),
+                // A name without records has no targets left, rather than failing
+                Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Vec::new(),
+                Err(e) => return Err(format!("{}: {}", name, e)),
+            };
+            targets.extend(to_targets(records));
+        }
+        Ok(targets)
+    }
+}
+
+/// A target per record, named after the host without the root's dot
+fn to_targets(records: Vec<(String, u16)>) -> impl Iterator<Item = TargetConfig> {
+    records
+        .into_iter()
+        .map(|(host, port)| TargetConfig::new(host.strip_suffix('.').unwrap_or(&host), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_targets() {
+        let targets: Vec<TargetConfig> =
+            to_targets(vec![("ups1.dc1.example.com.".to_string(), 3551), ("ups2.dc1.example.com.".to_string(), 3552)])
+                .collect();
+        assert_eq!(targets[0].host, "ups1.dc1.example.com");
+        assert_eq!(targets[1].port, 3552);
+    }
+}
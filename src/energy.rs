@@ -0,0 +1,243 @@
+//! energy.rs
+//!
+//! The energy each UPS delivers, integrated from its output power between
+//! polls, and what it costs at the electricity price: a flat
+//! `ELECTRICITY_PRICE` per kWh, or a time-of-use `ELECTRICITY_PRICE_SCHEDULE`
+//! of prices by time of day. The totals are kept in the SQLite history, if
+//! there is one, and carry on from there after a restart.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use apcaccess::ApcStatus;
+use chrono::{DateTime, Local, NaiveTime};
+use tracing::error;
+
+use crate::snapshot::Snapshot;
+
+/// Polls further apart than this aren't integrated across, as the power in
+/// between is anyone's guess
+const MAX_GAP: Duration = Duration::from_secs(300);
+
+const JOULES_PER_KWH: f64 = 3_600_000.0;
+
+/// Prices per kWh by time of day, falling back to a flat price
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tariff {
+    /// The price outside of the schedule
+    pub price: Option<f64>,
+    /// From, until, and the price in between, in local time. A period that
+    /// ends before it starts runs past midnight.
+    pub schedule: Vec<(NaiveTime, NaiveTime, f64)>,
+}
+
+impl Tariff {
+    /// Parse a schedule such as `07:00-23:00=0.30,23:00-07:00=0.12`
+    pub fn parse_schedule(value: &str) -> Option<Vec<(NaiveTime, NaiveTime, f64)>> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|period| !period.is_empty())
+            .map(|period| {
+                let (times, price) = period.split_once('=')?;
+                let (from, until) = times.split_once('-')?;
+                let time = |time: &str| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok();
+                Some((time(from)?, time(until)?, price.trim().parse().ok()?))
+            })
+            .collect()
+    }
+
+    /// The price per kWh at a time of day, if any applies
+    pub fn price_at(&self, time: NaiveTime) -> Option<f64> {
+        let scheduled = self.schedule.iter().find(|(from, until, _)| match from <= until {
+            true => (*from..*until).contains(&time),
+            false => time >= *from || time < *until,
+        });
+        scheduled.map(|&(_, _, price)| price).or(self.price)
+    }
+}
+
+/// What a target used since its last poll, or in total
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metered {
+    pub joules: f64,
+    /// What it cost, if there's a price
+    pub cost: Option<f64>,
+}
+
+impl Metered {
+    fn add(&mut self, other: &Metered) {
+        self.joules += other.joules;
+        self.cost = match (self.cost, other.cost) {
+            (None, None) => None,
+            (cost, other) => Some(cost.unwrap_or_default() + other.unwrap_or_default()),
+        };
+    }
+}
+
+#[derive(Default)]
+pub struct EnergyMeter {
+    tariff: Option<Tariff>,
+    /// The time and output power in watts of each target's last poll
+    last: HashMap<String, (SystemTime, f64)>,
+    /// What each target used since it was first metered
+    totals: HashMap<String, Metered>,
+    /// Totals from before a restart, counted again at each target's next
+    /// poll
+    restored: HashMap<String, Metered>,
+}
+
+impl EnergyMeter {
+    pub fn new(tariff: Option<Tariff>) -> Self {
+        EnergyMeter {
+            tariff,
+            last: HashMap::new(),
+            totals: HashMap::new(),
+            restored: HashMap::new(),
+        }
+    }
+
+    /// The cost is only estimated once `ELECTRICITY_PRICE` or
+    /// `ELECTRICITY_PRICE_SCHEDULE` says what a kWh costs
+    pub fn from_env() -> Self {
+        let price = std::env::var("ELECTRICITY_PRICE").ok().and_then(|value| {
+            let price = value.trim().parse::<f64>().ok().filter(|price| price.is_finite() && *price >= 0.0);
+            if price.is_none() {
+                error!("Ignoring ELECTRICITY_PRICE, {:?} is not a price", value);
+            }
+            price
+        });
+        let schedule = match std::env::var("ELECTRICITY_PRICE_SCHEDULE") {
+            Ok(value) => Tariff::parse_schedule(&value).unwrap_or_else(|| {
+                error!("Ignoring ELECTRICITY_PRICE_SCHEDULE, {:?} is not a list of HH:MM-HH:MM=price", value);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        let tariff = (price.is_some() || !schedule.is_empty()).then_some(Tariff { price, schedule });
+        EnergyMeter::new(tariff)
+    }
+
+    /// Carry on from the totals of each target before a restart
+    #[cfg_attr(not(feature = "sqlite"), allow(dead_code))]
+    pub fn restore(&mut self, totals: HashMap<String, Metered>) {
+        self.restored = totals;
+    }
+
+    /// Take in a poll. Returns the energy since the last poll, none on the
+    /// first one unless there's a restored total to count, or `None` if the
+    /// output power isn't known.
+    pub fn update(&mut self, snapshot: &Snapshot) -> Option<Metered> {
+        let mut metered = self.meter(snapshot)?;
+        if let Some(restored) = self.restored.remove(&snapshot.host) {
+            metered.add(&restored);
+        }
+        self.totals.entry(snapshot.host.clone()).or_default().add(&metered);
+        Some(metered)
+    }
+
+    fn meter(&mut self, snapshot: &Snapshot) -> Option<Metered> {
+        let status = ApcStatus::from(&snapshot.stats);
+        let Some(watts) = status.loadpct.zip(status.nompower).map(|(load, nominal)| load / 100.0 * nominal) else {
+            self.last.remove(&snapshot.host);
+            return None;
+        };
+        let last = self.last.insert(snapshot.host.clone(), (snapshot.timestamp, watts));
+        let Some((since, last_watts)) = last else {
+            return Some(Metered::default());
+        };
+        let elapsed = snapshot.timestamp.duration_since(since).unwrap_or_default();
+        if elapsed > MAX_GAP {
+            return Some(Metered::default());
+        }
+        let joules = (last_watts + watts) / 2.0 * elapsed.as_secs_f64();
+        let price = self.tariff.as_ref().and_then(|tariff| tariff.price_at(DateTime::<Local>::from(since).time()));
+        Some(Metered {
+            joules,
+            cost: price.map(|price| joules / JOULES_PER_KWH * price),
+        })
+    }
+
+    /// What the target used since it was first metered, restored totals
+    /// included
+    pub fn total(&self, target: &str) -> Option<&Metered> {
+        self.totals.get(target)
+    }
+
+    /// Forget a target that's no longer polled. Its total is counted again
+    /// if it comes back, as its counters start over.
+    pub fn remove(&mut self, target: &str) {
+        self.last.remove(target);
+        if let Some(total) = self.totals.remove(target) {
+            self.restored.insert(target.to_string(), total);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn snapshot(at: u64, loadpct: &str) -> Snapshot {
+        let mut snapshot = Snapshot::new(
+            "ups1",
+            BTreeMap::from([
+                ("LOADPCT".to_string(), loadpct.to_string()),
+                ("NOMPOWER".to_string(), "1000".to_string()),
+            ]),
+        );
+        snapshot.timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(at);
+        snapshot
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule = Tariff::parse_schedule("07:00-23:00=0.30, 23:00-07:00=0.12").unwrap();
+        let tariff = Tariff { price: None, schedule };
+        let time = |time: &str| NaiveTime::parse_from_str(time, "%H:%M").unwrap();
+        assert_eq!(tariff.price_at(time("12:00")), Some(0.30));
+        assert_eq!(tariff.price_at(time("23:00")), Some(0.12));
+        assert_eq!(tariff.price_at(time("03:30")), Some(0.12));
+        assert_eq!(tariff.price_at(time("07:00")), Some(0.30));
+
+        let tariff = Tariff { price: Some(0.2), schedule: Tariff::parse_schedule("17:00-20:00=0.45").unwrap() };
+        assert_eq!(tariff.price_at(time("18:00")), Some(0.45));
+        assert_eq!(tariff.price_at(time("21:00")), Some(0.2));
+        assert_eq!(Tariff::parse_schedule("7-23=0.3"), None);
+        assert_eq!(Tariff::parse_schedule("07:00-23:00"), None);
+    }
+
+    #[test]
+    fn test_update() {
+        let mut meter = EnergyMeter::new(Some(Tariff { price: Some(0.5), schedule: Vec::new() }));
+        assert_eq!(meter.update(&snapshot(0, "20.0")), Some(Metered::default()));
+        // Not integrated across an hour without polls
+        assert_eq!(meter.update(&snapshot(3600, "40.0")), Some(Metered::default()));
+        // 400 W, then 200 W, for a minute is 5 Wh
+        let metered = meter.update(&snapshot(3660, "20.0")).unwrap();
+        assert_eq!(metered.joules, 300.0 * 60.0);
+        assert!((metered.cost.unwrap() - 0.005 * 0.5).abs() < 1e-9);
+
+        let mut unpriced = EnergyMeter::default();
+        unpriced.update(&snapshot(0, "20.0"));
+        assert_eq!(unpriced.update(&snapshot(10, "20.0")).unwrap().cost, None);
+        let mut unknown = snapshot(20, "20.0");
+        unknown.stats.remove("NOMPOWER");
+        assert_eq!(unpriced.update(&unknown), None);
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut meter = EnergyMeter::default();
+        meter.restore(HashMap::from([("ups1".to_string(), Metered { joules: 1000.0, cost: Some(0.5) })]));
+        // The restored total is counted at the first poll, once
+        assert_eq!(meter.update(&snapshot(0, "20.0")), Some(Metered { joules: 1000.0, cost: Some(0.5) }));
+        assert_eq!(meter.update(&snapshot(10, "20.0")).unwrap().joules, 2000.0);
+        assert_eq!(meter.total("ups1"), Some(&Metered { joules: 3000.0, cost: Some(0.5) }));
+
+        // A target that comes back starts over from its total
+        meter.remove("ups1");
+        assert_eq!(meter.update(&snapshot(20, "20.0")).unwrap().joules, 3000.0);
+    }
+}
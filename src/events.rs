@@ -0,0 +1,578 @@
+//! events.rs
+//!
+//! Detects power events from changes of the apcupsd STATUS flags between polls.
+
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+use crate::alerts::AlertRule;
+use crate::snapshot::Snapshot;
+
+/// A power event worth notifying about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// The UPS switched to battery power
+    OnBattery,
+    /// The battery charge or runtime dropped below the shutdown threshold
+    LowBattery,
+    /// apcupsd lost communication with the UPS
+    CommLost,
+    /// The UPS is back on line power
+    Online,
+    /// The UPS has been on battery for longer than the configured threshold
+    ProlongedOnBattery,
+    /// The UPS reports that its battery needs replacing
+    ReplaceBattery,
+    /// The estimated runtime dropped below the configured threshold
+    LowRuntime,
+    /// The estimated runtime rose above the configured threshold again
+    RuntimeRestored,
+    /// A threshold alert of the config file started firing
+    Alert,
+    /// A threshold alert of the config file stopped firing
+    AlertResolved,
+}
+
+impl EventKind {
+    pub const ALL: &[EventKind] = &[
+        EventKind::OnBattery,
+        EventKind::LowBattery,
+        EventKind::CommLost,
+        EventKind::Online,
+        EventKind::ProlongedOnBattery,
+        EventKind::ReplaceBattery,
+        EventKind::LowRuntime,
+        EventKind::RuntimeRestored,
+        EventKind::Alert,
+        EventKind::AlertResolved,
+    ];
+
+    /// Stable identifier used in payloads and configuration
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventKind::OnBattery => "on_battery",
+            EventKind::LowBattery => "low_battery",
+            EventKind::CommLost => "comm_lost",
+            EventKind::Online => "online",
+            EventKind::ProlongedOnBattery => "prolonged_on_battery",
+            EventKind::ReplaceBattery => "replace_battery",
+            EventKind::LowRuntime => "low_runtime",
+            EventKind::RuntimeRestored => "runtime_restored",
+            EventKind::Alert => "alert",
+            EventKind::AlertResolved => "alert_resolved",
+        }
+    }
+
+    /// Parse an identifier as returned by `as_str`, or the apcupsd STATUS
+    /// flag that raises the event, e.g. `LOWBATT`.
+    pub fn parse(name: &str) -> Option<EventKind> {
+        EventKind::ALL
+            .iter()
+            .copied()
+            .find(|&k| k.as_str() == name || status_flag(k) == Some(name))
+    }
+
+    /// Parse a comma-separated list of identifiers, ignoring unknown ones.
+    pub fn parse_list(names: &str) -> Vec<EventKind> {
+        names
+            .split(',')
+            .map(str::trim)
+            .filter(|n| !n.is_empty())
+            .filter_map(|n| {
+                let kind = EventKind::parse(n);
+                if kind.is_none() {
+                    tracing::warn!("Ignoring unknown event type {:?}", n);
+                }
+                kind
+            })
+            .collect()
+    }
+
+    /// Human-readable description
+    pub fn description(&self) -> &'static str {
+        match self {
+            EventKind::OnBattery => "UPS switched to battery power",
+            EventKind::LowBattery => "UPS battery is low",
+            EventKind::CommLost => "apcupsd lost communication with the UPS",
+            EventKind::Online => "UPS is back on line power",
+            EventKind::ProlongedOnBattery => "UPS has been on battery for an extended period",
+            EventKind::ReplaceBattery => "UPS battery needs replacing",
+            EventKind::LowRuntime => "UPS runtime is below the threshold",
+            EventKind::RuntimeRestored => "UPS runtime is above the threshold again",
+            EventKind::Alert => "Alert is firing",
+            EventKind::AlertResolved => "Alert has cleared",
+        }
+    }
+
+    /// The built-in severity, which the config file may map to another
+    pub fn severity(&self) -> Severity {
+        match self {
+            EventKind::LowBattery | EventKind::CommLost | EventKind::LowRuntime => Severity::Critical,
+            EventKind::OnBattery | EventKind::ProlongedOnBattery | EventKind::ReplaceBattery | EventKind::Alert => {
+                Severity::Warning
+            }
+            EventKind::Online | EventKind::RuntimeRestored | EventKind::AlertResolved => Severity::Info,
+        }
+    }
+
+    /// Conditions that this event clears.
+    pub fn resolves(&self) -> &'static [EventKind] {
+        match self {
+            EventKind::Online => &[
+                EventKind::OnBattery,
+                EventKind::LowBattery,
+                EventKind::CommLost,
+                EventKind::ProlongedOnBattery,
+            ],
+            EventKind::RuntimeRestored => &[EventKind::LowRuntime],
+            EventKind::AlertResolved => &[EventKind::Alert],
+            _ => &[],
+        }
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for EventKind {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        EventKind::parse(&name).ok_or_else(|| serde::de::Error::custom(format!("unknown event type {:?}", name)))
+    }
+}
+
+/// How urgent an event is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Severity {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// The severities of event types by the `[severities]` of the config file,
+/// for every target or the targets of a group
+pub type SeverityMap = HashMap<EventKind, Severity>;
+
+/// The severity of each event type, as mapped by the config file or else
+/// as built in
+#[derive(Debug, Clone, Default)]
+pub struct Severities {
+    default: SeverityMap,
+    /// Those of the targets' groups, which take precedence
+    targets: HashMap<String, SeverityMap>,
+}
+
+impl Severities {
+    pub fn new(default: SeverityMap, targets: HashMap<String, SeverityMap>) -> Self {
+        Severities { default, targets }
+    }
+
+    /// The severity of an event type for a target
+    pub fn of(&self, kind: EventKind, target: &str) -> Severity {
+        self.targets
+            .get(target)
+            .and_then(|severities| severities.get(&kind))
+            .or_else(|| self.default.get(&kind))
+            .copied()
+            .unwrap_or_else(|| kind.severity())
+    }
+
+    /// The severity of an alert for a target: its own, or that of `alert`
+    pub fn of_alert(&self, alert: &AlertRule, target: &str) -> Severity {
+        alert.severity.unwrap_or_else(|| self.of(EventKind::Alert, target))
+    }
+
+    /// The severity of `kind` for the event's target, such as that of a
+    /// condition the event resolves, and the alert's for an alert
+    pub fn of_event(&self, kind: EventKind, event: &Event) -> Severity {
+        match (&event.alert, kind) {
+            (Some(alert), EventKind::Alert) => self.of_alert(alert, &event.snapshot.host),
+            _ => self.of(kind, &event.snapshot.host),
+        }
+    }
+}
+
+/// A detected transition, with the snapshot it was detected in
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub kind: EventKind,
+    pub previous_status: String,
+    pub snapshot: Snapshot,
+    /// The type's severity, as mapped for the target
+    pub severity: Severity,
+    /// Synthetic event sent to check the notification setup
+    pub test: bool,
+    /// The alert that started or stopped firing, for `Alert` and
+    /// `AlertResolved`
+    pub alert: Option<AlertRule>,
+}
+
+impl Event {
+    /// A synthetic event of the given kind for testing notifiers, with the
+    /// STATUS in the snapshot set to match and the severity as mapped for
+    /// its target, so that it is routed like a real one.
+    pub fn test(kind: EventKind, mut snapshot: Snapshot, severities: &Severities) -> Self {
+        let status = match kind {
+            EventKind::Online | EventKind::RuntimeRestored | EventKind::AlertResolved => "ONLINE",
+            EventKind::CommLost => "COMMLOST",
+            EventKind::LowBattery | EventKind::LowRuntime => "ONBATT LOWBATT",
+            EventKind::ReplaceBattery => "ONLINE REPLACEBATT",
+            EventKind::OnBattery | EventKind::ProlongedOnBattery | EventKind::Alert => "ONBATT",
+        };
+        snapshot.stats.insert("STATUS".to_string(), status.to_string());
+        Event {
+            kind,
+            previous_status: if status == "ONLINE" { "ONBATT" } else { "ONLINE" }.to_string(),
+            severity: severities.of(kind, &snapshot.host),
+            snapshot,
+            test: true,
+            alert: None,
+        }
+    }
+
+    pub fn status(&self) -> &str {
+        self.snapshot.stats.get("STATUS").map(String::as_str).unwrap_or_default()
+    }
+
+    /// The UPS name, falling back to the hostname.
+    pub fn upsname(&self) -> &str {
+        self.snapshot
+            .stats
+            .get("UPSNAME")
+            .map(String::as_str)
+            .filter(|n| !n.is_empty())
+            .unwrap_or(self.snapshot.hostname())
+    }
+
+    /// What happened, naming the alert for alert events, e.g. "Alert
+    /// low_runtime is firing: timeleft < 10m"
+    pub fn description(&self) -> String {
+        match (&self.alert, self.kind) {
+            (Some(alert), EventKind::Alert) => format!("Alert {} is firing: {}", alert.name, alert.expr),
+            (Some(alert), EventKind::AlertResolved) => format!("Alert {} has cleared: {}", alert.name, alert.expr),
+            _ => self.kind.description().to_string(),
+        }
+    }
+
+    /// One-line summary, e.g. "rack1: UPS switched to battery power (ONBATT)",
+    /// prefixed with "[TEST]" for test events.
+    pub fn summary(&self) -> String {
+        let prefix = if self.test { "[TEST] " } else { "" };
+        format!("{}{}: {} ({})", prefix, self.upsname(), self.description(), self.status())
+    }
+
+    /// Fill a message template. `{event}`, `{alert}`, `{description}`, `{severity}`,
+    /// `{summary}`, `{upsname}`, `{hostname}`, `{host}`, `{status}` and `{previous_status}`
+    /// are replaced, as is any apcupsd key in braces, e.g. `{BCHARGE}`.
+    pub fn render(&self, template: &str) -> String {
+        let mut message = template
+            .replace("{event}", self.kind.as_str())
+            .replace("{alert}", self.alert.as_ref().map(|a| a.name.as_str()).unwrap_or_default())
+            .replace("{description}", &self.description())
+            .replace("{severity}", self.severity.as_str())
+            .replace("{summary}", &self.summary())
+            .replace("{upsname}", self.upsname())
+            .replace("{hostname}", self.snapshot.hostname())
+            .replace("{host}", &self.snapshot.host)
+            .replace("{status}", self.status())
+            .replace("{previous_status}", &self.previous_status);
+        for (key, value) in &self.snapshot.stats {
+            let placeholder = format!("{{{}}}", key);
+            if message.contains(&placeholder) {
+                message = message.replace(&placeholder, value);
+            }
+        }
+        message
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "event": self.kind.as_str(),
+            "alert": self.alert.as_ref().map(|a| a.name.as_str()),
+            "description": self.description(),
+            "severity": self.severity.as_str(),
+            "host": self.snapshot.host,
+            "hostname": self.snapshot.hostname(),
+            "upsname": self.upsname(),
+            "timestamp": self.snapshot.unix_timestamp(),
+            "status": self.status(),
+            "previous_status": self.previous_status,
+            "test": self.test,
+            "snapshot": self.snapshot.to_json(),
+        })
+    }
+}
+
+/// Split a STATUS value such as "ONBATT LOWBATT" into its flags.
+fn flags(status: &str) -> HashSet<&str> {
+    status.split_whitespace().collect()
+}
+
+/// Remembers the last STATUS of every host and reports transitions.
+#[derive(Default)]
+pub struct EventDetector {
+    last_status: HashMap<String, String>,
+    /// When each host went on battery
+    on_battery_since: HashMap<String, SystemTime>,
+    /// Hosts for which the prolonged on-battery event was already sent
+    prolonged_sent: HashSet<String>,
+    /// Time on battery after which `ProlongedOnBattery` is reported
+    prolonged_threshold: Option<Duration>,
+    /// Hosts whose TIMELEFT is below the threshold
+    low_runtime: HashSet<String>,
+    /// TIMELEFT in minutes below which `LowRuntime` is reported
+    low_runtime_threshold: Option<f64>,
+    /// Hosts back on line power whose `Online` event is held back, with when
+    /// power returned and the status before that
+    pending_recovery: HashMap<String, (SystemTime, String)>,
+    /// How long line power must be stable before `Online` is reported
+    recovery_stable: Option<Duration>,
+    severities: Severities,
+}
+
+impl EventDetector {
+    /// Build the detector from the environment. `ONBATT_PROLONGED_SECONDS`
+    /// enables the prolonged on-battery event, `LOW_RUNTIME_MINUTES` the low
+    /// runtime events and `RECOVERY_STABLE_SECONDS` the recovery debounce.
+    pub fn from_env(severities: Severities) -> Self {
+        let prolonged_threshold = std::env::var("ONBATT_PROLONGED_SECONDS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .map(Duration::from_secs);
+        let low_runtime_threshold = std::env::var("LOW_RUNTIME_MINUTES")
+            .ok()
+            .and_then(|m| m.parse().ok());
+        let recovery_stable = std::env::var("RECOVERY_STABLE_SECONDS")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .filter(|&s: &u64| s > 0)
+            .map(Duration::from_secs);
+        EventDetector {
+            prolonged_threshold,
+            low_runtime_threshold,
+            recovery_stable,
+            severities,
+            ..Self::default()
+        }
+    }
+
+    /// Compare the snapshot with the previous one from the same host. The
+    /// first snapshot of a host only establishes the baseline.
+    pub fn detect(&mut self, snapshot: &Snapshot) -> Vec<Event> {
+        let Some(status) = snapshot.stats.get("STATUS") else {
+            return Vec::new();
+        };
+        let host = &snapshot.host;
+        let previous = self.last_status.insert(host.clone(), status.clone());
+        let new = flags(status);
+        // Detected events with the status they were a transition from
+        let mut detected: Vec<(EventKind, String)> = Vec::new();
+
+        // Status flag transitions
+        if let Some(previous) = &previous {
+            let old = flags(previous);
+            for kind in [
+                EventKind::CommLost,
+                EventKind::OnBattery,
+                EventKind::LowBattery,
+                EventKind::Online,
+                EventKind::ReplaceBattery,
+            ] {
+                let flag = status_flag(kind).unwrap_or_default();
+                if new.contains(flag) && !old.contains(flag) {
+                    detected.push((kind, previous.clone()));
+                }
+            }
+        }
+
+        // Recovery debounce: hold back `Online` until it has been stable, and
+        // treat a relapse within the window as if power was never restored.
+        if let Some(stable) = self.recovery_stable {
+            if detected.iter().any(|(k, _)| *k == EventKind::Online) {
+                detected.retain(|(k, _)| *k != EventKind::Online);
+                let problem_status = previous.clone().unwrap_or_default();
+                self.pending_recovery.insert(host.clone(), (snapshot.timestamp, problem_status));
+            } else if let Some((since, problem_status)) = self.pending_recovery.get(host).cloned() {
+                if !new.contains("ONLINE") || new.contains("ONBATT") || new.contains("COMMLOST") {
+                    let problem = flags(&problem_status);
+                    detected.retain(|(k, _)| !status_flag(*k).is_some_and(|f| problem.contains(f)));
+                    self.pending_recovery.remove(host);
+                } else if snapshot.timestamp.duration_since(since).unwrap_or_default() >= stable {
+                    detected.push((EventKind::Online, problem_status));
+                    self.pending_recovery.remove(host);
+                }
+            }
+        }
+
+        let previous_status = previous.clone().unwrap_or_default();
+
+        // Time on battery
+        if new.contains("ONBATT") {
+            let since = *self.on_battery_since.entry(host.clone()).or_insert(snapshot.timestamp);
+            let on_battery_for = snapshot.timestamp.duration_since(since).unwrap_or_default();
+            if self.prolonged_threshold.is_some_and(|t| on_battery_for >= t)
+                && self.prolonged_sent.insert(host.clone())
+            {
+                detected.push((EventKind::ProlongedOnBattery, previous_status.clone()));
+            }
+        } else if !self.pending_recovery.contains_key(host) {
+            self.on_battery_since.remove(host);
+            self.prolonged_sent.remove(host);
+        }
+
+        // Remaining runtime
+        let timeleft = snapshot.stats.get("TIMELEFT").and_then(|t| t.parse::<f64>().ok());
+        if let (Some(threshold), Some(timeleft)) = (self.low_runtime_threshold, timeleft) {
+            if timeleft < threshold {
+                if self.low_runtime.insert(host.clone()) {
+                    detected.push((EventKind::LowRuntime, previous_status.clone()));
+                }
+            } else if self.low_runtime.remove(host) {
+                detected.push((EventKind::RuntimeRestored, previous_status.clone()));
+            }
+        }
+
+        if previous.is_none() {
+            return Vec::new();
+        }
+        detected
+            .into_iter()
+            .map(|(kind, previous_status)| Event {
+                kind,
+                previous_status,
+                snapshot: snapshot.clone(),
+                severity: self.severities.of(kind, host),
+                test: false,
+                alert: None,
+            })
+            .collect()
+    }
+}
+
+/// The STATUS flag whose appearance raises an event kind.
+fn status_flag(kind: EventKind) -> Option<&'static str> {
+    match kind {
+        EventKind::OnBattery => Some("ONBATT"),
+        EventKind::LowBattery => Some("LOWBATT"),
+        EventKind::CommLost => Some("COMMLOST"),
+        EventKind::Online => Some("ONLINE"),
+        EventKind::ReplaceBattery => Some("REPLACEBATT"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn snapshot(status: &str) -> Snapshot {
+        let stats = BTreeMap::from([("STATUS".to_string(), status.to_string())]);
+        Snapshot::new("localhost", stats)
+    }
+
+    fn kinds(events: Vec<Event>) -> Vec<EventKind> {
+        events.into_iter().map(|e| e.kind).collect()
+    }
+
+    #[test]
+    fn test_detect_transitions() {
+        let mut detector = EventDetector::default();
+        assert!(detector.detect(&snapshot("ONLINE")).is_empty());
+        assert!(detector.detect(&snapshot("ONLINE")).is_empty());
+        assert_eq!(kinds(detector.detect(&snapshot("ONBATT"))), vec![EventKind::OnBattery]);
+        assert_eq!(kinds(detector.detect(&snapshot("ONBATT LOWBATT"))), vec![EventKind::LowBattery]);
+        assert_eq!(kinds(detector.detect(&snapshot("ONLINE"))), vec![EventKind::Online]);
+        assert_eq!(kinds(detector.detect(&snapshot("COMMLOST"))), vec![EventKind::CommLost]);
+        assert_eq!(kinds(detector.detect(&snapshot("ONLINE"))), vec![EventKind::Online]);
+    }
+
+    #[test]
+    fn test_prolonged_on_battery() {
+        let mut detector = EventDetector {
+            prolonged_threshold: Some(Duration::from_secs(60)),
+            ..EventDetector::default()
+        };
+        let at = |status: &str, secs: u64| {
+            let mut s = snapshot(status);
+            s.timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            s
+        };
+        detector.detect(&at("ONLINE", 0));
+        assert_eq!(kinds(detector.detect(&at("ONBATT", 10))), vec![EventKind::OnBattery]);
+        assert!(detector.detect(&at("ONBATT", 40)).is_empty());
+        assert_eq!(kinds(detector.detect(&at("ONBATT", 70))), vec![EventKind::ProlongedOnBattery]);
+        assert!(detector.detect(&at("ONBATT", 100)).is_empty());
+        assert_eq!(kinds(detector.detect(&at("ONLINE REPLACEBATT", 110))), vec![EventKind::Online, EventKind::ReplaceBattery]);
+    }
+
+    #[test]
+    fn test_recovery_debounce() {
+        let mut detector = EventDetector {
+            recovery_stable: Some(Duration::from_secs(60)),
+            ..EventDetector::default()
+        };
+        let at = |status: &str, secs: u64| {
+            let mut s = snapshot(status);
+            s.timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+            s
+        };
+        detector.detect(&at("ONLINE", 0));
+        assert_eq!(kinds(detector.detect(&at("ONBATT", 10))), vec![EventKind::OnBattery]);
+        // Flapping within the window produces no events at all
+        assert!(detector.detect(&at("ONLINE", 20)).is_empty());
+        assert!(detector.detect(&at("ONBATT", 30)).is_empty());
+        assert!(detector.detect(&at("ONLINE", 40)).is_empty());
+        assert!(detector.detect(&at("ONLINE", 90)).is_empty());
+        let events = detector.detect(&at("ONLINE", 100));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::Online);
+        assert_eq!(events[0].previous_status, "ONBATT");
+    }
+
+    #[test]
+    fn test_low_runtime() {
+        let mut detector = EventDetector {
+            low_runtime_threshold: Some(10.0),
+            ..EventDetector::default()
+        };
+        let runtime = |timeleft: &str| {
+            let mut s = snapshot("ONBATT");
+            s.stats.insert("TIMELEFT".to_string(), timeleft.to_string());
+            s
+        };
+        detector.detect(&runtime("30.0"));
+        assert!(detector.detect(&runtime("12.0")).is_empty());
+        assert_eq!(kinds(detector.detect(&runtime("9.5"))), vec![EventKind::LowRuntime]);
+        assert!(detector.detect(&runtime("8.0")).is_empty());
+        assert_eq!(kinds(detector.detect(&runtime("15.0"))), vec![EventKind::RuntimeRestored]);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut detector = EventDetector::default();
+        detector.detect(&snapshot("ONLINE"));
+        let mut onbatt = snapshot("ONBATT");
+        onbatt.stats.insert("UPSNAME".to_string(), "rack1".to_string());
+        onbatt.stats.insert("BCHARGE".to_string(), "97.0".to_string());
+        let event = detector.detect(&onbatt).remove(0);
+        assert_eq!(
+            event.render("[{event}] {upsname} {previous_status}->{status}, charge {BCHARGE}%"),
+            "[on_battery] rack1 ONLINE->ONBATT, charge 97.0%"
+        );
+    }
+
+    #[test]
+    fn test_first_snapshot_is_baseline() {
+        let mut detector = EventDetector::default();
+        assert!(detector.detect(&snapshot("ONBATT")).is_empty());
+    }
+}
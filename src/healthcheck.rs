@@ -0,0 +1,49 @@
+//! healthcheck.rs
+//!
+//! The `healthcheck` subcommand, for container health checks in images
+//! without curl. Asks a running exporter whether its poll loop is alive, or
+//! in textfile mode, checks that the metrics file is still being written.
+
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// Check `/-/healthy` on the given URL, failing on anything but a 200.
+pub fn check_url(url: &str, timeout: Duration) -> Result<(), String> {
+    let agent = ureq::AgentBuilder::new().timeout(timeout).build();
+    match agent.get(url).call() {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(code, response)) => {
+            let body = response.into_string().unwrap_or_default();
+            Err(format!("{} returned {}: {}", url, code, body.trim()))
+        }
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Check that the textfile was modified within `max_age`.
+pub fn check_file(path: &Path, max_age: Duration) -> Result<(), String> {
+    let modified = std::fs::metadata(path)
+        .and_then(|m| m.modified())
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+    if age > max_age {
+        return Err(format!("{} hasn't been written for {} seconds", path.display(), age.as_secs()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_file() {
+        let path = std::env::temp_dir().join(format!("rsapcupsdexporter-healthcheck-{}.prom", std::process::id()));
+        assert!(check_file(&path, Duration::from_secs(60)).is_err());
+        std::fs::write(&path, "apcupsd_up 1\n").unwrap();
+        assert!(check_file(&path, Duration::from_secs(60)).is_ok());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(check_file(&path, Duration::from_millis(10)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
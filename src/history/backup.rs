@@ -0,0 +1,209 @@
+//! history/backup.rs
+//!
+//! Backups of the persisted state as a gzipped tarball, so a move to new
+//! hardware keeps the history, the energy totals and the event log: made with `POST /api/v1/snapshot` or the
+//! `backup` subcommand, and restored on startup with `SQLITE_RESTORE`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use tracing::{info, warn};
+
+use super::sqlite::{SqliteQueries, TABLES};
+
+/// The database's name within the tarball
+const DATABASE: &str = "history.db";
+
+/// Describes the tarball, for whoever opens it
+const MANIFEST: &str = "manifest.json";
+
+/// Numbers the copies of backups made at the same time
+static COPIES: AtomicU64 = AtomicU64::new(0);
+
+/// Error type for backups and restores
+#[derive(Debug)]
+pub enum BackupError {
+    IoError(std::io::Error),
+    Sqlite(rusqlite::Error),
+    /// The tarball has no database
+    Invalid(String),
+}
+
+impl From<std::io::Error> for BackupError {
+    fn from(err: std::io::Error) -> Self {
+        BackupError::IoError(err)
+    }
+}
+
+impl From<rusqlite::Error> for BackupError {
+    fn from(err: rusqlite::Error) -> Self {
+        BackupError::Sqlite(err)
+    }
+}
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupError::IoError(e) => write!(f, "IO Error: {}", e),
+            BackupError::Sqlite(e) => write!(f, "SQLite Error: {}", e),
+            BackupError::Invalid(reason) => write!(f, "Invalid backup: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for BackupError {}
+
+/// Write a tarball of a consistent copy of the database to `out`. The sink
+/// keeps writing meanwhile. The copy is made next to the database, which
+/// has room for it, under a name of its own, so backups can be made at the
+/// same time.
+pub fn create(store: &SqliteQueries, out: impl Write) -> Result<(), BackupError> {
+    let copy = copy_file(&store.directory().unwrap_or_else(std::env::temp_dir))?;
+    store.copy_to(&copy.0)?;
+
+    let mut archive = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+    archive.append_path_with_name(&copy.0, DATABASE)?;
+    let created = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs();
+    let manifest = serde_json::to_vec_pretty(&serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "created": created,
+        "files": [DATABASE],
+        "tables": TABLES,
+    }))
+    .map_err(std::io::Error::other)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(created);
+    archive.append_data(&mut header, MANIFEST, manifest.as_slice())?;
+    archive.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Put the database of the tarball at `database`, unless there is one
+/// already, so the variable can stay set across restarts. Returns whether
+/// it was restored.
+pub fn restore(archive: impl Read, database: &Path) -> Result<bool, BackupError> {
+    if database.exists() {
+        return Ok(false);
+    }
+    let mut archive = tar::Archive::new(GzDecoder::new(archive));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        if entry.path()?.as_ref() == Path::new(DATABASE) {
+            // Unpacked next to it first, so a failure leaves no partial database
+            let partial = TempFile(database.with_extension("restoring"));
+            entry.unpack(&partial.0)?;
+            std::fs::rename(&partial.0, database)?;
+            return Ok(true);
+        }
+    }
+    Err(BackupError::Invalid(format!("no {} in the tarball", DATABASE)))
+}
+
+/// Restore `SQLITE_RESTORE` to `SQLITE_PATH` before the sink opens it.
+pub fn restore_from_env() {
+    let (Some(archive), Some(database)) = (std::env::var_os("SQLITE_RESTORE"), std::env::var_os("SQLITE_PATH")) else {
+        return;
+    };
+    let database = PathBuf::from(database);
+    match File::open(&archive).map_err(BackupError::from).and_then(|file| restore(file, &database)) {
+        Ok(true) => info!("Restored {} from {}", database.display(), Path::new(&archive).display()),
+        Ok(false) => info!("Not restoring SQLITE_RESTORE, {} exists already", database.display()),
+        Err(e) => warn!("Failed to restore {}: {}", Path::new(&archive).display(), e),
+    }
+}
+
+/// Create an empty file for a copy of the database in `dir`, with a name no
+/// other copy has
+fn copy_file(dir: &Path) -> std::io::Result<TempFile> {
+    loop {
+        let name = format!(".rsapcupsdexporter-backup-{}-{}.db", std::process::id(), COPIES.fetch_add(1, Ordering::Relaxed));
+        let path = dir.join(name);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => return Ok(TempFile(path)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// A file removed when dropped
+struct TempFile(PathBuf);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    use crate::energy::Metered;
+    use crate::events::{Event, EventKind};
+    use crate::history::sqlite::{Aggregation, Query, SqliteHistory};
+    use crate::snapshot::Snapshot;
+
+    #[test]
+    fn test_backup_and_restore() {
+        let dir = std::env::temp_dir().join(format!("rsapcupsdexporter-backup-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let original = dir.join("original.db");
+        let mut history = SqliteHistory::open(original.to_str().unwrap(), std::time::Duration::from_secs(86400)).unwrap();
+        let snapshot = Snapshot::new("ups1", BTreeMap::from([("LINEV".to_string(), "230.0".to_string())]));
+        history.insert(&snapshot).unwrap();
+        history.save_energy("ups1", &Metered { joules: 3.6e6, cost: Some(0.3) }).unwrap();
+        history.insert_events(&[Event::test(EventKind::OnBattery, snapshot.clone(), &Default::default())]).unwrap();
+        history.insert_events(&[Event { test: false, ..Event::test(EventKind::OnBattery, snapshot, &Default::default()) }]).unwrap();
+
+        let mut tarball = Vec::new();
+        create(&SqliteQueries::new(rusqlite::Connection::open(&original).unwrap()), &mut tarball).unwrap();
+
+        let restored = dir.join("restored.db");
+        assert!(restore(tarball.as_slice(), &restored).unwrap());
+        assert!(!restore(tarball.as_slice(), &restored).unwrap());
+        let query = Query {
+            key: "LINEV".to_string(),
+            target: None,
+            start: 0,
+            end: u64::MAX / 2,
+            step: 60,
+            aggregation: Aggregation::Avg,
+        };
+        let series = SqliteQueries::new(rusqlite::Connection::open(&restored).unwrap()).query(&query).unwrap();
+        assert_eq!(series["ups1"].len(), 1);
+        let restored_store = SqliteQueries::new(rusqlite::Connection::open(&restored).unwrap());
+        assert_eq!(restored_store.energy_totals().unwrap()["ups1"], Metered { joules: 3.6e6, cost: Some(0.3) });
+        let events: i64 = rusqlite::Connection::open(&restored)
+            .unwrap()
+            .query_row("SELECT COUNT(*) FROM events WHERE kind = 'on_battery'", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(events, 1);
+
+        assert!(matches!(restore(&b"not a tarball"[..], &dir.join("other.db")), Err(BackupError::IoError(_))));
+
+        // Backups made at the same time each get a copy of their own
+        let store = std::sync::Arc::new(SqliteQueries::new(rusqlite::Connection::open(&original).unwrap()));
+        let backups: Vec<_> = (0..4)
+            .map(|_| {
+                let store = std::sync::Arc::clone(&store);
+                std::thread::spawn(move || create(&store, Vec::new()))
+            })
+            .collect();
+        for backup in backups {
+            backup.join().unwrap().unwrap();
+        }
+        let left: Vec<_> = std::fs::read_dir(&dir).unwrap().filter_map(Result::ok).map(|e| e.file_name()).collect();
+        assert!(left.iter().all(|name| !name.to_string_lossy().contains("backup")), "{:?}", left);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
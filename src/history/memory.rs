@@ -0,0 +1,201 @@
+//! history/memory.rs
+//!
+//! The numeric values of recent polls, kept in memory per target for
+//! `HISTORY_RETENTION` and served at `/api/v1/history`, so a dashboard can
+//! show the last hours without any storage set up. They also make the
+//! rolling gauges of `ROLLING_WINDOW`.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tracing::error;
+
+use crate::config::parse_duration;
+use crate::snapshot::Snapshot;
+
+/// Samples kept per value of a target at most, a day of polls every second,
+/// however long the retention
+const MAX_SAMPLES: usize = 86_400;
+
+/// Timestamped values of one key, oldest first
+type Series = VecDeque<(u64, f64)>;
+
+/// What the values of a key were over a while
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+pub struct MemoryHistory {
+    retention: Duration,
+    /// The series of each key, by target
+    targets: Mutex<HashMap<String, HashMap<String, Series>>>,
+}
+
+impl MemoryHistory {
+    pub fn new(retention: Duration) -> Self {
+        MemoryHistory {
+            retention,
+            targets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// An hour of history for the API unless `HISTORY_RETENTION` says
+    /// otherwise, none if it is 0 or there's no API to serve it, but at
+    /// least the rolling gauges' `window`
+    pub fn from_env(served: bool, window: Option<Duration>) -> Option<Self> {
+        let retention = match std::env::var("HISTORY_RETENTION") {
+            _ if !served => Duration::ZERO,
+            Ok(value) => match parse_duration(&value) {
+                Some(retention) => retention,
+                None => {
+                    error!("Ignoring HISTORY_RETENTION, {:?} is not a duration", value);
+                    Duration::from_secs(3600)
+                }
+            },
+            Err(_) => Duration::from_secs(3600),
+        };
+        let retention = retention.max(window.unwrap_or_default());
+        (!retention.is_zero()).then(|| MemoryHistory::new(retention))
+    }
+
+    #[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+    pub fn retention(&self) -> Duration {
+        self.retention
+    }
+
+    /// Add the numeric values of a poll, dropping those that are too old
+    pub fn record(&self, snapshot: &Snapshot) {
+        let timestamp = snapshot.unix_timestamp();
+        let cutoff = timestamp.saturating_sub(self.retention.as_secs());
+        let mut targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        let series = targets.entry(snapshot.host.clone()).or_default();
+        for (key, value) in snapshot.numeric_values() {
+            let samples = series.entry(key.to_string()).or_default();
+            samples.push_back((timestamp, value));
+            if samples.len() > MAX_SAMPLES {
+                samples.pop_front();
+            }
+        }
+        // Keys that stopped being reported age out as well
+        series.retain(|_, samples| {
+            while samples.front().is_some_and(|&(t, _)| t < cutoff) {
+                samples.pop_front();
+            }
+            !samples.is_empty()
+        });
+    }
+
+    /// Forget a target that is no longer polled
+    pub fn remove(&self, target: &str) {
+        self.targets.lock().unwrap_or_else(|e| e.into_inner()).remove(target);
+    }
+
+    /// The lowest, highest and mean value of a target's `key` since `since`
+    pub fn summary(&self, target: &str, key: &str, since: u64) -> Option<Summary> {
+        let targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        let samples = targets.get(target)?.get(key)?;
+        let (mut min, mut max, mut sum, mut count) = (f64::INFINITY, f64::NEG_INFINITY, 0.0, 0);
+        for &(_, value) in samples.iter().filter(|&&(t, _)| t >= since) {
+            min = min.min(value);
+            max = max.max(value);
+            sum += value;
+            count += 1;
+        }
+        (count > 0).then(|| Summary { min, max, avg: sum / count as f64 })
+    }
+
+    /// Every key with recent values, of any target
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    pub fn keys(&self) -> std::collections::BTreeSet<String> {
+        let targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        targets.values().flat_map(|series| series.keys().cloned()).collect()
+    }
+
+    /// The values of `keys` of every poll since `since`
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    pub fn table(&self, keys: &[String], since: u64) -> super::Table {
+        let mut table = super::Table::new(keys);
+        let targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        for (target, series) in targets.iter() {
+            for key in keys {
+                for &(timestamp, value) in series.get(key).into_iter().flatten().filter(|&&(t, _)| t >= since) {
+                    table.insert(target, timestamp, key, value);
+                }
+            }
+        }
+        table
+    }
+
+    /// The samples of `key` since `since`, by target, of every target or
+    /// only the one given
+    #[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+    pub fn range(&self, key: &str, since: u64, target: Option<&str>) -> BTreeMap<String, Vec<(u64, f64)>> {
+        let targets = self.targets.lock().unwrap_or_else(|e| e.into_inner());
+        targets
+            .iter()
+            .filter(|(name, _)| target.is_none_or(|target| target == name.as_str()))
+            .filter_map(|(name, series)| {
+                let samples = series.get(key)?;
+                let samples = samples.iter().filter(|&&(t, _)| t >= since).copied().collect();
+                Some((name.clone(), samples))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::UNIX_EPOCH;
+
+    fn snapshot(host: &str, timestamp: u64, stats: &[(&str, &str)]) -> Snapshot {
+        let stats = stats.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        let mut snapshot = Snapshot::new(host, stats);
+        snapshot.timestamp = UNIX_EPOCH + Duration::from_secs(timestamp);
+        snapshot
+    }
+
+    #[test]
+    fn test_record_and_range() {
+        let history = MemoryHistory::new(Duration::from_secs(60));
+        history.record(&snapshot("ups1", 1000, &[("LINEV", "230.0"), ("BATTV", "27.1"), ("STATUS", "ONLINE")]));
+        history.record(&snapshot("ups1", 1030, &[("LINEV", "231.0")]));
+        history.record(&snapshot("ups2", 1030, &[("LINEV", "119.5")]));
+
+        let linev = history.range("LINEV", 0, None);
+        assert_eq!(linev["ups1"], vec![(1000, 230.0), (1030, 231.0)]);
+        assert_eq!(linev["ups2"], vec![(1030, 119.5)]);
+        assert_eq!(history.range("LINEV", 1010, Some("ups1"))["ups1"], vec![(1030, 231.0)]);
+        assert!(history.range("STATUS", 0, None).is_empty());
+
+        // The first poll is past the retention now, BATTV with it
+        history.record(&snapshot("ups1", 1070, &[("LINEV", "232.0")]));
+        assert_eq!(history.range("LINEV", 0, Some("ups1"))["ups1"], vec![(1030, 231.0), (1070, 232.0)]);
+        assert!(history.range("BATTV", 0, None).is_empty());
+
+        assert_eq!(history.summary("ups1", "LINEV", 0), Some(Summary { min: 231.0, max: 232.0, avg: 231.5 }));
+        assert_eq!(history.summary("ups1", "LINEV", 1050), Some(Summary { min: 232.0, max: 232.0, avg: 232.0 }));
+        assert_eq!(history.summary("ups1", "LINEV", 1080), None);
+
+        history.remove("ups2");
+        assert_eq!(history.range("LINEV", 0, None).len(), 1);
+    }
+
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    #[test]
+    fn test_table() {
+        let history = MemoryHistory::new(Duration::from_secs(60));
+        history.record(&snapshot("ups1", 1000, &[("LINEV", "230.0"), ("BCHARGE", "100.0")]));
+        history.record(&snapshot("ups1", 1030, &[("LINEV", "231.0")]));
+        let keys = ["LINEV".to_string(), "BCHARGE".to_string()];
+        assert_eq!(history.keys().into_iter().collect::<Vec<_>>(), vec!["BCHARGE".to_string(), "LINEV".to_string()]);
+        assert_eq!(
+            history.table(&keys, 1010).to_csv(),
+            "time,target,LINEV,BCHARGE\r\n1970-01-01T00:17:10Z,ups1,231,\r\n"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+//! history/mod.rs
+//!
+//! Stores of past poll results, so the exporter keeps memory across polls and
+//! restarts without an external TSDB.
+
+#[cfg(feature = "sqlite")]
+pub mod backup;
+pub mod memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+
+#[cfg(any(feature = "http", feature = "http-lite"))]
+use std::collections::BTreeMap;
+
+/// The values of some keys of past polls, a row per target and poll, as
+/// exported to spreadsheets.
+#[cfg(any(feature = "http", feature = "http-lite"))]
+pub struct Table {
+    keys: Vec<String>,
+    rows: BTreeMap<(String, u64), Vec<Option<f64>>>,
+}
+
+#[cfg(any(feature = "http", feature = "http-lite"))]
+impl Table {
+    pub fn new(keys: &[String]) -> Self {
+        Table {
+            keys: keys.to_vec(),
+            rows: BTreeMap::new(),
+        }
+    }
+
+    /// Add a sample to the row of its poll. Keys not asked for are ignored.
+    pub fn insert(&mut self, target: &str, timestamp: u64, key: &str, value: f64) {
+        let Some(column) = self.keys.iter().position(|k| k == key) else {
+            return;
+        };
+        let width = self.keys.len();
+        self.rows.entry((target.to_string(), timestamp)).or_insert_with(|| vec![None; width])[column] = Some(value);
+    }
+
+    /// The rows as CSV, with the time in RFC 3339 and a column per key.
+    /// Values a poll didn't have are left empty.
+    pub fn to_csv(&self) -> String {
+        let mut csv = format!("time,target,{}\r\n", self.keys.join(","));
+        for ((target, timestamp), values) in &self.rows {
+            let time = chrono::DateTime::from_timestamp(*timestamp as i64, 0)
+                .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+                .unwrap_or_default();
+            csv.push_str(&time);
+            csv.push(',');
+            csv.push_str(&csv_field(target));
+            for value in values {
+                csv.push(',');
+                if let Some(value) = value {
+                    csv.push_str(&value.to_string());
+                }
+            }
+            csv.push_str("\r\n");
+        }
+        csv
+    }
+}
+
+/// Quote a field if it holds a separator, quote or line break
+#[cfg(any(feature = "http", feature = "http-lite"))]
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\r', '\n']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_string(),
+    }
+}
+
+#[cfg(all(test, any(feature = "http", feature = "http-lite")))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let mut table = Table::new(&["LINEV".to_string(), "BCHARGE".to_string()]);
+        table.insert("ups1", 1717243200, "BCHARGE", 100.0);
+        table.insert("ups1", 1717243200, "LINEV", 230.5);
+        table.insert("ups1", 1717243200, "LOADPCT", 12.0);
+        table.insert("a,b", 1717243210, "LINEV", 119.0);
+        assert_eq!(
+            table.to_csv(),
+            "time,target,LINEV,BCHARGE\r\n\
+             2024-06-01T12:00:10Z,\"a,b\",119,\r\n\
+             2024-06-01T12:00:00Z,ups1,230.5,100\r\n"
+        );
+    }
+}
@@ -0,0 +1,418 @@
+//! history/sqlite.rs
+//!
+//! Persists the numeric values of every poll into a local SQLite database
+//! from a thread of its own, and reads them back downsampled for
+//! `/api/v1/query`. The energy totals
+//! and the events are kept there too, without a retention: the totals to
+//! carry on from after a restart, the events as a log of what happened.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rusqlite::{params, Connection, OpenFlags};
+use tracing::info;
+
+use crate::energy::Metered;
+use crate::events::Event;
+use crate::sinks::{Sender, Sink, SinkError};
+use crate::snapshot::Snapshot;
+
+/// How often old samples are pruned
+const PRUNE_INTERVAL: Duration = Duration::from_secs(3600);
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS samples (
+        timestamp INTEGER NOT NULL,
+        host      TEXT    NOT NULL,
+        key       TEXT    NOT NULL,
+        value     REAL    NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS samples_key_timestamp ON samples (key, timestamp);
+    CREATE TABLE IF NOT EXISTS energy (
+        host   TEXT PRIMARY KEY,
+        joules REAL NOT NULL,
+        cost   REAL
+    );
+    CREATE TABLE IF NOT EXISTS events (
+        timestamp INTEGER NOT NULL,
+        host      TEXT    NOT NULL,
+        kind      TEXT    NOT NULL,
+        severity  TEXT    NOT NULL,
+        summary   TEXT    NOT NULL
+    );
+";
+
+/// The tables of the database, as listed in backups
+pub const TABLES: &[&str] = &["samples", "energy", "events"];
+
+pub struct SqliteHistory {
+    conn: Connection,
+    retention: Duration,
+    last_prune: Option<Instant>,
+}
+
+impl SqliteHistory {
+    /// Open (or create) the database at `path` and apply the schema.
+    pub fn open(path: &str, retention: Duration) -> rusqlite::Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        conn.execute_batch(SCHEMA)?;
+        Ok(SqliteHistory {
+            conn,
+            retention,
+            last_prune: None,
+        })
+    }
+
+    /// Build the store from `SQLITE_*` environment variables. Returns `None`
+    /// unless `SQLITE_PATH` is set.
+    pub fn from_env() -> Option<rusqlite::Result<Self>> {
+        let path = std::env::var("SQLITE_PATH").ok()?;
+        let retention_days: u64 = std::env::var("SQLITE_RETENTION_DAYS")
+            .ok()
+            .and_then(|d| d.parse().ok())
+            .unwrap_or(30);
+        Some(SqliteHistory::open(&path, Duration::from_secs(retention_days * 86400)))
+    }
+
+    /// Insert all numeric values of a snapshot in a single transaction.
+    pub fn insert(&mut self, snapshot: &Snapshot) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached("INSERT INTO samples (timestamp, host, key, value) VALUES (?1, ?2, ?3, ?4)")?;
+            let timestamp = snapshot.unix_timestamp() as i64;
+            for (key, value) in snapshot.numeric_values() {
+                stmt.execute(params![timestamp, snapshot.host, key, value])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Replace the energy total of a target
+    pub fn save_energy(&mut self, target: &str, total: &Metered) -> rusqlite::Result<()> {
+        self.conn.execute(
+            "INSERT INTO energy (host, joules, cost) VALUES (?1, ?2, ?3)
+             ON CONFLICT (host) DO UPDATE SET joules = excluded.joules, cost = excluded.cost",
+            params![target, total.joules, total.cost],
+        )?;
+        Ok(())
+    }
+
+    /// Log events in a single transaction. Test events aren't kept.
+    pub fn insert_events(&mut self, events: &[Event]) -> rusqlite::Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO events (timestamp, host, kind, severity, summary) VALUES (?1, ?2, ?3, ?4, ?5)",
+            )?;
+            for event in events.iter().filter(|event| !event.test) {
+                stmt.execute(params![
+                    event.snapshot.unix_timestamp() as i64,
+                    event.snapshot.host,
+                    event.kind.as_str(),
+                    event.severity.as_str(),
+                    event.summary(),
+                ])?;
+            }
+        }
+        tx.commit()
+    }
+
+    /// Delete samples older than the retention period.
+    pub fn prune(&mut self, now: u64) -> rusqlite::Result<usize> {
+        let cutoff = now.saturating_sub(self.retention.as_secs()) as i64;
+        self.conn.execute("DELETE FROM samples WHERE timestamp < ?1", params![cutoff])
+    }
+
+    /// Apply a write of the sink, pruning old samples along with the polls
+    /// once per `PRUNE_INTERVAL`.
+    fn write(&mut self, write: Write) -> Result<(), SinkError> {
+        match write {
+            Write::Poll(snapshot) => {
+                self.insert(&snapshot)?;
+                if self.last_prune.is_none_or(|t| t.elapsed() >= PRUNE_INTERVAL) {
+                    let deleted = self.prune(snapshot.unix_timestamp())?;
+                    if deleted > 0 {
+                        info!("Pruned {} samples older than the retention period", deleted);
+                    }
+                    self.last_prune = Some(Instant::now());
+                }
+            }
+            Write::Energy(target, total) => self.save_energy(&target, &total)?,
+            Write::Events(events) => self.insert_events(&events)?,
+        }
+        Ok(())
+    }
+}
+
+/// What the sink hands to its thread
+enum Write {
+    Poll(Snapshot),
+    Energy(String, Metered),
+    Events(Vec<Event>),
+}
+
+/// Writes to the history from a thread of its own, so a slow disk never
+/// stalls the poll loop.
+pub struct SqliteSink {
+    sender: Sender<Write>,
+}
+
+impl SqliteSink {
+    /// Hand the history to the writing thread. The database is opened
+    /// beforehand, so that its schema exists once this returns.
+    pub fn new(mut history: SqliteHistory) -> Self {
+        SqliteSink {
+            sender: Sender::spawn("sqlite", move |write| history.write(write)),
+        }
+    }
+
+    /// Build the sink from `SQLITE_*` environment variables, see
+    /// [`SqliteHistory::from_env`].
+    pub fn from_env() -> Option<rusqlite::Result<Self>> {
+        Some(SqliteHistory::from_env()?.map(SqliteSink::new))
+    }
+}
+
+impl Sink for SqliteSink {
+    fn name(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn publish(&mut self, snapshot: &Snapshot) -> Result<(), SinkError> {
+        self.sender.queue(Write::Poll(snapshot.clone()))
+    }
+
+    fn record_energy(&mut self, target: &str, total: &Metered) -> Result<(), SinkError> {
+        self.sender.queue(Write::Energy(target.to_string(), total.clone()))
+    }
+
+    fn record_events(&mut self, events: &[Event]) -> Result<(), SinkError> {
+        self.sender.queue(Write::Events(events.to_vec()))
+    }
+}
+
+/// How the samples within a step are combined
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Aggregation {
+    Avg,
+    Min,
+    Max,
+    Sum,
+    Count,
+}
+
+impl std::str::FromStr for Aggregation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "avg" => Ok(Aggregation::Avg),
+            "min" => Ok(Aggregation::Min),
+            "max" => Ok(Aggregation::Max),
+            "sum" => Ok(Aggregation::Sum),
+            "count" => Ok(Aggregation::Count),
+            _ => Err(format!("unknown aggregation {:?}, expected avg, min, max, sum or count", s)),
+        }
+    }
+}
+
+impl Aggregation {
+    /// As given in queries, which is also the SQL function
+    pub fn name(self) -> &'static str {
+        match self {
+            Aggregation::Avg => "avg",
+            Aggregation::Min => "min",
+            Aggregation::Max => "max",
+            Aggregation::Sum => "sum",
+            Aggregation::Count => "count",
+        }
+    }
+}
+
+/// The samples of a key between `start` and `end`, in Unix seconds, one
+/// value per `step` seconds
+pub struct Query {
+    pub key: String,
+    /// Only this target's samples, rather than those of every target
+    pub target: Option<String>,
+    pub start: u64,
+    pub end: u64,
+    pub step: u64,
+    pub aggregation: Aggregation,
+}
+
+/// A read-only connection to the database of the sink, for the API. WAL
+/// lets it read while the sink writes.
+#[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+pub struct SqliteQueries {
+    conn: Mutex<Connection>,
+}
+
+#[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+impl SqliteQueries {
+    /// Open the database at `SQLITE_PATH`, which the sink has created.
+    /// Returns `None` unless it is set.
+    pub fn from_env() -> Option<rusqlite::Result<Self>> {
+        let path = std::env::var("SQLITE_PATH").ok()?;
+        Some(Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map(SqliteQueries::new))
+    }
+
+    pub fn new(conn: Connection) -> Self {
+        SqliteQueries { conn: Mutex::new(conn) }
+    }
+
+    /// The directory of the database file, none for one in memory
+    pub fn directory(&self) -> Option<std::path::PathBuf> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let path = std::path::Path::new(conn.path().filter(|path| !path.is_empty())?);
+        path.parent().map(std::path::Path::to_path_buf)
+    }
+
+    /// Write a consistent copy of the database to `path`, a new or empty file
+    pub fn copy_to(&self, path: &std::path::Path) -> rusqlite::Result<()> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        conn.execute("VACUUM INTO ?1", params![path.to_string_lossy()])?;
+        Ok(())
+    }
+
+    /// The energy total of every target, to carry on from
+    pub fn energy_totals(&self) -> rusqlite::Result<HashMap<String, Metered>> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stmt = conn.prepare_cached("SELECT host, joules, cost FROM energy")?;
+        let rows = stmt.query_map([], |row| Ok((row.get(0)?, Metered { joules: row.get(1)?, cost: row.get(2)? })))?;
+        rows.collect()
+    }
+
+    /// The downsampled series of every target, each value at the start of
+    /// its step
+    pub fn query(&self, query: &Query) -> rusqlite::Result<BTreeMap<String, Vec<(u64, f64)>>> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let sql = format!(
+            "SELECT host, (timestamp / ?1) * ?1 AS step, {}(value) FROM samples
+             WHERE key = ?2 AND timestamp >= ?3 AND timestamp <= ?4 AND (?5 IS NULL OR host = ?5)
+             GROUP BY host, step ORDER BY host, step",
+            query.aggregation.name()
+        );
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let rows = stmt.query_map(
+            params![query.step as i64, query.key, query.start as i64, query.end as i64, query.target],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)? as u64, row.get::<_, f64>(2)?)),
+        )?;
+        let mut series: BTreeMap<String, Vec<(u64, f64)>> = BTreeMap::new();
+        for row in rows {
+            let (host, step, value) = row?;
+            series.entry(host).or_default().push((step, value));
+        }
+        Ok(series)
+    }
+
+    /// Every key with samples, of any target
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    pub fn keys(&self) -> rusqlite::Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let mut stmt = conn.prepare_cached("SELECT DISTINCT key FROM samples ORDER BY key")?;
+        stmt.query_map([], |row| row.get(0))?.collect()
+    }
+
+    /// The values of `keys` of every poll since `since`
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    pub fn table(&self, keys: &[String], since: u64) -> rusqlite::Result<super::Table> {
+        let conn = self.conn.lock().unwrap_or_else(|e| e.into_inner());
+        let placeholders = vec!["?"; keys.len()].join(", ");
+        let mut stmt = conn.prepare(&format!(
+            "SELECT host, timestamp, key, value FROM samples WHERE timestamp >= {} AND key IN ({})",
+            since, placeholders
+        ))?;
+        let mut rows = stmt.query(rusqlite::params_from_iter(keys))?;
+        let mut table = super::Table::new(keys);
+        while let Some(row) = rows.next()? {
+            let host: String = row.get(0)?;
+            let key: String = row.get(2)?;
+            table.insert(&host, row.get::<_, i64>(1)? as u64, &key, row.get(3)?);
+        }
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_insert_and_prune() {
+        let mut history = SqliteHistory::open(":memory:", Duration::from_secs(60)).unwrap();
+        let stats = BTreeMap::from([
+            ("LINEV".to_string(), "120.0".to_string()),
+            ("STATUS".to_string(), "ONLINE".to_string()),
+        ]);
+        let mut snapshot = Snapshot::new("localhost", stats);
+        snapshot.timestamp = UNIX_EPOCH + Duration::from_secs(1000);
+        history.insert(&snapshot).unwrap();
+
+        let count: i64 = history.conn.query_row("SELECT COUNT(*) FROM samples", [], |r| r.get(0)).unwrap();
+        assert_eq!(count, 1);
+
+        assert_eq!(history.prune(1030).unwrap(), 0);
+        assert_eq!(history.prune(1100).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_query() {
+        let mut history = SqliteHistory::open(":memory:", Duration::from_secs(86400)).unwrap();
+        for (host, timestamp, loadpct) in [("ups1", 1000, "10"), ("ups1", 1100, "20"), ("ups1", 1300, "60"), ("ups2", 1000, "5")] {
+            let mut snapshot = Snapshot::new(host, BTreeMap::from([("LOADPCT".to_string(), loadpct.to_string())]));
+            snapshot.timestamp = UNIX_EPOCH + Duration::from_secs(timestamp);
+            history.insert(&snapshot).unwrap();
+        }
+        let queries = SqliteQueries::new(history.conn);
+        let mut query = Query {
+            key: "LOADPCT".to_string(),
+            target: None,
+            start: 900,
+            end: 1400,
+            step: 300,
+            aggregation: Aggregation::Avg,
+        };
+        let series = queries.query(&query).unwrap();
+        assert_eq!(series["ups1"], vec![(900, 15.0), (1200, 60.0)]);
+        assert_eq!(series["ups2"], vec![(900, 5.0)]);
+
+        query.target = Some("ups1".to_string());
+        query.aggregation = "max".parse().unwrap();
+        query.start = 1050;
+        let series = queries.query(&query).unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!(series["ups1"], vec![(900, 20.0), (1200, 60.0)]);
+        assert!("median".parse::<Aggregation>().is_err());
+        #[cfg(any(feature = "http", feature = "http-lite"))]
+        assert_eq!(queries.keys().unwrap(), vec!["LOADPCT".to_string()]);
+    }
+
+    #[test]
+    fn test_sink_writes_in_background() {
+        let path = std::env::temp_dir().join(format!("rsapcupsdexporter-sink-test-{}.db", std::process::id()));
+        let history = SqliteHistory::open(path.to_str().unwrap(), Duration::from_secs(86400)).unwrap();
+        let mut sink = SqliteSink::new(history);
+        let snapshot = Snapshot::new("ups1", BTreeMap::from([("LINEV".to_string(), "230.0".to_string())]));
+        sink.publish(&snapshot).unwrap();
+        sink.record_energy("ups1", &Metered { joules: 3.6e6, cost: None }).unwrap();
+
+        let queries = SqliteQueries::new(Connection::open_with_flags(&path, OpenFlags::SQLITE_OPEN_READ_ONLY).unwrap());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while queries.energy_totals().unwrap().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(queries.energy_totals().unwrap()["ups1"], Metered { joules: 3.6e6, cost: None });
+        let samples: i64 = queries.conn.lock().unwrap().query_row("SELECT COUNT(*) FROM samples", [], |r| r.get(0)).unwrap();
+        assert_eq!(samples, 1);
+
+        drop(sink);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,146 @@
+//! logging/eventlog.rs
+//!
+//! Writes warnings and errors to the Windows Event Log, so they surface in
+//! the monitoring of Windows hosts.
+
+use std::ffi::c_void;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::System::EventLog::{
+    DeregisterEventSource, RegisterEventSourceW, ReportEventW, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+    EVENTLOG_WARNING_TYPE, REPORT_EVENT_TYPE,
+};
+
+/// Event ID of every entry. The exporter doesn't ship a message file, so the
+/// message is passed as the only insertion string.
+const EVENT_ID: u32 = 1000;
+
+/// A registered event source, e.g. `rsapcupsdexporter` in the Application
+/// log.
+pub struct EventSource {
+    handle: HANDLE,
+}
+
+// The handle is only used through ReportEventW, which is thread-safe
+unsafe impl Send for EventSource {}
+unsafe impl Sync for EventSource {}
+
+impl EventSource {
+    /// Open the source named by `EVENT_LOG_SOURCE`, `rsapcupsdexporter` by
+    /// default. Registering the source beforehand, e.g. with PowerShell's
+    /// `New-EventLog -LogName Application -Source rsapcupsdexporter`, keeps
+    /// Event Viewer from complaining about a missing description.
+    pub fn from_env() -> io::Result<Self> {
+        let name = std::env::var("EVENT_LOG_SOURCE").unwrap_or_else(|_| env!("CARGO_PKG_NAME").to_string());
+        let name = wide(&name);
+        // SAFETY: name is a NUL-terminated UTF-16 string that outlives the call
+        let handle = unsafe { RegisterEventSourceW(std::ptr::null(), name.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventSource { handle })
+    }
+
+    fn report(&self, kind: REPORT_EVENT_TYPE, message: &str) -> io::Result<()> {
+        let message = wide(message);
+        let strings = [message.as_ptr()];
+        // SAFETY: the handle is open and strings points to one valid string
+        let ok = unsafe {
+            ReportEventW(
+                self.handle,
+                kind,
+                0,
+                EVENT_ID,
+                std::ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                std::ptr::null::<c_void>(),
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl Drop for EventSource {
+    fn drop(&mut self) {
+        // SAFETY: the handle came from RegisterEventSourceW and is closed once
+        unsafe {
+            DeregisterEventSource(self.handle);
+        }
+    }
+}
+
+/// NUL-terminated UTF-16
+fn wide(s: &str) -> Vec<u16> {
+    s.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+fn event_type(level: &Level) -> REPORT_EVENT_TYPE {
+    match *level {
+        Level::ERROR => EVENTLOG_ERROR_TYPE,
+        Level::WARN => EVENTLOG_WARNING_TYPE,
+        _ => EVENTLOG_INFORMATION_TYPE,
+    }
+}
+
+/// Writer for the fmt layer, one event log entry per log line.
+#[derive(Clone)]
+pub struct EventLogWriter(pub Arc<EventSource>);
+
+pub struct Entry<'a> {
+    source: &'a EventSource,
+    kind: REPORT_EVENT_TYPE,
+    buffer: Vec<u8>,
+}
+
+impl Write for Entry<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Entry<'_> {
+    fn drop(&mut self) {
+        let message = String::from_utf8_lossy(&self.buffer);
+        let message = message.trim_end();
+        if !message.is_empty()
+            && let Err(e) = self.source.report(self.kind, message)
+        {
+            // Logging the failure would only end up here again
+            eprintln!("Failed to write to the event log ({}): {}", e, message);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for EventLogWriter {
+    type Writer = Entry<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Entry {
+            source: &self.0,
+            kind: EVENTLOG_INFORMATION_TYPE,
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        Entry {
+            source: &self.0,
+            kind: event_type(meta.level()),
+            buffer: Vec::new(),
+        }
+    }
+}
@@ -0,0 +1,222 @@
+//! logging/file.rs
+//!
+//! Writes logs to a file, rotated by size or time, for deployments without
+//! a log collector.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::Local;
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Rotated files kept unless `LOG_FILE_RETAIN` is set
+const DEFAULT_RETAIN: usize = 5;
+
+/// Time-based rotation, on the boundaries of local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Hourly,
+    Daily,
+}
+
+impl Period {
+    pub fn parse(s: &str) -> Option<Option<Self>> {
+        match s.to_ascii_lowercase().as_str() {
+            "" | "never" => Some(None),
+            "hourly" => Some(Some(Period::Hourly)),
+            "daily" => Some(Some(Period::Daily)),
+            _ => None,
+        }
+    }
+
+    /// Changes whenever a new period starts
+    fn current(self) -> String {
+        let format = match self {
+            Period::Hourly => "%Y-%m-%d %H",
+            Period::Daily => "%Y-%m-%d",
+        };
+        Local::now().format(format).to_string()
+    }
+}
+
+struct State {
+    file: File,
+    size: u64,
+    /// Period the current file was started in
+    period: Option<String>,
+}
+
+/// A log file rotated to `<path>.1`, `<path>.2`, ... with the oldest
+/// removed once more than `retain` are kept.
+pub struct RotatingFile {
+    path: PathBuf,
+    max_size: Option<u64>,
+    period: Option<Period>,
+    retain: usize,
+    state: Mutex<State>,
+}
+
+impl RotatingFile {
+    pub fn open(path: &Path, max_size: Option<u64>, period: Option<Period>, retain: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            max_size,
+            period,
+            retain,
+            state: Mutex::new(State {
+                file,
+                size,
+                period: period.map(Period::current),
+            }),
+        })
+    }
+
+    /// Build the file from `LOG_FILE`, `LOG_FILE_MAX_SIZE` (e.g. `10M`),
+    /// `LOG_FILE_ROTATE` (`never`, `hourly` or `daily`) and `LOG_FILE_RETAIN`.
+    pub fn from_env() -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let path = std::env::var("LOG_FILE").map_err(|_| invalid("LOG_FILE is required".to_string()))?;
+        let max_size = match std::env::var("LOG_FILE_MAX_SIZE") {
+            Ok(size) => Some(parse_size(&size).ok_or_else(|| invalid(format!("invalid LOG_FILE_MAX_SIZE {:?}", size)))?),
+            Err(_) => None,
+        };
+        let rotate = std::env::var("LOG_FILE_ROTATE").unwrap_or_default();
+        let period = Period::parse(&rotate).ok_or_else(|| invalid(format!("invalid LOG_FILE_ROTATE {:?}", rotate)))?;
+        let retain = std::env::var("LOG_FILE_RETAIN")
+            .ok()
+            .and_then(|r| r.parse().ok())
+            .unwrap_or(DEFAULT_RETAIN);
+        RotatingFile::open(Path::new(&path), max_size, period, retain)
+    }
+
+    fn write_line(&self, line: &[u8]) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        let period = self.period.map(Period::current);
+        let full = self.max_size.is_some_and(|max| state.size > 0 && state.size + line.len() as u64 > max);
+        if full || period != state.period {
+            self.rotate()?;
+            state.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            state.size = 0;
+            state.period = period;
+        }
+        state.file.write_all(line)?;
+        state.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Shift the rotated files up by one, dropping the oldest, and move the
+    /// current file to `.1`.
+    fn rotate(&self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut name = self.path.clone().into_os_string();
+            name.push(format!(".{}", n));
+            PathBuf::from(name)
+        };
+        if self.retain == 0 {
+            return fs::remove_file(&self.path);
+        }
+        match fs::remove_file(rotated(self.retain)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        for n in (1..self.retain).rev() {
+            match fs::rename(rotated(n), rotated(n + 1)) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => {}
+            }
+        }
+        fs::rename(&self.path, rotated(1))
+    }
+}
+
+/// Parse a size in bytes, with an optional `K`, `M` or `G` suffix (powers
+/// of 1024).
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (number, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+        Some((i, _)) => s.split_at(i),
+        None => (s, ""),
+    };
+    let multiplier = match unit.trim().to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+pub struct Line<'a> {
+    file: &'a RotatingFile,
+    buffer: Vec<u8>,
+}
+
+impl Write for Line<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Written as a whole, so a line is never split across two files
+impl Drop for Line<'_> {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty()
+            && let Err(e) = self.file.write_line(&self.buffer)
+        {
+            // Logging the failure would only end up here again
+            eprintln!("Failed to write to {}: {}", self.file.path.display(), e);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFile {
+    type Writer = Line<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Line {
+            file: self,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("512"), Some(512));
+        assert_eq!(parse_size("10M"), Some(10 << 20));
+        assert_eq!(parse_size("1 GB"), Some(1 << 30));
+        assert_eq!(parse_size("10x"), None);
+    }
+
+    #[test]
+    fn test_rotate_by_size() {
+        let dir = std::env::temp_dir().join(format!("rsapcupsdexporter-log-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("exporter.log");
+        let file = RotatingFile::open(&path, Some(10), None, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            writeln!(file.make_writer(), "{}", line.trim_end()).unwrap();
+        }
+
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("exporter.log"), "fourth\n");
+        assert_eq!(read("exporter.log.1"), "third\n");
+        assert_eq!(read("exporter.log.2"), "second\n");
+        assert!(!dir.join("exporter.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,325 @@
+//! logging/mod.rs
+//!
+//! Sets up the tracing subscriber that all logs go through, and with the
+//! `otel` feature, OTLP export of the poll and scrape spans.
+
+#[cfg(windows)]
+pub mod eventlog;
+pub mod file;
+pub mod syslog;
+
+use std::io::IsTerminal;
+use std::sync::Arc;
+
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+#[cfg(windows)]
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+/// Level used unless `RUST_LOG` is set
+const DEFAULT_FILTER: &str = "info";
+
+/// Log output format, from `LOG_FORMAT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Human-readable lines
+    #[default]
+    Text,
+    /// One JSON object per line, for Loki, ELK and the like
+    Json,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" | "" => Some(Format::Text),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Where logs are written, from `LOG_OUTPUT`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Output {
+    #[default]
+    Stdout,
+    /// A rotated log file
+    File,
+    Syslog,
+    /// Native journald fields, without going through stdout
+    Journald,
+}
+
+impl Output {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s.to_ascii_lowercase().as_str() {
+            "stdout" | "" => Some(Output::Stdout),
+            "file" => Some(Output::File),
+            "syslog" => Some(Output::Syslog),
+            "journald" => Some(Output::Journald),
+            _ => None,
+        }
+    }
+}
+
+type BoxedLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Keeps trace export running. Dropping it flushes the spans not yet sent.
+#[derive(Default)]
+pub struct Guard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take()
+            && let Err(e) = provider.shutdown()
+        {
+            tracing::warn!("Failed to flush traces: {}", e);
+        }
+    }
+}
+
+/// Install the global subscriber. `RUST_LOG` selects what is logged, e.g.
+/// `debug` or `rsapcupsdexporter=debug,actix_web=warn`, `LOG_FORMAT`
+/// chooses between `text` and `json` output, and `LOG_OUTPUT` between
+/// `stdout`, `file`, `syslog` and `journald`. Logs from dependencies using
+/// the `log` crate are forwarded as well. The filter can be changed later
+/// through the returned `LogLevel`.
+pub fn init() -> (Guard, LogLevel) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let format_name = std::env::var("LOG_FORMAT").unwrap_or_default();
+    let format = Format::parse(&format_name).unwrap_or_default();
+    let output_name = std::env::var("LOG_OUTPUT").unwrap_or_default();
+
+    // Fall back to stdout if the chosen output can't be set up, and say
+    // why once logging works
+    let mut problems = Vec::new();
+    let output = match Output::parse(&output_name) {
+        Some(Output::File) => match file::RotatingFile::from_env() {
+            Ok(file) => Some(fmt_layer(format, file, true, false)),
+            Err(e) => {
+                problems.push(format!("Failed to open the log file, using stdout: {}", e));
+                None
+            }
+        },
+        Some(Output::Syslog) => match syslog::Syslog::from_env() {
+            Ok(syslog) => Some(fmt_layer(format, syslog::SyslogWriter(Arc::new(syslog)), false, false)),
+            Err(e) => {
+                problems.push(format!("Failed to set up syslog logging, using stdout: {}", e));
+                None
+            }
+        },
+        #[cfg(unix)]
+        Some(Output::Journald) => match tracing_journald::layer() {
+            Ok(layer) => Some(layer.with_syslog_identifier(env!("CARGO_PKG_NAME").to_string()).boxed()),
+            Err(e) => {
+                problems.push(format!("Failed to connect to journald, using stdout: {}", e));
+                None
+            }
+        },
+        #[cfg(not(unix))]
+        Some(Output::Journald) => {
+            problems.push("journald logging is only available on Linux, using stdout".to_string());
+            None
+        }
+        Some(Output::Stdout) => None,
+        None => {
+            problems.push(format!("Unknown LOG_OUTPUT {:?}, using stdout", output_name));
+            None
+        }
+    };
+    if Format::parse(&format_name).is_none() {
+        problems.push(format!("Unknown LOG_FORMAT {:?}, using text", format_name));
+    }
+    let output = output.unwrap_or_else(|| fmt_layer(format, std::io::stdout, true, std::io::stdout().is_terminal()));
+
+    #[cfg_attr(not(any(feature = "otel", windows)), allow(unused_mut))]
+    let mut layers = vec![output];
+
+    // Warnings and errors also go to the Windows Event Log if enabled
+    #[cfg(windows)]
+    if std::env::var("EVENT_LOG").is_ok_and(|v| matches!(v.as_str(), "1" | "true" | "yes")) {
+        match eventlog::EventSource::from_env() {
+            Ok(source) => {
+                let writer = eventlog::EventLogWriter(Arc::new(source));
+                layers.push(fmt_layer(Format::Text, writer, false, false).with_filter(LevelFilter::WARN).boxed());
+            }
+            Err(e) => problems.push(format!("Failed to open the Windows Event Log: {}", e)),
+        }
+    }
+    #[cfg_attr(not(feature = "otel"), allow(unused_mut))]
+    let mut guard = Guard::default();
+
+    // Set up before the subscriber, reported after it is installed
+    #[cfg(feature = "otel")]
+    let otel = otel::layer().map(|result| {
+        result.map(|(layer, provider)| {
+            layers.push(layer);
+            guard.provider = Some(provider);
+        })
+    });
+
+    let (filter, handle) = reload::Layer::new(filter);
+    tracing_subscriber::registry().with(layers).with(filter).init();
+    for problem in problems {
+        tracing::warn!("{}", problem);
+    }
+    #[cfg(feature = "otel")]
+    match otel {
+        Some(Err(e)) => tracing::error!("Failed to set up OTLP trace export: {}", e),
+        Some(Ok(_)) => tracing::info!("Exporting traces over OTLP"),
+        None => {}
+    }
+    (guard, LogLevel { handle })
+}
+
+type FilterHandle = reload::Handle<EnvFilter, Layered<Vec<BoxedLayer>, Registry>>;
+
+/// Changes the log filter at runtime, e.g. to capture debug logs during an
+/// incident without restarting.
+#[derive(Clone)]
+pub struct LogLevel {
+    handle: FilterHandle,
+}
+
+impl LogLevel {
+    /// The current filter directives
+    pub fn current(&self) -> String {
+        self.handle.with_current(|filter| filter.to_string()).unwrap_or_default()
+    }
+
+    /// Replace the filter with directives like `RUST_LOG`'s, e.g. `debug`.
+    pub fn set(&self, directives: &str) -> Result<(), String> {
+        let filter = EnvFilter::try_new(directives.trim()).map_err(|e| e.to_string())?;
+        self.handle.reload(filter).map_err(|e| e.to_string())?;
+        tracing::info!("Log filter set to {}", self.current());
+        Ok(())
+    }
+
+    /// Step through `info`, `debug` and `trace`, then back to `info`.
+    pub fn cycle(&self) -> Result<(), String> {
+        let next = match self.current().as_str() {
+            "info" => "debug",
+            "debug" => "trace",
+            _ => "info",
+        };
+        self.set(next)
+    }
+
+    /// Cycle the level on every SIGUSR2.
+    #[cfg(unix)]
+    pub fn cycle_on_signal(self) {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut signals = match signal(SignalKind::user_defined2()) {
+            Ok(signals) => signals,
+            Err(e) => {
+                tracing::warn!("Failed to listen for SIGUSR2: {}", e);
+                return;
+            }
+        };
+        tokio::spawn(async move {
+            while signals.recv().await.is_some() {
+                if let Err(e) = self.cycle() {
+                    tracing::error!("Failed to change the log filter: {}", e);
+                }
+            }
+        });
+    }
+}
+
+/// Formatted log lines, with a timestamp and level `header` unless the
+/// output, like syslog, adds its own.
+fn fmt_layer<W>(format: Format, writer: W, header: bool, ansi: bool) -> BoxedLayer
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(ansi)
+        .with_level(header);
+    match (format, header) {
+        (Format::Json, true) => layer.json().with_current_span(true).with_span_list(false).boxed(),
+        (Format::Json, false) => layer.json().without_time().with_current_span(true).with_span_list(false).boxed(),
+        (Format::Text, true) => layer.boxed(),
+        (Format::Text, false) => layer.without_time().boxed(),
+    }
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_otlp::{ExporterBuildError, Protocol, SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use tracing_subscriber::Layer;
+
+    use super::BoxedLayer;
+
+    /// Export spans over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` or
+    /// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` is set. The other standard
+    /// `OTEL_*` variables, e.g. `OTEL_SERVICE_NAME`, apply as usual.
+    pub fn layer() -> Option<Result<(BoxedLayer, SdkTracerProvider), ExporterBuildError>> {
+        let configured = ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"]
+            .iter()
+            .any(|var| std::env::var(var).is_ok_and(|v| !v.is_empty()));
+        if !configured {
+            return None;
+        }
+
+        let exporter = match SpanExporter::builder().with_http().with_protocol(Protocol::HttpBinary).build() {
+            Ok(exporter) => exporter,
+            Err(e) => return Some(Err(e)),
+        };
+        let mut resource = Resource::builder();
+        if std::env::var("OTEL_SERVICE_NAME").is_err() {
+            resource = resource.with_service_name(env!("CARGO_PKG_NAME"));
+        }
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource.build())
+            .build();
+        let tracer = provider.tracer(env!("CARGO_PKG_NAME"));
+        Some(Ok((tracing_opentelemetry::layer().with_tracer(tracer).boxed(), provider)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_format() {
+        assert_eq!(Format::parse("JSON"), Some(Format::Json));
+        assert_eq!(Format::parse(""), Some(Format::Text));
+        assert_eq!(Format::parse("logfmt"), None);
+    }
+
+    #[test]
+    fn test_log_level() {
+        let (filter, handle) = reload::Layer::new(EnvFilter::new("info"));
+        let _subscriber = tracing_subscriber::registry().with(Vec::<BoxedLayer>::new()).with(filter);
+        let level = LogLevel { handle };
+        level.cycle().unwrap();
+        assert_eq!(level.current(), "debug");
+        level.set("rsapcupsdexporter=trace").unwrap();
+        assert_eq!(level.current(), "rsapcupsdexporter=trace");
+        level.cycle().unwrap();
+        assert_eq!(level.current(), "info");
+        assert!(level.set("rsapcupsdexporter=loud").is_err());
+    }
+
+    #[test]
+    fn test_parse_output() {
+        assert_eq!(Output::parse("Journald"), Some(Output::Journald));
+        assert_eq!(Output::parse(""), Some(Output::Stdout));
+        assert_eq!(Output::parse("file"), Some(Output::File));
+        assert_eq!(Output::parse("stderr"), None);
+    }
+}
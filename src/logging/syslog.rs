@@ -0,0 +1,248 @@
+//! logging/syslog.rs
+//!
+//! Sends log lines to syslog as RFC 5424 messages, over UDP, TCP or the
+//! local `/dev/log` socket.
+
+use std::io::{self, Write};
+use std::net::{TcpStream, UdpSocket};
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+
+use chrono::{SecondsFormat, Utc};
+use tracing::{Level, Metadata};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// Local syslog socket used unless `SYSLOG_ADDRESS` is set
+#[cfg(unix)]
+const DEFAULT_SOCKET: &str = "/dev/log";
+
+/// Facility `daemon`
+const DEFAULT_FACILITY: u8 = 3;
+
+enum Transport {
+    Udp(UdpSocket),
+    /// Reconnected on the next message after a failed write
+    Tcp {
+        address: String,
+        stream: Mutex<Option<TcpStream>>,
+    },
+    #[cfg(unix)]
+    Unix(UnixDatagram, String),
+}
+
+pub struct Syslog {
+    transport: Transport,
+    facility: u8,
+    hostname: String,
+    app_name: String,
+    pid: u32,
+}
+
+impl Syslog {
+    /// Build the sender from `SYSLOG_ADDRESS`, e.g. `udp://logs:514`,
+    /// `tcp://logs:601` or `unix:///dev/log` (the default), and
+    /// `SYSLOG_FACILITY`, e.g. `daemon` (the default) or `local3`.
+    pub fn from_env() -> io::Result<Self> {
+        let facility = match std::env::var("SYSLOG_FACILITY") {
+            Ok(name) => facility(&name)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unknown SYSLOG_FACILITY {:?}", name)))?,
+            Err(_) => DEFAULT_FACILITY,
+        };
+        #[cfg(unix)]
+        let address = std::env::var("SYSLOG_ADDRESS").unwrap_or_else(|_| format!("unix://{}", DEFAULT_SOCKET));
+        #[cfg(not(unix))]
+        let address = std::env::var("SYSLOG_ADDRESS")
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "SYSLOG_ADDRESS is required"))?;
+        Ok(Syslog {
+            transport: Transport::connect(&address)?,
+            facility,
+            hostname: hostname(),
+            app_name: env!("CARGO_PKG_NAME").to_string(),
+            pid: std::process::id(),
+        })
+    }
+
+    /// Frame a message with the header for its severity.
+    fn format(&self, severity: u8, message: &str) -> String {
+        format!(
+            "<{}>1 {} {} {} {} - - {}",
+            self.facility * 8 + severity,
+            Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true),
+            self.hostname,
+            self.app_name,
+            self.pid,
+            message
+        )
+    }
+
+    fn send(&self, severity: u8, message: &str) {
+        let line = self.format(severity, message);
+        if let Err(e) = self.transport.send(&line) {
+            // Logging the failure would only end up here again
+            eprintln!("Failed to send to syslog ({}): {}", e, message);
+        }
+    }
+}
+
+impl Transport {
+    fn connect(address: &str) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid SYSLOG_ADDRESS {:?}", address));
+        let (scheme, rest) = address.split_once("://").ok_or_else(invalid)?;
+        match scheme {
+            "udp" => {
+                let socket = UdpSocket::bind(if rest.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" })?;
+                socket.connect(rest)?;
+                Ok(Transport::Udp(socket))
+            }
+            "tcp" => Ok(Transport::Tcp {
+                address: rest.to_string(),
+                stream: Mutex::new(TcpStream::connect(rest).ok()),
+            }),
+            #[cfg(unix)]
+            "unix" => Ok(Transport::Unix(UnixDatagram::unbound()?, rest.to_string())),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn send(&self, line: &str) -> io::Result<()> {
+        match self {
+            Transport::Udp(socket) => socket.send(line.as_bytes()).map(|_| ()),
+            Transport::Tcp { address, stream } => {
+                let mut stream = stream.lock().unwrap_or_else(|e| e.into_inner());
+                if stream.is_none() {
+                    *stream = Some(TcpStream::connect(address)?);
+                }
+                // Octet-counting framing, RFC 6587
+                let framed = format!("{} {}", line.len(), line);
+                let result = stream.as_mut().map_or(Ok(()), |s| s.write_all(framed.as_bytes()));
+                if result.is_err() {
+                    *stream = None;
+                }
+                result
+            }
+            #[cfg(unix)]
+            Transport::Unix(socket, path) => socket.send_to(line.as_bytes(), path).map(|_| ()),
+        }
+    }
+}
+
+/// Facility code for a name, e.g. `daemon` or `local0`.
+fn facility(name: &str) -> Option<u8> {
+    let code = match name.to_ascii_lowercase().as_str() {
+        "user" => 1,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        local => {
+            let n: u8 = local.strip_prefix("local")?.parse().ok()?;
+            if n > 7 {
+                return None;
+            }
+            16 + n
+        }
+    };
+    Some(code)
+}
+
+fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .map(|h| h.trim().to_string())
+        .ok()
+        .filter(|h| !h.is_empty())
+        .unwrap_or_else(|| "-".to_string())
+}
+
+/// Syslog severity for a tracing level
+fn severity(level: &Level) -> u8 {
+    match *level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        _ => 7,
+    }
+}
+
+/// Writer for the fmt layer, one syslog message per log line.
+#[derive(Clone)]
+pub struct SyslogWriter(pub Arc<Syslog>);
+
+pub struct Message<'a> {
+    syslog: &'a Syslog,
+    severity: u8,
+    buffer: Vec<u8>,
+}
+
+impl Write for Message<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for Message<'_> {
+    fn drop(&mut self) {
+        let message = String::from_utf8_lossy(&self.buffer);
+        let message = message.trim_end();
+        if !message.is_empty() {
+            self.syslog.send(self.severity, message);
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = Message<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Message {
+            syslog: &self.0,
+            severity: severity(&Level::INFO),
+            buffer: Vec::new(),
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        Message {
+            syslog: &self.0,
+            severity: severity(meta.level()),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_facility() {
+        assert_eq!(facility("daemon"), Some(3));
+        assert_eq!(facility("LOCAL3"), Some(19));
+        assert_eq!(facility("local8"), None);
+    }
+
+    #[test]
+    fn test_send_udp() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = format!("udp://{}", receiver.local_addr().unwrap());
+        let syslog = Syslog {
+            transport: Transport::connect(&address).unwrap(),
+            facility: DEFAULT_FACILITY,
+            hostname: "nas".to_string(),
+            app_name: "rsapcupsdexporter".to_string(),
+            pid: 42,
+        };
+        syslog.send(severity(&Level::WARN), "UPS on battery");
+
+        let mut buf = [0u8; 512];
+        let n = receiver.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..n]);
+        assert!(message.starts_with("<28>1 "), "{}", message);
+        assert!(message.ends_with(" nas rsapcupsdexporter 42 - - UPS on battery"), "{}", message);
+    }
+}
@@ -1,162 +1,467 @@
-mod apcaccess;
+#[cfg(any(feature = "http", feature = "http-lite"))]
+mod access_log;
+mod alerts;
+mod apcupsd_conf;
+#[cfg(any(feature = "http", feature = "http-lite"))]
+mod api;
+mod battery;
+mod check;
+mod cli;
+mod config;
+mod discovery;
+mod energy;
+mod events;
+mod healthcheck;
+mod history;
+mod logging;
+mod metrics;
+mod notify;
+mod poller;
+mod quirks;
+#[cfg(any(feature = "http", feature = "http-lite"))]
+mod server;
+mod settings;
+mod sinks;
+mod snapshot;
+mod status;
+mod systemd;
+mod targets;
+mod textfile;
+mod updater;
+mod watch;
 
-use std::sync::{Arc, Mutex};
-use tokio::time::{interval, Duration};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
 
-use actix_web::middleware::Compress;
-use actix_web::{web, App, HttpResponse, HttpServer, Result};
-use log::{debug, info};
-use prometheus::{Encoder, GaugeVec, IntGaugeVec, Opts, Registry, TextEncoder};
+use arc_swap::ArcSwap;
+use clap::Parser;
+use prometheus::Registry;
+use snapshot::Snapshot;
+use tracing::{debug, error, info, warn};
 
+/// State shared with the HTTP handlers. Neither needs a lock: the registry is
+/// internally synchronized and the poller swaps in new snapshots atomically.
 pub struct AppState {
     pub registry: Registry,
-    pub info_gauge: IntGaugeVec,
-    pub gauges: Arc<Mutex<std::collections::HashMap<String, GaugeVec>>>,
-    pub stats: std::collections::BTreeMap<String, String>,
+    /// Latest successful poll of each target, by name
+    pub snapshots: ArcSwap<HashMap<String, Arc<Snapshot>>>,
+    pub metric_errors: metrics::MetricErrors,
+    /// Severities of the event types, for test events
+    #[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+    pub severities: events::Severities,
 }
 
-pub async fn metrics_handler(state: web::Data<Arc<Mutex<AppState>>>) -> Result<HttpResponse> {
-    let state = state.lock().unwrap();
-    let encoder = TextEncoder::new();
-    let metric_families = state.registry.gather();
-    let mut buffer = Vec::new();
-    encoder.encode(&metric_families, &mut buffer).unwrap();
-    
-    Ok(HttpResponse::Ok()
-        .content_type("text/plain; charset=utf-8")
-        .body(buffer))
-}
+/// Send a test event built from the current UPS values, or from no values if
+/// apcupsd can't be reached, and report the outcome per channel.
+fn notify_test(
+    client: &mut apcaccess::NisClient,
+    channel: Option<&str>,
+    kind: events::EventKind,
+) -> std::result::Result<(), String> {
+    let stats = client.fetch_stats(true).unwrap_or_else(|e| {
+        warn!("Failed to fetch APC UPS stats, sending the test event without UPS values: {}", e);
+        Default::default()
+    });
+    let severities = config::Config::from_env().map_err(|e| format!("Failed to load config file: {}", e))?.severities();
+    let event = events::Event::test(kind, Snapshot::new(&client.host, stats), &severities);
+    let results = notify::send_test(&notify::from_env(), channel, &event, true);
+    if results.is_empty() {
+        return Err("No matching notification channel is configured".to_string());
+    }
 
-fn update_metrics(state: &mut AppState) {
-    // Update info gauge with labels
-    state.info_gauge.reset();
-    state.info_gauge
-        .with_label_values(&[
-            &state.stats.get("APC").cloned().unwrap_or_default(),
-            &state.stats.get("HOSTNAME").cloned().unwrap_or_default(),
-            &state.stats.get("UPSNAME").cloned().unwrap_or_default(),
-            &state.stats.get("VERSION").cloned().unwrap_or_default(),
-            &state.stats.get("CABLE").cloned().unwrap_or_default(),
-            &state.stats.get("MODEL").cloned().unwrap_or_default(),
-            &state.stats.get("UPSMODE").cloned().unwrap_or_default(),
-            &state.stats.get("DRIVER").cloned().unwrap_or_default(),
-            &state.stats.get("APCMODEL").cloned().unwrap_or_default(),
-        ])
-        .set(1);
-
-    // Update numeric metrics as gauges
-    let mut gauges = state.gauges.lock().unwrap();
-    
-    for (key, value) in &state.stats {
-        // Skip the tag keys that are already in the info metric
-        if matches!(key.as_str(), "APC" | "HOSTNAME" | "UPSNAME" | "VERSION" | "CABLE" | "MODEL" | "UPSMODE" | "DRIVER" | "APCMODEL") {
-            continue;
+    let mut failed = 0;
+    for (name, result) in results {
+        match result {
+            Ok(()) => println!("{}: ok", name),
+            Err(e) => {
+                println!("{}: {}", name, e);
+                failed += 1;
+            }
         }
+    }
+    if failed > 0 {
+        return Err(format!("{} channel(s) failed", failed));
+    }
+    Ok(())
+}
 
-        // Try to parse as f64
-        if let Ok(numeric_value) = value.parse::<f64>() {
-            let metric_name = format!("apcupsd_{}", key.to_lowercase());
-            
-            // Get or create the gauge for this metric
-            let gauge = gauges.entry(metric_name.clone()).or_insert_with(|| {
-                let opts = Opts::new(metric_name.clone(), format!("APC UPS {}", key));
-                let gauge_vec = GaugeVec::new(opts, &[]).unwrap();
-                state.registry.register(Box::new(gauge_vec.clone())).unwrap();
-                gauge_vec
-            });
-            
-            gauge.with_label_values(&[]).set(numeric_value);
-        }
+/// Load a config file and report what looks wrong in it.
+fn validate(path: Option<std::path::PathBuf>, strict: bool) -> std::result::Result<(), String> {
+    let path = path
+        .or_else(|| std::env::var_os("CONFIG_FILE").map(Into::into))
+        .ok_or("No config file given, use --config or set CONFIG_FILE")?;
+    let config = config::Config::load(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let notifiers = notify::from_env();
+    let names: Vec<&str> = notifiers.iter().map(|n| n.name()).collect();
+    let warnings = config.warnings(&names);
+    for warning in &warnings {
+        println!("warning: {}", warning);
+    }
+    if strict && !warnings.is_empty() {
+        return Err(format!("{}: {} warning(s)", path.display(), warnings.len()));
     }
+    println!("{}: ok", path.display());
+    Ok(())
 }
 
-#[actix_web::main]
+/// Write a backup of the SQLite history to `output`
+#[cfg(feature = "sqlite")]
+fn backup(output: &std::path::Path) -> std::result::Result<(), String> {
+    let store = history::sqlite::SqliteQueries::from_env()
+        .ok_or("SQLITE_PATH is not set")?
+        .map_err(|e| format!("Failed to open the SQLite history: {}", e))?;
+    let file = std::fs::File::create(output).map_err(|e| format!("{}: {}", output.display(), e))?;
+    history::backup::create(&store, file).map_err(|e| format!("Failed to write {}: {}", output.display(), e))?;
+    println!("Wrote {}", output.display());
+    Ok(())
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn backup(_output: &std::path::Path) -> std::result::Result<(), String> {
+    Err("Backups of the history need a build with the sqlite feature".to_string())
+}
+
+/// Log panics with a backtrace instead of printing them to stderr, so a
+/// panic that the poller or a handler recovers from still shows up in the logs.
+fn log_panics() {
+    std::panic::set_hook(Box::new(|info| {
+        error!("{}\n{}", info, std::backtrace::Backtrace::force_capture());
+    }));
+}
+
+#[cfg_attr(feature = "http", actix_web::main)]
+#[cfg_attr(not(feature = "http"), tokio::main(flavor = "current_thread"))]
 async fn main() -> std::io::Result<()> {
 
-    env_logger::init();
-    let apcupsd_host = std::env::var("APCUPSD_HOST").unwrap_or_else(|_| "localhost".to_string());
-    let apcupsd_port: u16 = std::env::var("APCUPSD_PORT")
-        .unwrap_or_else(|_| "3551".to_string())
-        .parse()
-        .unwrap_or(3551);
+    let (_logging, log_level) = logging::init();
+    #[cfg(unix)]
+    log_level.clone().cycle_on_signal();
+    log_panics();
+    let cli = cli::Cli::parse();
     let port_bind: u16 = std::env::var("METRICS_PORT")
         .unwrap_or_else(|_| "9090".to_string())
         .parse()
         .unwrap_or(9090);
-    let fetch_interval: u64 = std::env::var("INTERVAL")
-        .unwrap_or_else(|_| "10".to_string())
-        .parse()
-        .unwrap_or(10);
-    let timeout: u64 = std::env::var("TIMEOUT")
-        .unwrap_or_else(|_| "15".to_string())
-        .parse()
-        .unwrap_or(15);
-
-    // Initial fetch
-    debug!("Fetching initial APC UPS stats from {}:{}", apcupsd_host, apcupsd_port);
-    let stats = apcaccess::fetch_stats(&apcupsd_host, apcupsd_port, timeout, true)
-        .expect("Failed to fetch initial APC UPS stats");
-    debug!("Fetched stats: {:?}", stats);
-    info!("Successfully fetched initial APC UPS stats");
-    
+    // Disabled unless set to a positive number
+    let max_failures: Option<u32> = std::env::var("MAX_CONSECUTIVE_FAILURES")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0);
+    let poll_concurrency: usize = std::env::var("POLL_CONCURRENCY")
+        .ok()
+        .and_then(|n| n.parse().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(8);
+    let poll_spread = std::env::var("POLL_SPREAD")
+        .map(|v| matches!(v.as_str(), "1" | "true" | "yes"))
+        .unwrap_or(false);
+
+    // APCUPSD_HOST, which the commands talk to and the exporter polls unless
+    // the config file has targets
+    let defaults = targets::Defaults::from_env();
+    let default_target = targets::resolve(&[], &defaults).map_err(std::io::Error::other)?.remove(0);
+    let mut client = default_target.client(&defaults);
+
+    // Longest a poll may take before the poll loop counts as stuck
+    let max_poll_age = default_target.max_poll_age();
+
+    if let Some(command) = cli.command {
+        let result = match command {
+            cli::Command::NotifyTest { channel, event } => notify_test(&mut client, channel.as_deref(), event),
+            cli::Command::Status { nis } => {
+                client.host = nis.host.unwrap_or(client.host);
+                client.port = nis.port.unwrap_or(client.port);
+                client
+                    .fetch_stats(true)
+                    .map(|stats| print!("{}", status::table(&stats)))
+                    .map_err(|e| format!("Failed to fetch APC UPS stats from {}:{}: {}", client.host, client.port, e))
+            }
+            cli::Command::Watch { nis, interval } => {
+                client.host = nis.host.unwrap_or(client.host);
+                client.port = nis.port.unwrap_or(client.port);
+                watch::run(&mut client, Duration::from_secs(interval))
+            }
+            cli::Command::Check { nis, thresholds } => {
+                client.host = nis.host.unwrap_or(client.host);
+                client.port = nis.port.unwrap_or(client.port);
+                let (state, output) = match client.fetch_stats(true) {
+                    Ok(stats) => check::evaluate(&stats, &thresholds),
+                    Err(e) => (
+                        check::State::Critical,
+                        format!("UPS CRITICAL - Failed to fetch stats from {}:{}: {}", client.host, client.port, e),
+                    ),
+                };
+                println!("{}", output);
+                std::process::exit(state as i32);
+            }
+            cli::Command::PrintConfig { format } => settings::Effective::from_env()
+                .map_err(|e| format!("Failed to load config file: {}", e))
+                .and_then(|effective| match format {
+                    cli::ConfigFormat::Toml => toml::to_string(&effective).map_err(|e| e.to_string()),
+                    cli::ConfigFormat::Json => serde_json::to_string_pretty(&effective).map_err(|e| e.to_string()),
+                })
+                .map(|output| println!("{}", output.trim_end())),
+            cli::Command::Validate { config, strict } => validate(config, strict),
+            cli::Command::Backup { output } => backup(&output),
+            cli::Command::Completions { shell } => {
+                let mut command = <cli::Cli as clap::CommandFactory>::command();
+                let name = command.get_name().to_string();
+                clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+                Ok(())
+            }
+            cli::Command::Healthcheck { url, timeout } => {
+                match textfile::TextfileWriter::from_env(&Registry::new()) {
+                    Some(writer) if url.is_none() => healthcheck::check_file(writer.path(), max_poll_age),
+                    _ => {
+                        let scheme = if std::env::var_os("TLS_CERT_FILE").is_some() { "https" } else { "http" };
+                        let url = url.unwrap_or_else(|| format!("{}://127.0.0.1:{}/-/healthy", scheme, port_bind));
+                        healthcheck::check_url(&url, Duration::from_secs(timeout))
+                    }
+                }
+            }
+        };
+        if let Err(e) = result {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    let config = config::Config::from_env().map_err(|e| {
+        error!("Failed to load config file: {}", e);
+        std::io::Error::other(e)
+    })?;
+
+    // APCUPSD_HOST is only polled if targets are neither configured nor
+    // discovered, nor to be added through the API
+    let mut providers = discovery::from_env();
+    let (discovered, discoveries) = mpsc::channel(1);
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    let managed = discovery::managed::ManagedTargets::from_env(&defaults, discovered.clone());
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    let managing = managed.is_some();
+    #[cfg(not(any(feature = "http", feature = "http-lite")))]
+    let managing = false;
+    #[cfg(not(any(feature = "http", feature = "http-lite")))]
+    if std::env::var_os("TARGETS_API").is_some() {
+        error!("TARGETS_API is set, but managing targets needs a build with an HTTP server");
+    }
+    let multi_target = !config.targets.is_empty() || !providers.is_empty() || managing;
+    let targets = match multi_target && config.targets.is_empty() {
+        true => Vec::new(),
+        false => targets::resolve(&config.targets, &defaults).map_err(|e| {
+            error!("Invalid targets in the config file: {}", e);
+            std::io::Error::other(e)
+        })?,
+    };
+
     // Create registry and metrics
     let registry = Registry::new();
-    
-    // Create info gauge with all label names (using _metadata suffix to avoid info type confusion)
-    let info_opts = Opts::new("apcupsd_metadata", "APC UPS daemon information");
-    let info_gauge = IntGaugeVec::new(
-        info_opts,
-        &["apc", "hostname", "upsname", "version", "cable", "model", "upsmode", "driver", "apcmodel"]
-    ).unwrap();
-    registry.register(Box::new(info_gauge.clone())).unwrap();
-    
-    let state = Arc::new(Mutex::new(AppState {
-        registry,
-        info_gauge,
-        gauges: Arc::new(Mutex::new(std::collections::HashMap::new())),
-        stats: stats.clone(),
-    }));
-
-    // Initialize metrics
-    {
-        let mut state_guard = state.lock().unwrap();
-        update_metrics(&mut state_guard);
+    let registered = |e: prometheus::Error| {
+        error!("Failed to register metrics: {}", e);
+        std::io::Error::other(e)
+    };
+    let metric_errors = metrics::MetricErrors::new(&registry).map_err(registered)?;
+    let registries = metrics::TargetRegistries::new(&registry).map_err(registered)?;
+    let distributions = metrics::Distributions::from_env();
+    let severities = config.severities();
+    let alerts = alerts::Alerts::new(config.alerts, severities.clone());
+    // With several targets, the updater creates the gauges of each as it's added
+    let mut metrics = HashMap::new();
+    if !multi_target {
+        let target_metrics = metrics::UpsMetrics::new(&registries, metric_errors.clone(), &default_target.name)
+            .and_then(|target_metrics| target_metrics.with_distributions(&distributions))
+            .and_then(|target_metrics| target_metrics.with_alerts(alerts.rules()))
+            .map_err(registered)?;
+        metrics.insert(default_target.name.clone(), target_metrics);
     }
+    let poll_metrics = metrics::PollMetrics::new(&registry).map_err(registered)?;
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    let http_metrics = metrics::HttpMetrics::new(&registry).map_err(registered)?;
 
-    // Spawn background task to fetch stats periodically
-    let state_clone = Arc::clone(&state);
-    let host_clone = apcupsd_host.clone();
-
-    debug!("Starting background task to fetch APC UPS stats every {} seconds", fetch_interval);
-    tokio::spawn(async move {
-        let mut interval_timer = interval(Duration::from_secs(fetch_interval));
-        loop {
-            interval_timer.tick().await;
-
-            match apcaccess::fetch_stats(&host_clone, apcupsd_port, timeout, true) {
-                Ok(new_stats) => {
-                    let mut state_guard = state_clone.lock().unwrap();
-                    state_guard.stats = new_stats;
-                    update_metrics(&mut state_guard);
-                }
-                Err(e) => {
-                    eprintln!("Failed to fetch APC UPS stats: {}", e);
+    // Ad hoc silences from the API and recurring maintenance windows
+    let silences = Arc::new(notify::silence::Silences::new(config.maintenance, &registry).map_err(registered)?);
+
+    // node_exporter textfile collector output instead of the HTTP listener
+    let textfile = textfile::TextfileWriter::from_env(&registry);
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    let textfile_mode = textfile.is_some();
+
+    let oneshot = textfile.as_ref().is_some_and(|writer| writer.oneshot);
+
+    // Initial fetch, which has to succeed with a single target. With several,
+    // an unreachable one mustn't hold up the rest, so they're only fetched
+    // up front to write the textfile once.
+    let mut initial = Vec::new();
+    if !multi_target {
+        debug!("Fetching initial APC UPS stats from {}:{}", client.host, client.port);
+        let stats = client
+            .fetch_stats(true)
+            .expect("Failed to fetch initial APC UPS stats");
+        debug!("Fetched stats: {:?}", stats);
+        info!("Successfully fetched initial APC UPS stats");
+        initial.push(Snapshot::new(&default_target.name, stats));
+    } else if oneshot {
+        let mut oneshot_targets = targets.clone();
+        for provider in &mut providers {
+            match provider.discover() {
+                Ok(found) => oneshot_targets.extend(found.iter().map(|t| t.resolve(&defaults))),
+                Err(e) => warn!("Failed to discover targets through {}: {}", provider.name(), e),
+            }
+        }
+        for target in &oneshot_targets {
+            let fetched = target.client(&defaults).fetch_stats(true);
+            let created = metrics::UpsMetrics::for_target(&registries, metric_errors.clone(), &target.name, &target.labels)
+                .and_then(|target_metrics| target_metrics.with_distributions(&distributions))
+                .and_then(|target_metrics| target_metrics.with_alerts(alerts.rules()));
+            match (fetched, created) {
+                (Ok(stats), Ok(target_metrics)) => {
+                    metrics.insert(target.name.clone(), target_metrics);
+                    initial.push(Snapshot::new(&target.name, stats));
                 }
+                (Err(e), _) => warn!("Failed to fetch APC UPS stats from {}: {}", target.name, e),
+                (_, Err(e)) => warn!("Failed to register the metrics of target {}: {}", target.name, e),
             }
         }
+    }
+
+    let state = Arc::new(AppState {
+        registry,
+        snapshots: ArcSwap::from_pointee(
+            initial.iter().map(|snapshot| (snapshot.host.clone(), Arc::new(snapshot.clone()))).collect(),
+        ),
+        metric_errors,
+        severities: severities.clone(),
     });
-    info!("Started background task to fetch APC UPS stats every {} seconds", fetch_interval);
-
-    let state = web::Data::new(state);
-
-    debug!("Starting HTTP server on 0.0.0.0:{}", port_bind);
-    HttpServer::new(move || {
-        App::new()
-            .wrap(Compress::default())
-            .app_data(state.clone())
-            .service(web::resource("/metrics").route(web::get().to(metrics_handler)))
-    })
-    .bind(("0.0.0.0", port_bind))?
-    .run()
-    .await
+
+    if let Some(writer) = &textfile
+        && oneshot
+    {
+        for snapshot in &initial {
+            if let Some(metrics) = metrics.get_mut(&snapshot.host) {
+                metrics.update(&snapshot.stats);
+            }
+        }
+        writer.write()?;
+        info!("Wrote metrics to {}", writer.path().display());
+        return Ok(());
+    }
+
+    // Recent values for /api/v1/history, if there's a listener to serve
+    // them, and for the rolling gauges
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    let served = !textfile_mode;
+    #[cfg(not(any(feature = "http", feature = "http-lite")))]
+    let served = false;
+    let rolling = metrics::rolling_window();
+    let history = history::memory::MemoryHistory::from_env(served, rolling).map(Arc::new);
+
+    // A backup to move the history over, restored before the sink opens it
+    #[cfg(feature = "sqlite")]
+    history::backup::restore_from_env();
+    #[cfg(not(feature = "sqlite"))]
+    if std::env::var_os("SQLITE_RESTORE").is_some() {
+        error!("SQLITE_RESTORE is set, but the history needs a build with the sqlite feature");
+    }
+
+    // Everything fed by polls is owned by a single updater task
+    let mut updater = updater::Updater {
+        metrics,
+        registries: registries.clone(),
+        distributions,
+        state: Arc::clone(&state),
+        silences: Arc::clone(&silences),
+        textfile,
+        // Push-based outputs
+        sinks: sinks::from_env().await,
+        // Power event detection and notification
+        detector: events::EventDetector::from_env(severities.clone()),
+        alerts,
+        dispatcher: notify::Dispatcher::from_env(config.routes, severities, Arc::clone(&silences)),
+        history: history.clone(),
+        rolling,
+        temperatures: metrics::temperature_units(),
+        strict: std::env::var("STRICT").is_ok_and(|v| matches!(v.as_str(), "1" | "true" | "yes")),
+        battery: battery::BatteryTracker::from_env(),
+        energy: energy::EnergyMeter::from_env(),
+        poll_metrics: poll_metrics.clone(),
+    };
+    // The energy totals carry on from the SQLite history, which the sink
+    // has opened by now
+    #[cfg(feature = "sqlite")]
+    match history::sqlite::SqliteQueries::from_env().map(|store| store.and_then(|store| store.energy_totals())) {
+        Some(Ok(totals)) => updater.energy.restore(totals),
+        Some(Err(e)) => warn!("Failed to read the energy totals from the SQLite history: {}", e),
+        None => {}
+    }
+    for snapshot in initial {
+        updater.apply(updater::Update::Polled(snapshot, tracing::Span::none()));
+    }
+    let (sender, receiver) = mpsc::channel(updater::QUEUE_SIZE);
+    tokio::spawn(updater.run(receiver));
+
+    // systemd restarts the service if a poll takes much longer than it may
+    let max_poll_age = targets.iter().map(targets::Target::max_poll_age).max().unwrap_or(max_poll_age);
+    let heartbeat = Arc::new(systemd::Heartbeat::default());
+    heartbeat.beat();
+    systemd::start_watchdog(Arc::clone(&heartbeat), max_poll_age);
+
+    // Pollers for the configured targets, then for the discovered ones as
+    // they're found
+    let active = Arc::new(targets::ActiveTargets::default());
+    let settings = discovery::PollerSettings {
+        defaults,
+        max_failures,
+        heartbeat: Arc::clone(&heartbeat),
+        active: Arc::clone(&active),
+        metrics: poll_metrics,
+        limit: Arc::new(tokio::sync::Semaphore::new(poll_concurrency)),
+        spread: poll_spread,
+    };
+    let mut fleet = discovery::Fleet::new(settings, sender);
+    fleet.set("config", targets.clone()).await;
+    for provider in providers {
+        tokio::spawn(discovery::watch(provider, discovered.clone()));
+    }
+    drop(discovered);
+    let fleet = tokio::spawn(fleet.run(discoveries));
+
+    #[cfg(any(feature = "http", feature = "http-lite"))]
+    if !textfile_mode {
+        // Opened after the sink, which creates the database
+        #[cfg(feature = "sqlite")]
+        let store = match history::sqlite::SqliteQueries::from_env() {
+            Some(Ok(queries)) => Some(Arc::new(queries)),
+            Some(Err(e)) => {
+                error!("Failed to open SQLite history database for queries: {}", e);
+                None
+            }
+            None => None,
+        };
+        let api = api::Api {
+            state,
+            registries,
+            silences,
+            log_level,
+            health: api::Health { heartbeat, max_age: max_poll_age },
+            stale_after: api::stale_after(),
+            targets: managed,
+            active,
+            max_failures,
+            history,
+            #[cfg(feature = "sqlite")]
+            store,
+        };
+        let access_log = access_log::AccessLog::from_env();
+        return server::serve(server::Server { api, http_metrics, access_log }, port_bind)
+            .await
+            .inspect_err(|e| error!("HTTP server failed: {}", e));
+    }
+
+    // No listener needed: node_exporter serves the file, or the build has no
+    // HTTP server and polls only feed the push sinks and notifications
+    systemd::notify("READY=1");
+    fleet.await.map_err(std::io::Error::other)
 }
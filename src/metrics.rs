@@ -0,0 +1,1233 @@
+//! metrics.rs
+//!
+//! Prometheus metrics: the apcupsd values as gauges, and the exporter's own
+//! polling metrics.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use prometheus::core::{Collector, Desc};
+use prometheus::proto::MetricFamily;
+use prometheus::{
+    Counter, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGaugeVec, Opts, Registry,
+};
+use tracing::{error, warn};
+
+use apcaccess::{ApcAccessError, RequestStats};
+use crate::alerts::{AlertRule, AlertState};
+use crate::config::parse_duration;
+use crate::history::memory::MemoryHistory;
+use crate::snapshot::INFO_KEYS;
+
+/// How often an unparsable value of the same key is logged
+const PARSE_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// The keys with rolling gauges, by the name the gauges get
+const ROLLING: &[(&str, &str)] = &[("LINEV", "line_volts"), ("OUTPUTV", "output_volts"), ("BATTV", "battery_volts")];
+
+/// `ROLLING_WINDOW`, the time the rolling gauges cover, if they're wanted
+pub fn rolling_window() -> Option<Duration> {
+    let value = std::env::var("ROLLING_WINDOW").ok().filter(|v| !v.trim().is_empty())?;
+    match parse_duration(&value).filter(|window| !window.is_zero()) {
+        Some(window) => Some(window),
+        None => {
+            error!("Ignoring ROLLING_WINDOW, {:?} is not a duration", value);
+            None
+        }
+    }
+}
+
+/// The keys that may be counted into histograms, by the name of the
+/// histogram and the variable with its buckets
+const DISTRIBUTIONS: &[(&str, &str, &str)] =
+    &[("LINEV", "line_volts", "LINEV_BUCKETS"), ("BATTV", "battery_volts", "BATTV_BUCKETS")];
+
+/// The histograms every poll is counted into, each with its buckets. The
+/// exporter polls more often than Prometheus scrapes, so these show sags
+/// the gauges miss.
+#[derive(Clone, Default)]
+pub struct Distributions {
+    histograms: Vec<(&'static str, &'static str, Vec<f64>)>,
+}
+
+impl Distributions {
+    /// A histogram for each key whose `*_BUCKETS` is set, e.g.
+    /// `LINEV_BUCKETS=200,210,220,230,240,250`
+    pub fn from_env() -> Self {
+        let mut histograms = Vec::new();
+        for &(key, name, var) in DISTRIBUTIONS {
+            let Some(value) = std::env::var(var).ok().filter(|v| !v.trim().is_empty()) else {
+                continue;
+            };
+            match parse_buckets(&value) {
+                Some(buckets) => histograms.push((key, name, buckets)),
+                None => error!("Ignoring {}, {:?} is not a list of numbers", var, value),
+            }
+        }
+        Distributions { histograms }
+    }
+}
+
+/// The temperatures with gauges named for their unit, by what they measure
+const TEMPERATURES: &[(&str, &str)] = &[("ITEMP", "internal"), ("AMBTEMP", "ambient")];
+
+/// A unit temperatures are exported in, e.g.
+/// `apcupsd_internal_temperature_fahrenheit`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TemperatureUnit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl TemperatureUnit {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "celsius" | "c" => Some(TemperatureUnit::Celsius),
+            "fahrenheit" | "f" => Some(TemperatureUnit::Fahrenheit),
+            "kelvin" | "k" => Some(TemperatureUnit::Kelvin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "celsius",
+            TemperatureUnit::Fahrenheit => "fahrenheit",
+            TemperatureUnit::Kelvin => "kelvin",
+        }
+    }
+
+    /// A temperature in Celsius, as apcupsd's values are once parsed, in
+    /// this unit
+    pub fn convert(&self, celsius: f64) -> f64 {
+        match self {
+            TemperatureUnit::Celsius => celsius,
+            TemperatureUnit::Fahrenheit => celsius * 9.0 / 5.0 + 32.0,
+            TemperatureUnit::Kelvin => celsius + 273.15,
+        }
+    }
+}
+
+/// `TEMPERATURE_UNITS`, the units temperatures are exported in, Celsius
+/// only unless it says otherwise
+pub fn temperature_units() -> Vec<TemperatureUnit> {
+    let Some(value) = std::env::var("TEMPERATURE_UNITS").ok().filter(|v| !v.trim().is_empty()) else {
+        return vec![TemperatureUnit::Celsius];
+    };
+    match value.split(',').map(TemperatureUnit::parse).collect::<Option<Vec<_>>>() {
+        Some(units) => units,
+        None => {
+            error!("Ignoring TEMPERATURE_UNITS, {:?} is not a list of celsius, fahrenheit and kelvin", value);
+            vec![TemperatureUnit::Celsius]
+        }
+    }
+}
+
+/// The key a per-phase key such as `LINEV_L2` is reported for, and its
+/// phase, `L1` to `L3`
+fn phase_of(key: &str) -> Option<(&str, &str)> {
+    let split = key.len().checked_sub(2)?;
+    let phase = key.get(split..).filter(|phase| matches!(*phase, "L1" | "L2" | "L3"))?;
+    let base = key[..split].strip_suffix(['_', '-', ' '])?;
+    (!base.is_empty()).then_some((base, phase))
+}
+
+/// Whether a value is made of digits and separators only, so it's a
+/// number that failed to parse rather than text
+fn looks_numeric(value: &str) -> bool {
+    let digits = value.strip_prefix(['-', '+']).unwrap_or(value);
+    digits.bytes().any(|b| b.is_ascii_digit()) && digits.bytes().all(|b| b.is_ascii_digit() || b == b'.' || b == b',')
+}
+
+/// Comma-separated bucket bounds, in any order
+fn parse_buckets(value: &str) -> Option<Vec<f64>> {
+    let mut buckets = value
+        .split(',')
+        .map(|bound| bound.trim().parse::<f64>().ok().filter(|b| b.is_finite()))
+        .collect::<Option<Vec<f64>>>()?;
+    buckets.sort_by(f64::total_cmp);
+    buckets.dedup();
+    Some(buckets)
+}
+
+/// A window as it's written in metric names, e.g. `1h`, `90m` or `45s`
+fn window_suffix(window: Duration) -> String {
+    match window.as_secs() {
+        secs if secs % 86_400 == 0 => format!("{}d", secs / 86_400),
+        secs if secs % 3600 == 0 => format!("{}h", secs / 3600),
+        secs if secs % 60 == 0 => format!("{}m", secs / 60),
+        secs => format!("{}s", secs),
+    }
+}
+
+/// The registries of the targets' gauges, registered in the main registry
+/// as one collector. Each target has its own, so the keys of one UPS can't
+/// keep another's gauges from registering; their families are merged by
+/// name when the main registry is gathered.
+#[derive(Clone, Default)]
+pub struct TargetRegistries {
+    registries: Arc<RwLock<BTreeMap<String, Registry>>>,
+}
+
+impl TargetRegistries {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let registries = TargetRegistries::default();
+        registry.register(Box::new(registries.clone()))?;
+        Ok(registries)
+    }
+
+    /// A new, empty registry for the target, replacing any it had
+    fn create(&self, target: &str) -> Registry {
+        let registry = Registry::new();
+        self.registries
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(target.to_string(), registry.clone());
+        registry
+    }
+
+    fn remove(&self, target: &str) {
+        self.registries.write().unwrap_or_else(|e| e.into_inner()).remove(target);
+    }
+
+    /// The families of one target, if it's known
+    #[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+    pub fn gather(&self, target: &str) -> Option<Vec<MetricFamily>> {
+        self.registries.read().unwrap_or_else(|e| e.into_inner()).get(target).map(Registry::gather)
+    }
+}
+
+impl Collector for TargetRegistries {
+    /// None, the families differ by target and come and go with them
+    fn desc(&self) -> Vec<&Desc> {
+        Vec::new()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let registries = self.registries.read().unwrap_or_else(|e| e.into_inner());
+        registries.values().flat_map(Registry::gather).collect()
+    }
+}
+
+/// The UPS gauges, owned and updated by the metrics updater only.
+pub struct UpsMetrics {
+    /// The target's own registry within `registries`
+    registry: Registry,
+    registries: TargetRegistries,
+    target: String,
+    /// Labels on every series, to tell targets apart
+    labels: HashMap<String, String>,
+    /// `apcupsd_metadata`, what the UPS and daemon are
+    info: InfoGauge,
+    /// `apcupsd_settings_info`, how they're configured
+    settings: InfoGauge,
+    /// `apcupsd_role`, the place in a NIS master/slave setup
+    role: InfoGauge,
+    gauges: HashMap<String, GaugeVec>,
+    /// The canonical gauges until they're created
+    placeholders: Placeholders,
+    /// The counters the exporter derives, which keep counting while the
+    /// gauges are cleared
+    counters: HashMap<String, Counter>,
+    /// The histograms of the keys in `Distributions`
+    histograms: Vec<(&'static str, Histogram)>,
+    /// `apcupsd_alert`, if any alerts are configured
+    alerts: Option<IntGaugeVec>,
+    /// Metric names that couldn't be registered, so each is reported once
+    rejected: HashSet<String>,
+    /// `apcupsd_exporter_parse_errors_total`, values that look like a number
+    /// but couldn't be parsed, by key
+    parse_errors: IntCounterVec,
+    /// When each key's unparsable value was last logged, so each is logged
+    /// once per `PARSE_ERROR_LOG_INTERVAL`
+    unparsable: HashMap<String, Instant>,
+    /// Keys nothing is exported for, so each is reported once
+    unhandled: HashSet<String>,
+    errors: MetricErrors,
+}
+
+impl UpsMetrics {
+    /// The unlabelled gauges of the only target
+    pub fn new(registries: &TargetRegistries, errors: MetricErrors, target: &str) -> prometheus::Result<Self> {
+        UpsMetrics::with_labels(registries, errors, target, HashMap::new())
+    }
+
+    /// The gauges of one of several targets, told apart by a `target`
+    /// label, next to the target's own labels.
+    pub fn for_target(
+        registries: &TargetRegistries,
+        errors: MetricErrors,
+        target: &str,
+        labels: &BTreeMap<String, String>,
+    ) -> prometheus::Result<Self> {
+        let mut labels: HashMap<String, String> = labels.iter().map(|(k, v)| (k.clone(), v.clone())).collect();
+        labels.insert("target".to_string(), target.to_string());
+        UpsMetrics::with_labels(registries, errors, target, labels)
+    }
+
+    fn with_labels(
+        registries: &TargetRegistries,
+        errors: MetricErrors,
+        target: &str,
+        labels: HashMap<String, String>,
+    ) -> prometheus::Result<Self> {
+        let registry = registries.create(target);
+        // Using the _metadata suffix to avoid info type confusion
+        let info = InfoGauge::new(&registry, "apcupsd_metadata", "APC UPS daemon information", INFO_KEYS, &labels)?;
+        let settings = InfoGauge::new(&registry, "apcupsd_settings_info", "APC UPS configuration", SETTINGS_KEYS, &labels)?;
+        let opts = Opts::new("apcupsd_exporter_parse_errors_total", "Values from apcupsd that look like a number but couldn't be parsed, by key")
+            .const_labels(labels.clone());
+        let parse_errors = IntCounterVec::new(opts, &["key"])?;
+        registry.register(Box::new(parse_errors.clone()))?;
+        let role = InfoGauge::new(&registry, "apcupsd_role", "APC UPS daemon role in a NIS master/slave setup", ROLE_LABELS, &labels)?;
+        let placeholders = Placeholders::new(&labels);
+        let metrics = UpsMetrics {
+            registry,
+            registries: registries.clone(),
+            target: target.to_string(),
+            labels,
+            info,
+            settings,
+            role,
+            parse_errors,
+            gauges: HashMap::new(),
+            placeholders,
+            counters: HashMap::new(),
+            histograms: Vec::new(),
+            alerts: None,
+            rejected: HashSet::new(),
+            unparsable: HashMap::new(),
+            unhandled: HashSet::new(),
+            errors,
+        };
+        metrics.registry.register(Box::new(metrics.placeholders.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Also count every poll's values into the histograms, e.g.
+    /// `apcupsd_line_volts_bucket`
+    pub fn with_distributions(mut self, distributions: &Distributions) -> prometheus::Result<Self> {
+        for (key, name, buckets) in &distributions.histograms {
+            let opts = HistogramOpts::new(format!("apcupsd_{}", name), format!("Distribution of APC UPS {} across polls", key))
+                .const_labels(self.labels.clone())
+                .buckets(buckets.clone());
+            let histogram = Histogram::with_opts(opts)?;
+            self.registry.register(Box::new(histogram.clone()))?;
+            self.histograms.push((key, histogram));
+        }
+        Ok(self)
+    }
+
+    /// Also export whether each of the alerts fires, as
+    /// `apcupsd_alert{name,severity}`
+    pub fn with_alerts(mut self, rules: &[AlertRule]) -> prometheus::Result<Self> {
+        if rules.is_empty() {
+            return Ok(self);
+        }
+        let opts = Opts::new("apcupsd_alert", "1 while the alert of the config file fires").const_labels(self.labels.clone());
+        let gauge = IntGaugeVec::new(opts, &["name", "severity"])?;
+        self.registry.register(Box::new(gauge.clone()))?;
+        self.alerts = Some(gauge);
+        Ok(self)
+    }
+
+    pub fn update(&mut self, stats: &BTreeMap<String, String>) {
+        self.info.update(stats, &self.errors);
+        self.settings.update(stats, &self.errors);
+        self.update_role(stats);
+
+        // Update numeric metrics as gauges
+        for (key, value) in stats {
+            // Skip the tag keys that are already in the info metric
+            if INFO_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+
+            // Try to parse as f64. Text such as STATUS is skipped, numbers
+            // in a format the parser doesn't know are counted.
+            let Ok(numeric_value) = value.parse::<f64>() else {
+                if looks_numeric(value) {
+                    let logged = self.unparsable.get(key).is_some_and(|at| at.elapsed() < PARSE_ERROR_LOG_INTERVAL);
+                    if !logged {
+                        warn!("Skipping {}, {:?} is not a number the exporter can parse", key, value);
+                        self.unparsable.insert(key.clone(), Instant::now());
+                    }
+                    self.parse_errors.with_label_values(&[key]).inc();
+                    self.errors.record("parse");
+                }
+                continue;
+            };
+            // The phases of a three-phase UPS, e.g. LINEV_L1, share a family
+            let (key, phase) = match phase_of(key) {
+                Some((base, phase)) => (base, Some(phase)),
+                None => (key.as_str(), None),
+            };
+            let gauge = match phase {
+                Some(_) => self.phase_gauge(key),
+                None => self.gauge(key),
+            };
+            if let Some(gauge) = gauge {
+                let labels: Vec<&str> = phase.into_iter().collect();
+                match gauge.get_metric_with_label_values(&labels) {
+                    Ok(gauge) => gauge.set(numeric_value),
+                    Err(e) => {
+                        error!("Failed to update gauge for {}: {}", key, e);
+                        self.errors.record("update");
+                    }
+                }
+            }
+        }
+
+        for (key, histogram) in &self.histograms {
+            if let Some(value) = stats.get(*key).and_then(|v| v.parse::<f64>().ok()) {
+                histogram.observe(value);
+            }
+        }
+    }
+
+    /// Export the role of the UPS in a master/slave setup, and how many
+    /// slaves a master has connected where it reports them.
+    fn update_role(&mut self, stats: &BTreeMap<String, String>) {
+        let role = role(stats);
+        match role {
+            Some(role) => {
+                let master = stats.get("MASTER").map(|master| master.trim().to_string()).unwrap_or_default();
+                self.role.set(vec![role.to_string(), master], &self.errors);
+            }
+            None => self.role.clear(),
+        }
+        match slaves_connected(stats).filter(|_| role == Some("master")) {
+            Some(slaves) => self.set_derived("apcupsd_slaves_connected", "Slaves connected to this NIS master", slaves),
+            None => self.remove_derived("apcupsd_slaves_connected"),
+        }
+    }
+
+    /// Set the lowest, highest and mean voltages of the target's recent
+    /// polls, e.g. `apcupsd_line_volts_min_1h`, so dips between scrapes
+    /// still show up.
+    pub fn update_rolling(&mut self, history: &MemoryHistory, window: Duration, now: u64) {
+        let suffix = window_suffix(window);
+        let since = now.saturating_sub(window.as_secs());
+        for (key, name) in ROLLING {
+            let Some(summary) = history.summary(&self.target, key, since) else {
+                continue;
+            };
+            for (stat, value, help) in [("min", summary.min, "Lowest"), ("max", summary.max, "Highest"), ("avg", summary.avg, "Mean")] {
+                let metric_name = format!("apcupsd_{}_{}_{}", name, stat, suffix);
+                let help = format!("{} APC UPS {} over the last {}", help, key, suffix);
+                if let Some(gauge) = self.named_gauge(metric_name, help) {
+                    match gauge.get_metric_with_label_values(&[]) {
+                        Ok(gauge) => gauge.set(value),
+                        Err(e) => {
+                            error!("Failed to update rolling gauge for {}: {}", key, e);
+                            self.errors.record("update");
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Set the alert gauges from the state of each alert
+    pub fn update_alerts(&self, states: &[AlertState]) {
+        let Some(gauge) = &self.alerts else { return };
+        for state in states {
+            match gauge.get_metric_with_label_values(&[state.name, state.severity.as_str()]) {
+                Ok(gauge) => gauge.set(i64::from(state.firing)),
+                Err(e) => {
+                    error!("Failed to update the gauge of alert {}: {}", state.name, e);
+                    self.errors.record("update");
+                }
+            }
+        }
+    }
+
+    /// Set the temperatures in each of `units`, e.g.
+    /// `apcupsd_internal_temperature_celsius`
+    pub fn update_temperatures(&mut self, stats: &BTreeMap<String, String>, units: &[TemperatureUnit]) {
+        for (key, name) in TEMPERATURES {
+            let Some(celsius) = stats.get(*key).and_then(|v| v.parse::<f64>().ok()) else {
+                continue;
+            };
+            for unit in units {
+                self.set_derived(
+                    &format!("apcupsd_{}_temperature_{}", name, unit.as_str()),
+                    &format!("APC UPS {} in {}", key, unit.as_str()),
+                    unit.convert(celsius),
+                );
+            }
+        }
+    }
+
+    /// Count the keys that are neither numbers nor known to the exporter as
+    /// `apcupsd_exporter_unhandled_keys`, logging each the first time, so
+    /// their values can be reported upstream rather than lost unnoticed.
+    pub fn update_unhandled(&mut self, stats: &BTreeMap<String, String>) {
+        // The keys without a field of their own in the typed status
+        let untyped = apcaccess::ApcStatus::from(stats).other;
+        let unhandled: Vec<(&String, &String)> = untyped
+            .iter()
+            .filter(|(key, _)| {
+                !SETTINGS_KEYS.contains(&key.as_str()) && !TOPOLOGY_KEYS.contains(&key.as_str()) && phase_of(key).is_none()
+            })
+            .filter(|(_, value)| value.parse::<f64>().is_err() && !looks_numeric(value))
+            .collect();
+        for (key, value) in &unhandled {
+            if self.unhandled.insert(key.to_string()) {
+                warn!("{} reports {} = {:?}, which the exporter doesn't export", self.target, key, value);
+            }
+        }
+        self.set_derived(
+            "apcupsd_exporter_unhandled_keys",
+            "Keys of the last poll that are neither a number nor known to the exporter",
+            unhandled.len() as f64,
+        );
+    }
+
+    /// Set a gauge the exporter derives rather than apcupsd reports
+    pub fn set_derived(&mut self, metric_name: &str, help: &str, value: f64) {
+        if let Some(gauge) = self.named_gauge(metric_name.to_string(), help.to_string()) {
+            match gauge.get_metric_with_label_values(&[]) {
+                Ok(gauge) => gauge.set(value),
+                Err(e) => {
+                    error!("Failed to update {}: {}", metric_name, e);
+                    self.errors.record("update");
+                }
+            }
+        }
+    }
+
+    /// Add to a counter the exporter derives rather than apcupsd reports
+    pub fn inc_derived(&mut self, metric_name: &str, help: &str, delta: f64) {
+        if self.rejected.contains(metric_name) {
+            return;
+        }
+        if !self.counters.contains_key(metric_name) {
+            let opts = Opts::new(metric_name, help).const_labels(self.labels.clone());
+            let registered = Counter::with_opts(opts).and_then(|counter| {
+                self.registry.register(Box::new(counter.clone()))?;
+                Ok(counter)
+            });
+            match registered {
+                Ok(counter) => {
+                    self.counters.insert(metric_name.to_string(), counter);
+                }
+                Err(e) => {
+                    warn!("Skipping {}, failed to register it: {}", metric_name, e);
+                    self.errors.record("register");
+                    self.rejected.insert(metric_name.to_string());
+                    return;
+                }
+            }
+        }
+        self.counters[metric_name].inc_by(delta);
+    }
+
+    /// Drop a derived gauge that doesn't apply for now, such as one only
+    /// exported while on battery
+    pub fn remove_derived(&mut self, metric_name: &str) {
+        if let Some(gauge) = self.gauges.remove(metric_name)
+            && let Err(e) = self.registry.unregister(Box::new(gauge))
+        {
+            warn!("Failed to unregister {}: {}", metric_name, e);
+        }
+    }
+
+    /// Drop the values of the last poll, so `absent()` fires while the
+    /// target can't be polled. The histograms keep their counts, and the
+    /// next successful poll brings the gauges back.
+    pub fn clear(&mut self) {
+        self.info.clear();
+        self.settings.clear();
+        self.role.clear();
+        for (metric_name, gauge) in self.gauges.drain() {
+            if let Err(e) = self.registry.unregister(Box::new(gauge)) {
+                warn!("Failed to unregister {}: {}", metric_name, e);
+            }
+        }
+        if let Some(alerts) = &self.alerts {
+            alerts.reset();
+        }
+    }
+
+    /// Drop the target's registry with its gauges, once the target is gone.
+    pub fn unregister(&self) {
+        self.registries.remove(&self.target);
+    }
+
+    /// Get or create the gauge for an apcupsd key. Keys that don't make a
+    /// valid, unique metric name are skipped instead of failing the update.
+    fn gauge(&mut self, key: &str) -> Option<&GaugeVec> {
+        self.named_gauge(format!("apcupsd_{}", key.to_lowercase()), format!("APC UPS {}", key))
+    }
+
+    /// Get or create the gauge of a key reported per phase, with a `phase`
+    /// label. A UPS that also reports the key itself keeps whichever of the
+    /// two registered first.
+    fn phase_gauge(&mut self, key: &str) -> Option<&GaugeVec> {
+        let metric_name = format!("apcupsd_{}", key.to_lowercase());
+        self.labelled_gauge(metric_name, format!("APC UPS {} by phase", key), &["phase"])
+    }
+
+    /// Get or create a gauge by its metric name, skipping it if it can't be
+    /// registered.
+    fn named_gauge(&mut self, metric_name: String, help: String) -> Option<&GaugeVec> {
+        self.labelled_gauge(metric_name, help, &[])
+    }
+
+    /// Get or create a gauge with the given label names. Gauges are known by
+    /// their metric name, followed by their label names if they have any.
+    fn labelled_gauge(&mut self, metric_name: String, help: String, label_names: &[&str]) -> Option<&GaugeVec> {
+        let id = match label_names {
+            [] => metric_name.clone(),
+            names => format!("{}{{{}}}", metric_name, names.join(",")),
+        };
+        if self.rejected.contains(&id) {
+            return None;
+        }
+        if !self.gauges.contains_key(&id) {
+            let opts = Opts::new(metric_name, help).const_labels(self.labels.clone());
+            let registered = GaugeVec::new(opts, label_names).and_then(|gauge_vec| {
+                self.registry.register(Box::new(gauge_vec.clone()))?;
+                Ok(gauge_vec)
+            });
+            match registered {
+                Ok(gauge_vec) => {
+                    self.placeholders.created(gauge_vec.desc()[0].fq_name.as_str());
+                    self.gauges.insert(id.clone(), gauge_vec);
+                }
+                Err(e) => {
+                    warn!("Skipping {}, failed to register it: {}", id, e);
+                    self.errors.record("register");
+                    self.rejected.insert(id);
+                    return None;
+                }
+            }
+        }
+        self.gauges.get(&id)
+    }
+}
+
+/// The keys nearly every UPS reports, whose gauges are there from the first
+/// scrape after a restart, see `Placeholders`
+const CANONICAL_KEYS: &[&str] = &[
+    "LINEV", "LOADPCT", "BCHARGE", "TIMELEFT", "MBATTCHG", "MINTIMEL", "MAXTIME", "OUTPUTV", "ITEMP", "BATTV",
+    "LINEFREQ", "NUMXFERS", "TONBATT", "CUMONBATT", "NOMINV", "NOMBATTV", "NOMPOWER",
+];
+
+/// NaN samples of the canonical gauges until the first poll that reports
+/// them creates them, so `absent()` doesn't misfire after a restart. It
+/// declares no descriptors, which would fix the gauges' label names before
+/// it's known whether the UPS reports them per phase.
+#[derive(Clone)]
+struct Placeholders {
+    /// Metric names not created yet, with their keys
+    pending: Arc<RwLock<BTreeMap<String, &'static str>>>,
+    labels: HashMap<String, String>,
+}
+
+impl Placeholders {
+    fn new(labels: &HashMap<String, String>) -> Self {
+        let pending = CANONICAL_KEYS.iter().map(|key| (format!("apcupsd_{}", key.to_lowercase()), *key)).collect();
+        Placeholders {
+            pending: Arc::new(RwLock::new(pending)),
+            labels: labels.clone(),
+        }
+    }
+
+    /// The gauge is there now, its placeholder goes
+    fn created(&self, metric_name: &str) {
+        self.pending.write().unwrap_or_else(|e| e.into_inner()).remove(metric_name);
+    }
+}
+
+impl Collector for Placeholders {
+    fn desc(&self) -> Vec<&Desc> {
+        Vec::new()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        let pending = self.pending.read().unwrap_or_else(|e| e.into_inner());
+        pending
+            .iter()
+            .filter_map(|(metric_name, key)| {
+                let opts = Opts::new(metric_name, format!("APC UPS {}", key)).const_labels(self.labels.clone());
+                let gauge = prometheus::Gauge::with_opts(opts).ok()?;
+                gauge.set(f64::NAN);
+                gauge.collect().pop()
+            })
+            .collect()
+    }
+}
+
+/// The keys of `apcupsd_settings_info`, which tell how a UPS is configured
+/// so differences across the fleet can be queried
+const SETTINGS_KEYS: &[&str] = &[
+    "SENSE", "ALARMDEL", "FIRMWARE", "SERIALNO", "DSHUTD", "DWAKE", "DLOWBATT", "RETPCT", "STESTI",
+];
+
+/// The keys that tell the place of a UPS in a NIS master/slave setup: the
+/// master a slave reports to, and the slaves a master has connected
+const TOPOLOGY_KEYS: &[&str] = &["MASTER", "SLAVES"];
+
+/// The labels of `apcupsd_role`, in uppercase like the keys of the other
+/// info gauges
+const ROLE_LABELS: &[&str] = &["ROLE", "MASTER"];
+
+/// The role of a UPS in a master/slave setup, `master`, `slave` or
+/// `standalone`, from UPSMODE or, where it doesn't tell, the topology keys
+/// and the SLAVE status flag. `None` if nothing tells.
+fn role(stats: &BTreeMap<String, String>) -> Option<&'static str> {
+    let mode = stats.get("UPSMODE").map(|mode| mode.to_lowercase()).unwrap_or_default();
+    let slave_flag = stats.get("STATUS").is_some_and(|status| status.split_whitespace().any(|flag| flag == "SLAVE"));
+    if mode.contains("slave") || slave_flag || stats.contains_key("MASTER") {
+        Some("slave")
+    } else if mode.contains("master") || stats.contains_key("SLAVES") {
+        Some("master")
+    } else if !mode.trim().is_empty() {
+        Some("standalone")
+    } else {
+        None
+    }
+}
+
+/// The slaves a master has connected, from SLAVES as a count or a list of
+/// their names
+fn slaves_connected(stats: &BTreeMap<String, String>) -> Option<f64> {
+    let slaves = stats.get("SLAVES")?.trim();
+    if let Ok(count) = slaves.parse::<f64>() {
+        return Some(count);
+    }
+    Some(slaves.split([',', ' ']).filter(|slave| !slave.is_empty()).count() as f64)
+}
+
+/// A gauge whose only series is 1, with the values of some keys as labels
+struct InfoGauge {
+    name: &'static str,
+    keys: &'static [&'static str],
+    gauge: IntGaugeVec,
+    /// Label values of the current series
+    values: Vec<String>,
+}
+
+impl InfoGauge {
+    fn new(
+        registry: &Registry,
+        name: &'static str,
+        help: &str,
+        keys: &'static [&'static str],
+        labels: &HashMap<String, String>,
+    ) -> prometheus::Result<Self> {
+        let label_names: Vec<String> = keys.iter().map(|key| key.to_lowercase()).collect();
+        let label_names: Vec<&str> = label_names.iter().map(String::as_str).collect();
+        let gauge = IntGaugeVec::new(Opts::new(name, help).const_labels(labels.clone()), &label_names)?;
+        registry.register(Box::new(gauge.clone()))?;
+        Ok(InfoGauge { name, keys, gauge, values: Vec::new() })
+    }
+
+    /// Replace the series if the values changed. Only then is it reset, so
+    /// a concurrent scrape never sees the series missing.
+    fn update(&mut self, stats: &BTreeMap<String, String>, errors: &MetricErrors) {
+        let values: Vec<String> = self.keys.iter().map(|key| stats.get(*key).cloned().unwrap_or_default()).collect();
+        self.set(values, errors);
+    }
+
+    /// Replace the series with one of the given label values, one per key
+    fn set(&mut self, values: Vec<String>, errors: &MetricErrors) {
+        if values == self.values {
+            return;
+        }
+        self.gauge.reset();
+        let labels: Vec<&str> = values.iter().map(String::as_str).collect();
+        match self.gauge.get_metric_with_label_values(&labels) {
+            Ok(gauge) => gauge.set(1),
+            Err(e) => {
+                error!("Failed to update {}: {}", self.name, e);
+                errors.record("update");
+            }
+        }
+        self.values = values;
+    }
+
+    fn clear(&mut self) {
+        self.gauge.reset();
+        self.values.clear();
+    }
+}
+
+/// Counts failures to register, parse, update or encode metrics, which are
+/// logged and skipped rather than taking down a scrape or the exporter.
+#[derive(Clone)]
+pub struct MetricErrors {
+    errors: IntCounterVec,
+}
+
+impl MetricErrors {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let errors = IntCounterVec::new(
+            Opts::new("apcupsd_exporter_metric_errors_total", "Failures to register, update or encode metrics by stage"),
+            &["stage"],
+        )?;
+        registry.register(Box::new(errors.clone()))?;
+        Ok(MetricErrors { errors })
+    }
+
+    pub fn record(&self, stage: &str) {
+        self.errors.with_label_values(&[stage]).inc();
+    }
+}
+
+/// The exporter's own metrics about polling, updated by the pollers.
+#[derive(Clone)]
+pub struct PollMetrics {
+    polls: IntCounterVec,
+    consecutive_failures: IntGaugeVec,
+    errors: IntCounterVec,
+    panics: IntCounter,
+    update_panics: IntCounter,
+    nis_duration: HistogramVec,
+    response_bytes: IntGaugeVec,
+    records: IntGaugeVec,
+    anomalies: IntCounterVec,
+}
+
+impl PollMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let polls = IntCounterVec::new(
+            Opts::new("apcupsd_exporter_polls_total", "Polls of apcupsd by target and result"),
+            &["target", "result"],
+        )?;
+        registry.register(Box::new(polls.clone()))?;
+        let consecutive_failures = IntGaugeVec::new(
+            Opts::new("apcupsd_exporter_consecutive_failures", "Polls of apcupsd by target that failed since the last one that succeeded"),
+            &["target"],
+        )?;
+        registry.register(Box::new(consecutive_failures.clone()))?;
+        let errors = IntCounterVec::new(
+            Opts::new("apcupsd_exporter_poll_errors_total", "Failed polls of apcupsd by cause"),
+            &["kind"],
+        )?;
+        registry.register(Box::new(errors.clone()))?;
+        let panics = IntCounter::new("apcupsd_exporter_poll_panics_total", "Polls that panicked")?;
+        registry.register(Box::new(panics.clone()))?;
+        let update_panics = IntCounter::new("apcupsd_exporter_update_panics_total", "Updates from the pollers that panicked while being applied")?;
+        registry.register(Box::new(update_panics.clone()))?;
+        let nis_duration = HistogramVec::new(
+            HistogramOpts::new("apcupsd_nis_duration_seconds", "Time taken to talk to apcupsd by target and phase")
+                .buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+            &["target", "phase"],
+        )?;
+        registry.register(Box::new(nis_duration.clone()))?;
+        let response_bytes = IntGaugeVec::new(
+            Opts::new("apcupsd_nis_response_bytes", "Size of the last complete response from apcupsd by target"),
+            &["target"],
+        )?;
+        registry.register(Box::new(response_bytes.clone()))?;
+        let records = IntGaugeVec::new(
+            Opts::new("apcupsd_nis_records", "Records in the last complete response from apcupsd by target"),
+            &["target"],
+        )?;
+        registry.register(Box::new(records.clone()))?;
+        let anomalies = IntCounterVec::new(
+            Opts::new("apcupsd_nis_protocol_anomalies_total", "Responses from apcupsd that strayed from the NIS framing by kind"),
+            &["kind"],
+        )?;
+        registry.register(Box::new(anomalies.clone()))?;
+        Ok(PollMetrics {
+            polls,
+            consecutive_failures,
+            errors,
+            panics,
+            update_panics,
+            nis_duration,
+            response_bytes,
+            records,
+            anomalies,
+        })
+    }
+
+    /// Record how long connecting to a target, if it was needed, and the
+    /// whole request took, the size of the response if there was one, and
+    /// how it strayed from the framing.
+    pub fn record_request(&self, target: &str, stats: RequestStats) {
+        if let Some(connect) = stats.connect {
+            self.nis_duration.with_label_values(&[target, "connect"]).observe(connect.as_secs_f64());
+        }
+        self.nis_duration.with_label_values(&[target, "total"]).observe(stats.total.as_secs_f64());
+        if let Some(response) = stats.response {
+            self.response_bytes.with_label_values(&[target]).set(response.bytes as i64);
+            self.records.with_label_values(&[target]).set(response.records as i64);
+        }
+        for kind in stats.anomalies.kinds() {
+            self.anomalies.with_label_values(&[kind]).inc();
+        }
+    }
+
+    /// Count a finished poll of a target, `success` or `error`, and the
+    /// failures in a row
+    pub fn record_poll(&self, target: &str, success: bool) {
+        let result = if success { "success" } else { "error" };
+        self.polls.with_label_values(&[target, result]).inc();
+        let failures = self.consecutive_failures.with_label_values(&[target]);
+        match success {
+            true => failures.set(0),
+            false => failures.inc(),
+        }
+    }
+
+    /// Drop the counts of a target that is no longer polled
+    pub fn remove(&self, target: &str) {
+        for result in ["success", "error"] {
+            let _ = self.polls.remove_label_values(&[target, result]);
+        }
+        for phase in ["connect", "total"] {
+            let _ = self.nis_duration.remove_label_values(&[target, phase]);
+        }
+        let _ = self.response_bytes.remove_label_values(&[target]);
+        let _ = self.records.remove_label_values(&[target]);
+        let _ = self.consecutive_failures.remove_label_values(&[target]);
+    }
+
+    pub fn record_error(&self, err: &ApcAccessError) {
+        self.errors.with_label_values(&[err.kind()]).inc();
+    }
+
+    pub fn record_panic(&self) {
+        self.panics.inc();
+    }
+
+    pub fn record_update_panic(&self) {
+        self.update_panics.inc();
+    }
+}
+
+/// The exporter's own metrics about the HTTP requests it serves.
+#[cfg(any(feature = "http", feature = "http-lite"))]
+#[derive(Clone)]
+pub struct HttpMetrics {
+    requests: IntCounterVec,
+    duration: HistogramVec,
+}
+
+#[cfg(any(feature = "http", feature = "http-lite"))]
+impl HttpMetrics {
+    pub fn new(registry: &Registry) -> prometheus::Result<Self> {
+        let requests = IntCounterVec::new(
+            Opts::new("apcupsd_exporter_http_requests_total", "HTTP requests served by route and status code"),
+            &["path", "code"],
+        )?;
+        registry.register(Box::new(requests.clone()))?;
+        let duration = HistogramVec::new(
+            HistogramOpts::new("apcupsd_exporter_http_request_duration_seconds", "Time taken to serve HTTP requests by route")
+                .buckets(vec![0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5]),
+            &["path"],
+        )?;
+        registry.register(Box::new(duration.clone()))?;
+        Ok(HttpMetrics { requests, duration })
+    }
+
+    /// Count a served request. `path` is the matched route, so unknown
+    /// paths can't blow up the label cardinality.
+    pub fn record(&self, path: Option<&str>, code: u16, duration: Duration) {
+        let path = path.unwrap_or("unmatched");
+        self.requests.with_label_values(&[path, &code.to_string()]).inc();
+        self.duration.with_label_values(&[path]).observe(duration.as_secs_f64());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_skips_invalid_names() {
+        let registry = Registry::new();
+        let errors = MetricErrors::new(&registry).unwrap();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let mut metrics = UpsMetrics::new(&registries, errors.clone(), "localhost").unwrap();
+        let stats = BTreeMap::from([
+            ("BCHARGE".to_string(), "97.0".to_string()),
+            ("BAD-KEY".to_string(), "1".to_string()),
+        ]);
+        metrics.update(&stats);
+        metrics.update(&stats);
+        metrics.update(&BTreeMap::from([
+            ("LINEV".to_string(), "1,2.3".to_string()),
+            ("STATUS".to_string(), "ONLINE".to_string()),
+        ]));
+        assert_eq!(errors.errors.with_label_values(&["parse"]).get(), 1);
+        assert_eq!(metrics.parse_errors.with_label_values(&["LINEV"]).get(), 1);
+        assert!(metrics.unparsable.contains_key("LINEV"));
+
+        let names: Vec<String> = registry.gather().iter().map(|f| f.get_name().to_string()).collect();
+        assert!(names.contains(&"apcupsd_bcharge".to_string()));
+        assert_eq!(errors.errors.with_label_values(&["register"]).get(), 1);
+    }
+
+    #[test]
+    fn test_temperatures() {
+        let registry = Registry::new();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let mut metrics = UpsMetrics::new(&registries, MetricErrors::new(&registry).unwrap(), "ups1").unwrap();
+        let units = [TemperatureUnit::Celsius, TemperatureUnit::Fahrenheit, TemperatureUnit::Kelvin];
+        metrics.update_temperatures(&BTreeMap::from([("ITEMP".to_string(), "30.0".to_string())]), &units);
+        let value = |name: &str| {
+            let family = registry.gather().into_iter().find(|f| f.get_name() == name)?;
+            Some(family.get_metric()[0].get_gauge().get_value())
+        };
+        assert_eq!(value("apcupsd_internal_temperature_celsius"), Some(30.0));
+        assert_eq!(value("apcupsd_internal_temperature_fahrenheit"), Some(86.0));
+        assert_eq!(value("apcupsd_internal_temperature_kelvin"), Some(303.15));
+        assert_eq!(value("apcupsd_ambient_temperature_celsius"), None);
+        assert_eq!(TemperatureUnit::parse(" F"), Some(TemperatureUnit::Fahrenheit));
+        assert_eq!(TemperatureUnit::parse("rankine"), None);
+    }
+
+    #[test]
+    fn test_phases() {
+        let registry = Registry::new();
+        let errors = MetricErrors::new(&registry).unwrap();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let mut metrics = UpsMetrics::new(&registries, errors.clone(), "ups1").unwrap();
+        metrics.update(&BTreeMap::from([
+            ("LINEV_L1".to_string(), "230.0".to_string()),
+            ("LINEV_L2".to_string(), "231.0".to_string()),
+            ("LINEV_L3".to_string(), "229.0".to_string()),
+            ("LOADPCT".to_string(), "20.0".to_string()),
+        ]));
+        let family = registry.gather().into_iter().find(|f| f.get_name() == "apcupsd_linev").unwrap();
+        let phases: Vec<(String, f64)> = family
+            .get_metric()
+            .iter()
+            .map(|m| (m.get_label()[0].get_value().to_string(), m.get_gauge().get_value()))
+            .collect();
+        assert_eq!(phases, vec![("L1".to_string(), 230.0), ("L2".to_string(), 231.0), ("L3".to_string(), 229.0)]);
+        assert_eq!(errors.errors.with_label_values(&["register"]).get(), 0);
+
+        assert_eq!(phase_of("LINEV-L2"), Some(("LINEV", "L2")));
+        assert_eq!(phase_of("MINTIMEL"), None);
+        assert_eq!(phase_of("_L1"), None);
+        assert_eq!(phase_of("L1"), None);
+    }
+
+    #[test]
+    fn test_preregistered() {
+        let registry = Registry::new();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let mut metrics = UpsMetrics::new(&registries, MetricErrors::new(&registry).unwrap(), "ups1").unwrap();
+        let value = |registry: &Registry, name: &str| {
+            let family = registry.gather().into_iter().find(|f| f.get_name() == name)?;
+            Some(family.get_metric()[0].get_gauge().get_value())
+        };
+        assert!(value(&registry, "apcupsd_bcharge").unwrap().is_nan());
+        assert!(value(&registry, "apcupsd_linev").unwrap().is_nan());
+        assert_eq!(value(&registry, "apcupsd_extbatts"), None);
+
+        metrics.update(&BTreeMap::from([
+            ("BCHARGE".to_string(), "97.0".to_string()),
+            ("EXTBATTS".to_string(), "0".to_string()),
+        ]));
+        assert_eq!(value(&registry, "apcupsd_bcharge"), Some(97.0));
+        assert_eq!(value(&registry, "apcupsd_extbatts"), Some(0.0));
+    }
+
+    #[test]
+    fn test_settings_info() {
+        let registry = Registry::new();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let mut metrics = UpsMetrics::new(&registries, MetricErrors::new(&registry).unwrap(), "ups1").unwrap();
+        metrics.update(&BTreeMap::from([
+            ("SENSE".to_string(), "Medium".to_string()),
+            ("SERIALNO".to_string(), "AS1234567890".to_string()),
+            ("DSHUTD".to_string(), "90".to_string()),
+        ]));
+        let family = registry.gather().into_iter().find(|f| f.get_name() == "apcupsd_settings_info").unwrap();
+        assert_eq!(family.get_metric().len(), 1);
+        let labels: BTreeMap<&str, &str> =
+            family.get_metric()[0].get_label().iter().map(|l| (l.get_name(), l.get_value())).collect();
+        assert_eq!(labels["sense"], "Medium");
+        assert_eq!(labels["serialno"], "AS1234567890");
+        assert_eq!(labels["dshutd"], "90");
+        assert_eq!(labels["alarmdel"], "");
+    }
+
+    #[test]
+    fn test_role() {
+        let registry = Registry::new();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let mut metrics = UpsMetrics::new(&registries, MetricErrors::new(&registry).unwrap(), "ups1").unwrap();
+        let role = |registry: &Registry| {
+            let family = registry.gather().into_iter().find(|f| f.get_name() == "apcupsd_role")?;
+            let labels = family.get_metric().first()?.get_label().iter();
+            Some(labels.map(|l| (l.get_name().to_string(), l.get_value().to_string())).collect::<BTreeMap<_, _>>())
+        };
+        let slaves = |registry: &Registry| {
+            let family = registry.gather().into_iter().find(|f| f.get_name() == "apcupsd_slaves_connected")?;
+            Some(family.get_metric()[0].get_gauge().get_value())
+        };
+
+        metrics.update(&BTreeMap::from([
+            ("UPSMODE".to_string(), "Net Master".to_string()),
+            ("SLAVES".to_string(), "nas, backup".to_string()),
+        ]));
+        assert_eq!(role(&registry).unwrap()["role"], "master");
+        assert_eq!(slaves(&registry), Some(2.0));
+
+        metrics.update(&BTreeMap::from([
+            ("STATUS".to_string(), "ONLINE SLAVE".to_string()),
+            ("MASTER".to_string(), "ups-master:3551".to_string()),
+        ]));
+        let labels = role(&registry).unwrap();
+        assert_eq!((labels["role"].as_str(), labels["master"].as_str()), ("slave", "ups-master:3551"));
+        assert_eq!(slaves(&registry), None);
+
+        metrics.update(&BTreeMap::from([("UPSMODE".to_string(), "Stand Alone".to_string())]));
+        assert_eq!(role(&registry).unwrap()["role"], "standalone");
+        metrics.update(&BTreeMap::from([("STATUS".to_string(), "ONLINE".to_string())]));
+        assert_eq!(role(&registry), None);
+    }
+
+    #[test]
+    fn test_unhandled() {
+        let registry = Registry::new();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let mut metrics = UpsMetrics::new(&registries, MetricErrors::new(&registry).unwrap(), "ups1").unwrap();
+        metrics.update_unhandled(&BTreeMap::from([
+            ("STATUS".to_string(), "ONLINE".to_string()),
+            ("SENSE".to_string(), "Medium".to_string()),
+            ("STESTI".to_string(), "336".to_string()),
+            ("EXTBATTS".to_string(), "0".to_string()),
+            ("BADBATTS".to_string(), "none".to_string()),
+            ("MASTERUPD".to_string(), "N/A".to_string()),
+        ]));
+        let family = registry.gather().into_iter().find(|f| f.get_name() == "apcupsd_exporter_unhandled_keys").unwrap();
+        assert_eq!(family.get_metric()[0].get_gauge().get_value(), 2.0);
+        assert_eq!(metrics.unhandled, HashSet::from(["BADBATTS".to_string(), "MASTERUPD".to_string()]));
+    }
+
+    #[test]
+    fn test_clear() {
+        let registry = Registry::new();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let mut metrics = UpsMetrics::new(&registries, MetricErrors::new(&registry).unwrap(), "ups1").unwrap();
+        let stats = BTreeMap::from([
+            ("UPSNAME".to_string(), "rack1".to_string()),
+            ("BCHARGE".to_string(), "97.0".to_string()),
+        ]);
+        metrics.update(&stats);
+        metrics.clear();
+        let names = |registry: &Registry| -> Vec<String> { registry.gather().iter().map(|f| f.get_name().to_string()).collect() };
+        assert!(!names(&registry).contains(&"apcupsd_bcharge".to_string()));
+        assert!(!names(&registry).contains(&"apcupsd_metadata".to_string()));
+
+        metrics.update(&stats);
+        assert!(names(&registry).contains(&"apcupsd_bcharge".to_string()));
+        assert!(names(&registry).contains(&"apcupsd_metadata".to_string()));
+    }
+
+    #[test]
+    fn test_consecutive_failures() {
+        let registry = Registry::new();
+        let metrics = PollMetrics::new(&registry).unwrap();
+        let failures = || metrics.consecutive_failures.with_label_values(&["ups1"]).get();
+        metrics.record_poll("ups1", false);
+        metrics.record_poll("ups1", false);
+        assert_eq!(failures(), 2);
+        metrics.record_poll("ups1", true);
+        assert_eq!(failures(), 0);
+        metrics.record_poll("ups1", false);
+        let response = Some(apcaccess::ResponseSize { bytes: 512, records: 20 });
+        metrics.record_request("ups1", RequestStats { response, ..RequestStats::default() });
+        assert_eq!(metrics.records.with_label_values(&["ups1"]).get(), 20);
+        metrics.remove("ups1");
+        let names: Vec<_> = registry.gather().iter().map(|f| f.get_name().to_string()).collect();
+        assert!(!names.contains(&"apcupsd_exporter_consecutive_failures".to_string()));
+        assert!(!names.contains(&"apcupsd_nis_duration_seconds".to_string()));
+        assert!(!names.contains(&"apcupsd_nis_response_bytes".to_string()));
+    }
+
+    #[test]
+    fn test_target_registries() {
+        let registry = Registry::new();
+        let errors = MetricErrors::new(&registry).unwrap();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let mut rack = UpsMetrics::for_target(&registries, errors.clone(), "rack1", &BTreeMap::new()).unwrap();
+        // Label names differ between targets, and so may help strings
+        let labels = BTreeMap::from([("dc".to_string(), "fra1".to_string())]);
+        let mut remote = UpsMetrics::for_target(&registries, errors.clone(), "remote", &labels).unwrap();
+        rack.update(&BTreeMap::from([("BCHARGE".to_string(), "97.0".to_string())]));
+        remote.update(&BTreeMap::from([("BCHARGE".to_string(), "40.0".to_string())]));
+
+        let family = registry.gather().into_iter().find(|f| f.get_name() == "apcupsd_bcharge").unwrap();
+        let mut values: Vec<(String, f64)> = family
+            .get_metric()
+            .iter()
+            .map(|m| (m.get_label().last().unwrap().get_value().to_string(), m.get_gauge().get_value()))
+            .collect();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(values, vec![("rack1".to_string(), 97.0), ("remote".to_string(), 40.0)]);
+        assert_eq!(errors.errors.with_label_values(&["register"]).get(), 0);
+
+        remote.unregister();
+        let family = registry.gather().into_iter().find(|f| f.get_name() == "apcupsd_bcharge").unwrap();
+        assert_eq!(family.get_metric().len(), 1);
+
+        // Back with other labels, which a shared registry would refuse
+        let mut remote = UpsMetrics::for_target(&registries, errors.clone(), "remote", &BTreeMap::new()).unwrap();
+        remote.update(&BTreeMap::from([("BCHARGE".to_string(), "41.0".to_string())]));
+        let family = registry.gather().into_iter().find(|f| f.get_name() == "apcupsd_bcharge").unwrap();
+        assert_eq!(family.get_metric().len(), 2);
+        assert_eq!(errors.errors.with_label_values(&["register"]).get(), 0);
+    }
+
+    #[test]
+    fn test_rolling() {
+        let registry = Registry::new();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let mut metrics = UpsMetrics::new(&registries, MetricErrors::new(&registry).unwrap(), "ups1").unwrap();
+        let history = MemoryHistory::new(Duration::from_secs(3600));
+        for linev in ["230.0", "196.0", "232.0"] {
+            history.record(&crate::snapshot::Snapshot::new("ups1", BTreeMap::from([("LINEV".to_string(), linev.to_string())])));
+        }
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+        metrics.update_rolling(&history, Duration::from_secs(3600), now);
+
+        let value = |name: &str| {
+            let family = registry.gather().into_iter().find(|f| f.get_name() == name).unwrap();
+            family.get_metric()[0].get_gauge().get_value()
+        };
+        assert_eq!(value("apcupsd_line_volts_min_1h"), 196.0);
+        assert_eq!(value("apcupsd_line_volts_max_1h"), 232.0);
+        assert_eq!(value("apcupsd_line_volts_avg_1h"), 219.33333333333334);
+        assert!(registry.gather().iter().all(|f| !f.get_name().starts_with("apcupsd_battery_volts")));
+        assert_eq!(window_suffix(Duration::from_secs(900)), "15m");
+        assert_eq!(window_suffix(Duration::from_secs(90)), "90s");
+    }
+
+    #[test]
+    fn test_distributions() {
+        assert_eq!(parse_buckets("240, 200,220,220"), Some(vec![200.0, 220.0, 240.0]));
+        assert_eq!(parse_buckets("200,high"), None);
+
+        let registry = Registry::new();
+        let registries = TargetRegistries::new(&registry).unwrap();
+        let distributions = Distributions {
+            histograms: vec![("LINEV", "line_volts", vec![200.0, 220.0, 240.0])],
+        };
+        let mut metrics = UpsMetrics::new(&registries, MetricErrors::new(&registry).unwrap(), "ups1")
+            .unwrap()
+            .with_distributions(&distributions)
+            .unwrap();
+        for linev in ["230.0", "196.0", "232.0"] {
+            metrics.update(&BTreeMap::from([("LINEV".to_string(), linev.to_string())]));
+        }
+
+        let family = registry.gather().into_iter().find(|f| f.get_name() == "apcupsd_line_volts").unwrap();
+        let histogram = family.get_metric()[0].get_histogram();
+        assert_eq!(histogram.get_sample_count(), 3);
+        let counts: Vec<u64> = histogram.get_bucket().iter().map(|b| b.get_cumulative_count()).collect();
+        assert_eq!(counts, vec![1, 1, 3]);
+    }
+}
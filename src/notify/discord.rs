@@ -0,0 +1,37 @@
+//! notify/discord.rs
+//!
+//! Posts power events to a Discord webhook.
+
+use super::{Notifier, NotifyError, DEFAULT_TEMPLATE};
+use crate::events::Event;
+
+pub struct DiscordNotifier {
+    url: String,
+    template: String,
+    agent: ureq::Agent,
+}
+
+impl DiscordNotifier {
+    /// Build the notifier from `DISCORD_*` environment variables. Returns
+    /// `None` unless `DISCORD_WEBHOOK_URL` is set.
+    pub fn from_env(agent: &ureq::Agent) -> Option<Self> {
+        Some(DiscordNotifier {
+            url: std::env::var("DISCORD_WEBHOOK_URL").ok()?,
+            template: std::env::var("DISCORD_TEMPLATE").unwrap_or_else(|_| DEFAULT_TEMPLATE.to_string()),
+            agent: agent.clone(),
+        })
+    }
+}
+
+impl Notifier for DiscordNotifier {
+    fn name(&self) -> &str {
+        "discord"
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        self.agent
+            .post(&self.url)
+            .send_json(serde_json::json!({ "content": event.render(&self.template) }))?;
+        Ok(())
+    }
+}
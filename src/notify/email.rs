@@ -0,0 +1,121 @@
+//! notify/email.rs
+//!
+//! Sends power events by email over SMTP.
+
+use std::time::Duration;
+
+use lettre::message::Mailbox;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Message, SmtpTransport, Transport};
+use tracing::error;
+
+use super::{Notifier, NotifyError};
+use crate::events::{Event, EventKind};
+
+const DEFAULT_SUBJECT: &str = "[apcupsd] {summary}";
+const DEFAULT_BODY: &str = "{description}.\n\nUPS: {upsname}\nHost: {hostname}\nStatus: {status} (previously {previous_status})\n";
+
+pub struct EmailNotifier {
+    transport: SmtpTransport,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    subject: String,
+    body: String,
+    events: Vec<EventKind>,
+}
+
+impl EmailNotifier {
+    /// Build the notifier from `SMTP_*` and `EMAIL_*` environment variables.
+    /// Returns `None` unless `SMTP_HOST` and `EMAIL_TO` are set, or if the
+    /// settings are invalid.
+    ///
+    /// `SMTP_TLS` selects `starttls` (default, port 587), `tls` (port 465) or
+    /// `none` (port 25).
+    pub fn from_env(timeout: Duration) -> Option<Self> {
+        let host = std::env::var("SMTP_HOST").ok()?;
+        let to = std::env::var("EMAIL_TO").ok()?;
+        let tls = std::env::var("SMTP_TLS").unwrap_or_else(|_| "starttls".to_string());
+
+        let builder = match tls.as_str() {
+            "starttls" => SmtpTransport::starttls_relay(&host).map(|b| b.port(587)),
+            "tls" => SmtpTransport::relay(&host).map(|b| b.port(465)),
+            "none" => Ok(SmtpTransport::builder_dangerous(&host).port(25)),
+            other => {
+                error!("Invalid SMTP_TLS {:?}, expected starttls, tls or none", other);
+                return None;
+            }
+        };
+        let mut builder = match builder {
+            Ok(builder) => builder.timeout(Some(timeout)),
+            Err(e) => {
+                error!("Failed to configure SMTP transport for {}: {}", host, e);
+                return None;
+            }
+        };
+        if let Some(port) = std::env::var("SMTP_PORT").ok().and_then(|p| p.parse().ok()) {
+            builder = builder.port(port);
+        }
+        if let (Ok(user), Ok(password)) = (std::env::var("SMTP_USERNAME"), std::env::var("SMTP_PASSWORD")) {
+            builder = builder.credentials(Credentials::new(user, password));
+        }
+
+        let from = std::env::var("EMAIL_FROM").unwrap_or_else(|_| "rsapcupsdexporter@localhost".to_string());
+        let from: Mailbox = match from.parse() {
+            Ok(from) => from,
+            Err(e) => {
+                error!("Invalid EMAIL_FROM {:?}: {}", from, e);
+                return None;
+            }
+        };
+        let mut recipients = Vec::new();
+        for address in to.split(',').map(str::trim).filter(|a| !a.is_empty()) {
+            match address.parse() {
+                Ok(mailbox) => recipients.push(mailbox),
+                Err(e) => {
+                    error!("Invalid EMAIL_TO address {:?}: {}", address, e);
+                    return None;
+                }
+            }
+        }
+
+        let events = std::env::var("EMAIL_EVENTS")
+            .map(|e| EventKind::parse_list(&e))
+            .unwrap_or_else(|_| EventKind::ALL.to_vec());
+
+        Some(EmailNotifier {
+            transport: builder.build(),
+            from,
+            to: recipients,
+            subject: std::env::var("EMAIL_SUBJECT").unwrap_or_else(|_| DEFAULT_SUBJECT.to_string()),
+            // Allow multi-line bodies to be written as "\n" in the variable
+            body: std::env::var("EMAIL_BODY")
+                .map(|b| b.replace("\\n", "\n"))
+                .unwrap_or_else(|_| DEFAULT_BODY.to_string()),
+            events,
+        })
+    }
+}
+
+impl Notifier for EmailNotifier {
+    fn name(&self) -> &str {
+        "email"
+    }
+
+    fn accepts(&self, kind: EventKind) -> bool {
+        self.events.contains(&kind)
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        let mut builder = Message::builder()
+            .from(self.from.clone())
+            .subject(event.render(&self.subject));
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        let message = builder
+            .body(event.render(&self.body))
+            .map_err(|e| NotifyError::InvalidMessage(e.to_string()))?;
+        self.transport.send(&message)?;
+        Ok(())
+    }
+}
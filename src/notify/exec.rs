@@ -0,0 +1,223 @@
+//! notify/exec.rs
+//!
+//! Runs local commands on power events, similar to apcupsd's apccontrol.
+
+use std::collections::HashSet;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{error, info, warn};
+
+use super::{Notifier, NotifyError};
+use crate::events::{Event, EventKind};
+
+/// How often a running hook is checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+pub struct ExecHook {
+    name: String,
+    kind: EventKind,
+    command: String,
+    timeout: Duration,
+    /// The targets the command runs for, so a slow hook is never started
+    /// twice for one target, while every other target's still runs
+    running: Arc<Mutex<HashSet<String>>>,
+}
+
+impl ExecHook {
+    pub fn new(kind: EventKind, command: &str, timeout: Duration) -> Self {
+        ExecHook {
+            name: format!("exec {}", hook_name(kind)),
+            kind,
+            command: command.to_string(),
+            timeout,
+            running: Arc::new(Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// One hook per `EXEC_ON_*` variable, e.g. `EXEC_ON_BATTERY` or
+    /// `EXEC_ON_LOW_BATTERY`. `EXEC_TIMEOUT` limits how long each may run.
+    pub fn from_env() -> Vec<Self> {
+        let timeout: u64 = std::env::var("EXEC_TIMEOUT")
+            .ok()
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(60);
+        EventKind::ALL
+            .iter()
+            .filter_map(|&kind| {
+                let var = format!("EXEC_{}", hook_name(kind).to_uppercase());
+                let command = std::env::var(var).ok().filter(|c| !c.trim().is_empty())?;
+                Some(ExecHook::new(kind, &command, Duration::from_secs(timeout)))
+            })
+            .collect()
+    }
+}
+
+/// Hook name for an event kind: `on_battery`, `on_low_battery`, `on_online`, ...
+pub fn hook_name(kind: EventKind) -> String {
+    let name = kind.as_str();
+    if name.starts_with("on_") {
+        name.to_string()
+    } else {
+        format!("on_{}", name)
+    }
+}
+
+/// Environment passed to hooks: the event details plus every apcupsd value as
+/// `APCUPSD_<KEY>`, e.g. `APCUPSD_BCHARGE`.
+fn environment(event: &Event) -> Vec<(String, String)> {
+    let mut env = vec![
+        ("APCUPSD_EVENT".to_string(), event.kind.as_str().to_string()),
+        ("APCUPSD_EVENT_DESCRIPTION".to_string(), event.description()),
+        ("APCUPSD_EVENT_HOST".to_string(), event.snapshot.host.clone()),
+        ("APCUPSD_PREVIOUS_STATUS".to_string(), event.previous_status.clone()),
+        ("APCUPSD_TEST".to_string(), if event.test { "1" } else { "0" }.to_string()),
+    ];
+    for (key, value) in &event.snapshot.stats {
+        env.push((format!("APCUPSD_{}", key), value.clone()));
+    }
+    env
+}
+
+/// The command line run through `sh -c`
+#[cfg(not(windows))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+/// The command line run through `cmd /C`, passed as is since cmd doesn't
+/// unquote its arguments like other programs
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    use std::os::windows::process::CommandExt;
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").raw_arg(command);
+    shell
+}
+
+/// Lock the set of running targets. It stays consistent even if a holder of
+/// the lock panicked, so a poisoned lock is used as is.
+fn lock_running(running: &Mutex<HashSet<String>>) -> MutexGuard<'_, HashSet<String>> {
+    running.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+}
+
+/// Run a command through the shell, killing it once the timeout expires.
+pub fn run(command: &str, env: Vec<(String, String)>, timeout: Duration) -> std::io::Result<ExitStatus> {
+    let mut child = shell(command)
+        .envs(env)
+        .stdin(Stdio::null())
+        .spawn()?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            child.kill()?;
+            child.wait()?;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("killed after {} seconds", timeout.as_secs()),
+            ));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+impl Notifier for ExecHook {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn accepts(&self, kind: EventKind) -> bool {
+        kind == self.kind
+    }
+
+    /// Start the command in the background and return immediately, so a
+    /// long-running hook doesn't delay other channels.
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        let host = event.snapshot.host.clone();
+        if !lock_running(&self.running).insert(host.clone()) {
+            warn!("Skipping {} for {}, the previous run is still in progress", self.name, host);
+            return Ok(());
+        }
+
+        let name = self.name.clone();
+        let command = self.command.clone();
+        let timeout = self.timeout;
+        let running = Arc::clone(&self.running);
+        let env = environment(event);
+        thread::spawn(move || {
+            match run(&command, env, timeout) {
+                Ok(status) if status.success() => info!("{} finished", name),
+                Ok(status) => warn!("{} exited with {}", name, status),
+                Err(e) => error!("{} failed: {}", name, e),
+            }
+            lock_running(&running).remove(&host);
+        });
+        Ok(())
+    }
+
+    /// Run the command in the foreground so a failing hook is reported.
+    fn notify_test(&self, event: &Event) -> Result<(), NotifyError> {
+        let status = run(&self.command, environment(event), self.timeout)?;
+        if !status.success() {
+            return Err(std::io::Error::other(format!("exited with {}", status)).into());
+        }
+        Ok(())
+    }
+
+    fn has_side_effects(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hook_name() {
+        assert_eq!(hook_name(EventKind::OnBattery), "on_battery");
+        assert_eq!(hook_name(EventKind::LowBattery), "on_low_battery");
+    }
+
+    #[test]
+    fn test_run_passes_environment() {
+        let env = vec![("APCUPSD_EVENT".to_string(), "on_battery".to_string())];
+        let status = run("test \"$APCUPSD_EVENT\" = on_battery", env, Duration::from_secs(5)).unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_hooks_run_per_target() {
+        let dir = std::env::temp_dir().join(format!("rsapcupsdexporter-exec-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let command = format!("sleep 0.3; echo run >> '{}/'\"$APCUPSD_EVENT_HOST\"", dir.display());
+        let hook = ExecHook::new(EventKind::OnBattery, &command, Duration::from_secs(5));
+        let event = |host: &str| Event::test(EventKind::OnBattery, crate::snapshot::Snapshot::new(host, Default::default()), &Default::default());
+
+        // Another target's hook runs alongside, the same target's is skipped
+        hook.notify(&event("ups1")).unwrap();
+        hook.notify(&event("ups2")).unwrap();
+        hook.notify(&event("ups1")).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !lock_running(&hook.running).is_empty() && Instant::now() < deadline {
+            thread::sleep(POLL_INTERVAL);
+        }
+        assert_eq!(std::fs::read_to_string(dir.join("ups1")).unwrap(), "run\n");
+        assert_eq!(std::fs::read_to_string(dir.join("ups2")).unwrap(), "run\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_run_timeout() {
+        let err = run("sleep 5", Vec::new(), Duration::from_millis(200)).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,115 @@
+//! notify/grafana.rs
+//!
+//! Marks power events on Grafana dashboards as annotations, through the
+//! Grafana HTTP API.
+
+use super::{Notifier, NotifyError};
+use crate::events::{Event, EventKind};
+
+/// Transfers to battery and back unless overridden
+const DEFAULT_EVENTS: &[EventKind] = &[EventKind::OnBattery, EventKind::Online];
+
+pub struct GrafanaNotifier {
+    url: String,
+    token: Option<String>,
+    /// Annotate this dashboard only, rather than the organization
+    dashboard: Option<String>,
+    panel: Option<u64>,
+    tags: Vec<String>,
+    events: Vec<EventKind>,
+    agent: ureq::Agent,
+}
+
+impl GrafanaNotifier {
+    /// Build the notifier from `GRAFANA_*` environment variables. Returns
+    /// `None` unless `GRAFANA_URL` is set.
+    pub fn from_env(agent: &ureq::Agent) -> Option<Self> {
+        let server = std::env::var("GRAFANA_URL").ok().filter(|u| !u.is_empty())?;
+        Some(GrafanaNotifier {
+            url: format!("{}/api/annotations", server.trim_end_matches('/')),
+            token: std::env::var("GRAFANA_TOKEN").ok().filter(|t| !t.is_empty()),
+            dashboard: std::env::var("GRAFANA_DASHBOARD_UID").ok().filter(|d| !d.is_empty()),
+            panel: std::env::var("GRAFANA_PANEL_ID").ok().and_then(|p| p.parse().ok()),
+            tags: std::env::var("GRAFANA_TAGS")
+                .unwrap_or_else(|_| "apcupsd".to_string())
+                .split(',')
+                .map(str::trim)
+                .filter(|t| !t.is_empty())
+                .map(str::to_string)
+                .collect(),
+            events: std::env::var("GRAFANA_EVENTS")
+                .map(|e| EventKind::parse_list(&e))
+                .unwrap_or_else(|_| DEFAULT_EVENTS.to_vec()),
+            agent: agent.clone(),
+        })
+    }
+
+    /// An annotation at the time of the poll that saw the event, tagged
+    /// with the event type and the UPS so panels can filter on them.
+    fn annotation(&self, event: &Event) -> serde_json::Value {
+        let mut tags = self.tags.clone();
+        tags.push(event.kind.as_str().to_string());
+        tags.push(event.upsname().to_string());
+        let mut annotation = serde_json::json!({
+            "time": event.snapshot.timestamp.duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_millis() as u64,
+            "tags": tags,
+            "text": event.summary(),
+        });
+        if let Some(dashboard) = &self.dashboard {
+            annotation["dashboardUID"] = dashboard.as_str().into();
+        }
+        if let Some(panel) = self.panel {
+            annotation["panelId"] = panel.into();
+        }
+        annotation
+    }
+}
+
+impl Notifier for GrafanaNotifier {
+    fn name(&self) -> &str {
+        "grafana"
+    }
+
+    fn accepts(&self, kind: EventKind) -> bool {
+        self.events.contains(&kind)
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        let mut request = self.agent.post(&self.url);
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+        request.send_json(self.annotation(event))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use crate::snapshot::Snapshot;
+
+    #[test]
+    fn test_annotation() {
+        let notifier = GrafanaNotifier {
+            url: "http://grafana:3000/api/annotations".to_string(),
+            token: None,
+            dashboard: Some("power".to_string()),
+            panel: None,
+            tags: vec!["apcupsd".to_string()],
+            events: DEFAULT_EVENTS.to_vec(),
+            agent: crate::notify::http_agent(Duration::from_secs(1)),
+        };
+        let mut snapshot = Snapshot::new("ups1", BTreeMap::from([("UPSNAME".to_string(), "rack1".to_string())]));
+        snapshot.timestamp = UNIX_EPOCH + Duration::from_secs(1717243200);
+        let annotation = notifier.annotation(&Event::test(EventKind::OnBattery, snapshot, &Default::default()));
+        assert_eq!(annotation["time"], 1717243200000u64);
+        assert_eq!(annotation["tags"], serde_json::json!(["apcupsd", "on_battery", "rack1"]));
+        assert_eq!(annotation["dashboardUID"], "power");
+        assert!(annotation.get("panelId").is_none());
+        assert!(!notifier.accepts(EventKind::LowBattery));
+    }
+}
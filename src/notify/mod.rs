@@ -0,0 +1,374 @@
+//! notify/mod.rs
+//!
+//! Delivers power events to notification channels from background threads,
+//! one per channel, so slow or failing endpoints never stall the poll loop
+//! or each other.
+
+pub mod discord;
+#[cfg(feature = "email")]
+pub mod email;
+pub mod exec;
+pub mod grafana;
+pub mod ntfy;
+pub mod pagerduty;
+pub mod routes;
+pub mod silence;
+pub mod slack;
+pub mod telegram;
+pub mod throttle;
+pub mod webhook;
+
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::{debug, error, info, warn};
+
+use crate::events::{Event, EventKind, Severities, Severity};
+
+/// Message template used by chat channels unless overridden
+pub const DEFAULT_TEMPLATE: &str = "{summary}";
+
+/// Error type for notification delivery
+#[derive(Debug)]
+pub enum NotifyError {
+    IoError(std::io::Error),
+    HttpError(Box<ureq::Error>),
+    #[cfg(feature = "email")]
+    SmtpError(lettre::transport::smtp::Error),
+    #[cfg_attr(not(feature = "email"), allow(dead_code))]
+    InvalidMessage(String),
+}
+
+impl From<std::io::Error> for NotifyError {
+    fn from(err: std::io::Error) -> Self {
+        NotifyError::IoError(err)
+    }
+}
+
+impl From<ureq::Error> for NotifyError {
+    fn from(err: ureq::Error) -> Self {
+        NotifyError::HttpError(Box::new(err))
+    }
+}
+
+#[cfg(feature = "email")]
+impl From<lettre::transport::smtp::Error> for NotifyError {
+    fn from(err: lettre::transport::smtp::Error) -> Self {
+        NotifyError::SmtpError(err)
+    }
+}
+
+impl std::fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NotifyError::IoError(e) => write!(f, "IO Error: {}", e),
+            // ureq includes the URL in its messages, which for most chat
+            // services contains the credentials, so only the cause is shown.
+            NotifyError::HttpError(e) => match e.as_ref() {
+                ureq::Error::Status(code, _) => write!(f, "HTTP Error: status code {}", code),
+                ureq::Error::Transport(t) => match t.message() {
+                    Some(message) => write!(f, "HTTP Error: {}: {}", t.kind(), message),
+                    None => write!(f, "HTTP Error: {}", t.kind()),
+                },
+            },
+            #[cfg(feature = "email")]
+            NotifyError::SmtpError(e) => write!(f, "SMTP Error: {}", e),
+            NotifyError::InvalidMessage(reason) => write!(f, "Invalid Message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for NotifyError {}
+
+/// A channel that power events are delivered to.
+pub trait Notifier: Send {
+    /// Short name used in logs
+    fn name(&self) -> &str;
+
+    /// Whether this channel wants events of the given kind
+    fn accepts(&self, _kind: EventKind) -> bool {
+        true
+    }
+
+    /// Deliver a single event. Called again on failure, up to the retry limit.
+    fn notify(&self, event: &Event) -> Result<(), NotifyError>;
+
+    /// Deliver a test event, waiting for the outcome.
+    fn notify_test(&self, event: &Event) -> Result<(), NotifyError> {
+        self.notify(event)
+    }
+
+    /// Whether delivery does more than send a message, so that test events
+    /// only go here when asked for by name
+    fn has_side_effects(&self) -> bool {
+        false
+    }
+}
+
+/// Whether a notifier name matches a channel given by name, e.g. `webhook #2`,
+/// or by type, e.g. `webhook`.
+pub(crate) fn channel_matches(name: &str, channel: &str) -> bool {
+    name == channel || name.strip_prefix(channel).is_some_and(|rest| rest.starts_with(' '))
+}
+
+/// Shared HTTP agent for notifiers talking to web APIs.
+pub(crate) fn http_agent(timeout: Duration) -> ureq::Agent {
+    ureq::AgentBuilder::new().timeout(timeout).build()
+}
+
+/// Build every notifier that is enabled through the environment.
+pub fn from_env() -> Vec<Box<dyn Notifier>> {
+    let timeout: u64 = std::env::var("NOTIFY_TIMEOUT")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(10);
+    let timeout = Duration::from_secs(timeout);
+    let agent = http_agent(timeout);
+
+    let mut notifiers: Vec<Box<dyn Notifier>> = Vec::new();
+    for notifier in webhook::WebhookNotifier::from_env(&agent) {
+        notifiers.push(Box::new(notifier));
+    }
+    if let Some(notifier) = slack::SlackNotifier::from_env(&agent) {
+        notifiers.push(Box::new(notifier));
+    }
+    if let Some(notifier) = discord::DiscordNotifier::from_env(&agent) {
+        notifiers.push(Box::new(notifier));
+    }
+    if let Some(notifier) = telegram::TelegramNotifier::from_env(&agent) {
+        notifiers.push(Box::new(notifier));
+    }
+    if let Some(notifier) = ntfy::NtfyNotifier::from_env(&agent) {
+        notifiers.push(Box::new(notifier));
+    }
+    if let Some(notifier) = pagerduty::PagerDutyNotifier::from_env(&agent) {
+        notifiers.push(Box::new(notifier));
+    }
+    if let Some(notifier) = grafana::GrafanaNotifier::from_env(&agent) {
+        notifiers.push(Box::new(notifier));
+    }
+    #[cfg(feature = "email")]
+    if let Some(notifier) = email::EmailNotifier::from_env(timeout) {
+        notifiers.push(Box::new(notifier));
+    }
+    for hook in exec::ExecHook::from_env() {
+        notifiers.push(Box::new(hook));
+    }
+    notifiers
+}
+
+/// Send a test event to the notifiers matching the channel, or to all but
+/// those with side effects if no channel is given. Notifiers with side
+/// effects are only tested if `side_effects` allows it, and if they accept
+/// the event. Returns the outcome per notifier.
+pub fn send_test(
+    notifiers: &[Box<dyn Notifier>],
+    channel: Option<&str>,
+    event: &Event,
+    side_effects: bool,
+) -> Vec<(String, Result<(), NotifyError>)> {
+    notifiers
+        .iter()
+        .filter(|n| match channel {
+            Some(channel) => {
+                channel_matches(n.name(), channel)
+                    && (!n.has_side_effects() || (side_effects && n.accepts(event.kind)))
+            }
+            None => !n.has_side_effects(),
+        })
+        .map(|n| (n.name().to_string(), n.notify_test(event)))
+        .collect()
+}
+
+/// Hands events to the delivery threads.
+pub struct Dispatcher {
+    /// Queue of each notifier's delivery thread, by notifier name
+    senders: Vec<(String, mpsc::Sender<Arc<Event>>)>,
+    silences: Option<Arc<silence::Silences>>,
+}
+
+impl Dispatcher {
+    /// Start a delivery thread per notifier, so retries on a failing channel
+    /// only hold up that channel. With no notifiers, events are discarded.
+    pub fn start(
+        notifiers: Vec<Box<dyn Notifier>>,
+        retries: u32,
+        router: routes::Router,
+        throttle: throttle::Throttle,
+    ) -> Self {
+        let router = Arc::new(router);
+        let throttle = Arc::new(Mutex::new(throttle));
+        let senders = notifiers
+            .into_iter()
+            .map(|notifier| {
+                let (sender, receiver) = mpsc::channel::<Arc<Event>>();
+                let name = notifier.name().to_string();
+                let router = Arc::clone(&router);
+                let throttle = Arc::clone(&throttle);
+                thread::spawn(move || {
+                    for event in receiver {
+                        if !notifier.accepts(event.kind) || !router.allows(notifier.name(), &event) {
+                            continue;
+                        }
+                        let allowed = throttle
+                            .lock()
+                            .unwrap_or_else(std::sync::PoisonError::into_inner)
+                            .allow(notifier.name(), &event, Instant::now());
+                        if !allowed {
+                            info!("Suppressed {} event via {}, cooldown active", event.kind.as_str(), notifier.name());
+                            continue;
+                        }
+                        deliver(notifier.as_ref(), &event, retries);
+                    }
+                });
+                (name, sender)
+            })
+            .collect();
+        Dispatcher { senders, silences: None }
+    }
+
+    /// Start the delivery threads with the notifiers, retry limit and
+    /// cooldowns from the environment and the routes and severities from
+    /// the config file. Events are dropped while the silences mute their host.
+    pub fn from_env(routes: Vec<routes::Route>, severities: Severities, silences: Arc<silence::Silences>) -> Self {
+        let retries: u32 = std::env::var("NOTIFY_RETRIES")
+            .ok()
+            .and_then(|r| r.parse().ok())
+            .unwrap_or(3);
+        let notifiers = from_env();
+        for notifier in &notifiers {
+            info!("Enabled {} notifier", notifier.name());
+        }
+        Dispatcher {
+            silences: Some(silences),
+            ..Dispatcher::start(
+                notifiers,
+                retries,
+                routes::Router::new(routes, severities),
+                throttle::Throttle::from_env(),
+            )
+        }
+    }
+
+    /// Queue events for delivery.
+    pub fn dispatch(&self, events: Vec<Event>) {
+        for event in events {
+            let muted = self.silences.as_ref().is_some_and(|s| s.is_muted(&event.snapshot.host));
+            let summary = format!("Power event: {}{}", event.summary(), if muted { " (muted)" } else { "" });
+            // Warnings and above, so they reach outputs like the Windows
+            // Event Log that only take those
+            match event.severity {
+                Severity::Info => info!("{}", summary),
+                Severity::Warning | Severity::Critical => warn!("{}", summary),
+            }
+            if muted {
+                continue;
+            }
+            let event = Arc::new(event);
+            for (name, sender) in &self.senders {
+                if sender.send(Arc::clone(&event)).is_err() {
+                    error!("Notification thread for {} has stopped, dropping event", name);
+                }
+            }
+        }
+    }
+}
+
+/// Deliver an event, retrying with exponential backoff (1s, 2s, 4s, ...).
+fn deliver(notifier: &dyn Notifier, event: &Event, retries: u32) {
+    for attempt in 0..=retries {
+        match notifier.notify(event) {
+            Ok(()) => {
+                debug!("Delivered {} event via {}", event.kind.as_str(), notifier.name());
+                return;
+            }
+            Err(e) if attempt < retries => {
+                warn!("Failed to notify via {} (attempt {}): {}", notifier.name(), attempt + 1, e);
+                thread::sleep(Duration::from_secs(1 << attempt.min(6)));
+            }
+            Err(e) => {
+                error!("Giving up notifying via {} after {} attempts: {}", notifier.name(), attempt + 1, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::Snapshot;
+    use std::collections::BTreeMap;
+
+    struct Stub {
+        name: &'static str,
+        side_effects: bool,
+    }
+
+    impl Notifier for Stub {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+            Err(NotifyError::InvalidMessage(event.summary()))
+        }
+
+        fn has_side_effects(&self) -> bool {
+            self.side_effects
+        }
+    }
+
+    /// Records the hosts of delivered events
+    struct Recorder(Mutex<mpsc::Sender<String>>);
+
+    impl Notifier for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+            self.0.lock().unwrap().send(event.snapshot.host.clone()).unwrap();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_failing_channel_does_not_delay_others() {
+        let (sender, delivered) = mpsc::channel();
+        let notifiers: Vec<Box<dyn Notifier>> = vec![
+            Box::new(Stub { name: "webhook #1", side_effects: false }),
+            Box::new(Recorder(Mutex::new(sender))),
+        ];
+        // The failing webhook backs off for a second after each attempt
+        let dispatcher = Dispatcher::start(notifiers, 3, routes::Router::default(), throttle::Throttle::default());
+        let event = |host| Event::test(EventKind::OnBattery, Snapshot::new(host, BTreeMap::new()), &Severities::default());
+        dispatcher.dispatch(vec![event("ups1"), event("ups2")]);
+        for host in ["ups1", "ups2"] {
+            assert_eq!(delivered.recv_timeout(Duration::from_millis(500)).unwrap(), host);
+        }
+    }
+
+    #[test]
+    fn test_send_test() {
+        let notifiers: Vec<Box<dyn Notifier>> = vec![
+            Box::new(Stub { name: "webhook #1", side_effects: false }),
+            Box::new(Stub { name: "telegram", side_effects: false }),
+            Box::new(Stub { name: "exec on_battery", side_effects: true }),
+        ];
+        let event = Event::test(EventKind::OnBattery, Snapshot::new("ups1", BTreeMap::new()), &Severities::default());
+        let sent = |channel| -> Vec<String> {
+            send_test(&notifiers, channel, &event, true).into_iter().map(|(name, _)| name).collect()
+        };
+        assert_eq!(sent(None), vec!["webhook #1", "telegram"]);
+        assert_eq!(sent(Some("webhook")), vec!["webhook #1"]);
+        assert_eq!(sent(Some("exec")), vec!["exec on_battery"]);
+        assert!(send_test(&notifiers, Some("exec"), &event, false).is_empty());
+
+        let (_, result) = send_test(&notifiers, Some("telegram"), &event, true).remove(0);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Invalid Message: [TEST] ups1: UPS switched to battery power (ONBATT)"
+        );
+    }
+}
@@ -0,0 +1,64 @@
+//! notify/ntfy.rs
+//!
+//! Publishes power events to an ntfy topic, self-hosted or on ntfy.sh.
+
+use super::{Notifier, NotifyError, DEFAULT_TEMPLATE};
+use crate::events::{Event, EventKind};
+
+pub struct NtfyNotifier {
+    url: String,
+    priority: Option<String>,
+    token: Option<String>,
+    template: String,
+    agent: ureq::Agent,
+}
+
+impl NtfyNotifier {
+    /// Build the notifier from `NTFY_*` environment variables. Returns `None`
+    /// unless `NTFY_TOPIC` is set.
+    pub fn from_env(agent: &ureq::Agent) -> Option<Self> {
+        let topic = std::env::var("NTFY_TOPIC").ok()?;
+        let server = std::env::var("NTFY_URL").unwrap_or_else(|_| "https://ntfy.sh".to_string());
+        Some(NtfyNotifier {
+            url: format!("{}/{}", server.trim_end_matches('/'), topic),
+            priority: std::env::var("NTFY_PRIORITY").ok().filter(|p| p != "auto"),
+            token: std::env::var("NTFY_TOKEN").ok(),
+            template: std::env::var("NTFY_TEMPLATE").unwrap_or_else(|_| DEFAULT_TEMPLATE.to_string()),
+            agent: agent.clone(),
+        })
+    }
+
+    /// ntfy priority (1-5) by event severity, used unless a fixed priority is set.
+    fn auto_priority(kind: EventKind) -> &'static str {
+        match kind {
+            EventKind::LowBattery | EventKind::LowRuntime | EventKind::CommLost => "urgent",
+            EventKind::OnBattery | EventKind::ProlongedOnBattery | EventKind::Alert => "high",
+            EventKind::ReplaceBattery => "default",
+            EventKind::Online | EventKind::RuntimeRestored | EventKind::AlertResolved => "low",
+        }
+    }
+}
+
+impl Notifier for NtfyNotifier {
+    fn name(&self) -> &str {
+        "ntfy"
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        let priority = self
+            .priority
+            .as_deref()
+            .unwrap_or_else(|| NtfyNotifier::auto_priority(event.kind));
+        let mut request = self
+            .agent
+            .post(&self.url)
+            .set("Title", &event.description())
+            .set("Priority", priority)
+            .set("Tags", event.kind.as_str());
+        if let Some(token) = &self.token {
+            request = request.set("Authorization", &format!("Bearer {}", token));
+        }
+        request.send_string(&event.render(&self.template))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,88 @@
+//! notify/pagerduty.rs
+//!
+//! Triggers and resolves PagerDuty incidents through the Events API v2.
+
+use super::{Notifier, NotifyError};
+use crate::events::{Event, EventKind};
+
+/// Events API v2 endpoint
+const EVENTS_URL: &str = "https://events.pagerduty.com/v2/enqueue";
+
+/// Conditions that page unless overridden
+const DEFAULT_EVENTS: &[EventKind] = &[EventKind::LowBattery, EventKind::LowRuntime, EventKind::CommLost];
+
+pub struct PagerDutyNotifier {
+    routing_key: String,
+    severity: String,
+    events: Vec<EventKind>,
+    agent: ureq::Agent,
+}
+
+impl PagerDutyNotifier {
+    /// Build the notifier from `PAGERDUTY_*` environment variables. Returns
+    /// `None` unless `PAGERDUTY_ROUTING_KEY` is set.
+    pub fn from_env(agent: &ureq::Agent) -> Option<Self> {
+        Some(PagerDutyNotifier {
+            routing_key: std::env::var("PAGERDUTY_ROUTING_KEY").ok()?,
+            severity: std::env::var("PAGERDUTY_SEVERITY").unwrap_or_else(|_| "critical".to_string()),
+            events: std::env::var("PAGERDUTY_EVENTS")
+                .map(|e| EventKind::parse_list(&e))
+                .unwrap_or_else(|_| DEFAULT_EVENTS.to_vec()),
+            agent: agent.clone(),
+        })
+    }
+
+    /// One incident per UPS and condition, so e.g. a COMMLOST page isn't
+    /// merged into an open LOWBATT page. Each alert is a condition of its own.
+    fn dedup_key(event: &Event, kind: EventKind) -> String {
+        let key = format!("rsapcupsdexporter/{}/{}/{}", event.snapshot.host, event.upsname(), kind.as_str());
+        match &event.alert {
+            Some(alert) => format!("{}/{}", key, alert.name),
+            None => key,
+        }
+    }
+
+    fn send(&self, body: serde_json::Value) -> Result<(), NotifyError> {
+        self.agent.post(EVENTS_URL).send_json(body)?;
+        Ok(())
+    }
+}
+
+impl Notifier for PagerDutyNotifier {
+    fn name(&self) -> &str {
+        "pagerduty"
+    }
+
+    fn accepts(&self, kind: EventKind) -> bool {
+        self.events.contains(&kind) || kind.resolves().iter().any(|k| self.events.contains(k))
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        if self.events.contains(&event.kind) {
+            return self.send(serde_json::json!({
+                "routing_key": self.routing_key,
+                "event_action": "trigger",
+                "dedup_key": PagerDutyNotifier::dedup_key(event, event.kind),
+                "payload": {
+                    "summary": event.summary(),
+                    "source": event.snapshot.hostname(),
+                    "severity": self.severity,
+                    "component": event.upsname(),
+                    "group": "apcupsd",
+                    "class": event.kind.as_str(),
+                    "custom_details": event.snapshot.to_json()["stats"],
+                },
+            }));
+        }
+
+        // Resolving an incident that isn't open is a no-op for PagerDuty
+        for kind in event.kind.resolves().iter().filter(|k| self.events.contains(k)) {
+            self.send(serde_json::json!({
+                "routing_key": self.routing_key,
+                "event_action": "resolve",
+                "dedup_key": PagerDutyNotifier::dedup_key(event, *kind),
+            }))?;
+        }
+        Ok(())
+    }
+}
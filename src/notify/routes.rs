@@ -0,0 +1,157 @@
+//! notify/routes.rs
+//!
+//! Restricts which events each notification channel receives.
+
+use serde::Deserialize;
+
+use crate::events::{Event, EventKind, Severities, Severity};
+
+/// One `[[routes]]` entry of the config file
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// Notifier name, e.g. `email`, or its type to match every instance,
+    /// e.g. `webhook` for `webhook #1` and `webhook #2`
+    pub channel: String,
+    /// Event types to deliver, all if unset
+    pub events: Option<Vec<EventKind>>,
+    /// Severities to deliver, all if unset
+    pub severities: Option<Vec<Severity>>,
+    /// Target names the route applies to, all if unset. The routes of a
+    /// `[group.<name>]` apply to the targets of the group.
+    pub targets: Option<Vec<String>>,
+}
+
+impl Route {
+    fn applies_to(&self, channel: &str, target: &str) -> bool {
+        super::channel_matches(channel, &self.channel)
+            && self.targets.as_ref().is_none_or(|targets| targets.iter().any(|t| t == target))
+    }
+
+    fn matches_kind(&self, kind: EventKind, severity: Severity) -> bool {
+        self.events.as_ref().is_none_or(|events| events.contains(&kind))
+            && self.severities.as_ref().is_none_or(|s| s.contains(&severity))
+    }
+}
+
+/// Decides per channel whether an event is delivered. Channels without any
+/// route for the event's target receive every event they accept.
+#[derive(Debug, Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    /// To match the severities of the conditions a recovery event clears
+    severities: Severities,
+}
+
+impl Router {
+    pub fn new(routes: Vec<Route>, severities: Severities) -> Self {
+        Router { routes, severities }
+    }
+
+    /// Whether any route of the channel matches the event, by its mapped
+    /// severity. Recovery events follow the conditions they clear, so e.g.
+    /// a channel routed only `low_battery` still hears about `online`.
+    pub fn allows(&self, channel: &str, event: &Event) -> bool {
+        let mut routes = self.routes.iter().filter(|r| r.applies_to(channel, &event.snapshot.host)).peekable();
+        if routes.peek().is_none() {
+            return true;
+        }
+        let kind = event.kind;
+        routes.any(|r| {
+            r.matches_kind(kind, event.severity)
+                || kind.resolves().iter().any(|&k| r.matches_kind(k, self.severities.of_event(k, event)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::Snapshot;
+    use std::collections::{BTreeMap, HashMap};
+
+    fn router(toml: &str) -> Router {
+        #[derive(Deserialize)]
+        struct Routes {
+            routes: Vec<Route>,
+        }
+        Router::new(toml::from_str::<Routes>(toml).unwrap().routes, Severities::default())
+    }
+
+    fn event(kind: EventKind, target: &str) -> Event {
+        Event::test(kind, Snapshot::new(target, BTreeMap::new()), &Severities::default())
+    }
+
+    #[test]
+    fn test_routing() {
+        let router = router(
+            r#"
+            [[routes]]
+            channel = "pagerduty"
+            events = ["LOWBATT", "comm_lost"]
+
+            [[routes]]
+            channel = "webhook"
+            severities = ["critical", "warning"]
+            "#,
+        );
+        assert!(router.allows("pagerduty", &event(EventKind::LowBattery, "ups1")));
+        assert!(router.allows("pagerduty", &event(EventKind::CommLost, "ups1")));
+        assert!(!router.allows("pagerduty", &event(EventKind::OnBattery, "ups1")));
+        assert!(router.allows("pagerduty", &event(EventKind::Online, "ups1")));
+        assert!(!router.allows("pagerduty", &event(EventKind::RuntimeRestored, "ups1")));
+
+        assert!(router.allows("webhook #2", &event(EventKind::OnBattery, "ups1")));
+        assert!(router.allows("webhook #2", &event(EventKind::Online, "ups1")));
+
+        assert!(router.allows("email", &event(EventKind::ReplaceBattery, "ups1")));
+    }
+
+    #[test]
+    fn test_routing_by_target() {
+        let router = router(
+            r#"
+            [[routes]]
+            channel = "pagerduty"
+            events = ["LOWBATT"]
+            targets = ["rack1"]
+            "#,
+        );
+        assert!(!router.allows("pagerduty", &event(EventKind::OnBattery, "rack1")));
+        assert!(router.allows("pagerduty", &event(EventKind::LowBattery, "rack1")));
+        // Other targets have no route for the channel
+        assert!(router.allows("pagerduty", &event(EventKind::OnBattery, "rack2")));
+    }
+
+    #[test]
+    fn test_routing_by_mapped_severity() {
+        let mut router = router(
+            r#"
+            [[routes]]
+            channel = "pagerduty"
+            severities = ["critical"]
+            "#,
+        );
+        let home = HashMap::from([(EventKind::CommLost, Severity::Warning)]);
+        router.severities = Severities::new(HashMap::new(), HashMap::from([("home".to_string(), home)]));
+        let mut comm_lost = event(EventKind::CommLost, "home");
+        comm_lost.severity = router.severities.of(EventKind::CommLost, "home");
+        assert!(!router.allows("pagerduty", &comm_lost));
+        assert!(router.allows("pagerduty", &event(EventKind::CommLost, "datacenter")));
+    }
+
+    #[test]
+    fn test_test_event_routed_by_mapped_severity() {
+        let mut router = router(
+            r#"
+            [[routes]]
+            channel = "pagerduty"
+            severities = ["critical"]
+            "#,
+        );
+        router.severities = Severities::new(HashMap::from([(EventKind::OnBattery, Severity::Critical)]), HashMap::new());
+        let test = |severities| Event::test(EventKind::OnBattery, Snapshot::new("ups1", BTreeMap::new()), severities);
+        assert!(router.allows("pagerduty", &test(&router.severities)));
+        assert!(!router.allows("pagerduty", &test(&Severities::default())));
+    }
+}
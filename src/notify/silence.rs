@@ -0,0 +1,247 @@
+//! notify/silence.rs
+//!
+//! Mutes notifications during planned maintenance, either ad hoc through the
+//! API or on a recurring schedule from the config file. Events are still
+//! detected and logged, and metrics keep flowing.
+
+use std::sync::{Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeDelta, Weekday};
+use prometheus::{IntGaugeVec, Opts, Registry};
+use serde::Deserialize;
+
+use crate::config::deserialize_duration;
+
+/// A silence created through the API
+#[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+#[derive(Debug, Clone)]
+pub struct Silence {
+    pub id: u64,
+    /// apcupsd host the silence applies to, all if unset
+    pub target: Option<String>,
+    pub until: SystemTime,
+    pub comment: Option<String>,
+}
+
+#[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+impl Silence {
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "id": self.id,
+            "target": self.target,
+            "until": self.until.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs(),
+            "comment": self.comment,
+        })
+    }
+}
+
+/// One `[[maintenance]]` entry of the config file, in local time
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaintenanceWindow {
+    /// apcupsd host the window applies to, all if unset
+    pub target: Option<String>,
+    /// Days the window starts on, every day if unset
+    pub days: Option<Vec<Weekday>>,
+    /// Start time as `HH:MM`
+    #[serde(deserialize_with = "deserialize_time")]
+    pub start: NaiveTime,
+    #[serde(deserialize_with = "deserialize_duration")]
+    pub duration: Duration,
+}
+
+fn deserialize_time<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<NaiveTime, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&time, "%H:%M").map_err(|_| serde::de::Error::custom(format!("invalid time {:?}, expected HH:MM", time)))
+}
+
+/// Longest window allowed, as the schedule repeats every week
+const MAX_WINDOW: Duration = Duration::from_secs(7 * 86_400);
+
+impl MaintenanceWindow {
+    /// Reject windows longer than a week, which would overlap their next
+    /// start.
+    pub fn validate(windows: &[MaintenanceWindow]) -> Result<(), String> {
+        match windows.iter().position(|window| window.duration > MAX_WINDOW) {
+            Some(i) => Err(format!("maintenance[{}]: duration is longer than a week", i)),
+            None => Ok(()),
+        }
+    }
+
+    fn is_active(&self, now: DateTime<Local>) -> bool {
+        let duration = TimeDelta::from_std(self.duration).unwrap_or(TimeDelta::MAX);
+        // A window started on one of the previous days may still be running,
+        // and one started more than a week ago has started again since
+        let days_back = self.duration.as_secs().div_ceil(86_400).min(7) as i64;
+        (0..=days_back).any(|back| {
+            let Some(day) = now.date_naive().checked_sub_signed(TimeDelta::days(back)) else {
+                return false;
+            };
+            if self.days.as_ref().is_some_and(|days| !days.contains(&day.weekday())) {
+                return false;
+            }
+            let Some(start) = day.and_time(self.start).and_local_timezone(Local).earliest() else {
+                return false;
+            };
+            // Ending past what the clock can tell, it never ends
+            start <= now && start.checked_add_signed(duration).is_none_or(|end| now < end)
+        })
+    }
+}
+
+fn applies(target: &Option<String>, host: &str) -> bool {
+    target.as_deref().is_none_or(|t| t == host)
+}
+
+/// Active silences and maintenance windows, shared between the API and the
+/// notification dispatcher.
+#[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+pub struct Silences {
+    silences: Mutex<Vec<Silence>>,
+    next_id: AtomicU64,
+    windows: Vec<MaintenanceWindow>,
+    muted: IntGaugeVec,
+}
+
+impl Silences {
+    /// Register `apcupsd_exporter_muted` and load the recurring windows.
+    pub fn new(windows: Vec<MaintenanceWindow>, registry: &Registry) -> prometheus::Result<Self> {
+        let muted = IntGaugeVec::new(
+            Opts::new(
+                "apcupsd_exporter_muted",
+                "Whether notifications about the target are currently muted by a silence or maintenance window",
+            ),
+            &["target"],
+        )?;
+        registry.register(Box::new(muted.clone()))?;
+        Ok(Silences {
+            silences: Mutex::new(Vec::new()),
+            next_id: AtomicU64::new(1),
+            windows,
+            muted,
+        })
+    }
+
+    /// The list stays consistent even if a holder of the lock panicked, so
+    /// a poisoned lock is used as is.
+    fn silences(&self) -> MutexGuard<'_, Vec<Silence>> {
+        self.silences.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Mute notifications for the target, or all targets, for a while.
+    /// Returns `None` if the silence would last past what the clock can
+    /// tell.
+    #[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+    pub fn add(&self, target: Option<String>, duration: Duration, comment: Option<String>) -> Option<Silence> {
+        let silence = Silence {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            target,
+            until: SystemTime::now().checked_add(duration)?,
+            comment,
+        };
+        self.silences().push(silence.clone());
+        Some(silence)
+    }
+
+    /// Lift a silence early. Returns false if there is no such silence.
+    #[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+    pub fn remove(&self, id: u64) -> bool {
+        let mut silences = self.silences();
+        let before = silences.len();
+        silences.retain(|s| s.id != id);
+        silences.len() != before
+    }
+
+    /// Silences that haven't expired yet
+    pub fn active(&self) -> Vec<Silence> {
+        let now = SystemTime::now();
+        let mut silences = self.silences();
+        silences.retain(|s| s.until > now);
+        silences.clone()
+    }
+
+    /// Whether notifications about the host are muted right now.
+    pub fn is_muted(&self, host: &str) -> bool {
+        let now = Local::now();
+        self.active().iter().any(|s| applies(&s.target, host))
+            || self.windows.iter().any(|w| applies(&w.target, host) && w.is_active(now))
+    }
+
+    /// Update `apcupsd_exporter_muted` for the host.
+    pub fn refresh(&self, host: &str) {
+        self.muted.with_label_values(&[host]).set(self.is_muted(host) as i64);
+    }
+
+    /// Drop the series of a target that's no longer polled.
+    pub fn forget(&self, host: &str) {
+        let _ = self.muted.remove_label_values(&[host]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn window(toml: &str) -> MaintenanceWindow {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn test_window_across_midnight() {
+        let window = window("days = [\"Sun\"]\nstart = \"23:00\"\nduration = \"2h\"\n");
+        // 2024-06-02 is a Sunday
+        let at = |d, h, m| Local.with_ymd_and_hms(2024, 6, d, h, m, 0).unwrap();
+        assert!(!window.is_active(at(2, 22, 59)));
+        assert!(window.is_active(at(2, 23, 0)));
+        assert!(window.is_active(at(3, 0, 59)));
+        assert!(!window.is_active(at(3, 1, 0)));
+        assert!(!window.is_active(at(3, 23, 30)));
+    }
+
+    #[test]
+    fn test_window_too_long() {
+        let week = window("days = [\"Sat\"]\nstart = \"00:00\"\nduration = \"7d\"\n");
+        assert!(MaintenanceWindow::validate(&[week]).is_ok());
+        let huge = window("start = \"00:00\"\nduration = \"100000000d\"\n");
+        assert_eq!(
+            MaintenanceWindow::validate(std::slice::from_ref(&huge)),
+            Err("maintenance[0]: duration is longer than a week".to_string())
+        );
+
+        // Even unchecked, it neither loops for ages nor overflows
+        assert!(huge.is_active(Local::now()));
+        let early = chrono::NaiveDate::MIN.and_hms_opt(12, 0, 0).unwrap().and_local_timezone(Local).earliest();
+        if let Some(early) = early {
+            assert!(huge.is_active(early));
+        }
+    }
+
+    #[test]
+    fn test_silence_target() {
+        let silences = Silences::new(Vec::new(), &Registry::new()).unwrap();
+        let silence = silences.add(Some("ups1".to_string()), Duration::from_secs(60), None).unwrap();
+        assert!(silences.add(None, Duration::from_secs(u64::MAX), None).is_none());
+        assert!(silences.is_muted("ups1"));
+        assert!(!silences.is_muted("ups2"));
+        assert!(silences.remove(silence.id));
+        assert!(!silences.is_muted("ups1"));
+    }
+
+    #[test]
+    fn test_muted_per_target() {
+        let registry = Registry::new();
+        let silences = Silences::new(Vec::new(), &registry).unwrap();
+        silences.add(Some("ups1".to_string()), Duration::from_secs(60), None).unwrap();
+        silences.refresh("ups1");
+        silences.refresh("ups2");
+        let muted = |target: &str| silences.muted.with_label_values(&[target]).get();
+        assert_eq!((muted("ups1"), muted("ups2")), (1, 0));
+
+        silences.forget("ups2");
+        let families = registry.gather();
+        assert_eq!(families[0].get_metric().len(), 1);
+    }
+}
@@ -0,0 +1,37 @@
+//! notify/slack.rs
+//!
+//! Posts power events to a Slack incoming webhook.
+
+use super::{Notifier, NotifyError, DEFAULT_TEMPLATE};
+use crate::events::Event;
+
+pub struct SlackNotifier {
+    url: String,
+    template: String,
+    agent: ureq::Agent,
+}
+
+impl SlackNotifier {
+    /// Build the notifier from `SLACK_*` environment variables. Returns
+    /// `None` unless `SLACK_WEBHOOK_URL` is set.
+    pub fn from_env(agent: &ureq::Agent) -> Option<Self> {
+        Some(SlackNotifier {
+            url: std::env::var("SLACK_WEBHOOK_URL").ok()?,
+            template: std::env::var("SLACK_TEMPLATE").unwrap_or_else(|_| DEFAULT_TEMPLATE.to_string()),
+            agent: agent.clone(),
+        })
+    }
+}
+
+impl Notifier for SlackNotifier {
+    fn name(&self) -> &str {
+        "slack"
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        self.agent
+            .post(&self.url)
+            .send_json(serde_json::json!({ "text": event.render(&self.template) }))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,45 @@
+//! notify/telegram.rs
+//!
+//! Sends power events to a Telegram chat through the Bot API.
+
+use super::{Notifier, NotifyError, DEFAULT_TEMPLATE};
+use crate::events::Event;
+
+/// Bot API base URL
+const API_URL: &str = "https://api.telegram.org";
+
+pub struct TelegramNotifier {
+    token: String,
+    chat_id: String,
+    template: String,
+    agent: ureq::Agent,
+}
+
+impl TelegramNotifier {
+    /// Build the notifier from `TELEGRAM_*` environment variables. Returns
+    /// `None` unless both `TELEGRAM_BOT_TOKEN` and `TELEGRAM_CHAT_ID` are set.
+    pub fn from_env(agent: &ureq::Agent) -> Option<Self> {
+        Some(TelegramNotifier {
+            token: std::env::var("TELEGRAM_BOT_TOKEN").ok()?,
+            chat_id: std::env::var("TELEGRAM_CHAT_ID").ok()?,
+            template: std::env::var("TELEGRAM_TEMPLATE").unwrap_or_else(|_| DEFAULT_TEMPLATE.to_string()),
+            agent: agent.clone(),
+        })
+    }
+}
+
+impl Notifier for TelegramNotifier {
+    fn name(&self) -> &str {
+        "telegram"
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        self.agent
+            .post(&format!("{}/bot{}/sendMessage", API_URL, self.token))
+            .send_json(serde_json::json!({
+                "chat_id": self.chat_id,
+                "text": event.render(&self.template),
+            }))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+//! notify/throttle.rs
+//!
+//! Rate limits notifications so flapping power doesn't flood every channel.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::events::{Event, EventKind};
+
+/// Cooldowns applied per channel and per event kind on a channel.
+#[derive(Default)]
+pub struct Throttle {
+    /// Minimum time between any two notifications on a channel
+    channel_cooldown: Duration,
+    /// Minimum time between notifications of the same kind from the same
+    /// host, alerts told apart by name
+    event_cooldown: Duration,
+    last_channel: HashMap<String, Instant>,
+    last_event: HashMap<(String, String, EventKind, Option<String>), Instant>,
+}
+
+impl Throttle {
+    pub fn new(channel_cooldown: Duration, event_cooldown: Duration) -> Self {
+        Throttle {
+            channel_cooldown,
+            event_cooldown,
+            ..Self::default()
+        }
+    }
+
+    /// Build the throttle from `NOTIFY_CHANNEL_COOLDOWN` and
+    /// `NOTIFY_EVENT_COOLDOWN`, both in seconds. Both default to 0 (off).
+    pub fn from_env() -> Self {
+        let seconds = |var: &str| {
+            Duration::from_secs(std::env::var(var).ok().and_then(|s| s.parse().ok()).unwrap_or(0))
+        };
+        Throttle::new(seconds("NOTIFY_CHANNEL_COOLDOWN"), seconds("NOTIFY_EVENT_COOLDOWN"))
+    }
+
+    /// Whether the event may be sent on the channel now. Allowed events
+    /// start new cooldowns; suppressed ones don't extend them.
+    pub fn allow(&mut self, channel: &str, event: &Event, now: Instant) -> bool {
+        let alert = event.alert.as_ref().map(|a| a.name.clone());
+        let event_key = (channel.to_string(), event.snapshot.host.clone(), event.kind, alert);
+        let cooling = |last: Option<&Instant>, cooldown: Duration| {
+            last.is_some_and(|&last| now.duration_since(last) < cooldown)
+        };
+        if cooling(self.last_channel.get(channel), self.channel_cooldown)
+            || cooling(self.last_event.get(&event_key), self.event_cooldown)
+        {
+            return false;
+        }
+        self.last_channel.insert(channel.to_string(), now);
+        self.last_event.insert(event_key, now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::Snapshot;
+    use std::collections::BTreeMap;
+
+    fn event(kind: EventKind) -> Event {
+        Event {
+            kind,
+            previous_status: "ONLINE".to_string(),
+            snapshot: Snapshot::new("ups1", BTreeMap::new()),
+            severity: kind.severity(),
+            test: false,
+            alert: None,
+        }
+    }
+
+    #[test]
+    fn test_event_cooldown() {
+        let mut throttle = Throttle::new(Duration::ZERO, Duration::from_secs(300));
+        let start = Instant::now();
+        assert!(throttle.allow("slack", &event(EventKind::OnBattery), start));
+        assert!(throttle.allow("slack", &event(EventKind::Online), start));
+        assert!(throttle.allow("telegram", &event(EventKind::OnBattery), start));
+        assert!(!throttle.allow("slack", &event(EventKind::OnBattery), start + Duration::from_secs(60)));
+        assert!(throttle.allow("slack", &event(EventKind::OnBattery), start + Duration::from_secs(300)));
+    }
+
+    #[test]
+    fn test_channel_cooldown() {
+        let mut throttle = Throttle::new(Duration::from_secs(60), Duration::ZERO);
+        let start = Instant::now();
+        assert!(throttle.allow("slack", &event(EventKind::OnBattery), start));
+        assert!(!throttle.allow("slack", &event(EventKind::Online), start + Duration::from_secs(10)));
+        assert!(throttle.allow("telegram", &event(EventKind::Online), start + Duration::from_secs(10)));
+        assert!(throttle.allow("slack", &event(EventKind::Online), start + Duration::from_secs(60)));
+    }
+}
@@ -0,0 +1,48 @@
+//! notify/webhook.rs
+//!
+//! POSTs power events as JSON to arbitrary webhook URLs.
+
+use super::{Notifier, NotifyError};
+use crate::events::Event;
+
+pub struct WebhookNotifier {
+    name: String,
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl WebhookNotifier {
+    /// Webhook URLs often embed credentials, so only the index is used as name.
+    pub fn new(index: usize, url: &str, agent: &ureq::Agent) -> Self {
+        WebhookNotifier {
+            name: format!("webhook #{}", index + 1),
+            url: url.to_string(),
+            agent: agent.clone(),
+        }
+    }
+
+    /// One notifier per URL in the comma-separated `WEBHOOK_URLS`.
+    pub fn from_env(agent: &ureq::Agent) -> Vec<Self> {
+        std::env::var("WEBHOOK_URLS")
+            .map(|urls| {
+                urls.split(',')
+                    .map(str::trim)
+                    .filter(|u| !u.is_empty())
+                    .enumerate()
+                    .map(|(i, u)| WebhookNotifier::new(i, u, agent))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Notifier for WebhookNotifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn notify(&self, event: &Event) -> Result<(), NotifyError> {
+        self.agent.post(&self.url).send_json(event.to_json())?;
+        Ok(())
+    }
+}
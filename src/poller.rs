@@ -0,0 +1,169 @@
+//! poller.rs
+//!
+//! Polls an apcupsd host on a fixed interval and hands every result to the
+//! updater. Each target has its own poller, and polls run on the blocking
+//! thread pool, a limited number at a time.
+
+use std::fmt::Display;
+use std::hash::{BuildHasher, RandomState};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{interval_at, sleep};
+use tracing::{debug, error, info_span, warn};
+
+use apcaccess::NisClient;
+use crate::metrics::PollMetrics;
+use crate::snapshot::Snapshot;
+use crate::systemd::{self, Heartbeat};
+use crate::targets::{ActiveTargets, FailurePolicy};
+use crate::updater::Update;
+
+pub struct Poller {
+    /// The name snapshots are tagged with
+    pub target: String,
+    pub client: NisClient,
+    pub every: Duration,
+    /// Delay before the first poll, to spread targets across the interval
+    pub offset: Duration,
+    /// Most each poll is randomly delayed by
+    pub jitter: Duration,
+    /// Extra attempts within a poll before it counts as failed
+    pub retries: u32,
+    /// When the target's gauges go while its polls fail
+    pub on_failure: FailurePolicy,
+    /// Exit the process after this many failed polls in a row
+    pub max_failures: Option<u32>,
+    /// Beaten after every completed poll, successful or not
+    pub heartbeat: Arc<Heartbeat>,
+    /// Told how every poll went, for `/api/v1/targets`
+    pub active: Arc<ActiveTargets>,
+    pub metrics: PollMetrics,
+    /// Shared by all pollers, bounds how many polls run at once
+    pub limit: Arc<Semaphore>,
+}
+
+impl Poller {
+    /// Poll the host until the updater goes away. The first tick fires
+    /// after the offset. Every completed poll also updates the systemd status.
+    pub async fn run(self, sender: mpsc::Sender<Update>) {
+        let mut interval_timer = interval_at(tokio::time::Instant::now() + self.offset, self.every);
+        let limit = Arc::clone(&self.limit);
+        let mut poller = self;
+        let mut failures = 0;
+        // Counted from the start until the first poll succeeds
+        let mut last_success = Instant::now();
+        let mut cleared = false;
+        loop {
+            interval_timer.tick().await;
+            if !poller.jitter.is_zero() {
+                sleep(random_up_to(poller.jitter)).await;
+            }
+            let Ok(_permit) = limit.acquire().await else {
+                return;
+            };
+
+            // The updater applies the snapshot within the poll's span. The
+            // poller moves to a blocking thread and back, so a slow host
+            // doesn't hold up the others or the HTTP server.
+            let span = info_span!("poll", target = %poller.target, host = %poller.client.host);
+            let target = poller.target.clone();
+            let polled = tokio::task::spawn_blocking({
+                let span = span.clone();
+                move || {
+                    let snapshot = span.in_scope(|| poller.poll(&mut failures));
+                    (poller, failures, snapshot)
+                }
+            })
+            .await;
+            let Ok((returned, counted, snapshot)) = polled else {
+                error!("Poller for {} stopped unexpectedly", target);
+                return;
+            };
+            (poller, failures) = (returned, counted);
+            let update = match snapshot {
+                Some(snapshot) => {
+                    (last_success, cleared) = (Instant::now(), false);
+                    Update::Polled(snapshot, span)
+                }
+                None if !cleared && poller.on_failure.clears(last_success.elapsed()) => {
+                    cleared = true;
+                    Update::Cleared(target.clone())
+                }
+                None => continue,
+            };
+            if sender.send(update).await.is_err() {
+                error!("Metrics updater has stopped, stopping the poller for {}", target);
+                return;
+            }
+        }
+    }
+
+    /// Poll once, counting consecutive failures. A poll that panics counts
+    /// as failed, the loop carries on.
+    fn poll(&mut self, failures: &mut u32) -> Option<Snapshot> {
+        let start = Instant::now();
+        // The panic hook has already logged the message and backtrace
+        let polled = panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut result = self.client.fetch_stats(true);
+            for attempt in 1..=self.retries {
+                let Err(e) = &result else { break };
+                debug!("Attempt {} to poll {} failed, retrying: {}", attempt, self.target, e);
+                result = self.client.fetch_stats(true);
+            }
+            result.map(|stats| Snapshot::new(&self.target, stats))
+        }));
+        self.heartbeat.beat();
+        self.metrics.record_request(&self.target, self.client.last_request());
+        let duration_ms = start.elapsed().as_millis() as u64;
+        self.metrics.record_poll(&self.target, matches!(polled, Ok(Ok(_))));
+        let Ok(result) = polled else {
+            *failures += 1;
+            self.active.record(&self.target, start.elapsed(), Some("poll panicked".to_string()));
+            self.metrics.record_panic();
+            systemd::status(&format!("Last poll of {} panicked", self.client.host));
+            error!(duration_ms, "Poll of {} panicked, continuing with the next poll", self.client.host);
+            self.check_failures(*failures, "poll panicked");
+            return None;
+        };
+        match result {
+            Ok(snapshot) => {
+                *failures = 0;
+                self.active.record(&self.target, start.elapsed(), None);
+                let status = snapshot.stats.get("STATUS").map(String::as_str).unwrap_or("unknown");
+                systemd::status(&format!("Last poll of {} succeeded, STATUS {}", self.client.host, status));
+                debug!(duration_ms, status, "Polled {}", self.client.host);
+                Some(snapshot)
+            }
+            Err(e) => {
+                *failures += 1;
+                self.active.record(&self.target, start.elapsed(), Some(e.to_string()));
+                self.metrics.record_error(&e);
+                systemd::status(&format!("Last poll of {} failed: {}", self.client.host, e));
+                warn!(duration_ms, error_kind = e.kind(), "Failed to fetch APC UPS stats: {}", e);
+                self.check_failures(*failures, e);
+                None
+            }
+        }
+    }
+
+    /// Exit once `MAX_CONSECUTIVE_FAILURES` is reached.
+    fn check_failures(&self, failures: u32, last_error: impl Display) {
+        if self.max_failures.is_some_and(|max| failures >= max) {
+            error!(
+                "Exiting after {} consecutive failed polls of {}:{} (MAX_CONSECUTIVE_FAILURES), last error: {}",
+                failures, self.client.host, self.client.port, last_error
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// A random duration up to `max`. Randomness only needs to differ between
+/// polls and processes, which std's hash keys are good enough for.
+fn random_up_to(max: Duration) -> Duration {
+    let random = RandomState::new().hash_one(Instant::now());
+    max.mul_f64(random as f64 / u64::MAX as f64)
+}
@@ -0,0 +1,159 @@
+//! quirks.rs
+//!
+//! Differences between apcupsd versions and drivers in how they report the
+//! status, smoothed over as each poll's values come in, so a fleet of mixed
+//! versions exports the same metrics. The VERSION and DRIVER of the status
+//! pick the quirks that apply.
+
+use std::collections::BTreeMap;
+
+use chrono::{Local, NaiveDate, NaiveDateTime, TimeZone};
+
+/// How apcupsd writes timestamps since 3.14, and how they're passed on
+const TIMESTAMP: &str = "%Y-%m-%d %H:%M:%S %z";
+
+/// The keys holding a timestamp
+const TIMESTAMP_KEYS: &[&str] = &["DATE", "STARTTIME", "XONBATT", "XOFFBATT", "LASTSTEST", "END APC"];
+
+/// The keys holding a date
+const DATE_KEYS: &[&str] = &["BATTDATE", "MANDATE"];
+
+/// What some versions or drivers report differently
+struct Quirk {
+    /// apcupsd versions before this one only, if set
+    before: Option<(u32, u32)>,
+    /// Drivers whose DRIVER contains this only, if set
+    driver: Option<&'static str>,
+    /// Keys reported under another name, and the name they go by
+    aliases: &'static [(&'static str, &'static str)],
+    /// Units the parser doesn't strip, by key
+    units: &'static [(&'static str, &'static str)],
+    /// The format of the timestamps, if not `TIMESTAMP`
+    timestamps: Option<&'static str>,
+    /// The format of the dates, if not `%Y-%m-%d`
+    dates: Option<&'static str>,
+}
+
+const QUIRKS: &[Quirk] = &[
+    // Before 3.14, timestamps were written like date(1) does, the version
+    // was RELEASE, and some units were spelled differently
+    Quirk {
+        before: Some((3, 14)),
+        driver: None,
+        aliases: &[("RELEASE", "VERSION")],
+        units: &[("TONBATT", "seconds"), ("CUMONBATT", "seconds"), ("ITEMP", "C Internal")],
+        timestamps: Some("%a %b %d %H:%M:%S %Z %Y"),
+        dates: None,
+    },
+    // UPSes that report their dates in the firmware's own format
+    Quirk {
+        before: None,
+        driver: None,
+        aliases: &[],
+        units: &[],
+        timestamps: None,
+        dates: Some("%m/%d/%y"),
+    },
+];
+
+/// The major and minor version at the start of VERSION, such as
+/// `3.14.14 (31 May 2016) debian`
+fn version(stats: &BTreeMap<String, String>) -> Option<(u32, u32)> {
+    let version = stats.get("VERSION").or_else(|| stats.get("RELEASE"))?;
+    let mut parts = version.split_whitespace().next()?.split('.');
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+impl Quirk {
+    fn applies(&self, version: Option<(u32, u32)>, driver: &str) -> bool {
+        let version = match self.before {
+            Some(before) => version.is_some_and(|version| version < before),
+            None => true,
+        };
+        version && self.driver.is_none_or(|name| driver.contains(name))
+    }
+
+    fn apply(&self, stats: &mut BTreeMap<String, String>) {
+        for (alias, key) in self.aliases {
+            if !stats.contains_key(*key)
+                && let Some(value) = stats.remove(*alias)
+            {
+                stats.insert(key.to_string(), value);
+            }
+        }
+        for (key, unit) in self.units {
+            if let Some(value) = stats.get_mut(*key)
+                && let Some(stripped) = value.strip_suffix(unit)
+            {
+                *value = stripped.trim_end().to_string();
+            }
+        }
+        // Timestamps without an offset are taken to be in the exporter's
+        // time zone, which is usually apcupsd's too
+        if let Some(format) = self.timestamps {
+            for key in TIMESTAMP_KEYS {
+                if let Some(value) = stats.get_mut(*key)
+                    && let Ok(time) = NaiveDateTime::parse_from_str(value.trim(), format)
+                    && let Some(time) = Local.from_local_datetime(&time).earliest()
+                {
+                    *value = time.format(TIMESTAMP).to_string();
+                }
+            }
+        }
+        if let Some(format) = self.dates {
+            for key in DATE_KEYS {
+                if let Some(value) = stats.get_mut(*key)
+                    && let Ok(date) = NaiveDate::parse_from_str(value.trim(), format)
+                {
+                    *value = date.format("%Y-%m-%d").to_string();
+                }
+            }
+        }
+    }
+}
+
+/// Apply the quirks of the version and driver that reported the status.
+pub fn apply(mut stats: BTreeMap<String, String>) -> BTreeMap<String, String> {
+    let version = version(&stats);
+    let driver = stats.get("DRIVER").cloned().unwrap_or_default();
+    for quirk in QUIRKS.iter().filter(|quirk| quirk.applies(version, &driver)) {
+        quirk.apply(&mut stats);
+    }
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stats(pairs: &[(&str, &str)]) -> BTreeMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_legacy_version() {
+        let legacy = apply(stats(&[
+            ("RELEASE", "3.13.7"),
+            ("DATE", "Sat Sep 16 17:13:00 EDT 2006"),
+            ("TONBATT", "0 seconds"),
+            ("ITEMP", "35.1 C Internal"),
+            ("BATTDATE", "11/21/03"),
+        ]));
+        assert_eq!(legacy["VERSION"], "3.13.7");
+        assert!(!legacy.contains_key("RELEASE"));
+        assert!(legacy["DATE"].starts_with("2006-09-16 17:13:00 "));
+        assert_eq!(legacy["TONBATT"], "0");
+        assert_eq!(legacy["ITEMP"], "35.1");
+        assert_eq!(legacy["BATTDATE"], "2003-11-21");
+
+        // Current versions are left alone, other than their dates
+        let current = stats(&[
+            ("VERSION", "3.14.14 (31 May 2016) debian"),
+            ("DATE", "2024-06-01 12:00:00 +0200"),
+            ("RELEASE", "unrelated"),
+            ("BATTDATE", "2023-01-15"),
+        ]);
+        assert_eq!(apply(current.clone()), current);
+        assert_eq!(version(&current), Some((3, 14)));
+    }
+}
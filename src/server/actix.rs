@@ -0,0 +1,76 @@
+//! server/actix.rs
+//!
+//! actix-web backend, with response compression as configured, and TLS with HTTP/2 when
+//! built with the `tls` feature.
+
+use std::sync::Arc;
+
+use actix_web::dev::Service;
+use actix_web::http::header::{HeaderValue, ACCEPT_ENCODING};
+use actix_web::http::StatusCode;
+use actix_web::middleware::{Compress, Condition};
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use tracing::{debug, info};
+
+use super::compression::Compression;
+use super::{Request, Server, MAX_BODY_SIZE};
+use crate::systemd;
+
+async fn handle(server: web::Data<Arc<Server>>, request: HttpRequest, body: web::Bytes) -> HttpResponse {
+    let header = |name| request.headers().get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+    let request = Request {
+        method: request.method().to_string(),
+        path: request.path().to_string(),
+        query: Some(request.query_string().to_string()).filter(|q| !q.is_empty()),
+        peer: request.peer_addr().map(|a| a.ip()),
+        forwarded_for: header("x-forwarded-for"),
+        user_agent: header("user-agent"),
+        body: body.to_vec(),
+    };
+    let reply = server.handle(request).await;
+    HttpResponse::build(StatusCode::from_u16(reply.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR))
+        .content_type(reply.content_type)
+        .body(reply.body)
+}
+
+pub async fn serve(server: Server, port: u16) -> std::io::Result<()> {
+    let server = web::Data::new(Arc::new(server));
+    let compression = Compression::from_env()?;
+    debug!("Starting HTTP server on 0.0.0.0:{}", port);
+    let http = HttpServer::new(move || {
+        let compression = compression.clone();
+        App::new()
+            .wrap(Condition::new(compression.is_enabled(), Compress::default()))
+            // Runs before Compress, which only sees the allowed codings
+            .wrap_fn(move |mut request, service| {
+                let accepted = request.headers().get(ACCEPT_ENCODING).map(|value| {
+                    value.to_str().ok().and_then(|v| compression.filter(v)).and_then(|v| HeaderValue::from_str(&v).ok())
+                });
+                match accepted {
+                    Some(Some(value)) => {
+                        request.headers_mut().insert(ACCEPT_ENCODING, value);
+                    }
+                    Some(None) => {
+                        request.headers_mut().remove(ACCEPT_ENCODING);
+                    }
+                    None => {}
+                }
+                service.call(request)
+            })
+            .app_data(server.clone())
+            .app_data(web::PayloadConfig::new(MAX_BODY_SIZE))
+            .default_service(web::to(handle))
+    });
+    #[cfg(feature = "tls")]
+    let http = match super::tls::from_env()? {
+        Some(config) => {
+            info!("Serving HTTPS with HTTP/2 on port {}", port);
+            http.bind_rustls_0_23(("0.0.0.0", port), config)?
+        }
+        None => http.bind(("0.0.0.0", port))?,
+    };
+    #[cfg(not(feature = "tls"))]
+    let http = http.bind(("0.0.0.0", port))?;
+    systemd::notify("READY=1");
+    http.run().await
+}
@@ -0,0 +1,107 @@
+//! server/compression.rs
+//!
+//! Which content codings responses may be compressed with, from
+//! `HTTP_COMPRESSION`. actix-web picks among everything it was built with,
+//! so the choice is enforced by narrowing the client's `Accept-Encoding`.
+
+use std::io;
+
+/// Codings actix-web is built with
+pub const CODINGS: &[&str] = &["zstd", "br", "gzip"];
+
+/// Allowed codings. Without any, responses aren't compressed at all.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Compression {
+    codings: Vec<&'static str>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression { codings: vec!["gzip"] }
+    }
+}
+
+impl Compression {
+    /// Parse `off`, or a comma-separated list such as `gzip,zstd,br`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        let s = s.trim();
+        if matches!(s, "off" | "none" | "false" | "0") {
+            return Ok(Compression { codings: Vec::new() });
+        }
+        let mut codings = Vec::new();
+        for name in s.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+            let name = if name == "brotli" { "br" } else { name };
+            match CODINGS.iter().find(|&&c| c == name) {
+                Some(coding) if !codings.contains(coding) => codings.push(*coding),
+                Some(_) => {}
+                None => return Err(format!("unknown compression {:?}, expected off or {}", name, CODINGS.join(", "))),
+            }
+        }
+        Ok(Compression { codings })
+    }
+
+    /// `HTTP_COMPRESSION`, gzip only if unset
+    pub fn from_env() -> io::Result<Self> {
+        match std::env::var("HTTP_COMPRESSION") {
+            Ok(value) if !value.trim().is_empty() => Compression::parse(&value)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("HTTP_COMPRESSION: {}", e))),
+            _ => Ok(Compression::default()),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.codings.is_empty()
+    }
+
+    /// Narrow an `Accept-Encoding` value to the allowed codings, keeping
+    /// their quality values. A wildcard stands for the allowed codings.
+    /// Returns None if nothing is left, i.e. the response stays uncompressed.
+    pub fn filter(&self, accept_encoding: &str) -> Option<String> {
+        let items: Vec<(String, Option<&str>)> = accept_encoding
+            .split(',')
+            .map(|item| match item.split_once(';') {
+                Some((coding, params)) => (coding.trim().to_ascii_lowercase(), Some(params.trim())),
+                None => (item.trim().to_ascii_lowercase(), None),
+            })
+            .collect();
+        let named = |coding: &str| items.iter().any(|(c, _)| c == coding);
+        let mut accepted = Vec::new();
+        for (coding, params) in &items {
+            for &allowed in &self.codings {
+                if allowed == coding || (coding == "*" && !named(allowed)) {
+                    accepted.push(match params {
+                        Some(params) => format!("{};{}", allowed, params),
+                        None => allowed.to_string(),
+                    });
+                }
+            }
+        }
+        (!accepted.is_empty()).then(|| accepted.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Compression::parse("gzip").unwrap(), Compression::default());
+        assert!(!Compression::parse("off").unwrap().is_enabled());
+        assert_eq!(Compression::parse("zstd, brotli,zstd").unwrap().codings, vec!["zstd", "br"]);
+        assert!(Compression::parse("deflate").is_err());
+    }
+
+    #[test]
+    fn test_filter() {
+        let gzip = Compression::default();
+        assert_eq!(gzip.filter("gzip, deflate, br, zstd"), Some("gzip".to_string()));
+        assert_eq!(gzip.filter("br;q=1.0, gzip;q=0.5"), Some("gzip;q=0.5".to_string()));
+        assert_eq!(gzip.filter("br"), None);
+        assert_eq!(gzip.filter("*;q=0.1"), Some("gzip;q=0.1".to_string()));
+
+        let all = Compression::parse("gzip,br").unwrap();
+        assert_eq!(all.filter("gzip, zstd, br"), Some("gzip, br".to_string()));
+        assert_eq!(all.filter("br, *"), Some("br, gzip".to_string()));
+    }
+}
@@ -0,0 +1,74 @@
+//! server/hyper.rs
+//!
+//! Minimal hyper backend for the `http-lite` feature: HTTP/1.1 only, on the
+//! main runtime thread and without response compression.
+
+use std::convert::Infallible;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{header, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::TcpListener;
+use tracing::{debug, warn};
+
+use super::{Reply, Request, Server, MAX_BODY_SIZE};
+use crate::systemd;
+
+async fn handle(server: &Server, request: hyper::Request<Incoming>, peer: IpAddr) -> Response<Full<Bytes>> {
+    let (parts, body) = request.into_parts();
+    let header = |name| parts.headers.get(name).and_then(|v: &header::HeaderValue| v.to_str().ok()).map(str::to_string);
+    let body = match Limited::new(body, MAX_BODY_SIZE).collect().await {
+        Ok(body) => body.to_bytes().to_vec(),
+        Err(e) => return response(Reply::text(413, e.to_string())),
+    };
+    let request = Request {
+        method: parts.method.to_string(),
+        path: parts.uri.path().to_string(),
+        query: parts.uri.query().map(str::to_string),
+        peer: Some(peer),
+        forwarded_for: header("x-forwarded-for"),
+        user_agent: header(header::USER_AGENT.as_str()),
+        body,
+    };
+    response(server.handle(request).await)
+}
+
+fn response(reply: Reply) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(reply.body)));
+    *response.status_mut() = StatusCode::from_u16(reply.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    response
+        .headers_mut()
+        .insert(header::CONTENT_TYPE, header::HeaderValue::from_static(reply.content_type));
+    response
+}
+
+pub async fn serve(server: Server, port: u16) -> std::io::Result<()> {
+    let server = Arc::new(server);
+    debug!("Starting HTTP server on 0.0.0.0:{}", port);
+    let listener = TcpListener::bind(("0.0.0.0", port)).await?;
+    systemd::notify("READY=1");
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Failed to accept an HTTP connection: {}", e);
+                continue;
+            }
+        };
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            let service = service_fn(|request| {
+                let server = Arc::clone(&server);
+                async move { Ok::<_, Infallible>(handle(&server, request, peer.ip()).await) }
+            });
+            if let Err(e) = http1::Builder::new().serve_connection(TokioIo::new(stream), service).await {
+                debug!("HTTP connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
@@ -0,0 +1,120 @@
+//! server/mod.rs
+//!
+//! The HTTP listener. Requests are answered by `api::route` the same way
+//! whichever server accepts them: actix-web by default, or with the
+//! `http-lite` feature a minimal hyper server for small devices.
+
+#[cfg(feature = "http")]
+mod actix;
+#[cfg(feature = "http")]
+mod compression;
+#[cfg(all(feature = "http-lite", not(feature = "http")))]
+mod hyper;
+#[cfg(feature = "tls")]
+mod tls;
+
+#[cfg(feature = "http")]
+use actix as backend;
+#[cfg(all(feature = "http-lite", not(feature = "http")))]
+use hyper as backend;
+
+use std::io;
+use std::net::IpAddr;
+use std::time::Instant;
+
+use serde::Serialize;
+use tracing::{debug, info_span, Instrument};
+
+use crate::access_log::AccessLog;
+use crate::api::{self, Api};
+use crate::metrics::HttpMetrics;
+
+/// Largest request body accepted
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// A request, as far as the handlers and the access log care
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub query: Option<String>,
+    pub peer: Option<IpAddr>,
+    pub forwarded_for: Option<String>,
+    pub user_agent: Option<String>,
+    pub body: Vec<u8>,
+}
+
+/// A response, turned into the server's own type by the backend
+pub struct Reply {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Reply {
+    pub fn empty(status: u16) -> Self {
+        Reply {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: Vec::new(),
+        }
+    }
+
+    pub fn text(status: u16, body: impl Into<String>) -> Self {
+        Reply {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    pub fn json<T: Serialize + ?Sized>(status: u16, value: &T) -> Self {
+        match serde_json::to_vec(value) {
+            Ok(body) => Reply {
+                status,
+                content_type: "application/json",
+                body,
+            },
+            Err(e) => Reply::text(500, e.to_string()),
+        }
+    }
+}
+
+/// Listen on the port until the process is told to stop.
+pub async fn serve(server: Server, port: u16) -> io::Result<()> {
+    // Refuse rather than serve plain HTTP when TLS was asked for
+    #[cfg(not(feature = "tls"))]
+    if std::env::var_os("TLS_CERT_FILE").is_some() {
+        return Err(io::Error::other("TLS_CERT_FILE is set, but TLS needs a build with the tls feature"));
+    }
+    #[cfg(not(feature = "http"))]
+    if std::env::var("HTTP_COMPRESSION").is_ok_and(|v| v != "off") {
+        tracing::warn!("HTTP_COMPRESSION is ignored, http-lite doesn't compress responses");
+    }
+    backend::serve(server, port).await
+}
+
+/// The routes together with what's recorded about each request
+pub struct Server {
+    pub api: Api,
+    pub http_metrics: HttpMetrics,
+    pub access_log: Option<AccessLog>,
+}
+
+impl Server {
+    /// Answer a request in an `http_request` span, then count and log it.
+    pub async fn handle(&self, request: Request) -> Reply {
+        let span = info_span!("http_request", method = %request.method, path = %request.path);
+        async {
+            let start = Instant::now();
+            let reply = api::route(&self.api, &request).await;
+            self.http_metrics.record(api::pattern(&request.path), reply.status, start.elapsed());
+            match &self.access_log {
+                Some(log) => log.log(&log.request(&request), reply.status, start.elapsed()),
+                None => debug!(status = reply.status, duration_ms = start.elapsed().as_millis() as u64, "Served request"),
+            }
+            reply
+        }
+        .instrument(span)
+        .await
+    }
+}
@@ -0,0 +1,57 @@
+//! server/tls.rs
+//!
+//! TLS for the listener, from `TLS_CERT_FILE` and `TLS_KEY_FILE`. actix-web
+//! offers HTTP/2 through ALPN next to HTTP/1.1, so scrapers can multiplex
+//! their requests over one connection.
+
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::ServerConfig;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// The TLS config if `TLS_CERT_FILE` and `TLS_KEY_FILE` are set. Only one of
+/// them being set is an error rather than a silent fallback to plain HTTP.
+pub fn from_env() -> io::Result<Option<ServerConfig>> {
+    match (std::env::var_os("TLS_CERT_FILE"), std::env::var_os("TLS_KEY_FILE")) {
+        (None, None) => Ok(None),
+        (Some(cert), Some(key)) => load(Path::new(&cert), Path::new(&key)).map(Some),
+        _ => Err(invalid("TLS_CERT_FILE and TLS_KEY_FILE must be set together".to_string())),
+    }
+}
+
+/// Load a PEM certificate chain and its private key.
+pub fn load(cert: &Path, key: &Path) -> io::Result<ServerConfig> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(format!("{}: {}", cert.display(), e)))?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(format!("{}: {}", key.display(), e)))?;
+    ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()
+        .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+        .map_err(|e| invalid(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load() {
+        let dir = std::env::temp_dir();
+        let missing = dir.join("rsapcupsdexporter-missing.pem");
+        let error = load(&missing, &missing).unwrap_err();
+        assert!(error.to_string().contains("rsapcupsdexporter-missing.pem"));
+
+        let garbage = dir.join(format!("rsapcupsdexporter-{}.pem", std::process::id()));
+        std::fs::write(&garbage, "not a certificate").unwrap();
+        assert_eq!(load(&garbage, &garbage).unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        std::fs::remove_file(garbage).unwrap();
+    }
+}
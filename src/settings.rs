@@ -0,0 +1,342 @@
+//! settings.rs
+//!
+//! Inventory of the environment variables the exporter reads, with their
+//! defaults, for `print-config`. Each module still reads its own variables;
+//! a new variable needs adding here too to show up.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::config::ConfigError;
+
+/// Shown in place of secret values
+pub const REDACTED: &str = "<redacted>";
+
+/// An environment variable, with the value used when it isn't set
+pub struct Setting {
+    pub name: &'static str,
+    pub default: Option<&'static str>,
+    /// Credentials, or URLs that usually embed them
+    pub secret: bool,
+}
+
+/// Settings for one feature, which are only shown once the variable that
+/// enables it is set
+pub struct Section {
+    pub name: &'static str,
+    pub enabled_by: Option<&'static str>,
+    pub settings: &'static [Setting],
+}
+
+const fn var(name: &'static str) -> Setting {
+    Setting { name, default: None, secret: false }
+}
+
+const fn default(name: &'static str, default: &'static str) -> Setting {
+    Setting { name, default: Some(default), secret: false }
+}
+
+const fn secret(name: &'static str) -> Setting {
+    Setting { name, default: None, secret: true }
+}
+
+const fn section(name: &'static str, enabled_by: Option<&'static str>, settings: &'static [Setting]) -> Section {
+    Section { name, enabled_by, settings }
+}
+
+/// The HTTP listener's settings, left out of builds without it
+#[cfg(any(feature = "http", feature = "http-lite"))]
+const HTTP: &[Setting] = &[
+    default("ACCESS_LOG", "false"),
+    default("ACCESS_LOG_FORMAT", crate::access_log::DEFAULT_FORMAT),
+    var("TRUSTED_PROXIES"),
+    default("HTTP_COMPRESSION", "gzip"),
+    var("TLS_CERT_FILE"),
+    var("TLS_KEY_FILE"),
+    default("TARGETS_API", "false"),
+    default("TARGETS_API_PERSIST", "false"),
+    default("HISTORY_RETENTION", "1h"),
+    var("STALE_AFTER"),
+];
+#[cfg(not(any(feature = "http", feature = "http-lite")))]
+const HTTP: &[Setting] = &[];
+
+pub const SECTIONS: &[Section] = &[
+    section("apcupsd", None, &[
+        default("APCUPSD_HOST", "localhost"),
+        default("APCUPSD_PORT", "3551"),
+        default("APCUPSD_CONF", crate::apcupsd_conf::DEFAULT_PATH),
+        default("INTERVAL", "10"),
+        default("TIMEOUT", "15"),
+        var("NIS_SOURCE_ADDRESS"),
+        var("NIS_INTERFACE"),
+        var("NIS_RESOLVE_TIMEOUT"),
+        var("NIS_PREFER_FAMILY"),
+        var("NIS_SOCKS5_PROXY"),
+        var("NIS_SOCKS5_USERNAME"),
+        secret("NIS_SOCKS5_PASSWORD"),
+        default("NIS_PERSISTENT", "false"),
+        default("NIS_RETRIES", "0"),
+        var("MAX_CONSECUTIVE_FAILURES"),
+        default("POLL_CONCURRENCY", "8"),
+        default("POLL_JITTER", "0"),
+        default("ON_FAILURE", "hold"),
+        default("POLL_SPREAD", "false"),
+    ]),
+    section("exporter", None, &[
+        default("METRICS_PORT", "9090"),
+        var("CONFIG_FILE"),
+        var("ROLLING_WINDOW"),
+        var("LINEV_BUCKETS"),
+        var("BATTV_BUCKETS"),
+        var("BATTERY_RATED_RUNTIME"),
+        var("ELECTRICITY_PRICE"),
+        var("ELECTRICITY_PRICE_SCHEDULE"),
+        default("TEMPERATURE_UNITS", "celsius"),
+        default("STRICT", "false"),
+    ]),
+    section("http", None, HTTP),
+    section("file_sd", Some("TARGETS_FILE"), &[
+        var("TARGETS_FILE"),
+        default("TARGETS_FILE_INTERVAL", "10s"),
+    ]),
+    section("srv", Some("TARGETS_SRV"), &[
+        var("TARGETS_SRV"),
+        default("TARGETS_SRV_INTERVAL", "60s"),
+    ]),
+    section("mdns", Some("TARGETS_MDNS"), &[
+        default("TARGETS_MDNS", "false"),
+        default("TARGETS_MDNS_SERVICE", "_apcupsd._tcp.local."),
+    ]),
+    section("consul", Some("CONSUL_SERVICE"), &[
+        var("CONSUL_SERVICE"),
+        default("CONSUL_HTTP_ADDR", "http://127.0.0.1:8500"),
+        var("CONSUL_TAGS"),
+        var("CONSUL_DATACENTER"),
+        secret("CONSUL_HTTP_TOKEN"),
+    ]),
+    section("probe", Some("TARGETS_PROBE_SUBNETS"), &[
+        var("TARGETS_PROBE_SUBNETS"),
+        default("TARGETS_PROBE_PORT", "3551"),
+        default("TARGETS_PROBE_INTERVAL", "5m"),
+    ]),
+    section("logging", None, &[
+        default("RUST_LOG", "info"),
+        default("LOG_FORMAT", "text"),
+        default("LOG_OUTPUT", "stdout"),
+        var("LOG_FILE"),
+        var("LOG_FILE_MAX_SIZE"),
+        default("LOG_FILE_ROTATE", "never"),
+        default("LOG_FILE_RETAIN", "5"),
+        var("SYSLOG_ADDRESS"),
+        default("SYSLOG_FACILITY", "daemon"),
+        default("EVENT_LOG", "false"),
+        var("EVENT_LOG_SOURCE"),
+    ]),
+    section("tracing", Some("OTEL_EXPORTER_OTLP_ENDPOINT"), &[
+        var("OTEL_EXPORTER_OTLP_ENDPOINT"),
+        var("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"),
+        default("OTEL_SERVICE_NAME", env!("CARGO_PKG_NAME")),
+    ]),
+    section("textfile", Some("TEXTFILE_DIR"), &[
+        var("TEXTFILE_DIR"),
+        default("TEXTFILE_NAME", "apcupsd.prom"),
+        default("TEXTFILE_ONESHOT", "false"),
+    ]),
+    section("events", None, &[
+        var("ONBATT_PROLONGED_SECONDS"),
+        var("LOW_RUNTIME_MINUTES"),
+        var("RECOVERY_STABLE_SECONDS"),
+    ]),
+    section("notify", None, &[
+        default("NOTIFY_TIMEOUT", "10"),
+        default("NOTIFY_RETRIES", "3"),
+        default("NOTIFY_CHANNEL_COOLDOWN", "0"),
+        default("NOTIFY_EVENT_COOLDOWN", "0"),
+        default("EXEC_TIMEOUT", "60"),
+    ]),
+    section("webhook", Some("WEBHOOK_URLS"), &[secret("WEBHOOK_URLS")]),
+    section("slack", Some("SLACK_WEBHOOK_URL"), &[
+        secret("SLACK_WEBHOOK_URL"),
+        default("SLACK_TEMPLATE", crate::notify::DEFAULT_TEMPLATE),
+    ]),
+    section("discord", Some("DISCORD_WEBHOOK_URL"), &[
+        secret("DISCORD_WEBHOOK_URL"),
+        default("DISCORD_TEMPLATE", crate::notify::DEFAULT_TEMPLATE),
+    ]),
+    section("telegram", Some("TELEGRAM_BOT_TOKEN"), &[
+        secret("TELEGRAM_BOT_TOKEN"),
+        var("TELEGRAM_CHAT_ID"),
+        default("TELEGRAM_TEMPLATE", crate::notify::DEFAULT_TEMPLATE),
+    ]),
+    section("ntfy", Some("NTFY_TOPIC"), &[
+        default("NTFY_URL", "https://ntfy.sh"),
+        var("NTFY_TOPIC"),
+        default("NTFY_PRIORITY", "auto"),
+        secret("NTFY_TOKEN"),
+        default("NTFY_TEMPLATE", crate::notify::DEFAULT_TEMPLATE),
+    ]),
+    section("pagerduty", Some("PAGERDUTY_ROUTING_KEY"), &[
+        secret("PAGERDUTY_ROUTING_KEY"),
+        default("PAGERDUTY_SEVERITY", "critical"),
+        var("PAGERDUTY_EVENTS"),
+    ]),
+    section("grafana", Some("GRAFANA_URL"), &[
+        var("GRAFANA_URL"),
+        secret("GRAFANA_TOKEN"),
+        var("GRAFANA_DASHBOARD_UID"),
+        var("GRAFANA_PANEL_ID"),
+        default("GRAFANA_TAGS", "apcupsd"),
+        default("GRAFANA_EVENTS", "on_battery,online"),
+    ]),
+    section("email", Some("SMTP_HOST"), &[
+        var("SMTP_HOST"),
+        var("SMTP_PORT"),
+        default("SMTP_TLS", "starttls"),
+        var("SMTP_USERNAME"),
+        secret("SMTP_PASSWORD"),
+        default("EMAIL_FROM", "rsapcupsdexporter@localhost"),
+        var("EMAIL_TO"),
+        var("EMAIL_EVENTS"),
+        var("EMAIL_SUBJECT"),
+        var("EMAIL_BODY"),
+    ]),
+    section("sqlite", Some("SQLITE_PATH"), &[
+        var("SQLITE_PATH"),
+        default("SQLITE_RETENTION_DAYS", "30"),
+        var("SQLITE_RESTORE"),
+    ]),
+    section("postgres", Some("POSTGRES_DSN"), &[
+        secret("POSTGRES_DSN"),
+        default("POSTGRES_TABLE", "apcupsd_samples"),
+        default("POSTGRES_BATCH_SIZE", "1"),
+        default("POSTGRES_TIMESCALE", "false"),
+    ]),
+    section("graphite", Some("GRAPHITE_HOST"), &[
+        var("GRAPHITE_HOST"),
+        default("GRAPHITE_PORT", "2003"),
+        default("GRAPHITE_PREFIX", "apcupsd"),
+        default("GRAPHITE_PROTOCOL", "tcp"),
+        default("GRAPHITE_TIMEOUT", "5"),
+    ]),
+    section("statsd", Some("STATSD_HOST"), &[
+        var("STATSD_HOST"),
+        default("STATSD_PORT", "8125"),
+        default("STATSD_PREFIX", "apcupsd"),
+        default("STATSD_DOGSTATSD", "false"),
+        var("STATSD_TAGS"),
+    ]),
+    section("zabbix", Some("ZABBIX_SERVER"), &[
+        var("ZABBIX_SERVER"),
+        default("ZABBIX_PORT", "10051"),
+        default("ZABBIX_HOST", "{hostname}"),
+        default("ZABBIX_KEY_FORMAT", "apcupsd.{key}"),
+        default("ZABBIX_TIMEOUT", "5"),
+    ]),
+    section("kafka", Some("KAFKA_BROKERS"), &[
+        var("KAFKA_BROKERS"),
+        default("KAFKA_TOPIC", "apcupsd"),
+        var("KAFKA_SECURITY_PROTOCOL"),
+        var("KAFKA_SASL_MECHANISM"),
+        var("KAFKA_SASL_USERNAME"),
+        secret("KAFKA_SASL_PASSWORD"),
+    ]),
+    section("nats", Some("NATS_URL"), &[
+        var("NATS_URL"),
+        default("NATS_SUBJECT_PREFIX", "ups"),
+        default("NATS_JETSTREAM", "false"),
+        var("NATS_CREDS"),
+        secret("NATS_TOKEN"),
+        var("NATS_USER"),
+        secret("NATS_PASSWORD"),
+    ]),
+];
+
+/// Variables read by prefix rather than by name
+const PREFIXES: &[(&str, &str)] = &[("exec", "EXEC_ON_")];
+
+/// The configuration in effect: the variables per section, and the parsed
+/// config file
+#[derive(Serialize)]
+pub struct Effective {
+    pub environment: BTreeMap<&'static str, BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub config: Option<toml::Value>,
+}
+
+impl Effective {
+    /// Resolve the settings from the given variables, with secrets redacted.
+    /// Sections whose feature isn't enabled are left out.
+    pub fn resolve(vars: &BTreeMap<String, String>) -> Self {
+        let mut environment = BTreeMap::new();
+        for section in SECTIONS {
+            if section.enabled_by.is_some_and(|name| !vars.contains_key(name)) {
+                continue;
+            }
+            let values: BTreeMap<String, String> = section
+                .settings
+                .iter()
+                .filter_map(|setting| {
+                    let value = match vars.get(setting.name) {
+                        Some(_) if setting.secret => REDACTED.to_string(),
+                        Some(value) => value.clone(),
+                        None => setting.default?.to_string(),
+                    };
+                    Some((setting.name.to_string(), value))
+                })
+                .collect();
+            if !values.is_empty() {
+                environment.insert(section.name, values);
+            }
+        }
+        for (name, prefix) in PREFIXES {
+            let values: BTreeMap<String, String> = vars
+                .iter()
+                .filter(|(var, _)| var.starts_with(prefix))
+                .map(|(var, value)| (var.clone(), value.clone()))
+                .collect();
+            if !values.is_empty() {
+                environment.insert(name, values);
+            }
+        }
+        Effective { environment, config: None }
+    }
+
+    /// Resolve the settings from the process environment, including the
+    /// config file named by `CONFIG_FILE`.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        let mut effective = Effective::resolve(&std::env::vars().collect());
+        if let Ok(path) = std::env::var("CONFIG_FILE") {
+            let content = std::fs::read_to_string(Path::new(&path))?;
+            effective.config = Some(toml::from_str(&content)?);
+        }
+        Ok(effective)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let vars = BTreeMap::from([
+            ("APCUPSD_HOST".to_string(), "ups1".to_string()),
+            ("SMTP_HOST".to_string(), "mail".to_string()),
+            ("SMTP_PASSWORD".to_string(), "hunter2".to_string()),
+            ("EXEC_ON_BATTERY".to_string(), "/usr/local/bin/onbatt".to_string()),
+        ]);
+        let effective = Effective::resolve(&vars);
+        let env = &effective.environment;
+        assert_eq!(env["apcupsd"]["APCUPSD_HOST"], "ups1");
+        assert_eq!(env["apcupsd"]["APCUPSD_PORT"], "3551");
+        assert!(!env["apcupsd"].contains_key("NIS_INTERFACE"));
+        assert_eq!(env["email"]["SMTP_PASSWORD"], REDACTED);
+        assert_eq!(env["email"]["SMTP_TLS"], "starttls");
+        assert_eq!(env["exec"]["EXEC_ON_BATTERY"], "/usr/local/bin/onbatt");
+        assert!(!env.contains_key("graphite"));
+        assert!(!env.contains_key("events"));
+    }
+}
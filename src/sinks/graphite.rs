@@ -0,0 +1,168 @@
+//! sinks/graphite.rs
+//!
+//! Pushes numeric values to Graphite/carbon using the plaintext protocol,
+//! from a thread of its own.
+
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use super::{sanitize, udp_connect, Sender, Sink, SinkError};
+use crate::snapshot::Snapshot;
+
+/// Default carbon plaintext port
+const DEFAULT_PORT: u16 = 2003;
+
+/// Largest UDP datagram we send, to stay under common MTUs
+const MAX_DATAGRAM: usize = 1400;
+
+/// Transport used to reach carbon
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+/// Where the lines go, and how
+struct Carbon {
+    host: String,
+    port: u16,
+    protocol: Protocol,
+    timeout: Duration,
+}
+
+impl Carbon {
+    fn send(&self, lines: Vec<String>) -> Result<(), SinkError> {
+        match self.protocol {
+            Protocol::Tcp => {
+                let addr = (self.host.as_str(), self.port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address for graphite host"))?;
+                let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+                stream.set_write_timeout(Some(self.timeout))?;
+                stream.write_all(lines.concat().as_bytes())?;
+            }
+            Protocol::Udp => {
+                let socket = udp_connect(&self.host, self.port)?;
+                let mut datagram = String::new();
+                for line in lines {
+                    if !datagram.is_empty() && datagram.len() + line.len() > MAX_DATAGRAM {
+                        socket.send(datagram.as_bytes())?;
+                        datagram.clear();
+                    }
+                    datagram.push_str(&line);
+                }
+                socket.send(datagram.as_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub struct GraphiteSink {
+    prefix: String,
+    sender: Sender<Vec<String>>,
+}
+
+impl GraphiteSink {
+    pub fn new(host: &str, port: u16, prefix: &str, protocol: Protocol, timeout: Duration) -> Self {
+        let carbon = Carbon {
+            host: host.to_string(),
+            port,
+            protocol,
+            timeout,
+        };
+        GraphiteSink {
+            prefix: prefix.trim_end_matches('.').to_string(),
+            sender: Sender::spawn("graphite", move |lines| carbon.send(lines)),
+        }
+    }
+
+    /// Build the sink from `GRAPHITE_*` environment variables. Returns `None`
+    /// unless `GRAPHITE_HOST` is set.
+    pub fn from_env() -> Option<Self> {
+        let host = std::env::var("GRAPHITE_HOST").ok()?;
+        let port: u16 = std::env::var("GRAPHITE_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(DEFAULT_PORT);
+        let prefix = std::env::var("GRAPHITE_PREFIX").unwrap_or_else(|_| "apcupsd".to_string());
+        let protocol = match std::env::var("GRAPHITE_PROTOCOL").as_deref() {
+            Ok("udp") | Ok("UDP") => Protocol::Udp,
+            _ => Protocol::Tcp,
+        };
+        let timeout: u64 = std::env::var("GRAPHITE_TIMEOUT")
+            .ok()
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(5);
+        Some(GraphiteSink::new(&host, port, &prefix, protocol, Duration::from_secs(timeout)))
+    }
+
+    /// Render the snapshot as plaintext protocol lines.
+    pub fn render(&self, snapshot: &Snapshot) -> Vec<String> {
+        let host = sanitize(snapshot.hostname());
+        let timestamp = snapshot.unix_timestamp();
+        snapshot
+            .numeric_values()
+            .map(|(key, value)| {
+                let path = if self.prefix.is_empty() {
+                    format!("{}.{}", host, sanitize(&key.to_lowercase()))
+                } else {
+                    format!("{}.{}.{}", self.prefix, host, sanitize(&key.to_lowercase()))
+                };
+                format!("{} {} {}\n", path, value, timestamp)
+            })
+            .collect()
+    }
+}
+
+impl Sink for GraphiteSink {
+    fn name(&self) -> &'static str {
+        "graphite"
+    }
+
+    fn publish(&mut self, snapshot: &Snapshot) -> Result<(), SinkError> {
+        let lines = self.render(snapshot);
+        if lines.is_empty() {
+            return Ok(());
+        }
+        self.sender.queue(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_render() {
+        let stats = BTreeMap::from([
+            ("HOSTNAME".to_string(), "nas.local".to_string()),
+            ("LINEV".to_string(), "120.5".to_string()),
+            ("STATUS".to_string(), "ONLINE".to_string()),
+        ]);
+        let mut snapshot = Snapshot::new("localhost", stats);
+        snapshot.timestamp = UNIX_EPOCH + Duration::from_secs(1700000000);
+
+        let sink = GraphiteSink::new("carbon", DEFAULT_PORT, "apcupsd.", Protocol::Tcp, Duration::from_secs(1));
+        assert_eq!(sink.render(&snapshot), vec!["apcupsd.nas_local.linev 120.5 1700000000\n"]);
+    }
+
+    #[test]
+    fn test_publish_in_background() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let stats = BTreeMap::from([("LINEV".to_string(), "120.5".to_string())]);
+        let mut snapshot = Snapshot::new("localhost", stats);
+        snapshot.timestamp = UNIX_EPOCH + Duration::from_secs(1700000000);
+
+        let mut sink = GraphiteSink::new("127.0.0.1", port, "", Protocol::Tcp, Duration::from_secs(1));
+        sink.publish(&snapshot).unwrap();
+        let mut received = String::new();
+        std::io::Read::read_to_string(&mut listener.accept().unwrap().0, &mut received).unwrap();
+        assert_eq!(received, "localhost.linev 120.5 1700000000\n");
+    }
+}
@@ -0,0 +1,65 @@
+//! sinks/kafka.rs
+//!
+//! Publishes a JSON snapshot of every poll to a Kafka topic.
+
+use rdkafka::config::ClientConfig;
+use rdkafka::producer::{BaseRecord, DefaultProducerContext, ThreadedProducer};
+use tracing::error;
+
+use super::{Sink, SinkError};
+use crate::snapshot::Snapshot;
+
+pub struct KafkaSink {
+    producer: ThreadedProducer<DefaultProducerContext>,
+    topic: String,
+}
+
+impl KafkaSink {
+    /// Build the sink from `KAFKA_*` environment variables. Returns `None`
+    /// unless `KAFKA_BROKERS` is set, or if the producer can't be created.
+    ///
+    /// Authentication is configured with `KAFKA_SECURITY_PROTOCOL`,
+    /// `KAFKA_SASL_MECHANISM`, `KAFKA_SASL_USERNAME` and `KAFKA_SASL_PASSWORD`,
+    /// which map directly onto the librdkafka properties of the same name.
+    pub fn from_env() -> Option<Self> {
+        let brokers = std::env::var("KAFKA_BROKERS").ok()?;
+        let topic = std::env::var("KAFKA_TOPIC").unwrap_or_else(|_| "apcupsd".to_string());
+
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &brokers)
+            .set("message.timeout.ms", "30000");
+        for (var, property) in [
+            ("KAFKA_SECURITY_PROTOCOL", "security.protocol"),
+            ("KAFKA_SASL_MECHANISM", "sasl.mechanism"),
+            ("KAFKA_SASL_USERNAME", "sasl.username"),
+            ("KAFKA_SASL_PASSWORD", "sasl.password"),
+        ] {
+            if let Ok(value) = std::env::var(var) {
+                config.set(property, value);
+            }
+        }
+
+        match config.create() {
+            Ok(producer) => Some(KafkaSink { producer, topic }),
+            Err(e) => {
+                error!("Failed to create Kafka producer for {}: {}", brokers, e);
+                None
+            }
+        }
+    }
+}
+
+impl Sink for KafkaSink {
+    fn name(&self) -> &'static str {
+        "kafka"
+    }
+
+    fn publish(&mut self, snapshot: &Snapshot) -> Result<(), SinkError> {
+        let payload = snapshot.to_json().to_string();
+        let record = BaseRecord::to(&self.topic)
+            .key(snapshot.hostname())
+            .payload(&payload);
+        self.producer.send(record).map_err(|(e, _)| SinkError::Kafka(e))
+    }
+}
@@ -0,0 +1,210 @@
+//! sinks/mod.rs
+//!
+//! Push-based outputs that receive every poll result, for monitoring systems
+//! that don't scrape Prometheus.
+
+pub mod graphite;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "nats")]
+pub mod nats;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod statsd;
+pub mod zabbix;
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::mpsc;
+use std::thread;
+
+use tracing::{info, warn};
+
+use crate::energy::Metered;
+use crate::events::Event;
+use crate::snapshot::Snapshot;
+
+/// Error type for sink operations
+#[derive(Debug)]
+pub enum SinkError {
+    IoError(std::io::Error),
+    Protocol(String),
+    #[cfg(feature = "kafka")]
+    Kafka(rdkafka::error::KafkaError),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+}
+
+impl From<std::io::Error> for SinkError {
+    fn from(err: std::io::Error) -> Self {
+        SinkError::IoError(err)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for SinkError {
+    fn from(err: rusqlite::Error) -> Self {
+        SinkError::Sqlite(err)
+    }
+}
+
+impl std::fmt::Display for SinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SinkError::IoError(e) => write!(f, "IO Error: {}", e),
+            SinkError::Protocol(reason) => write!(f, "Protocol Error: {}", reason),
+            #[cfg(feature = "kafka")]
+            SinkError::Kafka(e) => write!(f, "Kafka Error: {}", e),
+            #[cfg(feature = "sqlite")]
+            SinkError::Sqlite(e) => write!(f, "SQLite Error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SinkError {}
+
+/// A destination that poll results are pushed to.
+pub trait Sink: Send {
+    /// Short name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Push a single poll result to the destination.
+    fn publish(&mut self, snapshot: &Snapshot) -> Result<(), SinkError>;
+
+    /// Keep what a target used in total, for sinks that persist it across
+    /// restarts. Others ignore it.
+    fn record_energy(&mut self, _target: &str, _total: &Metered) -> Result<(), SinkError> {
+        Ok(())
+    }
+
+    /// Keep the events of a poll, for sinks that log them. Others ignore
+    /// them.
+    fn record_events(&mut self, _events: &[Event]) -> Result<(), SinkError> {
+        Ok(())
+    }
+}
+
+/// Build every sink that is enabled through the environment.
+pub async fn from_env() -> Vec<Box<dyn Sink>> {
+    let mut sinks: Vec<Box<dyn Sink>> = Vec::new();
+
+    if let Some(sink) = graphite::GraphiteSink::from_env() {
+        sinks.push(Box::new(sink));
+    }
+    if let Some(sink) = statsd::StatsdSink::from_env() {
+        sinks.push(Box::new(sink));
+    }
+    if let Some(sink) = zabbix::ZabbixSink::from_env() {
+        sinks.push(Box::new(sink));
+    }
+    #[cfg(feature = "kafka")]
+    if let Some(sink) = kafka::KafkaSink::from_env() {
+        sinks.push(Box::new(sink));
+    }
+    #[cfg(feature = "nats")]
+    if let Some(sink) = nats::NatsSink::from_env().await {
+        sinks.push(Box::new(sink));
+    }
+    #[cfg(feature = "postgres")]
+    if let Some(sink) = postgres::PostgresSink::from_env() {
+        sinks.push(Box::new(sink));
+    }
+
+    #[cfg(feature = "sqlite")]
+    match crate::history::sqlite::SqliteSink::from_env() {
+        Some(Ok(sink)) => sinks.push(Box::new(sink)),
+        Some(Err(e)) => tracing::error!("Failed to open SQLite history database: {}", e),
+        None => {}
+    }
+
+    for sink in &sinks {
+        info!("Enabled {} sink", sink.name());
+    }
+    sinks
+}
+
+/// Batches waiting for a sender thread before new ones are dropped
+const QUEUE_SIZE: usize = 16;
+
+/// Hands batches to a thread of their own for sinks whose I/O blocks, so a
+/// slow or unreachable destination never stalls the poll loop.
+pub(crate) struct Sender<T> {
+    sender: mpsc::SyncSender<T>,
+}
+
+impl<T: Send + 'static> Sender<T> {
+    /// Start the thread, which sends each batch and logs failures. It stops
+    /// with the sink.
+    pub fn spawn(name: &'static str, mut send: impl FnMut(T) -> Result<(), SinkError> + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<T>(QUEUE_SIZE);
+        thread::spawn(move || {
+            for batch in receiver {
+                if let Err(e) = send(batch) {
+                    warn!("Failed to publish to {} sink: {}", name, e);
+                }
+            }
+        });
+        Sender { sender }
+    }
+
+    /// Queue a batch without waiting.
+    pub fn queue(&self, batch: T) -> Result<(), SinkError> {
+        self.sender.try_send(batch).map_err(|e| match e {
+            mpsc::TrySendError::Full(_) => SinkError::Protocol("send queue is full, dropping batch".to_string()),
+            mpsc::TrySendError::Disconnected(_) => SinkError::Protocol("sender thread has stopped".to_string()),
+        })
+    }
+}
+
+/// Resolve the host and connect a UDP socket to it, bound to the unspecified
+/// address of the host's family so that IPv6-only hosts can be reached.
+pub(crate) fn udp_connect(host: &str, port: u16) -> std::io::Result<UdpSocket> {
+    let addr = (host, port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, format!("no address for {}", host)))?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local)?;
+    socket.connect(addr)?;
+    Ok(socket)
+}
+
+/// Replace characters that carry meaning in dotted metric paths.
+pub(crate) fn sanitize(component: &str) -> String {
+    component
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// Push a poll result to all sinks, logging (but otherwise ignoring) failures.
+pub fn publish_all(sinks: &mut [Box<dyn Sink>], snapshot: &Snapshot) {
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.publish(snapshot) {
+            warn!("Failed to publish to {} sink: {}", sink.name(), e);
+        }
+    }
+}
+
+/// Hand a target's energy totals to all sinks, logging failures.
+pub fn record_energy_all(sinks: &mut [Box<dyn Sink>], target: &str, total: &Metered) {
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.record_energy(target, total) {
+            warn!("Failed to record the energy of {} in {} sink: {}", target, sink.name(), e);
+        }
+    }
+}
+
+/// Hand the events of a poll to all sinks, logging failures.
+pub fn record_events_all(sinks: &mut [Box<dyn Sink>], events: &[Event]) {
+    if events.is_empty() {
+        return;
+    }
+    for sink in sinks.iter_mut() {
+        if let Err(e) = sink.record_events(events) {
+            warn!("Failed to record events in {} sink: {}", sink.name(), e);
+        }
+    }
+}
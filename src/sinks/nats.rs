@@ -0,0 +1,123 @@
+//! sinks/nats.rs
+//!
+//! Publishes poll snapshots and STATUS transitions to NATS subjects.
+
+use std::collections::HashMap;
+
+use async_nats::{Client, ConnectOptions};
+use tracing::{error, warn};
+
+use super::{sanitize, Sink, SinkError};
+use crate::snapshot::Snapshot;
+
+pub struct NatsSink {
+    client: Client,
+    jetstream: Option<async_nats::jetstream::Context>,
+    prefix: String,
+    last_status: HashMap<String, String>,
+}
+
+impl NatsSink {
+    /// Build the sink from `NATS_*` environment variables. Returns `None`
+    /// unless `NATS_URL` is set, or if the options are invalid.
+    ///
+    /// The connection is established in the background, so an unreachable
+    /// server at startup doesn't prevent the exporter from starting.
+    pub async fn from_env() -> Option<Self> {
+        let urls = std::env::var("NATS_URL").ok()?;
+        let servers: Vec<&str> = urls.split(',').map(str::trim).filter(|s| !s.is_empty()).collect();
+        let prefix = std::env::var("NATS_SUBJECT_PREFIX").unwrap_or_else(|_| "ups".to_string());
+        let use_jetstream = std::env::var("NATS_JETSTREAM")
+            .map(|v| matches!(v.as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+
+        let mut options = ConnectOptions::new().retry_on_initial_connect();
+        if let Ok(path) = std::env::var("NATS_CREDS") {
+            options = match options.credentials_file(&path).await {
+                Ok(options) => options,
+                Err(e) => {
+                    error!("Failed to read NATS credentials file {}: {}", path, e);
+                    return None;
+                }
+            };
+        }
+        if let Ok(token) = std::env::var("NATS_TOKEN") {
+            options = options.token(token);
+        }
+        if let (Ok(user), Ok(password)) = (std::env::var("NATS_USER"), std::env::var("NATS_PASSWORD")) {
+            options = options.user_and_password(user, password);
+        }
+
+        let client = match options.connect(servers.as_slice()).await {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed to connect to NATS at {}: {}", urls, e);
+                return None;
+            }
+        };
+        let jetstream = use_jetstream.then(|| async_nats::jetstream::new(client.clone()));
+
+        Some(NatsSink {
+            client,
+            jetstream,
+            prefix,
+            last_status: HashMap::new(),
+        })
+    }
+
+    /// Subject for the given UPS and message kind, e.g. `ups.rack1.status`.
+    fn subject(&self, snapshot: &Snapshot, kind: &str) -> String {
+        let name = snapshot
+            .stats
+            .get("UPSNAME")
+            .map(String::as_str)
+            .filter(|n| !n.is_empty())
+            .unwrap_or(snapshot.hostname());
+        format!("{}.{}.{}", self.prefix, sanitize(name), kind)
+    }
+
+    /// Publish without waiting, so a slow server never stalls the poll loop.
+    fn send(&self, subject: String, payload: String) {
+        let client = self.client.clone();
+        let jetstream = self.jetstream.clone();
+        tokio::spawn(async move {
+            let result = match jetstream {
+                Some(jetstream) => match jetstream.publish(subject.clone(), payload.into()).await {
+                    Ok(ack) => ack.await.map(|_| ()).map_err(|e| e.to_string()),
+                    Err(e) => Err(e.to_string()),
+                },
+                None => client.publish(subject.clone(), payload.into()).await.map_err(|e| e.to_string()),
+            };
+            if let Err(e) = result {
+                warn!("Failed to publish to NATS subject {}: {}", subject, e);
+            }
+        });
+    }
+}
+
+impl Sink for NatsSink {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    fn publish(&mut self, snapshot: &Snapshot) -> Result<(), SinkError> {
+        self.send(self.subject(snapshot, "status"), snapshot.to_json().to_string());
+
+        // Emit an event whenever STATUS changes, e.g. ONLINE -> ONBATT
+        if let Some(status) = snapshot.stats.get("STATUS") {
+            let subject = self.subject(snapshot, "events");
+            let previous = self.last_status.insert(subject.clone(), status.clone());
+            if let Some(previous) = previous.filter(|p| p != status) {
+                let event = serde_json::json!({
+                    "host": snapshot.host,
+                    "hostname": snapshot.hostname(),
+                    "timestamp": snapshot.unix_timestamp(),
+                    "from": previous,
+                    "to": status,
+                });
+                self.send(subject, event.to_string());
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,184 @@
+//! sinks/postgres.rs
+//!
+//! Writes poll snapshots into a PostgreSQL table or TimescaleDB hypertable.
+
+use postgres_native_tls::MakeTlsConnector;
+use tokio::sync::mpsc;
+use tokio_postgres::Client;
+use tracing::{error, info, warn};
+
+use super::{Sink, SinkError};
+use crate::snapshot::Snapshot;
+
+/// Batches waiting to be written before new ones are dropped
+const QUEUE_SIZE: usize = 16;
+
+/// Rows of a batch, in the column layout used by the insert statement.
+#[derive(Default)]
+struct Batch {
+    timestamps: Vec<f64>,
+    hosts: Vec<String>,
+    keys: Vec<String>,
+    values: Vec<f64>,
+}
+
+impl Batch {
+    fn push(&mut self, snapshot: &Snapshot) {
+        let timestamp = snapshot.unix_timestamp() as f64;
+        for (key, value) in snapshot.numeric_values() {
+            self.timestamps.push(timestamp);
+            self.hosts.push(snapshot.host.clone());
+            self.keys.push(key.to_string());
+            self.values.push(value);
+        }
+    }
+}
+
+pub struct PostgresSink {
+    batch: Batch,
+    snapshots: usize,
+    batch_size: usize,
+    sender: mpsc::Sender<Batch>,
+}
+
+impl PostgresSink {
+    /// Build the sink from `POSTGRES_*` environment variables. Returns `None`
+    /// unless `POSTGRES_DSN` is set, or if the table name is invalid.
+    ///
+    /// Batches are written by a background task that (re)connects as needed,
+    /// so an unreachable database never stalls the poll loop.
+    pub fn from_env() -> Option<Self> {
+        let dsn = std::env::var("POSTGRES_DSN").ok()?;
+        let table = std::env::var("POSTGRES_TABLE").unwrap_or_else(|_| "apcupsd_samples".to_string());
+        let batch_size: usize = std::env::var("POSTGRES_BATCH_SIZE")
+            .ok()
+            .and_then(|b| b.parse().ok())
+            .filter(|&b| b > 0)
+            .unwrap_or(1);
+        let timescale = std::env::var("POSTGRES_TIMESCALE")
+            .map(|v| matches!(v.as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+
+        if !is_valid_identifier(&table) {
+            error!("Invalid POSTGRES_TABLE {:?}, expected letters, digits, '_' and an optional schema", table);
+            return None;
+        }
+
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(writer(dsn, table, timescale, receiver));
+
+        Some(PostgresSink {
+            batch: Batch::default(),
+            snapshots: 0,
+            batch_size,
+            sender,
+        })
+    }
+}
+
+impl Sink for PostgresSink {
+    fn name(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn publish(&mut self, snapshot: &Snapshot) -> Result<(), SinkError> {
+        self.batch.push(snapshot);
+        self.snapshots += 1;
+        if self.snapshots < self.batch_size {
+            return Ok(());
+        }
+
+        self.snapshots = 0;
+        self.sender
+            .try_send(std::mem::take(&mut self.batch))
+            .map_err(|_| SinkError::Protocol("write queue is full, dropping batch".to_string()))
+    }
+}
+
+/// Allow `table` or `schema.table` made of identifier characters only, since
+/// the name is interpolated into SQL.
+fn is_valid_identifier(name: &str) -> bool {
+    let parts: Vec<&str> = name.split('.').collect();
+    parts.len() <= 2
+        && parts.iter().all(|p| {
+            !p.is_empty()
+                && !p.starts_with(|c: char| c.is_ascii_digit())
+                && p.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        })
+}
+
+async fn connect(dsn: &str, table: &str, timescale: bool) -> Result<Client, Box<dyn std::error::Error + Send + Sync>> {
+    let tls = MakeTlsConnector::new(native_tls::TlsConnector::new()?);
+    let (client, connection) = tokio_postgres::connect(dsn, tls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            warn!("PostgreSQL connection closed: {}", e);
+        }
+    });
+
+    client
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {} (
+                time  TIMESTAMPTZ      NOT NULL,
+                host  TEXT             NOT NULL,
+                key   TEXT             NOT NULL,
+                value DOUBLE PRECISION NOT NULL
+            )",
+            table
+        ))
+        .await?;
+    if timescale {
+        client
+            .execute(
+                "SELECT create_hypertable($1::text::regclass, 'time', if_not_exists => TRUE)",
+                &[&table],
+            )
+            .await?;
+    }
+    info!("Connected to PostgreSQL, writing to {}", table);
+    Ok(client)
+}
+
+/// Background task owning the connection and writing batches as they arrive.
+async fn writer(dsn: String, table: String, timescale: bool, mut receiver: mpsc::Receiver<Batch>) {
+    let insert = format!(
+        "INSERT INTO {} (time, host, key, value)
+         SELECT to_timestamp(t), h, k, v FROM unnest($1::float8[], $2::text[], $3::text[], $4::float8[]) AS u(t, h, k, v)",
+        table
+    );
+    let mut client: Option<Client> = None;
+
+    while let Some(batch) = receiver.recv().await {
+        if client.as_ref().is_none_or(Client::is_closed) {
+            client = match connect(&dsn, &table, timescale).await {
+                Ok(c) => Some(c),
+                Err(e) => {
+                    warn!("Failed to connect to PostgreSQL, dropping batch: {}", e);
+                    continue;
+                }
+            };
+        }
+        let Some(c) = &client else { continue };
+
+        if let Err(e) = c
+            .execute(&insert, &[&batch.timestamps, &batch.hosts, &batch.keys, &batch.values])
+            .await
+        {
+            warn!("Failed to write batch to PostgreSQL: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_identifier() {
+        assert!(is_valid_identifier("apcupsd_samples"));
+        assert!(is_valid_identifier("metrics.ups"));
+        assert!(!is_valid_identifier("samples; DROP TABLE x"));
+        assert!(!is_valid_identifier("a.b.c"));
+        assert!(!is_valid_identifier("1table"));
+    }
+}
@@ -0,0 +1,185 @@
+//! sinks/statsd.rs
+//!
+//! Emits numeric values as StatsD gauges over UDP, optionally with DogStatsD
+//! tags, from a thread of its own.
+
+use std::net::UdpSocket;
+
+use super::{sanitize, udp_connect, Sender, Sink, SinkError};
+use crate::snapshot::Snapshot;
+
+/// Default StatsD port
+const DEFAULT_PORT: u16 = 8125;
+
+/// Largest UDP datagram we send, to stay under common MTUs
+const MAX_DATAGRAM: usize = 1400;
+
+/// Where the gauges go, over a socket kept between polls
+struct Statsd {
+    host: String,
+    port: u16,
+    socket: Option<UdpSocket>,
+}
+
+impl Statsd {
+    fn send(&mut self, lines: Vec<String>) -> Result<(), SinkError> {
+        let result = self.send_datagrams(lines);
+        if result.is_err() {
+            // Re-resolve the host on the next poll
+            self.socket = None;
+        }
+        result
+    }
+
+    fn send_datagrams(&mut self, lines: Vec<String>) -> Result<(), SinkError> {
+        if self.socket.is_none() {
+            self.socket = Some(udp_connect(&self.host, self.port)?);
+        }
+        let socket = self.socket.as_ref().expect("socket was just created");
+        let mut datagram = String::new();
+        for line in lines {
+            if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM {
+                socket.send(datagram.as_bytes())?;
+                datagram.clear();
+            }
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+            datagram.push_str(&line);
+        }
+        socket.send(datagram.as_bytes())?;
+        Ok(())
+    }
+}
+
+pub struct StatsdSink {
+    prefix: String,
+    dogstatsd: bool,
+    tags: Vec<String>,
+    sender: Sender<Vec<String>>,
+}
+
+impl StatsdSink {
+    pub fn new(host: &str, port: u16, prefix: &str, dogstatsd: bool, tags: Vec<String>) -> Self {
+        let mut statsd = Statsd {
+            host: host.to_string(),
+            port,
+            socket: None,
+        };
+        StatsdSink {
+            prefix: prefix.trim_end_matches('.').to_string(),
+            dogstatsd,
+            tags,
+            sender: Sender::spawn("statsd", move |lines| statsd.send(lines)),
+        }
+    }
+
+    /// Build the sink from `STATSD_*` environment variables. Returns `None`
+    /// unless `STATSD_HOST` is set.
+    pub fn from_env() -> Option<Self> {
+        let host = std::env::var("STATSD_HOST").ok()?;
+        let port: u16 = std::env::var("STATSD_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(DEFAULT_PORT);
+        let prefix = std::env::var("STATSD_PREFIX").unwrap_or_else(|_| "apcupsd".to_string());
+        let dogstatsd = std::env::var("STATSD_DOGSTATSD")
+            .map(|v| matches!(v.as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        let tags = std::env::var("STATSD_TAGS")
+            .map(|t| t.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default();
+        Some(StatsdSink::new(&host, port, &prefix, dogstatsd, tags))
+    }
+
+    /// Render the snapshot as StatsD gauge lines.
+    ///
+    /// Plain StatsD has no tags, so the host becomes part of the metric name.
+    /// With DogStatsD the host and UPS name are sent as tags instead.
+    pub fn render(&self, snapshot: &Snapshot) -> Vec<String> {
+        let host = sanitize(snapshot.hostname());
+        let tags = if self.dogstatsd {
+            let mut tags = vec![format!("host:{}", snapshot.hostname())];
+            if let Some(upsname) = snapshot.stats.get("UPSNAME").filter(|n| !n.is_empty()) {
+                tags.push(format!("upsname:{}", upsname));
+            }
+            tags.extend(self.tags.iter().cloned());
+            format!("|#{}", tags.join(","))
+        } else {
+            String::new()
+        };
+
+        snapshot
+            .numeric_values()
+            .map(|(key, value)| {
+                let key = sanitize(&key.to_lowercase());
+                let name = match (self.prefix.is_empty(), self.dogstatsd) {
+                    (true, true) => key,
+                    (false, true) => format!("{}.{}", self.prefix, key),
+                    (true, false) => format!("{}.{}", host, key),
+                    (false, false) => format!("{}.{}.{}", self.prefix, host, key),
+                };
+                format!("{}:{}|g{}", name, value, tags)
+            })
+            .collect()
+    }
+}
+
+impl Sink for StatsdSink {
+    fn name(&self) -> &'static str {
+        "statsd"
+    }
+
+    fn publish(&mut self, snapshot: &Snapshot) -> Result<(), SinkError> {
+        let lines = self.render(snapshot);
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        self.sender.queue(lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn snapshot() -> Snapshot {
+        let stats = BTreeMap::from([
+            ("HOSTNAME".to_string(), "nas".to_string()),
+            ("UPSNAME".to_string(), "rack1".to_string()),
+            ("LOADPCT".to_string(), "15.0".to_string()),
+        ]);
+        Snapshot::new("localhost", stats)
+    }
+
+    #[test]
+    fn test_render_plain() {
+        let sink = StatsdSink::new("statsd", DEFAULT_PORT, "apcupsd", false, Vec::new());
+        assert_eq!(sink.render(&snapshot()), vec!["apcupsd.nas.loadpct:15|g"]);
+    }
+
+    #[test]
+    fn test_render_dogstatsd() {
+        let sink = StatsdSink::new("statsd", DEFAULT_PORT, "apcupsd", true, vec!["env:prod".to_string()]);
+        assert_eq!(
+            sink.render(&snapshot()),
+            vec!["apcupsd.loadpct:15|g|#host:nas,upsname:rack1,env:prod"]
+        );
+    }
+
+    #[test]
+    fn test_publish_in_background() {
+        // IPv6 may be unavailable where the tests run
+        for address in ["127.0.0.1", "::1"] {
+            let Ok(receiver) = UdpSocket::bind((address, 0)) else { continue };
+            receiver.set_read_timeout(Some(std::time::Duration::from_secs(5))).unwrap();
+            let mut sink = StatsdSink::new(address, receiver.local_addr().unwrap().port(), "apcupsd", false, Vec::new());
+            sink.publish(&snapshot()).unwrap();
+            let mut datagram = [0; MAX_DATAGRAM];
+            let len = receiver.recv(&mut datagram).unwrap();
+            assert_eq!(&datagram[..len], b"apcupsd.nas.loadpct:15|g");
+        }
+    }
+}
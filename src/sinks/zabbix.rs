@@ -0,0 +1,189 @@
+//! sinks/zabbix.rs
+//!
+//! Pushes values to a Zabbix server or proxy using the sender (trapper)
+//! protocol, from a thread of its own.
+
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use tracing::debug;
+
+use super::{Sender, Sink, SinkError};
+use crate::snapshot::Snapshot;
+
+/// Default Zabbix trapper port
+const DEFAULT_PORT: u16 = 10051;
+
+/// Protocol header: magic followed by the "ZBX_TCP_PROTOCOL" flag
+const HEADER: &[u8] = b"ZBXD\x01";
+
+/// Refuse responses larger than this, the server only sends a short summary
+const MAX_RESPONSE: u64 = 64 * 1024;
+
+/// The server or proxy the requests go to
+struct Trapper {
+    server: String,
+    port: u16,
+    timeout: Duration,
+}
+
+impl Trapper {
+    fn send(&self, payload: String) -> Result<(), SinkError> {
+        let addr = (self.server.as_str(), self.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no address for zabbix server"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.write_all(&encode(payload.as_bytes()))?;
+
+        let response = read_response(&mut stream)?;
+        if response["response"] != "success" {
+            return Err(SinkError::Protocol(format!("server rejected data: {}", response)));
+        }
+        debug!("Zabbix server response: {}", response["info"]);
+        Ok(())
+    }
+}
+
+pub struct ZabbixSink {
+    host_format: String,
+    key_format: String,
+    sender: Sender<String>,
+}
+
+impl ZabbixSink {
+    pub fn new(server: &str, port: u16, host_format: &str, key_format: &str, timeout: Duration) -> Self {
+        let trapper = Trapper {
+            server: server.to_string(),
+            port,
+            timeout,
+        };
+        ZabbixSink {
+            host_format: host_format.to_string(),
+            key_format: key_format.to_string(),
+            sender: Sender::spawn("zabbix", move |payload| trapper.send(payload)),
+        }
+    }
+
+    /// Build the sink from `ZABBIX_*` environment variables. Returns `None`
+    /// unless `ZABBIX_SERVER` is set.
+    pub fn from_env() -> Option<Self> {
+        let server = std::env::var("ZABBIX_SERVER").ok()?;
+        let port: u16 = std::env::var("ZABBIX_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .unwrap_or(DEFAULT_PORT);
+        let host_format = std::env::var("ZABBIX_HOST").unwrap_or_else(|_| "{hostname}".to_string());
+        let key_format = std::env::var("ZABBIX_KEY_FORMAT").unwrap_or_else(|_| "apcupsd.{key}".to_string());
+        let timeout: u64 = std::env::var("ZABBIX_TIMEOUT")
+            .ok()
+            .and_then(|t| t.parse().ok())
+            .unwrap_or(5);
+        Some(ZabbixSink::new(&server, port, &host_format, &key_format, Duration::from_secs(timeout)))
+    }
+
+    /// Build the "sender data" request for a snapshot.
+    ///
+    /// `{hostname}`, `{upsname}` and `{host}` are substituted in the host
+    /// format, and `{key}` (the lowercased apcupsd key) in the key format.
+    pub fn request(&self, snapshot: &Snapshot) -> serde_json::Value {
+        let upsname = snapshot.stats.get("UPSNAME").map(String::as_str).unwrap_or_default();
+        let host = self
+            .host_format
+            .replace("{hostname}", snapshot.hostname())
+            .replace("{upsname}", upsname)
+            .replace("{host}", &snapshot.host);
+        let clock = snapshot.unix_timestamp();
+
+        let data: Vec<serde_json::Value> = snapshot
+            .stats
+            .iter()
+            .map(|(key, value)| {
+                serde_json::json!({
+                    "host": host,
+                    "key": self.key_format.replace("{key}", &key.to_lowercase()),
+                    "value": value,
+                    "clock": clock,
+                })
+            })
+            .collect();
+
+        serde_json::json!({
+            "request": "sender data",
+            "data": data,
+            "clock": clock,
+        })
+    }
+}
+
+/// Frame a payload with the Zabbix protocol header and little-endian length.
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER.len() + 8 + payload.len());
+    frame.extend_from_slice(HEADER);
+    frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    frame.extend_from_slice(&[0u8; 4]);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Read a framed response and return its JSON body.
+fn read_response(stream: &mut impl Read) -> Result<serde_json::Value, SinkError> {
+    let mut header = [0u8; 13];
+    stream.read_exact(&mut header)?;
+    if &header[..4] != b"ZBXD" {
+        return Err(SinkError::Protocol("response is missing the ZBXD header".to_string()));
+    }
+    let len = u32::from_le_bytes([header[5], header[6], header[7], header[8]]) as u64;
+    if len > MAX_RESPONSE {
+        return Err(SinkError::Protocol(format!("response of {} bytes is too large", len)));
+    }
+    let mut body = Vec::new();
+    stream.take(len).read_to_end(&mut body)?;
+    serde_json::from_slice(&body).map_err(|e| SinkError::Protocol(format!("invalid response: {}", e)))
+}
+
+impl Sink for ZabbixSink {
+    fn name(&self) -> &'static str {
+        "zabbix"
+    }
+
+    fn publish(&mut self, snapshot: &Snapshot) -> Result<(), SinkError> {
+        self.sender.queue(self.request(snapshot).to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_request() {
+        let stats = BTreeMap::from([
+            ("HOSTNAME".to_string(), "nas".to_string()),
+            ("UPSNAME".to_string(), "rack1".to_string()),
+            ("LINEV".to_string(), "120.0".to_string()),
+        ]);
+        let snapshot = Snapshot::new("localhost", stats);
+        let sink = ZabbixSink::new("zabbix", DEFAULT_PORT, "ups-{upsname}", "apcupsd[{key}]", Duration::from_secs(1));
+        let request = sink.request(&snapshot);
+        assert_eq!(request["request"], "sender data");
+        let linev = &request["data"][1];
+        assert_eq!(linev["host"], "ups-rack1");
+        assert_eq!(linev["key"], "apcupsd[linev]");
+        assert_eq!(linev["value"], "120.0");
+    }
+
+    #[test]
+    fn test_encode_and_read_response() {
+        let frame = encode(br#"{"response":"success","info":"processed: 1"}"#);
+        assert_eq!(&frame[..5], b"ZBXD\x01");
+        assert_eq!(u32::from_le_bytes([frame[5], frame[6], frame[7], frame[8]]), 44);
+
+        let response = read_response(&mut frame.as_slice()).unwrap();
+        assert_eq!(response["response"], "success");
+    }
+}
@@ -0,0 +1,139 @@
+//! snapshot.rs
+//!
+//! A single poll result from the apcupsd NIS, shared by the metrics and sinks.
+
+use std::collections::BTreeMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Keys that describe the UPS rather than measure it. These are exported as
+/// labels on the metadata metric instead of as gauges.
+pub const INFO_KEYS: &[&str] = &[
+    "APC",
+    "HOSTNAME",
+    "UPSNAME",
+    "VERSION",
+    "CABLE",
+    "MODEL",
+    "UPSMODE",
+    "DRIVER",
+    "APCMODEL",
+];
+
+/// The parsed status of one UPS at a point in time.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The apcupsd host the status was fetched from
+    pub host: String,
+    /// When the status was fetched
+    pub timestamp: SystemTime,
+    /// The parsed key-value pairs
+    pub stats: BTreeMap<String, String>,
+}
+
+impl Snapshot {
+    /// The status as the exporter uses it, whatever the quirks of the
+    /// apcupsd version that reported it
+    pub fn new(host: &str, stats: BTreeMap<String, String>) -> Self {
+        Snapshot {
+            host: host.to_string(),
+            timestamp: SystemTime::now(),
+            stats: crate::quirks::apply(stats),
+        }
+    }
+
+    /// Seconds since the Unix epoch at which the status was fetched.
+    pub fn unix_timestamp(&self) -> u64 {
+        self.timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+
+    /// The name the UPS reports for its host, falling back to the polled host.
+    pub fn hostname(&self) -> &str {
+        self.stats
+            .get("HOSTNAME")
+            .map(String::as_str)
+            .filter(|h| !h.is_empty())
+            .unwrap_or(&self.host)
+    }
+
+    /// apcupsd's `DATE` minus the time of the poll in seconds, how far the
+    /// clock of the UPS host is off from ours give or take a second.
+    pub fn clock_skew(&self) -> Option<f64> {
+        let date = apcaccess::ApcStatus::from(&self.stats).date?;
+        let polled = self.timestamp.duration_since(UNIX_EPOCH).ok()?.as_secs_f64();
+        Some(date.timestamp() as f64 - polled)
+    }
+
+    /// Serialize the snapshot as a JSON object. Values that parse as numbers
+    /// are emitted as JSON numbers, everything else as strings.
+    pub fn to_json(&self) -> serde_json::Value {
+        let stats: serde_json::Map<String, serde_json::Value> = self
+            .stats
+            .iter()
+            .map(|(key, value)| {
+                let value = match value.parse::<f64>() {
+                    Ok(v) if !INFO_KEYS.contains(&key.as_str()) => serde_json::json!(v),
+                    _ => serde_json::json!(value),
+                };
+                (key.clone(), value)
+            })
+            .collect();
+        serde_json::json!({
+            "host": self.host,
+            "hostname": self.hostname(),
+            "timestamp": self.unix_timestamp(),
+            "stats": stats,
+        })
+    }
+
+    /// Iterate over all non-info values that parse as numbers.
+    pub fn numeric_values(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.stats.iter().filter_map(|(key, value)| {
+            if INFO_KEYS.contains(&key.as_str()) {
+                return None;
+            }
+            value.parse::<f64>().ok().map(|v| (key.as_str(), v))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_numeric_values_skips_info_and_text() {
+        let stats = BTreeMap::from([
+            ("HOSTNAME".to_string(), "nas".to_string()),
+            ("STATUS".to_string(), "ONLINE".to_string()),
+            ("LINEV".to_string(), "120.0".to_string()),
+        ]);
+        let snapshot = Snapshot::new("localhost", stats);
+        let values: Vec<_> = snapshot.numeric_values().collect();
+        assert_eq!(values, vec![("LINEV", 120.0)]);
+        assert_eq!(snapshot.hostname(), "nas");
+    }
+
+    #[test]
+    fn test_clock_skew() {
+        let stats = BTreeMap::from([("DATE".to_string(), "2024-06-01 14:00:30 +0200".to_string())]);
+        let mut snapshot = Snapshot::new("localhost", stats);
+        snapshot.timestamp = UNIX_EPOCH + std::time::Duration::from_secs(1717243200);
+        assert_eq!(snapshot.clock_skew(), Some(30.0));
+        assert_eq!(Snapshot::new("localhost", BTreeMap::new()).clock_skew(), None);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let stats = BTreeMap::from([
+            ("APC".to_string(), "001,036,0876".to_string()),
+            ("LINEV".to_string(), "120.0".to_string()),
+        ]);
+        let json = Snapshot::new("localhost", stats).to_json();
+        assert_eq!(json["host"], "localhost");
+        assert_eq!(json["stats"]["APC"], "001,036,0876");
+        assert_eq!(json["stats"]["LINEV"], 120.0);
+    }
+}
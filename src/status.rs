@@ -0,0 +1,112 @@
+//! status.rs
+//!
+//! Human-readable rendering of apcupsd values for the CLI, in place of
+//! running `apcaccess` on the monitoring host.
+
+use std::collections::BTreeMap;
+
+/// A well-known apcupsd key, with a description and the unit that was
+/// stripped from its value
+pub struct Field {
+    pub key: &'static str,
+    pub label: &'static str,
+    pub unit: &'static str,
+}
+
+const fn field(key: &'static str, label: &'static str, unit: &'static str) -> Field {
+    Field { key, label, unit }
+}
+
+/// Known keys, in the order they are shown. Anything else follows
+/// alphabetically, without a description.
+pub const FIELDS: &[Field] = &[
+    field("UPSNAME", "UPS name", ""),
+    field("MODEL", "Model", ""),
+    field("STATUS", "Status", ""),
+    field("BCHARGE", "Battery charge", "%"),
+    field("TIMELEFT", "Runtime left", "min"),
+    field("LOADPCT", "Load", "%"),
+    field("NOMPOWER", "Nominal power", "W"),
+    field("NOMAPNT", "Nominal apparent power", "VA"),
+    field("LINEV", "Line voltage", "V"),
+    field("LINEFREQ", "Line frequency", "Hz"),
+    field("OUTPUTV", "Output voltage", "V"),
+    field("BATTV", "Battery voltage", "V"),
+    field("NOMBATTV", "Nominal battery voltage", "V"),
+    field("NOMINV", "Nominal input voltage", "V"),
+    field("NOMOUTV", "Nominal output voltage", "V"),
+    field("ITEMP", "Internal temperature", "°C"),
+    field("HITRANS", "High transfer voltage", "V"),
+    field("LOTRANS", "Low transfer voltage", "V"),
+    field("MBATTCHG", "Shutdown at charge", "%"),
+    field("MINTIMEL", "Shutdown at runtime", "min"),
+    field("MAXTIME", "Shutdown after on battery", "s"),
+    field("DLOWBATT", "Low battery signal", "min"),
+    field("TONBATT", "Time on battery", "s"),
+    field("CUMONBATT", "Total time on battery", "s"),
+    field("ALARMDEL", "Alarm delay", "s"),
+    field("NUMXFERS", "Transfers", ""),
+    field("LASTXFER", "Last transfer reason", ""),
+    field("XONBATT", "Last transfer to battery", ""),
+    field("XOFFBATT", "Last transfer from battery", ""),
+    field("SELFTEST", "Self-test result", ""),
+    field("BATTDATE", "Battery date", ""),
+    field("SERIALNO", "Serial number", ""),
+    field("FIRMWARE", "Firmware", ""),
+    field("HOSTNAME", "Host name", ""),
+    field("VERSION", "apcupsd version", ""),
+    field("DATE", "Last update", ""),
+];
+
+pub fn lookup(key: &str) -> Option<&'static Field> {
+    FIELDS.iter().find(|f| f.key == key)
+}
+
+/// A value with its unit re-attached, e.g. `97.0 %`
+pub fn format_value(key: &str, value: &str) -> String {
+    match lookup(key) {
+        Some(field) if !field.unit.is_empty() => format!("{} {}", value, field.unit),
+        _ => value.to_string(),
+    }
+}
+
+/// Render values parsed with their units stripped as a three column table of
+/// key, description and value.
+pub fn table(stats: &BTreeMap<String, String>) -> String {
+    let known = FIELDS.iter().filter(|f| stats.contains_key(f.key)).map(|f| (f.key, f.label));
+    let others = stats.keys().filter(|k| lookup(k).is_none()).map(|k| (k.as_str(), ""));
+    let rows: Vec<(&str, &str, String)> = known
+        .chain(others)
+        .map(|(key, label)| (key, label, format_value(key, &stats[key])))
+        .collect();
+
+    let key_width = rows.iter().map(|(key, _, _)| key.len()).max().unwrap_or(0);
+    let label_width = rows.iter().map(|(_, label, _)| label.chars().count()).max().unwrap_or(0);
+    let mut out = String::new();
+    for (key, label, value) in rows {
+        let line = format!("{:key_width$}  {:label_width$}  {}", key, label, value);
+        out.push_str(line.trim_end());
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_table() {
+        let stats = BTreeMap::from([
+            ("BCHARGE".to_string(), "97.0".to_string()),
+            ("STATUS".to_string(), "ONLINE".to_string()),
+            ("DRIVER".to_string(), "USB UPS Driver".to_string()),
+        ]);
+        assert_eq!(
+            table(&stats),
+            "STATUS   Status          ONLINE\n\
+             BCHARGE  Battery charge  97.0 %\n\
+             DRIVER                   USB UPS Driver\n"
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! systemd.rs
+//!
+//! Readiness, status and watchdog notifications for systemd `Type=notify`
+//! services. Everything is a no-op unless systemd set `NOTIFY_SOCKET`.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use tracing::{debug, warn};
+
+/// Send a notification such as `READY=1` or `STATUS=...`. Returns false if
+/// not running under systemd or the message couldn't be sent.
+#[cfg(unix)]
+pub fn notify(message: &str) -> bool {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return false;
+    };
+    match send(&path.to_string_lossy(), message) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Failed to notify systemd: {}", e);
+            false
+        }
+    }
+}
+
+#[cfg(unix)]
+fn send(path: &str, message: &str) -> std::io::Result<()> {
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    // A leading '@' names a socket in the abstract namespace
+    #[cfg(target_os = "linux")]
+    if let Some(name) = path.strip_prefix('@') {
+        use std::os::linux::net::SocketAddrExt;
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        socket.send_to_addr(message.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    socket.send_to(message.as_bytes(), path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn notify(_message: &str) -> bool {
+    false
+}
+
+/// Report the service status shown by `systemctl status`.
+pub fn status(status: &str) {
+    notify(&format!("STATUS={}", status));
+}
+
+/// When the poll loop last completed an iteration, successful or not
+#[derive(Default)]
+pub struct Heartbeat {
+    last: AtomicU64,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.last.store(unix_millis(SystemTime::now()), Ordering::Relaxed);
+    }
+
+    /// Time since the last beat, or since the epoch if there was none
+    pub fn age(&self) -> Duration {
+        let now = unix_millis(SystemTime::now());
+        Duration::from_millis(now.saturating_sub(self.last.load(Ordering::Relaxed)))
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+/// The watchdog interval systemd asks for through `WATCHDOG_USEC`
+fn watchdog_interval() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // Only meant for this process if WATCHDOG_PID is unset or matches
+    if let Ok(pid) = std::env::var("WATCHDOG_PID")
+        && pid.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Ping the systemd watchdog from a background thread for as long as the
+/// poll loop keeps beating within `max_age`. Once it stops, e.g. because a
+/// poll hangs, the pings stop and systemd restarts the service.
+pub fn start_watchdog(heartbeat: Arc<Heartbeat>, max_age: Duration) {
+    let Some(interval) = watchdog_interval() else {
+        return;
+    };
+    debug!("Pinging the systemd watchdog every {:?}", interval / 2);
+    thread::spawn(move || {
+        loop {
+            let age = heartbeat.age();
+            if age <= max_age {
+                notify("WATCHDOG=1");
+            } else {
+                warn!("Poll loop hasn't completed for {} seconds, not pinging the watchdog", age.as_secs());
+            }
+            thread::sleep(interval / 2);
+        }
+    });
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::net::UnixDatagram;
+
+    #[test]
+    fn test_send() {
+        let dir = std::env::temp_dir().join(format!("rsapcupsdexporter-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+
+        send(path.to_str().unwrap(), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let n = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"READY=1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let heartbeat = Heartbeat::default();
+        assert!(heartbeat.age() > Duration::from_secs(3600));
+        heartbeat.beat();
+        assert!(heartbeat.age() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,481 @@
+//! targets.rs
+//!
+//! The apcupsd hosts to poll: `APCUPSD_HOST` by default, or the `[[targets]]`
+//! of the config file, each of which may override the poll interval, timeout,
+//! retries and jitter taken from the environment.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+use tracing::error;
+
+use apcaccess::{ConnectOptions, NisClient};
+use crate::apcupsd_conf::DaemonConfig;
+use crate::config::{deserialize_duration, parse_duration};
+use crate::events::SeverityMap;
+use crate::notify::routes::Route;
+
+/// One `[[targets]]` entry of the config file
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetConfig {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    /// Value of the `target` label, and the host notifications and silences
+    /// refer to. The host, with the port unless it's 3551, if unset
+    pub name: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub interval: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub timeout: Option<Duration>,
+    /// Extra attempts within a poll before it counts as failed
+    pub retries: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub jitter: Option<Duration>,
+    /// Extra labels on the target's series
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// What happens to the target's gauges while its polls fail
+    pub on_failure: Option<FailurePolicy>,
+    /// `[group.<name>]` whose settings the target inherits
+    pub group: Option<String>,
+}
+
+/// One `[group.<name>]` of the config file: settings shared by the targets
+/// that name it, and notification routes and severities for their events
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TargetGroup {
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub interval: Option<Duration>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub timeout: Option<Duration>,
+    pub retries: Option<u32>,
+    #[serde(default, deserialize_with = "deserialize_optional_duration")]
+    pub jitter: Option<Duration>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    pub on_failure: Option<FailurePolicy>,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+    /// Severities of event types for the group's targets
+    #[serde(default)]
+    pub severities: SeverityMap,
+}
+
+/// What happens to a target's gauges while its polls fail
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FailurePolicy {
+    /// Keep the values of the last successful poll
+    #[default]
+    Hold,
+    /// Drop the gauges at the first failed poll, so `absent()` fires
+    Clear,
+    /// Keep the values this long after the last successful poll, then drop
+    /// them
+    HoldFor(Duration),
+}
+
+impl FailurePolicy {
+    /// `hold`, `clear` or `hold_for: <duration>`, e.g. `hold_for: 60s`
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim() {
+            "hold" => Some(FailurePolicy::Hold),
+            "clear" => Some(FailurePolicy::Clear),
+            value => {
+                let hold = value.strip_prefix("hold_for")?.trim_start();
+                parse_duration(hold.strip_prefix(':').unwrap_or(hold).trim()).map(FailurePolicy::HoldFor)
+            }
+        }
+    }
+
+    /// Whether the gauges go, a while after the last successful poll
+    pub fn clears(&self, since_success: Duration) -> bool {
+        match self {
+            FailurePolicy::Hold => false,
+            FailurePolicy::Clear => true,
+            FailurePolicy::HoldFor(hold) => since_success >= *hold,
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FailurePolicy {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = String::deserialize(deserializer)?;
+        FailurePolicy::parse(&value).ok_or_else(|| {
+            serde::de::Error::custom(format!("{:?} is not hold, clear or hold_for: <duration>", value))
+        })
+    }
+}
+
+fn default_port() -> u16 {
+    3551
+}
+
+fn deserialize_optional_duration<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+    deserialize_duration(deserializer).map(Some)
+}
+
+impl TargetConfig {
+    /// A target with the default settings, as discovered
+    pub fn new(host: &str, port: u16) -> Self {
+        TargetConfig {
+            host: host.to_string(),
+            port,
+            name: None,
+            interval: None,
+            timeout: None,
+            retries: None,
+            jitter: None,
+            labels: BTreeMap::new(),
+            on_failure: None,
+            group: None,
+        }
+    }
+
+    /// Take what the entry leaves out from its group. The entry's own
+    /// labels win over the group's.
+    pub fn inherit(&mut self, group: &TargetGroup) {
+        self.interval = self.interval.or(group.interval);
+        self.timeout = self.timeout.or(group.timeout);
+        self.retries = self.retries.or(group.retries);
+        self.jitter = self.jitter.or(group.jitter);
+        self.on_failure = self.on_failure.or(group.on_failure);
+        for (name, value) in &group.labels {
+            self.labels.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+    }
+
+    /// The name, or the host with the port unless it's 3551
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| match self.port {
+            3551 => self.host.clone(),
+            port => format!("{}:{}", self.host, port),
+        })
+    }
+
+    /// Fill in what the entry leaves out from the defaults.
+    pub fn resolve(&self, defaults: &Defaults) -> Target {
+        let interval = self.interval.unwrap_or(defaults.interval);
+        Target {
+            name: self.name(),
+            host: self.host.clone(),
+            port: self.port,
+            interval,
+            timeout: self.timeout.unwrap_or(defaults.timeout),
+            retries: self.retries.unwrap_or(defaults.retries),
+            jitter: self.jitter.unwrap_or(defaults.jitter).min(interval),
+            labels: self.labels.clone(),
+            on_failure: self.on_failure.unwrap_or(defaults.on_failure),
+        }
+    }
+}
+
+/// Poll settings from the environment, for targets that don't override them
+#[derive(Debug, Clone)]
+pub struct Defaults {
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub retries: u32,
+    pub jitter: Duration,
+    pub on_failure: FailurePolicy,
+    pub persistent: bool,
+    pub options: ConnectOptions,
+    /// The apcupsd.conf of an apcupsd on this host, if there is one
+    pub daemon: Option<DaemonConfig>,
+}
+
+impl Defaults {
+    pub fn from_env() -> Self {
+        let seconds = |name: &str, default: u64| {
+            std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+        };
+        Defaults {
+            interval: Duration::from_secs(seconds("INTERVAL", 10)),
+            timeout: Duration::from_secs(seconds("TIMEOUT", 15)),
+            retries: std::env::var("NIS_RETRIES").ok().and_then(|v| v.parse().ok()).unwrap_or(0),
+            jitter: std::env::var("POLL_JITTER").ok().and_then(|v| parse_duration(&v)).unwrap_or_default(),
+            on_failure: match std::env::var("ON_FAILURE") {
+                Ok(value) => FailurePolicy::parse(&value).unwrap_or_else(|| {
+                    error!("Ignoring ON_FAILURE, {:?} is not hold, clear or hold_for: <duration>", value);
+                    FailurePolicy::Hold
+                }),
+                Err(_) => FailurePolicy::Hold,
+            },
+            persistent: std::env::var("NIS_PERSISTENT")
+                .map(|v| matches!(v.as_str(), "1" | "true" | "yes"))
+                .unwrap_or(false),
+            options: ConnectOptions::from_env(),
+            daemon: DaemonConfig::from_env(),
+        }
+    }
+}
+
+/// A host to poll, with its settings resolved
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    pub name: String,
+    pub host: String,
+    pub port: u16,
+    pub interval: Duration,
+    pub timeout: Duration,
+    pub retries: u32,
+    /// Most a poll is randomly delayed by, at most the interval
+    pub jitter: Duration,
+    pub labels: BTreeMap<String, String>,
+    pub on_failure: FailurePolicy,
+}
+
+impl Target {
+    /// A NIS client for the target. Its timeout is rounded up to whole
+    /// seconds, as the client counts it.
+    pub fn client(&self, defaults: &Defaults) -> NisClient {
+        let timeout = self.timeout.as_secs() + u64::from(self.timeout.subsec_nanos() > 0);
+        let mut client = NisClient::new(&self.host, self.port, timeout.max(1), defaults.persistent);
+        client.options = defaults.options.clone();
+        client
+    }
+
+    /// Longest a poll may take before it counts as stuck: the interval and
+    /// jitter, plus connecting and reading for every attempt.
+    pub fn max_poll_age(&self) -> Duration {
+        self.interval + self.jitter + 2 * self.timeout * (self.retries + 1)
+    }
+}
+
+/// How a running target's polls have gone
+#[derive(Debug, Clone, Default)]
+pub struct PollState {
+    /// When the last poll finished
+    pub last_poll: Option<SystemTime>,
+    pub last_duration: Option<Duration>,
+    pub last_success: Option<SystemTime>,
+    /// Why the last poll failed, none if it succeeded
+    pub last_error: Option<String>,
+    /// Failed polls since the last successful one
+    pub failures: u32,
+}
+
+/// A running target, where it came from, and how its polls have gone
+#[derive(Debug, Clone)]
+#[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+pub struct ActiveTarget {
+    pub target: Target,
+    /// The config file, or the discovery that found the target
+    pub source: &'static str,
+    /// When the target started being polled
+    pub since: SystemTime,
+    pub state: PollState,
+}
+
+#[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+impl ActiveTarget {
+    /// `up` after a successful poll, `down` after a failed one, and
+    /// `unknown` before the first, as on Prometheus' targets page
+    pub fn health(&self) -> &'static str {
+        match (&self.state.last_poll, &self.state.last_error) {
+            (None, _) => "unknown",
+            (Some(_), None) => "up",
+            (Some(_), Some(_)) => "down",
+        }
+    }
+
+    /// How long ago a poll last succeeded, or the target was added if none
+    /// has yet
+    pub fn success_age(&self) -> Duration {
+        self.state.last_success.unwrap_or(self.since).elapsed().unwrap_or_default()
+    }
+
+    pub fn to_json(&self, max_failures: Option<u32>) -> serde_json::Value {
+        let unix = |time: &Option<SystemTime>| {
+            time.map(|t| t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default().as_secs())
+        };
+        serde_json::json!({
+            "name": self.target.name,
+            "source": self.source,
+            "host": self.target.host,
+            "port": self.target.port,
+            "labels": self.target.labels,
+            "interval": self.target.interval.as_secs_f64(),
+            "health": self.health(),
+            "last_poll": unix(&self.state.last_poll),
+            "last_duration": self.state.last_duration.map(|d| d.as_secs_f64()),
+            "last_success": unix(&self.state.last_success),
+            "last_error": self.state.last_error,
+            "consecutive_failures": self.state.failures,
+            "max_consecutive_failures": max_failures,
+        })
+    }
+}
+
+/// The targets being polled, kept by the fleet, told about every poll by
+/// the pollers, and listed by `/api/v1/targets`
+#[derive(Debug, Default)]
+pub struct ActiveTargets {
+    targets: Mutex<BTreeMap<String, ActiveTarget>>,
+}
+
+impl ActiveTargets {
+    pub fn insert(&self, source: &'static str, target: Target) {
+        let active = ActiveTarget { target, source, since: SystemTime::now(), state: PollState::default() };
+        self.lock().insert(active.target.name.clone(), active);
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.lock().remove(name);
+    }
+
+    /// Note the outcome of a poll of the target, if it's still running
+    pub fn record(&self, name: &str, duration: Duration, error: Option<String>) {
+        if let Some(active) = self.lock().get_mut(name) {
+            let now = SystemTime::now();
+            let state = &mut active.state;
+            state.last_poll = Some(now);
+            state.last_duration = Some(duration);
+            match error {
+                Some(_) => state.failures += 1,
+                None => {
+                    state.last_success = Some(now);
+                    state.failures = 0;
+                }
+            }
+            state.last_error = error;
+        }
+    }
+
+    /// The running targets, by name
+    #[cfg_attr(not(any(feature = "http", feature = "http-lite")), allow(dead_code))]
+    pub fn list(&self) -> Vec<ActiveTarget> {
+        self.lock().values().cloned().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, ActiveTarget>> {
+        self.targets.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The targets from the config file, or the single host from
+/// `APCUPSD_HOST` and `APCUPSD_PORT` if it has none. Without `APCUPSD_HOST`,
+/// the NISIP, NISPORT and UPSNAME of a local apcupsd.conf fill in for them.
+/// Names must be unique.
+pub fn resolve(configured: &[TargetConfig], defaults: &Defaults) -> Result<Vec<Target>, String> {
+    if configured.is_empty() {
+        let explicit = std::env::var("APCUPSD_HOST").ok();
+        let local = defaults.daemon.as_ref().filter(|_| explicit.is_none());
+        let host = explicit
+            .or_else(|| local.and_then(DaemonConfig::host).map(str::to_string))
+            .unwrap_or_else(|| "localhost".to_string());
+        let port = std::env::var("APCUPSD_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok())
+            .or(local.and_then(|local| local.nis_port))
+            .unwrap_or(3551);
+        let mut target = TargetConfig::new(&host, port).resolve(defaults);
+        target.name = local.and_then(|local| local.upsname.clone()).unwrap_or(host);
+        return Ok(vec![target]);
+    }
+    let mut targets: Vec<Target> = Vec::new();
+    for target in configured.iter().map(|t| t.resolve(defaults)) {
+        if targets.iter().any(|t| t.name == target.name) {
+            return Err(format!("target {:?} is configured twice, give one of them another name", target.name));
+        }
+        targets.push(target);
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_failure_policy() {
+        assert_eq!(FailurePolicy::parse("clear"), Some(FailurePolicy::Clear));
+        assert_eq!(FailurePolicy::parse("hold_for: 60s"), Some(FailurePolicy::HoldFor(Duration::from_secs(60))));
+        assert_eq!(FailurePolicy::parse("hold_for 2m"), Some(FailurePolicy::HoldFor(Duration::from_secs(120))));
+        assert_eq!(FailurePolicy::parse("hold_for"), None);
+        assert_eq!(FailurePolicy::parse("drop"), None);
+        assert!(!FailurePolicy::Hold.clears(Duration::from_secs(3600)));
+        assert!(FailurePolicy::Clear.clears(Duration::ZERO));
+        assert!(!FailurePolicy::HoldFor(Duration::from_secs(60)).clears(Duration::from_secs(59)));
+        assert!(FailurePolicy::HoldFor(Duration::from_secs(60)).clears(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_active_targets() {
+        let active = ActiveTargets::default();
+        active.insert("config", TargetConfig::new("rack1", 3551).resolve(&Defaults::from_env()));
+        assert_eq!(active.list()[0].health(), "unknown");
+
+        active.record("rack1", Duration::from_millis(20), Some("connection refused".to_string()));
+        active.record("rack1", Duration::from_millis(20), Some("connection refused".to_string()));
+        let json = active.list()[0].to_json(Some(5));
+        assert_eq!(json["health"], "down");
+        assert_eq!(json["consecutive_failures"], 2);
+        assert_eq!(json["last_success"], serde_json::Value::Null);
+
+        active.record("rack1", Duration::from_millis(20), None);
+        let json = active.list()[0].to_json(None);
+        assert_eq!(json["health"], "up");
+        assert_eq!(json["consecutive_failures"], 0);
+        assert_eq!(json["last_error"], serde_json::Value::Null);
+
+        active.remove("rack1");
+        active.record("rack1", Duration::from_millis(20), None);
+        assert!(active.list().is_empty());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let config: Config = toml::from_str(
+            r#"
+            [[targets]]
+            host = "rack1"
+            interval = 5
+
+            [[targets]]
+            host = "remote.example.com"
+            port = 3552
+            interval = "1m"
+            timeout = "30s"
+            retries = 2
+            jitter = "2m"
+            on_failure = "hold_for: 90s"
+            "#,
+        )
+        .unwrap();
+        let defaults = Defaults {
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(15),
+            retries: 0,
+            jitter: Duration::from_secs(1),
+            on_failure: FailurePolicy::Hold,
+            persistent: false,
+            options: ConnectOptions::default(),
+            daemon: None,
+        };
+        let targets = resolve(&config.targets, &defaults).unwrap();
+        assert_eq!(targets[0].name, "rack1");
+        assert_eq!(targets[0].interval, Duration::from_secs(5));
+        assert_eq!(targets[0].timeout, Duration::from_secs(15));
+        assert_eq!(targets[0].jitter, Duration::from_secs(1));
+        assert_eq!(targets[1].name, "remote.example.com:3552");
+        assert_eq!(targets[1].interval, Duration::from_secs(60));
+        assert_eq!(targets[1].retries, 2);
+        // Jitter is capped at the interval
+        assert_eq!(targets[1].jitter, Duration::from_secs(60));
+        assert_eq!(targets[1].max_poll_age(), Duration::from_secs(60 + 60 + 2 * 30 * 3));
+        assert_eq!(targets[1].client(&defaults).timeout, 30);
+        assert_eq!(targets[0].on_failure, FailurePolicy::Hold);
+        assert_eq!(targets[1].on_failure, FailurePolicy::HoldFor(Duration::from_secs(90)));
+
+        assert!(toml::from_str::<Config>("[[targets]]\nhost = \"a\"\ninterval = \"soon\"\n").is_err());
+        assert!(toml::from_str::<Config>("[[targets]]\nhost = \"a\"\non_failure = \"drop\"\n").is_err());
+        let twice: Config = toml::from_str("[[targets]]\nhost = \"a\"\n\n[[targets]]\nhost = \"a\"\nport = 3551\n").unwrap();
+        assert!(resolve(&twice.targets, &defaults).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+//! textfile.rs
+//!
+//! Writes the metrics to a `.prom` file for node_exporter's textfile collector,
+//! as an alternative to serving them over HTTP.
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use prometheus::{Encoder, Registry, TextEncoder};
+
+pub struct TextfileWriter {
+    registry: Registry,
+    path: PathBuf,
+    /// Write once and exit instead of polling
+    pub oneshot: bool,
+}
+
+impl TextfileWriter {
+    /// Build the writer from `TEXTFILE_*` environment variables. Returns
+    /// `None` unless `TEXTFILE_DIR` is set.
+    pub fn from_env(registry: &Registry) -> Option<Self> {
+        let dir = std::env::var("TEXTFILE_DIR").ok()?;
+        let name = std::env::var("TEXTFILE_NAME").unwrap_or_else(|_| "apcupsd.prom".to_string());
+        let oneshot = std::env::var("TEXTFILE_ONESHOT")
+            .map(|v| matches!(v.as_str(), "1" | "true" | "yes"))
+            .unwrap_or(false);
+        Some(TextfileWriter {
+            registry: registry.clone(),
+            path: Path::new(&dir).join(name),
+            oneshot,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Encode the registry and replace the file atomically, so the collector
+    /// never reads a partially written file.
+    pub fn write(&self) -> std::io::Result<()> {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .map_err(std::io::Error::other)?;
+
+        // node_exporter only reads files ending in .prom, so the temporary
+        // file in the same directory is never picked up.
+        let tmp = self.path.with_extension("prom.tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&buffer)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{IntGauge, Opts};
+
+    #[test]
+    fn test_write() {
+        let registry = Registry::new();
+        let gauge = IntGauge::with_opts(Opts::new("apcupsd_test", "test gauge")).unwrap();
+        registry.register(Box::new(gauge.clone())).unwrap();
+        gauge.set(42);
+
+        let dir = std::env::temp_dir().join(format!("rsapcupsdexporter-textfile-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let writer = TextfileWriter {
+            registry,
+            path: dir.join("apcupsd.prom"),
+            oneshot: false,
+        };
+        writer.write().unwrap();
+
+        let contents = fs::read_to_string(writer.path()).unwrap();
+        assert!(contents.contains("apcupsd_test 42"));
+        assert!(!dir.join("apcupsd.prom.tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
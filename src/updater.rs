@@ -0,0 +1,362 @@
+//! updater.rs
+//!
+//! Applies snapshots from the pollers to everything that consumes them: the
+//! gauges, the shared state, the textfile, the push sinks and the event
+//! detector. A single task owns all of it, so none of it needs locking.
+
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tracing::{debug, error, info, info_span, Span};
+
+use crate::alerts::Alerts;
+use crate::battery::BatteryTracker;
+use crate::energy::EnergyMeter;
+use crate::events::EventDetector;
+use crate::history::memory::MemoryHistory;
+use crate::metrics::{Distributions, PollMetrics, TargetRegistries, TemperatureUnit, UpsMetrics};
+use crate::notify::silence::Silences;
+use crate::notify::Dispatcher;
+use crate::sinks::{self, Sink};
+use crate::snapshot::Snapshot;
+use crate::textfile::TextfileWriter;
+use crate::AppState;
+
+/// Snapshots that may be waiting for the updater before pollers block
+pub const QUEUE_SIZE: usize = 16;
+
+/// What the pollers and the fleet of them send the updater
+pub enum Update {
+    /// A poll result, applied within the poll's span
+    Polled(Snapshot, Span),
+    /// A target is about to be polled, its gauges get these labels
+    Added(String, BTreeMap<String, String>),
+    /// A target's polls have failed for longer than its `on_failure` holds
+    /// its values, its gauges are dropped until it's polled again
+    Cleared(String),
+    /// A target's poller was stopped, its gauges go too
+    Removed(String),
+}
+
+pub struct Updater {
+    /// The gauges of each target, by name
+    pub metrics: HashMap<String, UpsMetrics>,
+    /// Where the gauges of added targets are registered
+    pub registries: TargetRegistries,
+    /// The histograms the gauges of added targets come with
+    pub distributions: Distributions,
+    pub state: Arc<AppState>,
+    pub silences: Arc<Silences>,
+    pub textfile: Option<TextfileWriter>,
+    pub sinks: Vec<Box<dyn Sink>>,
+    pub detector: EventDetector,
+    /// The threshold alerts of the config file
+    pub alerts: Alerts,
+    pub dispatcher: Dispatcher,
+    /// Recent values for `/api/v1/history` and the rolling gauges
+    pub history: Option<Arc<MemoryHistory>>,
+    /// `ROLLING_WINDOW`, if the rolling gauges are wanted
+    pub rolling: Option<Duration>,
+    /// `TEMPERATURE_UNITS`, the units of the temperature gauges
+    pub temperatures: Vec<TemperatureUnit>,
+    /// `STRICT`, whether keys nothing is exported for are reported
+    pub strict: bool,
+    /// Estimates of the batteries' wear and remaining time
+    pub battery: BatteryTracker,
+    /// The energy delivered, and what it cost
+    pub energy: EnergyMeter,
+    /// Where updates that panic are counted
+    pub poll_metrics: PollMetrics,
+}
+
+impl Updater {
+    /// Apply one snapshot.
+    pub fn handle(&mut self, snapshot: Snapshot) {
+        if let Some(history) = &self.history {
+            history.record(&snapshot);
+        }
+        let (alerts, alert_events) = self.alerts.evaluate(&snapshot);
+        let estimates = self.battery.update(&snapshot);
+        let metered = self.energy.update(&snapshot);
+        match self.metrics.get_mut(&snapshot.host) {
+            Some(metrics) => {
+                metrics.update(&snapshot.stats);
+                if let (Some(history), Some(window)) = (&self.history, self.rolling) {
+                    metrics.update_rolling(history, window, snapshot.unix_timestamp());
+                }
+                metrics.update_alerts(&alerts);
+                metrics.update_temperatures(&snapshot.stats, &self.temperatures);
+                if self.strict {
+                    metrics.update_unhandled(&snapshot.stats);
+                }
+                if let Some(skew) = snapshot.clock_skew() {
+                    metrics.set_derived(
+                        "apcupsd_clock_skew_seconds",
+                        "apcupsd's DATE minus the exporter's time when it was polled",
+                        skew,
+                    );
+                }
+                if let Some(health) = estimates.health {
+                    metrics.set_derived(
+                        "apcupsd_battery_health_ratio",
+                        "Estimated battery capacity as a fraction of a new battery's",
+                        health,
+                    );
+                }
+                match estimates.until_shutdown {
+                    Some(seconds) => metrics.set_derived(
+                        "apcupsd_estimated_seconds_until_shutdown",
+                        "Estimated seconds until apcupsd shuts down, while on battery",
+                        seconds,
+                    ),
+                    None => metrics.remove_derived("apcupsd_estimated_seconds_until_shutdown"),
+                }
+                for (suffix, seconds) in [("24h", estimates.on_battery_24h), ("7d", estimates.on_battery_7d)] {
+                    metrics.set_derived(
+                        &format!("apcupsd_time_on_battery_seconds_{}", suffix),
+                        &format!("Seconds on battery over the last {}, as seen by the exporter", suffix),
+                        seconds,
+                    );
+                }
+                if let Some(seconds) = estimates.last_outage {
+                    metrics.set_derived(
+                        "apcupsd_last_on_battery_duration_seconds",
+                        "Seconds the last time on battery lasted",
+                        seconds,
+                    );
+                }
+                if let Some(metered) = &metered {
+                    metrics.inc_derived(
+                        "apcupsd_energy_joules_total",
+                        "Energy delivered by the UPS, from LOADPCT and NOMPOWER between polls",
+                        metered.joules,
+                    );
+                    if let Some(cost) = metered.cost {
+                        metrics.inc_derived(
+                            "apcupsd_estimated_cost_total",
+                            "Estimated cost of the energy delivered by the UPS, at the electricity price",
+                            cost,
+                        );
+                    }
+                }
+            }
+            None => debug!("No gauges for target {}, skipping them", snapshot.host),
+        }
+        let latest = Arc::new(snapshot.clone());
+        self.state.snapshots.rcu(|snapshots| {
+            let mut snapshots = HashMap::clone(snapshots);
+            snapshots.insert(snapshot.host.clone(), Arc::clone(&latest));
+            snapshots
+        });
+        if let Some(writer) = &self.textfile
+            && let Err(e) = writer.write()
+        {
+            error!("Failed to write metrics to {}: {}", writer.path().display(), e);
+        }
+        self.silences.refresh(&snapshot.host);
+        sinks::publish_all(&mut self.sinks, &snapshot);
+        if metered.is_some()
+            && let Some(total) = self.energy.total(&snapshot.host)
+        {
+            sinks::record_energy_all(&mut self.sinks, &snapshot.host, total);
+        }
+        let mut events = self.detector.detect(&snapshot);
+        events.extend(alert_events);
+        sinks::record_events_all(&mut self.sinks, &events);
+        self.dispatcher.dispatch(events);
+    }
+
+    /// Create the gauges of a new target. Those of a target that's already
+    /// known, such as the only one, are kept.
+    fn add(&mut self, target: String, labels: BTreeMap<String, String>) {
+        if self.metrics.contains_key(&target) {
+            return;
+        }
+        let errors = self.state.metric_errors.clone();
+        let created = UpsMetrics::for_target(&self.registries, errors, &target, &labels)
+            .and_then(|metrics| metrics.with_distributions(&self.distributions))
+            .and_then(|metrics| metrics.with_alerts(self.alerts.rules()));
+        match created {
+            Ok(metrics) => {
+                self.metrics.insert(target, metrics);
+            }
+            Err(e) => {
+                error!("Failed to register the metrics of target {}: {}", target, e);
+                self.state.metric_errors.record("register");
+            }
+        }
+    }
+
+    /// Apply updates until every poller has stopped, each snapshot in the
+    /// span of the poll that produced it.
+    pub async fn run(mut self, mut receiver: mpsc::Receiver<Update>) {
+        while let Some(update) = receiver.recv().await {
+            self.apply(update);
+        }
+    }
+
+    /// Apply one update. One that panics, e.g. in a sink, is counted and
+    /// skipped, so the updates of every target carry on rather than the
+    /// pollers stopping with the updater.
+    pub fn apply(&mut self, update: Update) {
+        // The panic hook has already logged the message and backtrace
+        if panic::catch_unwind(AssertUnwindSafe(|| self.process(update))).is_err() {
+            self.poll_metrics.record_update_panic();
+            error!("Applying an update panicked, continuing with the next one");
+        }
+    }
+
+    fn process(&mut self, update: Update) {
+        match update {
+            Update::Polled(snapshot, poll) => info_span!(parent: &poll, "update").in_scope(|| self.handle(snapshot)),
+            Update::Added(target, labels) => self.add(target, labels),
+            Update::Cleared(target) => {
+                if let Some(metrics) = self.metrics.get_mut(&target) {
+                    info!("Dropping the gauges of {} until it can be polled again", target);
+                    metrics.clear();
+                }
+            }
+            Update::Removed(target) => {
+                if let Some(metrics) = self.metrics.remove(&target) {
+                    metrics.unregister();
+                }
+                if let Some(history) = &self.history {
+                    history.remove(&target);
+                }
+                self.alerts.remove(&target);
+                self.battery.remove(&target);
+                self.energy.remove(&target);
+                self.silences.forget(&target);
+                self.state.snapshots.rcu(|snapshots| {
+                    let mut snapshots = HashMap::clone(snapshots);
+                    snapshots.remove(&target);
+                    snapshots
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notify::{routes::Router, throttle::Throttle};
+    use crate::metrics::MetricErrors;
+    use arc_swap::ArcSwap;
+    use prometheus::Registry;
+    use std::collections::BTreeMap;
+
+    fn snapshot(status: &str, bcharge: &str) -> Snapshot {
+        let stats = BTreeMap::from([
+            ("STATUS".to_string(), status.to_string()),
+            ("BCHARGE".to_string(), bcharge.to_string()),
+        ]);
+        Snapshot::new("localhost", stats)
+    }
+
+    fn value(registry: &Registry, name: &str) -> Option<f64> {
+        let family = registry.gather().into_iter().find(|f| f.get_name() == name)?;
+        Some(family.get_metric()[0].get_gauge().get_value())
+    }
+
+    /// An updater without targets, sinks or notifiers
+    fn updater(registry: &Registry) -> (Updater, Arc<AppState>) {
+        let state = Arc::new(AppState {
+            registry: registry.clone(),
+            snapshots: ArcSwap::default(),
+            metric_errors: MetricErrors::new(registry).unwrap(),
+            severities: Default::default(),
+        });
+        let updater = Updater {
+            metrics: HashMap::new(),
+            registries: TargetRegistries::new(registry).unwrap(),
+            distributions: Distributions::default(),
+            state: Arc::clone(&state),
+            silences: Arc::new(Silences::new(Vec::new(), registry).unwrap()),
+            textfile: None,
+            sinks: Vec::new(),
+            detector: EventDetector::default(),
+            alerts: Alerts::default(),
+            dispatcher: Dispatcher::start(Vec::new(), 0, Router::default(), Throttle::default()),
+            history: None,
+            rolling: None,
+            temperatures: vec![TemperatureUnit::Celsius],
+            strict: false,
+            battery: BatteryTracker::default(),
+            energy: EnergyMeter::default(),
+            poll_metrics: PollMetrics::new(registry).unwrap(),
+        };
+        (updater, state)
+    }
+
+    #[tokio::test]
+    async fn test_updates_from_channel() {
+        let registry = Registry::new();
+        let (mut updater, state) = updater(&registry);
+        let metrics = UpsMetrics::new(&updater.registries, state.metric_errors.clone(), "localhost").unwrap();
+        updater.metrics.insert("localhost".to_string(), metrics);
+
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        sender.send(Update::Polled(snapshot("ONLINE", "100.0"), Span::none())).await.unwrap();
+        sender.send(Update::Polled(snapshot("ONBATT", "97.0"), Span::none())).await.unwrap();
+        drop(sender);
+        updater.run(receiver).await;
+
+        assert_eq!(value(&registry, "apcupsd_bcharge"), Some(97.0));
+        assert_eq!(state.snapshots.load()["localhost"].stats["STATUS"], "ONBATT");
+    }
+
+    #[tokio::test]
+    async fn test_targets_come_and_go() {
+        let registry = Registry::new();
+        let (updater, _) = updater(&registry);
+
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        sender.send(Update::Added("localhost".to_string(), BTreeMap::new())).await.unwrap();
+        sender.send(Update::Polled(snapshot("ONLINE", "100.0"), Span::none())).await.unwrap();
+        sender.send(Update::Removed("localhost".to_string())).await.unwrap();
+        drop(sender);
+        updater.run(receiver).await;
+
+        assert_eq!(value(&registry, "apcupsd_bcharge"), None);
+    }
+
+    /// Panics on the first snapshot it's given
+    struct FaultySink {
+        published: usize,
+    }
+
+    impl Sink for FaultySink {
+        fn name(&self) -> &'static str {
+            "faulty"
+        }
+
+        fn publish(&mut self, _snapshot: &Snapshot) -> Result<(), sinks::SinkError> {
+            self.published += 1;
+            assert!(self.published > 1, "first snapshot");
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panic_does_not_stop_updates() {
+        let registry = Registry::new();
+        let (mut updater, state) = updater(&registry);
+        updater.sinks.push(Box::new(FaultySink { published: 0 }));
+
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        sender.send(Update::Added("localhost".to_string(), BTreeMap::new())).await.unwrap();
+        sender.send(Update::Polled(snapshot("ONLINE", "100.0"), Span::none())).await.unwrap();
+        sender.send(Update::Polled(snapshot("ONBATT", "97.0"), Span::none())).await.unwrap();
+        drop(sender);
+        updater.run(receiver).await;
+
+        let panics = registry.gather().into_iter().find(|f| f.get_name() == "apcupsd_exporter_update_panics_total");
+        assert_eq!(panics.unwrap().get_metric()[0].get_counter().get_value(), 1.0);
+        assert_eq!(value(&registry, "apcupsd_bcharge"), Some(97.0));
+        assert_eq!(state.snapshots.load()["localhost"].stats["STATUS"], "ONBATT");
+    }
+}
@@ -0,0 +1,114 @@
+//! watch.rs
+//!
+//! The `watch` subcommand: a terminal view of the key UPS values that
+//! refreshes in place, with sparklines of their recent history, e.g. to
+//! follow a generator test.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use apcaccess::NisClient;
+use crate::status;
+
+/// Samples kept per sparkline
+const HISTORY: usize = 60;
+
+/// The values that get a sparkline
+const KEYS: &[&str] = &["BCHARGE", "LOADPCT", "TIMELEFT", "LINEV"];
+
+const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// Clear the screen and move the cursor to the top left
+const CLEAR: &str = "\x1b[2J\x1b[H";
+
+/// Draw values as bars scaled between their minimum and maximum. Missing
+/// samples are left blank.
+pub fn sparkline(values: &VecDeque<Option<f64>>) -> String {
+    let known = values.iter().flatten();
+    let min = known.clone().copied().fold(f64::INFINITY, f64::min);
+    let max = known.copied().fold(f64::NEG_INFINITY, f64::max);
+    values
+        .iter()
+        .map(|value| match value {
+            None => ' ',
+            // A flat line sits in the middle
+            Some(_) if max <= min => BARS[BARS.len() / 2],
+            Some(v) => BARS[(((v - min) / (max - min)) * (BARS.len() - 1) as f64).round() as usize],
+        })
+        .collect()
+}
+
+struct Watch {
+    history: BTreeMap<&'static str, VecDeque<Option<f64>>>,
+}
+
+impl Watch {
+    fn new() -> Self {
+        Watch {
+            history: KEYS.iter().map(|&key| (key, VecDeque::with_capacity(HISTORY))).collect(),
+        }
+    }
+
+    fn record(&mut self, stats: Option<&BTreeMap<String, String>>) {
+        for (key, history) in &mut self.history {
+            if history.len() == HISTORY {
+                history.pop_front();
+            }
+            history.push_back(stats.and_then(|s| s.get(*key)).and_then(|v| v.parse().ok()));
+        }
+    }
+
+    fn render(&self, client: &NisClient, stats: &Result<BTreeMap<String, String>, String>) -> String {
+        let mut out = String::new();
+        let get = |key: &str| stats.as_ref().ok().and_then(|s| s.get(key)).map(String::as_str);
+        out.push_str(&format!(
+            "{} ({}:{})  {}\n\n",
+            get("UPSNAME").unwrap_or("-"),
+            client.host,
+            client.port,
+            get("DATE").unwrap_or("")
+        ));
+        out.push_str(&format!("{:<16}{}\n", "Status", get("STATUS").unwrap_or("-")));
+        for &key in KEYS {
+            let label = status::lookup(key).map_or(key, |f| f.label);
+            let value = get(key).map_or_else(|| "-".to_string(), |v| status::format_value(key, v));
+            out.push_str(&format!("{:<16}{:<12}{}\n", label, value, sparkline(&self.history[key])));
+        }
+        if let Err(e) = stats {
+            out.push_str(&format!("\n{}\n", e));
+        }
+        out
+    }
+}
+
+/// Poll and redraw every `every` until interrupted. Failed polls are shown
+/// and leave a gap in the sparklines.
+pub fn run(client: &mut NisClient, every: Duration) -> Result<(), String> {
+    let mut watch = Watch::new();
+    let mut stdout = std::io::stdout();
+    loop {
+        let stats = client.fetch_stats(true).map_err(|e| format!("Failed to fetch APC UPS stats: {}", e));
+        watch.record(stats.as_ref().ok());
+        let screen = watch.render(client, &stats);
+        write!(stdout, "{}{}", CLEAR, screen)
+            .and_then(|_| stdout.flush())
+            .map_err(|e| e.to_string())?;
+        thread::sleep(every);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sparkline() {
+        let values = VecDeque::from([Some(0.0), Some(50.0), None, Some(100.0)]);
+        assert_eq!(sparkline(&values), "▁▅ █");
+        let flat = VecDeque::from([Some(230.0), Some(230.0)]);
+        assert_eq!(sparkline(&flat), "▅▅");
+        assert_eq!(sparkline(&VecDeque::new()), "");
+    }
+}